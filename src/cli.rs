@@ -1,22 +1,81 @@
-use bson::to_bson;
+use chrono::{NaiveDate, Utc};
 use clap::Parser;
+use lazy_static::lazy_static;
 use log::{debug, error, info, warn};
-use mongodb::Database;
 use snafu::ResultExt;
-use std::{path::PathBuf, time::Duration};
-use tokio::{process::Command, time::timeout};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 
 use crate::{
-    command::{self, migrate::DB_VERSION},
-    config, error,
-    model::BowerbirdMetadata,
+    command::{
+        self,
+        export::{ExportFormat, GraphFormat},
+    },
+    config::{self, LogTimeFormat, PartialPolicy, UgoiraFormat},
+    error,
+    sync::{
+        self, CancellationToken, IdImportStatus, PageStart, PixivSyncKind, PixivSyncParams,
+        ProgressWriter, UserRef,
+    },
+    utils::{set_log_levels, LogLevels},
 };
 
+/// The command completed.
+pub const EXIT_SUCCESS: i32 = 0;
+/// An error not covered by the other codes.
+pub const EXIT_ERROR: i32 = 1;
+/// The command completed, but some downloads failed.
+pub const EXIT_TASKS_FAILED: i32 = 2;
+/// The config cannot be loaded or is invalid.
+pub const EXIT_CONFIG: i32 = 3;
+/// Cannot log in with the refresh token.
+pub const EXIT_AUTH: i32 = 4;
+/// The database needs `bowerbird migrate`.
+pub const EXIT_MIGRATION_REQUIRED: i32 = 5;
+/// Stopped by `--max-runtime`. The works saved so far are kept, the next run continues.
+pub const EXIT_TIME_LIMITED: i32 = 6;
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    success
+    1    error
+    2    completed with some downloads failed
+    3    invalid config
+    4    pixiv login failed
+    5    database migration required
+    6    stopped by --max-runtime";
+
 #[derive(Parser)]
-#[clap(version)]
+#[clap(version, after_help = EXIT_CODES_HELP)]
 struct Main {
     #[clap(short, long)]
     config: Option<String>,
+    /// Use this proxy for all requests, taking precedence over the proxies in the config.
+    /// Supports `http://`, `https://`, `socks5://` and `socks5h://`.
+    #[clap(long)]
+    proxy: Option<String>,
+    /// Use this ffmpeg instead of the one in the config, for this run only.
+    #[clap(long)]
+    ffmpeg_path: Option<String>,
+    /// Use this aria2c instead of the one in the config, for this run only.
+    #[clap(long)]
+    aria2_path: Option<String>,
+    /// Write newline-delimited JSON progress events to this file descriptor.
+    /// Use `1` for stdout. Logs are always written to stderr, so `2` is refused like `0`.
+    #[clap(long)]
+    progress_fd: Option<i32>,
+    /// Log more, `-v` for info of every module, `-vv` for debug and `-vvv` for trace.
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u64,
+    /// The log level of each module like `RUST_LOG`, e.g. `downloader=debug,mongodb=off`.
+    /// Applied after `-v`. The modules of bowerbird can be written without `bowerbird::`.
+    #[clap(long, parse(try_from_str = parse_log_directives))]
+    log: Option<String>,
     #[clap(subcommand)]
     subcommand: SubcommandMain,
 }
@@ -24,17 +83,211 @@ struct Main {
 #[derive(Parser)]
 enum SubcommandMain {
     Pixiv(Pixiv),
-    Init,
+    /// Create the config with the defaults, asking for the essentials. Never overwrites it.
+    Init(Init),
     Migrate,
     Serve,
+    Export(Export),
+    TagGraph(TagGraph),
+    Import(Import),
+    Backup(Backup),
+    Verify(Verify),
+    /// Transcode the stored JPEG and PNG originals to WebP or AVIF to save space.
+    /// The originals are saved in the database and `compact-manifest.jsonl` in the root storage dir.
+    Compact(Compact),
+    /// Check the config, MongoDB, ffmpeg, aria2, the storage dir and the pixiv login.
+    Doctor,
+}
+
+#[derive(Parser)]
+struct Init {
+    /// Use this instead of asking for it.
+    #[clap(long)]
+    mongodb_uri: Option<String>,
+    /// Use this instead of asking for it. Relative to the directory of the config.
+    #[clap(long)]
+    root_storage_dir: Option<String>,
+    /// Use the defaults for everything not given, without asking.
+    #[clap(short, long)]
+    yes: bool,
+}
+
+#[derive(Parser)]
+struct Verify {
+    /// Only check this percentage of the files, e.g. `5%`.
+    #[clap(long, parse(try_from_str = parse_percent))]
+    sample: Option<f64>,
+    /// Save the progress to this file, and continue from it if it exists.
+    /// Defaults to `verify-checkpoint.json` in the root storage dir.
+    #[clap(long)]
+    checkpoint: Option<PathBuf>,
+    /// Number of files checked at the same time.
+    #[clap(long, default_value = "16")]
+    io_concurrency: usize,
+    /// Save the hashes of the files without one, so they are checked next time.
+    #[clap(long)]
+    save_hashes: bool,
+}
+
+#[derive(Parser)]
+struct Compact {
+    #[clap(long, arg_enum, default_value = "webp")]
+    format: command::compact::CompactFormat,
+    /// Keep every pixel, ignoring `--quality`.
+    #[clap(long)]
+    lossless: bool,
+    /// From 1 to 100.
+    #[clap(long, default_value = "90")]
+    quality: u8,
+    /// Save the progress to this file, and continue from it if it exists.
+    /// Defaults to `compact-checkpoint.json` in the root storage dir.
+    #[clap(long)]
+    checkpoint: Option<PathBuf>,
+    /// Number of files transcoded at the same time. Defaults to the number of CPUs.
+    #[clap(long)]
+    jobs: Option<usize>,
+    /// Transcode the compacted files back to the formats of their originals.
+    #[clap(long, conflicts_with_all = &["format", "lossless", "quality"])]
+    revert: bool,
+}
+
+#[derive(Parser)]
+struct Backup {
+    /// Create the timestamped backup directory in this directory.
+    /// Defaults to `backups` in the root storage dir.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Only dump the database, without the manifest of media files.
+    #[clap(long)]
+    exclude_media: bool,
+    #[clap(subcommand)]
+    subcommand: Option<SubcommandBackup>,
+}
+
+#[derive(Parser)]
+enum SubcommandBackup {
+    /// Stream the media files as a `.tar.gz` with a manifest, e.g. to pipe to remote storage.
+    Media(BackupMedia),
+}
+
+#[derive(Parser)]
+struct BackupMedia {
+    /// Write to this file instead of stdout.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Leave out the files of unfinished downloads, e.g. `.part` and `.aria2`.
+    #[clap(long)]
+    exclude_transient: bool,
+}
+
+#[derive(Parser)]
+struct Import {
+    /// The collection to import to, e.g. `pixiv_illust`.
+    collection: String,
+    /// Read from this file instead of stdin.
+    #[clap(short, long)]
+    input: Option<PathBuf>,
+    /// Report the counts and conflicts without writing to the database.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// Export how often the tags of illusts appear together.
+#[derive(Parser)]
+struct TagGraph {
+    #[clap(long, arg_enum, default_value = "csv")]
+    format: GraphFormat,
+    /// Leave out the pairs of tags appearing together in fewer illusts.
+    #[clap(long, default_value = "2")]
+    min_count: u32,
+    /// Write to this file instead of stdout.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct Export {
+    /// The collection to export, e.g. `pixiv_illust`.
+    collection: String,
+    #[clap(long, arg_enum, default_value = "json")]
+    format: ExportFormat,
+    /// Write to this file instead of stdout.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
 }
 
 #[derive(Parser)]
 struct Pixiv {
     #[clap(short, long)]
     limit: Option<u32>,
-    #[clap(short, long)]
-    user_id: Option<i32>,
+    /// The user id, profile URL, or account name of a user saved before.
+    #[clap(short, long, parse(try_from_str = UserRef::parse))]
+    user_id: Option<UserRef>,
+    /// Download to this directory instead of the configured storage dir.
+    #[clap(long)]
+    output_dir: Option<PathBuf>,
+    /// Transcode ugoira to these formats instead of the configured ones,
+    /// e.g. `mp4,webm`. Requires ffmpeg.
+    #[clap(long, arg_enum, use_value_delimiter = true)]
+    ugoira_format: Vec<UgoiraFormat>,
+    /// Only download illusts with any of these tags, e.g. `風景,landscape`.
+    /// Both the original and translated tag names are matched.
+    #[clap(long, use_value_delimiter = true)]
+    include_tags: Vec<String>,
+    /// Do not download illusts with any of these tags. Takes precedence over `--include-tags`.
+    #[clap(long, use_value_delimiter = true)]
+    exclude_tags: Vec<String>,
+    /// Skip illusts larger than this in total, e.g. `200M`. Only the files not downloaded
+    /// yet count. The skipped illusts are marked with `skipped_too_large` in the database
+    /// until downloaded.
+    #[clap(long, parse(try_from_str = parse_size))]
+    max_illust_size: Option<u64>,
+    /// Only download the first pages of the illusts with more pages, e.g. `50`.
+    /// The truncated illusts are marked with `truncated_pages` in the database.
+    /// Defaults to the config, which is unlimited.
+    #[clap(long)]
+    max_pages: Option<usize>,
+    /// Only download the illusts with this number of pages, e.g. `1`, `2-`, `-10` or `2-10`.
+    /// The others are still saved to the database.
+    #[clap(long)]
+    page_count: Option<command::pixiv::PageRange>,
+    /// Only download the illusts with at least this number of bookmarks.
+    /// The others are still saved to the database.
+    #[clap(long)]
+    min_bookmarks: Option<u32>,
+    /// Count the illusts skipped by the tags, `--page-count` or `--min-bookmarks`
+    /// towards `--limit`. Only the illusts passing them are counted by default.
+    #[clap(long)]
+    count_filtered: bool,
+    /// Only the works created on or after this day in Japan, e.g. `2024-01-01`.
+    /// The uploads stop once past it, as they are listed from the newest.
+    /// The skipped works are not counted towards `--limit`, and novels are not saved.
+    #[clap(long, parse(try_from_str = parse_date))]
+    since: Option<NaiveDate>,
+    /// Only the works created on or before this day in Japan, e.g. `2024-12-31`.
+    #[clap(long, parse(try_from_str = parse_date))]
+    until: Option<NaiveDate>,
+    /// `strict` downloads the missing pages of partially failed illusts of the synced
+    /// uploads or bookmarks again,
+    /// `lenient` keeps them as they are. Defaults to the config.
+    #[clap(long, arg_enum)]
+    partial_policy: Option<PartialPolicy>,
+    /// Download the existing files again and overwrite them once the new download succeeds.
+    /// Files saved from another URL still follow the collision policy.
+    #[clap(long)]
+    replace: bool,
+    /// Only download the illusts of the users without any illust in the database,
+    /// to fill the gaps of the archive.
+    #[clap(long, conflicts_with = "no-db")]
+    only_new_users: bool,
+    /// Only download the files without connecting to MongoDB.
+    /// The server will not see these files. Illusts only.
+    #[clap(long)]
+    no_db: bool,
+    /// Stop the sync like Ctrl-C after this long, e.g. `30m`, and exit with code 6.
+    /// Supports the `s`, `m` and `h` suffixes, seconds if none.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    max_runtime: Option<Duration>,
     #[clap(subcommand)]
     subcommand: SubcommandPixiv,
 }
@@ -48,7 +301,78 @@ enum SubcommandPixiv {
 #[derive(Parser)]
 struct PixivIllust {
     #[clap(subcommand)]
-    subcommand: SubcommandPixivAction,
+    subcommand: SubcommandPixivIllustAction,
+}
+
+#[derive(Parser)]
+enum SubcommandPixivIllustAction {
+    Bookmarks(PixivIllustBookmarks),
+    Uploads(PixivUploads),
+    ImportIds(PixivImportIds),
+    DownloadById(PixivDownloadById),
+    Ranking(PixivRanking),
+    Search(PixivSearch),
+}
+
+#[derive(Parser)]
+struct PixivIllustBookmarks {
+    #[clap(long)]
+    private: bool,
+    /// Start from this page, logged when a sync stops early.
+    #[clap(long)]
+    start_page_token: Option<String>,
+    /// Continue from the page saved by the last sync, like `pixiv illust uploads --resume`.
+    #[clap(long, conflicts_with = "start-page-token")]
+    resume: bool,
+}
+
+#[derive(Parser)]
+struct PixivUploads {
+    /// Start from this page, logged when a sync stops early.
+    #[clap(long)]
+    start_page_token: Option<String>,
+    /// Continue from the page saved by the last sync of the user, e.g. stopped by `--limit`,
+    /// so the runs with a limit go through all the works instead of the newest ones again.
+    /// The limit counts the works of this run, the filtered ones only with `--count-filtered`.
+    /// The total of the runs is logged, and reset once the last page is done.
+    #[clap(long, conflicts_with = "start-page-token")]
+    resume: bool,
+}
+
+#[derive(Parser)]
+struct PixivImportIds {
+    /// A text file with one illust id per line. Text after `#` is ignored.
+    file: PathBuf,
+}
+
+/// Download a single illust, e.g. `pixiv illust download-by-id 12345678`.
+/// Its metadata is updated if it is already in the database.
+#[derive(Parser)]
+struct PixivDownloadById {
+    id: u64,
+}
+
+/// Download the illusts in a ranking, e.g. `pixiv illust ranking --mode daily --date 2024-01-01`.
+#[derive(Parser)]
+struct PixivRanking {
+    #[clap(long, arg_enum, default_value = "daily")]
+    mode: command::pixiv::RankingMode,
+    /// The last day of the ranking, e.g. `2024-01-01`. Defaults to the latest.
+    #[clap(long, parse(try_from_str = parse_ranking_date))]
+    date: Option<NaiveDate>,
+}
+
+/// Download the illusts found by a search, e.g. `pixiv illust search "東方 10000users入り"`.
+#[derive(Parser)]
+struct PixivSearch {
+    /// The words to search, separated by spaces.
+    word: String,
+    /// `popular_desc` needs a premium account.
+    #[clap(long, arg_enum, default_value = "date_desc")]
+    sort: command::pixiv::SearchSort,
+    /// Match the tags partially or exactly, or the title and the caption.
+    #[clap(long, arg_enum, default_value = "partial")]
+    target: command::pixiv::SearchTarget,
 }
 
 #[derive(Parser)]
@@ -71,217 +395,590 @@ struct PixivBookmarks {
     private: bool,
 }
 
-async fn migrate_guard(db: &Database, fail_if_out_of_date: bool) -> crate::Result<()> {
-    if let Some(metadata) = command::migrate::get_metadata(db).await? {
-        if fail_if_out_of_date && metadata.version < DB_VERSION {
-            return error::MigrationRequired.fail();
-        }
-        if metadata.version > DB_VERSION {
-            return error::DatabaseIsNewer.fail();
-        }
+fn page_start(token: &Option<String>, resume: bool) -> PageStart {
+    match (token, resume) {
+        (Some(token), _) => PageStart::Token(token.clone()),
+        (None, true) => PageStart::Saved,
+        (None, false) => PageStart::First,
+    }
+}
+
+/// Parse a size in bytes with an optional `K`, `M` or `G` suffix in powers of 1024.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let num: u64 = num.parse().map_err(|e| format!("invalid size {s}: {e}"))?;
+    num.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size too large: {s}"))
+}
+
+/// Parse a duration with an optional `s`, `m` or `h` suffix.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (num, unit) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        _ => (s, 1),
+    };
+    let num: u64 = num
+        .parse()
+        .map_err(|e| format!("invalid duration {s}: {e}"))?;
+    num.checked_mul(unit)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration out of range: {s}"))
+}
+
+fn parse_date(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+        .map_err(|e| format!("invalid date {s}, expected like 2024-01-01: {e}"))
+}
+
+/// Parse a day of a ranking, which cannot be after today in Japan.
+fn parse_ranking_date(s: &str) -> Result<NaiveDate, String> {
+    let date = parse_date(s)?;
+    let today = (Utc::now() + chrono::Duration::hours(9)).naive_utc().date();
+    if date > today {
+        return Err(format!("no ranking of {s} yet"));
+    }
+    Ok(date)
+}
+
+/// Check the log directives, which are applied once the verbosity is known.
+fn parse_log_directives(s: &str) -> Result<String, String> {
+    LogLevels::default().with_directives(s)?;
+    Ok(s.to_string())
+}
+
+/// Parse a percentage with an optional `%` suffix.
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let p: f64 = s
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|e| format!("invalid percentage {s}: {e}"))?;
+    if (0.0..=100.0).contains(&p) {
+        Ok(p)
     } else {
-        db.collection("bowerbird_metadata")
-            .insert_one(
-                to_bson(&BowerbirdMetadata {
-                    version: DB_VERSION,
-                })
-                .unwrap(),
-                None,
-            )
-            .await
-            .context(error::MongoDb)?;
+        Err(format!("percentage out of range: {s}"))
+    }
+}
+
+/// The exit code of a command failed with `err`.
+fn exit_code(err: &error::Error) -> i32 {
+    use error::Error::*;
+    match err {
+        ConfigJson { .. }
+        | ConfigIo { .. }
+        | ConfigInvalid { .. }
+        | StorageRootIo { .. }
+        | ConfigPathNotSet
+        | ProxyParse { .. }
+        | ProxyInvalid { .. }
+        | ProgramPathInvalid { .. }
+        | UgoiraFormatNoFfmpeg { .. } => EXIT_CONFIG,
+        PixivAuth { .. } => EXIT_AUTH,
+        MigrationRequired => EXIT_MIGRATION_REQUIRED,
+        _ => EXIT_ERROR,
     }
-    Ok(())
 }
 
-async fn run_internal() -> crate::Result<()> {
+fn tasks_exit_code(failed: usize, time_limited: bool) -> i32 {
+    if time_limited {
+        EXIT_TIME_LIMITED
+    } else if failed > 0 {
+        EXIT_TASKS_FAILED
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+/// Returns the exit code of a completed command.
+async fn run_internal() -> crate::Result<i32> {
     let opts = Main::parse();
+    let log_levels = LogLevels::default().with_verbosity(opts.verbose);
+    set_log_levels(match &opts.log {
+        Some(directives) => log_levels.with_directives(directives).unwrap(),
+        None => log_levels,
+    });
 
+    let config_path = if let Some(c) = &opts.config {
+        PathBuf::from(c)
+    } else {
+        dirs::home_dir().unwrap_or_default().join(".bowerbird")
+    }
+    .join("config.json");
     let config_builder = || {
-        let config_path = if let Some(c) = &opts.config {
-            PathBuf::from(c)
-        } else {
-            dirs::home_dir().unwrap_or_default().join(".bowerbird")
-        }
-        .join("config.json");
-        let config = config::Config::from_file(&config_path)?;
+        let mut config = config::Config::from_file(&config_path)?;
         debug!("config loaded: {:?}", config_path);
+        set_log_time_format(config.log_time_format);
+        let root = config.ensure_root_dir()?;
+        debug!("root storage dir: {:?}", root);
+        if let Some(proxy) = &opts.proxy {
+            config.set_proxy_override(proxy)?;
+            info!("using proxy from command line");
+        }
+        config.set_program_overrides(opts.ffmpeg_path.as_deref(), opts.aria2_path.as_deref())?;
 
         Ok(config)
     };
 
-    let pre_fn = |fail_if_out_of_date: bool| async move {
-        let config = config_builder()?;
-        let db_client = mongodb::Client::with_options(
-            mongodb::options::ClientOptions::parse(&config.mongodb.uri)
-                .await
-                .context(error::MongoDb)?,
-        )
-        .context(error::MongoDb)?;
-
-        debug!("connected to mongodb: {}", config.mongodb.uri);
-
-        let ffmpeg_path = if config.ffmpeg_path.is_empty() {
-            PathBuf::from("ffmpeg")
-        } else {
-            PathBuf::from(&config.ffmpeg_path)
-        };
-
-        debug!("checking ffmpeg: {:?}", ffmpeg_path);
-
-        let mut ffmpeg = Command::new(&ffmpeg_path);
-        ffmpeg.args(["-hide_banner", "-loglevel", "error"]);
-        let ffmpeg_path = match ffmpeg.spawn() {
-            Ok(mut child) => {
-                let _ = timeout(Duration::from_secs(1), child.wait()).await;
-                Some(ffmpeg_path)
-            }
-            Err(err) => {
-                warn!(
-                    "ffmpeg not found, some functions will not work: {}: {}",
-                    ffmpeg_path.to_string_lossy(),
-                    err
-                );
-                None
-            }
-        };
-
-        let db = db_client.database(&config.mongodb.database_name);
-        migrate_guard(&db, fail_if_out_of_date).await?;
-
-        Ok((config, ffmpeg_path, db))
-    };
-
     match &opts.subcommand {
         SubcommandMain::Migrate => {
-            let (_, _, db) = pre_fn(false).await?;
+            let config = config_builder()?;
+            let db = sync::connect_db(&config, false).await?;
             command::migrate::migrate(&db).await?;
         }
         SubcommandMain::Serve => {
-            let (config, _, db) = pre_fn(true).await?;
-            crate::server::run(db, config).await?;
+            let config = config_builder()?;
+            let db = sync::connect_db(&config, true).await?;
+            let downloader = match sync::new_downloader(&config, &Default::default()).await {
+                Ok(downloader) => Some(Arc::from(downloader)),
+                Err(e) => {
+                    warn!("the download queue is unavailable: {}", e);
+                    None
+                }
+            };
+            crate::server::run(db, config, downloader).await?;
         }
-        SubcommandMain::Init => {
-            config_builder()?;
+        SubcommandMain::Export(c) => {
+            let config = config_builder()?;
+            let db = sync::connect_db(&config, true).await?;
+            match &c.output {
+                Some(output) => {
+                    let file = std::fs::File::create(output).context(error::ExportIo)?;
+                    command::export::export(
+                        &db,
+                        &c.collection,
+                        c.format,
+                        std::io::BufWriter::new(file),
+                    )
+                    .await?;
+                }
+                None => {
+                    let stdout = std::io::stdout();
+                    command::export::export(&db, &c.collection, c.format, stdout.lock()).await?;
+                }
+            }
         }
-        SubcommandMain::Pixiv(c) => {
-            use pixivcrab::AuthMethod;
-            let user_id = c.user_id;
-            let limit = c.limit;
-            let pre_fn = async {
-                let (mut config, ffmpeg_path, db) = pre_fn(true).await?;
-                command::pixiv::database::create_indexes(&db).await?;
-                let mut api_client = reqwest::ClientBuilder::new();
-                if let Some(proxy) = config.pxoxy(&config.pixiv.proxy_api)? {
-                    debug!("pixiv api proxy set: {:?}", proxy);
-                    api_client = api_client.proxy(proxy);
+        SubcommandMain::TagGraph(c) => {
+            let config = config_builder()?;
+            let db = sync::connect_db(&config, true).await?;
+            match &c.output {
+                Some(output) => {
+                    let file = std::fs::File::create(output).context(error::ExportIo)?;
+                    command::export::export_tag_graph(
+                        &db,
+                        c.format,
+                        c.min_count,
+                        std::io::BufWriter::new(file),
+                    )
+                    .await?;
                 }
-                if std::env::var("BOWERBIRD_ACCEPT_INVALID_CERTS").is_ok() {
-                    warn!("invalid certs will be accepted for pixiv api requests");
-                    api_client = api_client.danger_accept_invalid_certs(true);
+                None => {
+                    let stdout = std::io::stdout();
+                    command::export::export_tag_graph(&db, c.format, c.min_count, stdout.lock())
+                        .await?;
                 }
-                let api = pixivcrab::AppApi::new(
-                    AuthMethod::RefreshToken(config.pixiv.refresh_token.clone()),
-                    &config.pixiv.language,
-                    api_client,
-                )
-                .context(error::PixivApi)?;
-                let auth_result = api.auth().await.context(error::PixivApi)?;
-                debug!("pixiv authed: {:?}", auth_result);
-                info!(
-                    "pixiv logged in: {} ({})",
-                    auth_result.user.name, auth_result.user.id
-                );
-                config.pixiv.refresh_token = auth_result.refresh_token;
-                config.save()?;
-                let selected_user_id = user_id.map_or(auth_result.user.id, |i| i.to_string());
-                let downloader =
-                    crate::downloader::Aria2Downloader::new(&config.aria2_path).await?;
-
-                let task_config = command::pixiv::TaskConfig {
+            }
+        }
+        SubcommandMain::Import(c) => {
+            let config = config_builder()?;
+            let db = sync::connect_db(&config, true).await?;
+            command::pixiv::database::create_indexes(&db).await?;
+            let media_dir = if c.collection.starts_with("pixiv_") {
+                Some(config.sub_dir(&config.pixiv.storage_dir))
+            } else {
+                None
+            };
+            match &c.input {
+                Some(input) => {
+                    let file = std::fs::File::open(input).context(error::ImportIo)?;
+                    command::import::import(
+                        &db,
+                        &c.collection,
+                        std::io::BufReader::new(file),
+                        c.dry_run,
+                        media_dir.as_deref(),
+                    )
+                    .await?;
+                }
+                None => {
+                    let stdin = std::io::stdin();
+                    command::import::import(
+                        &db,
+                        &c.collection,
+                        stdin.lock(),
+                        c.dry_run,
+                        media_dir.as_deref(),
+                    )
+                    .await?;
+                }
+            }
+        }
+        SubcommandMain::Backup(Backup {
+            subcommand: Some(SubcommandBackup::Media(c)),
+            ..
+        }) => {
+            let config = config_builder()?;
+            command::backup::backup_media(&config, c.output.clone(), c.exclude_transient).await?;
+        }
+        SubcommandMain::Backup(c) => {
+            let config = config_builder()?;
+            let output = c
+                .output
+                .clone()
+                .unwrap_or_else(|| config.sub_dir("backups"));
+            command::backup::backup(&config, &output, c.exclude_media).await?;
+        }
+        SubcommandMain::Verify(c) => {
+            let config = config_builder()?;
+            let db = sync::connect_db(&config, true).await?;
+            let options = command::verify::VerifyOptions {
+                sample_percent: c.sample,
+                checkpoint: Some(
+                    c.checkpoint
+                        .clone()
+                        .unwrap_or_else(|| config.sub_dir("verify-checkpoint.json")),
+                ),
+                io_concurrency: c.io_concurrency,
+                save_hashes: c.save_hashes,
+            };
+            let report =
+                command::verify::verify(&db, &config.pixiv_storage_dirs(), &options).await?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&report).context(error::ExportJson)?
+            );
+        }
+        SubcommandMain::Compact(c) => {
+            let config = config_builder()?;
+            let db = sync::connect_db(&config, true).await?;
+            let storage_dirs = config.pixiv_storage_dirs();
+            let ffmpeg_path = sync::configured_ffmpeg_path(&config);
+            let report = if c.revert {
+                command::compact::revert(&db, &storage_dirs, &ffmpeg_path).await?
+            } else {
+                let options = command::compact::CompactOptions {
+                    format: c.format,
+                    lossless: c.lossless,
+                    quality: c.quality,
                     ffmpeg_path,
-                    parent_dir: config.sub_dir(&config.pixiv.storage_dir),
-                    proxy: config.pxoxy_string(&config.pixiv.proxy_download),
+                    checkpoint: Some(
+                        c.checkpoint
+                            .clone()
+                            .unwrap_or_else(|| config.sub_dir("compact-checkpoint.json")),
+                    ),
+                    manifest: config.sub_dir("compact-manifest.jsonl"),
+                    jobs: c.jobs.unwrap_or_else(num_cpus::get),
                 };
-                Ok((db, api, selected_user_id, downloader, task_config))
+                command::compact::compact(&db, &storage_dirs, options).await?
+            };
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&report).context(error::ExportJson)?
+            );
+        }
+        SubcommandMain::Doctor => {
+            let checks = command::doctor::doctor(
+                &config_path,
+                opts.proxy.as_deref(),
+                opts.ffmpeg_path.as_deref(),
+                opts.aria2_path.as_deref(),
+            )
+            .await;
+            for check in &checks {
+                println!("{}", check);
+            }
+            let failed = checks
+                .iter()
+                .filter(|c| c.status == command::doctor::CheckStatus::Fail)
+                .count();
+            if failed > 0 {
+                println!("{} checks failed", failed);
+                return Ok(EXIT_ERROR);
+            }
+            println!("all checks passed");
+        }
+        SubcommandMain::Init(c) => {
+            if config_path.exists() {
+                println!(
+                    "config already exists, not overwritten: {}",
+                    config_path.to_string_lossy()
+                );
+                return Ok(EXIT_SUCCESS);
+            }
+            let mut config = config::Config::default_at(&config_path);
+            config.mongodb.uri = match &c.mongodb_uri {
+                Some(uri) => uri.clone(),
+                None if c.yes => config.mongodb.uri.clone(),
+                None => prompt("MongoDB URI", &config.mongodb.uri)?,
+            };
+            config.root_storage_dir = match &c.root_storage_dir {
+                Some(dir) => dir.clone(),
+                None if c.yes => config.root_storage_dir.clone(),
+                None => prompt("Root storage dir", &config.root_storage_dir)?,
+            };
+            config.save()?;
+            let root = config.ensure_root_dir()?;
+            println!("config created: {}", config_path.to_string_lossy());
+            println!("root storage dir: {}", root.to_string_lossy());
+            let database_name = &config.mongodb.database_name;
+            match sync::test_db(&config).await {
+                Ok(0) => println!(
+                    "connected to MongoDB, database `{database_name}` will be created on first use"
+                ),
+                Ok(_) => println!("connected to MongoDB, database `{database_name}` found"),
+                Err(e) => {
+                    println!("cannot connect to MongoDB: {e}");
+                    println!("{}", e.hint());
+                }
+            }
+            println!("set `pixiv.refresh_token` in the config, then run `bowerbird doctor`");
+        }
+        SubcommandMain::Pixiv(c) => {
+            let progress = match opts.progress_fd {
+                Some(fd) => Some(ProgressWriter::from_fd(fd).context(error::ProgressIo { fd })?),
+                None => None,
+            };
+            let cancel = CancellationToken::new();
+            tokio::spawn({
+                let cancel = cancel.clone();
+                async move {
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        info!("interrupted, stopping the sync, press Ctrl-C again to exit now");
+                        cancel.cancel();
+                        // The handler of tokio cannot be removed,
+                        // so exit like the default handler on the second one.
+                        if tokio::signal::ctrl_c().await.is_ok() {
+                            std::process::exit(130);
+                        }
+                    }
+                }
+            });
+            let time_limited = Arc::new(AtomicBool::new(false));
+            if let Some(max_runtime) = c.max_runtime {
+                let cancel = cancel.clone();
+                let time_limited = time_limited.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(max_runtime).await;
+                    if !cancel.is_cancelled() {
+                        info!("reached --max-runtime {:?}, stopping the sync", max_runtime);
+                        time_limited.store(true, Ordering::SeqCst);
+                        cancel.cancel();
+                    }
+                });
+            }
+            let params = PixivSyncParams {
+                user_id: c.user_id.clone(),
+                limit: c.limit,
+                output_dir: c.output_dir.clone(),
+                ugoira_formats: if c.ugoira_format.is_empty() {
+                    None
+                } else {
+                    Some(c.ugoira_format.clone())
+                },
+                progress,
+                progress_tracker: None,
+                include_tags: c.include_tags.clone(),
+                exclude_tags: c.exclude_tags.clone(),
+                max_illust_size: c.max_illust_size,
+                max_pages: c.max_pages,
+                page_range: c.page_count,
+                min_bookmarks: c.min_bookmarks,
+                count_filtered: c.count_filtered,
+                since: c.since,
+                until: c.until,
+                partial_policy: c.partial_policy,
+                replace: c.replace,
+                only_new_users: c.only_new_users,
+                no_db: c.no_db,
+                cancel,
             };
-            match &c.subcommand {
+            let kind = match &c.subcommand {
                 SubcommandPixiv::Illust(c) => match &c.subcommand {
-                    SubcommandPixivAction::Bookmarks(c) => {
-                        let (db, api, selected_user_id, downloader, task_config) = pre_fn.await?;
-                        command::pixiv::illust_bookmarks(
-                            &api,
-                            &db,
-                            &downloader,
-                            &selected_user_id,
-                            c.private,
-                            limit,
-                            &task_config,
-                        )
-                        .await?;
-                        downloader.wait_shutdown().await;
+                    SubcommandPixivIllustAction::Bookmarks(c) => PixivSyncKind::IllustBookmarks {
+                        private: c.private,
+                        start: page_start(&c.start_page_token, c.resume),
+                    },
+                    SubcommandPixivIllustAction::Uploads(c) => PixivSyncKind::IllustUploads {
+                        start: page_start(&c.start_page_token, c.resume),
+                    },
+                    SubcommandPixivIllustAction::Ranking(c) => PixivSyncKind::IllustRanking {
+                        mode: c.mode,
+                        date: c.date,
+                    },
+                    SubcommandPixivIllustAction::Search(c) => PixivSyncKind::IllustSearch {
+                        word: c.word.clone(),
+                        sort: c.sort,
+                        target: c.target,
+                    },
+                    SubcommandPixivIllustAction::ImportIds(c) => {
+                        let text = std::fs::read_to_string(&c.file).context(error::ImportIo)?;
+                        let ids = command::pixiv::parse_ids(&text);
+                        let mut config = config_builder()?;
+                        let (report, failed_tasks) =
+                            sync::sync_illust_ids(&mut config, &params, ids).await?;
+                        let mut failed = 0;
+                        for (id, status) in &report {
+                            match status {
+                                IdImportStatus::Queued => info!("{}: queued", id),
+                                IdImportStatus::Exists => info!("{}: already exists", id),
+                                IdImportStatus::Invisible => {
+                                    failed += 1;
+                                    warn!("{}: deleted or private", id);
+                                }
+                                IdImportStatus::Failed(e) => {
+                                    failed += 1;
+                                    error!("{}: {}", id, e);
+                                }
+                            }
+                        }
+                        info!(
+                            "{} illusts imported, {} failed, {} downloads failed",
+                            report.len() - failed,
+                            failed,
+                            failed_tasks
+                        );
+                        return Ok(tasks_exit_code(
+                            failed + failed_tasks,
+                            time_limited.load(Ordering::SeqCst),
+                        ));
                     }
-                    SubcommandPixivAction::Uploads => {
-                        let (db, api, selected_user_id, downloader, task_config) = pre_fn.await?;
-                        command::pixiv::illust_uploads(
-                            &api,
-                            &db,
-                            &downloader,
-                            &selected_user_id,
-                            limit,
-                            &task_config,
-                        )
-                        .await?;
-                        downloader.wait_shutdown().await;
+                    SubcommandPixivIllustAction::DownloadById(c) => {
+                        let mut config = config_builder()?;
+                        let (status, failed_tasks) =
+                            sync::sync_illust_id(&mut config, &params, c.id).await?;
+                        let failed = match status {
+                            IdImportStatus::Queued | IdImportStatus::Exists => {
+                                if failed_tasks > 0 {
+                                    error!("{} downloads of illust {} failed", failed_tasks, c.id);
+                                } else {
+                                    info!("illust {} downloaded", c.id);
+                                }
+                                failed_tasks
+                            }
+                            IdImportStatus::Invisible => {
+                                warn!("illust {} is deleted or private, nothing to download", c.id);
+                                1
+                            }
+                            IdImportStatus::Failed(e) => {
+                                error!("cannot download illust {}: {}", c.id, e);
+                                1
+                            }
+                        };
+                        return Ok(tasks_exit_code(failed, time_limited.load(Ordering::SeqCst)));
                     }
                 },
                 SubcommandPixiv::Novel(c) => {
                     let update_exists = c.update_exists;
                     match &c.subcommand {
-                        SubcommandPixivAction::Bookmarks(c) => {
-                            let (db, api, selected_user_id, downloader, task_config) =
-                                pre_fn.await?;
-                            command::pixiv::novel_bookmarks(
-                                &api,
-                                &db,
-                                &downloader,
-                                update_exists,
-                                &selected_user_id,
-                                c.private,
-                                limit,
-                                &task_config,
-                            )
-                            .await?;
-                        }
+                        SubcommandPixivAction::Bookmarks(c) => PixivSyncKind::NovelBookmarks {
+                            private: c.private,
+                            update_exists,
+                        },
                         SubcommandPixivAction::Uploads => {
-                            let (db, api, selected_user_id, downloader, task_config) =
-                                pre_fn.await?;
-                            command::pixiv::novel_uploads(
-                                &api,
-                                &db,
-                                &downloader,
-                                update_exists,
-                                &selected_user_id,
-                                limit,
-                                &task_config,
-                            )
-                            .await?;
+                            PixivSyncKind::NovelUploads { update_exists }
                         }
-                    };
+                    }
                 }
+            };
+            let mut config = config_builder()?;
+            let result = sync::sync_pixiv(&mut config, &params, kind).await?;
+            info!(
+                "{} works examined, {} filtered out, {} downloads queued, {} files already downloaded, {} bytes downloaded",
+                result.examined,
+                result.works_filtered,
+                result.queued,
+                result.files_existing,
+                result.bytes_estimate
+            );
+            if result.failed > 0 {
+                error!("{} downloads failed", result.failed);
             }
+            return Ok(tasks_exit_code(
+                result.failed,
+                time_limited.load(Ordering::SeqCst),
+            ));
         }
     };
 
-    Ok(())
+    Ok(EXIT_SUCCESS)
+}
+
+/// Ask on stderr, keeping `default` if the answer is empty or stdin is closed.
+fn prompt(question: &str, default: &str) -> crate::Result<String> {
+    use std::io::Write;
+
+    eprint!("{question} [{default}]: ");
+    std::io::stderr().flush().context(error::ConfigIo)?;
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .context(error::ConfigIo)?;
+    let line = line.trim();
+    Ok(if line.is_empty() {
+        default.to_string()
+    } else {
+        line.to_string()
+    })
+}
+
+/// Whether a log is written, according to `-v` and `--log`.
+pub use crate::utils::log_enabled;
+
+/// Logs are written as lines of JSON if `BOWERBIRD_LOG_FORMAT` is `json`.
+pub fn json_log_enabled() -> bool {
+    std::env::var("BOWERBIRD_LOG_FORMAT").map_or(false, |f| f.eq_ignore_ascii_case("json"))
+}
+
+lazy_static! {
+    static ref LOG_TIME_FORMAT: RwLock<LogTimeFormat> = RwLock::new(LogTimeFormat::default());
+    static ref LOG_TIME_FORMAT_ENV: Option<LogTimeFormat> = std::env::var("BOWERBIRD_LOG_TIME")
+        .ok()
+        .and_then(|f| f.parse().ok());
+}
+
+/// `BOWERBIRD_LOG_TIME` takes precedence over the config, which is used once loaded.
+pub fn log_time_format() -> LogTimeFormat {
+    LOG_TIME_FORMAT_ENV.unwrap_or_else(|| *LOG_TIME_FORMAT.read().unwrap())
+}
+
+pub fn set_log_time_format(format: LogTimeFormat) {
+    *LOG_TIME_FORMAT.write().unwrap() = format;
 }
 
 /// Run the app and return the exit code.
 pub async fn run() -> i32 {
-    if let Err(e) = run_internal().await {
-        error!("{}", e);
-        1
-    } else {
-        0
+    match run_internal().await {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{}", e);
+            exit_code(&e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size(" 2k "), Ok(2048));
+        assert_eq!(parse_size("3M"), Ok(3 << 20));
+        assert_eq!(parse_size("1g"), Ok(1 << 30));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size(&format!("{}G", u64::MAX)).is_err());
     }
 }
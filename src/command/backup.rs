@@ -0,0 +1,361 @@
+use chrono::Local;
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, info};
+use path_slash::PathBufExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::{process::Command, task::spawn_blocking};
+
+use crate::{config::Config, error};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    pub dir: PathBuf,
+    pub dump_size: u64,
+    pub media_files: u64,
+    pub media_size: u64,
+}
+
+/// A line in `media.jsonl`.
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    /// Relative to the storage dir.
+    path: String,
+    /// The storage tier holding the file, like in the database. None for `pixiv.storage_dir`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    storage_dir: Option<String>,
+    size: u64,
+    sha256: String,
+}
+
+/// A media file in one of the storage dirs.
+struct StorageFile {
+    path: PathBuf,
+    /// Relative to the storage dir, with slashes.
+    name: String,
+    storage_dir: Option<String>,
+}
+
+fn mongodump_path(config: &Config) -> PathBuf {
+    if config.mongodump_path.is_empty() {
+        PathBuf::from("mongodump")
+    } else {
+        PathBuf::from(&config.mongodump_path)
+    }
+}
+
+/// Write the uri for `mongodump --config`, readable only by the user,
+/// so the password in it is not in the arguments seen by the other processes.
+fn write_dump_config(path: &Path, uri: &str) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    // A JSON string is a double quoted YAML string.
+    let uri = serde_json::to_string(uri).map_err(io::Error::from)?;
+    writeln!(options.open(path)?, "uri: {uri}")
+}
+
+fn io_context(path: &Path) -> error::BackupIo<String> {
+    error::BackupIo {
+        path: path.to_string_lossy().to_string(),
+    }
+}
+
+/// Get the files under `dir` recursively, in a stable order.
+fn walk_files(dir: &Path, files: &mut Vec<PathBuf>) -> crate::Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .context(io_context(dir))?
+        .collect::<Result<Vec<_>, _>>()
+        .context(io_context(dir))?;
+    entries.sort_by_key(|e| e.file_name());
+    for e in entries {
+        let path = e.path();
+        if e.file_type().context(io_context(&path))?.is_dir() {
+            walk_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Get the files in all the storage dirs, in the order of `Config::pixiv_storage_dirs`.
+///
+/// A file shadowed by the one at the same path in an earlier dir is skipped,
+/// as the server and `verify` never read it.
+fn walk_storage_dirs(storage_dirs: &[PathBuf]) -> crate::Result<Vec<StorageFile>> {
+    let mut seen = HashSet::new();
+    let mut rv = Vec::new();
+    for (tier, storage_dir) in storage_dirs.iter().enumerate() {
+        let mut files = Vec::new();
+        if storage_dir.exists() {
+            walk_files(storage_dir, &mut files)?;
+        }
+        for path in files {
+            let name = path
+                .strip_prefix(storage_dir)
+                .unwrap_or(&path)
+                .to_path_buf()
+                .to_slash_lossy();
+            if !seen.insert(name.clone()) {
+                debug!("skipping shadowed file: {:?}", path);
+                continue;
+            }
+            rv.push(StorageFile {
+                path,
+                name,
+                storage_dir: (tier > 0).then(|| storage_dir.to_string_lossy().to_string()),
+            });
+        }
+    }
+    Ok(rv)
+}
+
+fn dir_size(dir: &Path) -> crate::Result<u64> {
+    let mut files = Vec::new();
+    walk_files(dir, &mut files)?;
+    let mut size = 0;
+    for f in files {
+        size += std::fs::metadata(&f).context(io_context(&f))?.len();
+    }
+    Ok(size)
+}
+
+/// Write the path, size and hash of every media file, so the media can be verified after restoring.
+fn write_manifest(storage_dirs: &[PathBuf], manifest_path: &Path) -> crate::Result<(u64, u64)> {
+    let files = walk_storage_dirs(storage_dirs)?;
+    let mut out = BufWriter::new(File::create(manifest_path).context(io_context(manifest_path))?);
+    let (mut count, mut total) = (0, 0);
+    for f in files {
+        let mut file = File::open(&f.path).context(io_context(&f.path))?;
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut file, &mut hasher).context(io_context(&f.path))?;
+        let entry = ManifestEntry {
+            path: f.name,
+            storage_dir: f.storage_dir,
+            size,
+            sha256: hex::encode(hasher.finalize()),
+        };
+        serde_json::to_writer(&mut out, &entry).context(error::BackupManifest)?;
+        out.write_all(b"\n").context(io_context(manifest_path))?;
+        count += 1;
+        total += size;
+    }
+    out.flush().context(io_context(manifest_path))?;
+    Ok((count, total))
+}
+
+/// Dump the database with `mongodump` and write a manifest of the media files
+/// into a new timestamped directory under `output_dir`.
+pub async fn backup(
+    config: &Config,
+    output_dir: &Path,
+    exclude_media: bool,
+) -> crate::Result<BackupReport> {
+    let dir = output_dir.join(format!(
+        "bowerbird-{}",
+        Local::now().format("%Y%m%d-%H%M%S")
+    ));
+    std::fs::create_dir_all(&dir).context(io_context(&dir))?;
+    info!("backing up to: {}", dir.to_string_lossy());
+
+    let dump_dir = dir.join("dump");
+    let dump_config = dir.join("mongodump.yaml");
+    write_dump_config(&dump_config, &config.mongodb.uri).context(io_context(&dump_config))?;
+    let mongodump = mongodump_path(config);
+    debug!("running mongodump: {:?}", mongodump);
+    let output = Command::new(&mongodump)
+        .arg("--config")
+        .arg(&dump_config)
+        .arg(format!("--db={}", config.mongodb.database_name))
+        .arg("--out")
+        .arg(&dump_dir)
+        .output()
+        .await;
+    std::fs::remove_file(&dump_config).context(io_context(&dump_config))?;
+    let output = output.context(io_context(&mongodump))?;
+    if !output.status.success() {
+        return error::BackupDump {
+            message: format!(
+                "mongodump exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        }
+        .fail();
+    }
+    // mongodump writes a directory for the database, which is missing if nothing is dumped.
+    let db_dump_dir = dump_dir.join(&config.mongodb.database_name);
+    if !db_dump_dir.is_dir() {
+        return error::BackupDump {
+            message: format!("no dump found in {}", db_dump_dir.to_string_lossy()),
+        }
+        .fail();
+    }
+    let dump_size = dir_size(&dump_dir)?;
+    info!("database dumped: {} bytes", dump_size);
+
+    let mut report = BackupReport {
+        dir: dir.clone(),
+        dump_size,
+        ..Default::default()
+    };
+    if !exclude_media {
+        let storage_dirs = config.pixiv_storage_dirs();
+        let manifest_path = dir.join("media.jsonl");
+        let (media_files, media_size) =
+            spawn_blocking(move || write_manifest(&storage_dirs, &manifest_path))
+                .await
+                .unwrap()?;
+        report.media_files = media_files;
+        report.media_size = media_size;
+        info!(
+            "media manifest written: {} files, {} bytes",
+            media_files, media_size
+        );
+    }
+
+    info!(
+        "backup finished: {} bytes in total",
+        report.dump_size + report.media_size
+    );
+    Ok(report)
+}
+
+/// Files left by unfinished downloads, not worth backing up.
+fn is_transient(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    [".part", ".aria2", ".replace", ".tmp"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Hashes what is read through it.
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+fn tar_header(size: u64, mtime: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header
+}
+
+/// Write the files in the storage dirs as a gzipped tar, one file at a time.
+/// The files of all the tiers are put at their paths relative to their storage dir.
+///
+/// `media.jsonl` like the one of `backup` is appended last,
+/// as the hashes are computed while writing the files.
+fn write_media_tar(
+    storage_dirs: &[PathBuf],
+    out: impl Write,
+    exclude_transient: bool,
+) -> crate::Result<(u64, u64)> {
+    let files = walk_storage_dirs(storage_dirs)?;
+    let mut tar = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    let mut manifest = Vec::new();
+    let (mut count, mut total) = (0, 0);
+    for StorageFile {
+        path,
+        name,
+        storage_dir,
+    } in files
+    {
+        if exclude_transient && is_transient(&path) {
+            debug!("skipping transient file: {:?}", path);
+            continue;
+        }
+        let file = File::open(&path).context(io_context(&path))?;
+        let metadata = file.metadata().context(io_context(&path))?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let size = metadata.len();
+        // Files growing while archived are cut at the size in the header.
+        let mut reader = HashReader {
+            inner: file.take(size),
+            hasher: Sha256::new(),
+            len: 0,
+        };
+        tar.append_data(&mut tar_header(size, mtime), &name, &mut reader)
+            .context(io_context(&path))?;
+        if reader.len != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file truncated while archiving",
+            ))
+            .context(io_context(&path));
+        }
+        let entry = ManifestEntry {
+            path: name,
+            storage_dir,
+            size,
+            sha256: hex::encode(reader.hasher.finalize()),
+        };
+        serde_json::to_writer(&mut manifest, &entry).context(error::BackupManifest)?;
+        manifest.push(b'\n');
+        count += 1;
+        total += size;
+    }
+
+    let manifest_path = Path::new("media.jsonl");
+    let mtime = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    tar.append_data(
+        &mut tar_header(manifest.len() as u64, mtime),
+        manifest_path,
+        manifest.as_slice(),
+    )
+    .context(io_context(manifest_path))?;
+    tar.into_inner()
+        .and_then(|gz| gz.finish())
+        .and_then(|mut out| out.flush())
+        .context(io_context(manifest_path))?;
+    Ok((count, total))
+}
+
+/// Stream the media files as a `.tar.gz` to `output`, or to stdout if it is `None`.
+pub async fn backup_media(
+    config: &Config,
+    output: Option<PathBuf>,
+    exclude_transient: bool,
+) -> crate::Result<(u64, u64)> {
+    let storage_dirs = config.pixiv_storage_dirs();
+    info!("archiving media: {:?}", storage_dirs);
+    let (files, size) = spawn_blocking(move || match output {
+        Some(output) => {
+            let out = File::create(&output).context(io_context(&output))?;
+            write_media_tar(&storage_dirs, BufWriter::new(out), exclude_transient)
+        }
+        None => write_media_tar(&storage_dirs, io::stdout().lock(), exclude_transient),
+    })
+    .await
+    .unwrap()?;
+    info!("media archived: {} files, {} bytes", files, size);
+    Ok((files, size))
+}
@@ -0,0 +1,491 @@
+use bson::{doc, oid::ObjectId, to_document, DateTime};
+use futures::{StreamExt, TryStreamExt};
+use log::{info, warn};
+use mongodb::{options::FindOptions, Collection, Database};
+use path_slash::PathBufExt;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+};
+use tokio::{sync::Semaphore, task::spawn_blocking};
+
+use super::verify::{hash_file, load_checkpoint, save_checkpoint};
+use crate::{
+    error::{self, BoxError},
+    utils::find_in_storage_dirs,
+};
+
+/// Save the progress after this number of files.
+const CHECKPOINT_INTERVAL: u64 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactFormat {
+    Webp,
+    /// Smaller than WebP but slow to encode. The server cannot make thumbnails of it.
+    Avif,
+}
+
+impl CompactFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Webp => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Webp => "image/webp",
+            Self::Avif => "image/avif",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactOptions {
+    pub format: CompactFormat,
+    /// Keep every pixel, otherwise encoded with `quality`.
+    pub lossless: bool,
+    /// From 1 to 100.
+    pub quality: u8,
+    pub ffmpeg_path: PathBuf,
+    /// Save the progress to this file, and continue from it if it exists.
+    pub checkpoint: Option<PathBuf>,
+    /// Every transcoded file is appended to this file as a line of JSON,
+    /// to know the originals even without the database.
+    pub manifest: PathBuf,
+    /// Number of files transcoded at the same time.
+    pub jobs: usize,
+}
+
+/// The original of a transcoded file, saved as `compacted` in its record.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Original {
+    pub local_path: String,
+    pub size: i64,
+    /// To verify a copy of the original downloaded again.
+    pub sha256: String,
+    pub mime: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompactReport {
+    pub checked: u64,
+    /// Transcoded, or reverted by `revert`.
+    pub compacted: u64,
+    /// Not smaller after transcoding, kept as they are.
+    pub not_smaller: u64,
+    /// Negative when reverting.
+    pub bytes_saved: i64,
+    pub failed: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Files are transcoded in the order of their ids.
+    last_id: ObjectId,
+    format: CompactFormat,
+    lossless: bool,
+    report: CompactReport,
+}
+
+#[derive(Debug, Deserialize)]
+struct Media {
+    _id: ObjectId,
+    local_path: String,
+    size: i64,
+    mime: Option<String>,
+    compacted: Option<Original>,
+}
+
+/// A file written next to the one it replaces.
+struct Transcoded {
+    local_path: String,
+    size: i64,
+    sha256: String,
+}
+
+fn ffmpeg_transcode(
+    ffmpeg_path: &Path,
+    input: &Path,
+    output: &Path,
+    args: &[String],
+) -> Result<(), BoxError> {
+    let status = Command::new(ffmpeg_path)
+        .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+        .arg(input)
+        .args(args)
+        .arg(output)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| format!("cannot start ffmpeg: {e}"))?;
+    if !status.success() {
+        Err(format!("FFmpeg exited with status {status}"))?
+    }
+    Ok(())
+}
+
+fn encode_args(options: &CompactOptions) -> Vec<String> {
+    let quality = options.quality.clamp(1, 100) as u32;
+    let mut args: Vec<String> = match options.format {
+        CompactFormat::Webp => vec!["-c:v".into(), "libwebp".into()],
+        CompactFormat::Avif => vec![
+            "-c:v".into(),
+            "libaom-av1".into(),
+            "-still-picture".into(),
+            "1".into(),
+        ],
+    };
+    match (options.format, options.lossless) {
+        (CompactFormat::Webp, true) => args.extend(["-lossless".into(), "1".into()]),
+        (CompactFormat::Webp, false) => args.extend(["-quality".into(), quality.to_string()]),
+        (CompactFormat::Avif, true) => args.extend(["-aom-params".into(), "lossless=1".into()]),
+        // 0 is the best and 63 the worst.
+        (CompactFormat::Avif, false) => {
+            args.extend(["-crf".into(), ((100 - quality) * 63 / 100).to_string()])
+        }
+    }
+    args.extend(["-f".into(), options.format.extension().into()]);
+    args
+}
+
+/// The arguments to encode back to the format of the original.
+fn decode_args(mime: &str) -> Result<Vec<String>, BoxError> {
+    let args: &[&str] = match mime {
+        "image/png" => &["-c:v", "png", "-f", "image2"],
+        "image/jpeg" => &["-c:v", "mjpeg", "-q:v", "1", "-f", "image2"],
+        _ => Err(format!("cannot revert to {mime}"))?,
+    };
+    Ok(args.iter().map(|a| a.to_string()).collect())
+}
+
+/// Transcode the file at `from` to `to` through a temporary file.
+///
+/// Returns `None` without writing anything if the result is not smaller than `max_size`.
+fn transcode_file(
+    storage_dir: &Path,
+    from: &str,
+    to: &str,
+    args: &[String],
+    ffmpeg_path: &Path,
+    max_size: Option<i64>,
+) -> Result<Option<Transcoded>, BoxError> {
+    let output = storage_dir.join(PathBuf::from_slash(to));
+    let mut tmp = output.clone().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    if let Err(e) = ffmpeg_transcode(ffmpeg_path, &storage_dir.join(from), &tmp, args) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    let size = std::fs::metadata(&tmp)?.len() as i64;
+    if max_size.map_or(false, |max| size >= max) {
+        std::fs::remove_file(&tmp)?;
+        return Ok(None);
+    }
+    std::fs::rename(&tmp, &output)?;
+    Ok(Some(Transcoded {
+        local_path: to.to_string(),
+        size,
+        sha256: hash_file(&output)?,
+    }))
+}
+
+fn append_manifest(path: &Path, line: serde_json::Value) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{line}")
+}
+
+/// The storage dir holding the file, where it is transcoded.
+fn holding_dir(storage_dirs: &[PathBuf], local_path: &str) -> Result<Arc<Path>, BoxError> {
+    match find_in_storage_dirs(storage_dirs, local_path) {
+        Some((i, _)) => Ok(storage_dirs[i].as_path().into()),
+        None => Err(format!("{local_path} is missing"))?,
+    }
+}
+
+/// Transcode a file and point its record to it, removing the original.
+///
+/// Returns `None` if the file is kept for not getting smaller.
+async fn compact_media(
+    c_image: &Collection<Media>,
+    storage_dirs: &[PathBuf],
+    media: &Media,
+    options: &Arc<CompactOptions>,
+    cpu: &Semaphore,
+) -> Result<Option<i64>, BoxError> {
+    let storage_dir = holding_dir(storage_dirs, &media.local_path)?;
+    let to = PathBuf::from_slash(&media.local_path)
+        .with_extension(options.format.extension())
+        .to_slash_lossy();
+    // A file left by an interrupted run has no record, and is overwritten.
+    if c_image
+        .count_documents(doc! { "local_path": &to }, None)
+        .await?
+        > 0
+    {
+        Err(format!("{to} already exists"))?
+    }
+
+    let permit = cpu.acquire().await.unwrap();
+    let transcoded = spawn_blocking({
+        let storage_dir = storage_dir.clone();
+        let options = options.clone();
+        let from = media.local_path.clone();
+        let size = media.size;
+        move || -> Result<_, BoxError> {
+            let sha256 = hash_file(&storage_dir.join(&from))?;
+            let args = encode_args(&options);
+            let transcoded = transcode_file(
+                &storage_dir,
+                &from,
+                &to,
+                &args,
+                &options.ffmpeg_path,
+                Some(size),
+            )?;
+            Ok(transcoded.map(|t| (sha256, t)))
+        }
+    })
+    .await
+    .unwrap()?;
+    drop(permit);
+    let (sha256, transcoded) = match transcoded {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+
+    let original = Original {
+        local_path: media.local_path.clone(),
+        size: media.size,
+        sha256,
+        mime: media.mime.clone().unwrap_or_default(),
+    };
+    let mut compacted = to_document(&original)?;
+    compacted.insert("at", DateTime::now());
+    c_image
+        .update_one(
+            doc! { "_id": media._id },
+            doc! { "$set": {
+                "local_path": &transcoded.local_path,
+                "size": transcoded.size,
+                "mime": options.format.mime(),
+                "sha256": &transcoded.sha256,
+                "compacted": compacted,
+            }},
+            None,
+        )
+        .await?;
+    append_manifest(
+        &options.manifest,
+        serde_json::json!({
+            "local_path": transcoded.local_path,
+            "original": original,
+            "at": chrono::Utc::now().to_rfc3339(),
+        }),
+    )?;
+    if let Err(e) = tokio::fs::remove_file(storage_dir.join(&media.local_path)).await {
+        warn!("cannot remove the original {}: {}", media.local_path, e);
+    }
+    Ok(Some(media.size - transcoded.size))
+}
+
+/// Transcode the JPEG and PNG originals to a smaller format, skipping those not getting smaller.
+///
+/// The files are transcoded in the storage dirs holding them. The originals are saved in the records as `compacted`, and in the manifest.
+pub async fn compact(
+    db: &Database,
+    storage_dirs: &[PathBuf],
+    options: CompactOptions,
+) -> crate::Result<CompactReport> {
+    let c_image = db.collection::<Media>("pixiv_image");
+
+    let mut report = CompactReport::default();
+    let mut filter = doc! {
+        "mime": { "$in": ["image/jpeg", "image/png"] },
+        "compacted": { "$exists": false },
+    };
+    if let Some(ref path) = options.checkpoint {
+        match load_checkpoint::<Checkpoint>(path)? {
+            Some(c) if c.format == options.format && c.lossless == options.lossless => {
+                info!(
+                    "resuming from checkpoint: {} files checked",
+                    c.report.checked
+                );
+                filter.insert("_id", doc! { "$gt": c.last_id });
+                report = c.report;
+            }
+            Some(_) => warn!("checkpoint is for different options, starting over"),
+            None => {}
+        }
+    }
+
+    let cpu = Arc::new(Semaphore::new(num_cpus::get()));
+    let storage_dirs: Arc<[PathBuf]> = storage_dirs.into();
+    let options = Arc::new(options);
+    let mut results = c_image
+        .find(
+            filter,
+            FindOptions::builder()
+                .sort(doc! { "_id": 1 })
+                .projection(doc! { "_id": 1, "local_path": 1, "size": 1, "mime": 1 })
+                .build(),
+        )
+        .await
+        .context(error::MongoDb)?
+        .map(|r| r.context(error::MongoDb))
+        .map_ok(|m| {
+            let c_image = c_image.clone();
+            let cpu = cpu.clone();
+            let storage_dirs = storage_dirs.clone();
+            let options = options.clone();
+            async move {
+                let r = compact_media(&c_image, &storage_dirs, &m, &options, &cpu).await;
+                Ok((m, r))
+            }
+        })
+        .try_buffered(options.jobs.max(1));
+
+    let mut since_checkpoint = 0;
+    while let Some((media, r)) = results.try_next().await? {
+        report.checked += 1;
+        match r {
+            Ok(Some(saved)) => {
+                report.compacted += 1;
+                report.bytes_saved += saved;
+            }
+            Ok(None) => report.not_smaller += 1,
+            Err(e) => {
+                warn!("cannot compact {}: {}", media.local_path, e);
+                report.failed.push(media.local_path);
+            }
+        }
+        since_checkpoint += 1;
+        if since_checkpoint >= CHECKPOINT_INTERVAL {
+            since_checkpoint = 0;
+            info!(
+                "{} files checked, {} bytes saved",
+                report.checked, report.bytes_saved
+            );
+            if let Some(ref path) = options.checkpoint {
+                save_checkpoint(
+                    path,
+                    &Checkpoint {
+                        last_id: media._id,
+                        format: options.format,
+                        lossless: options.lossless,
+                        report: report.clone(),
+                    },
+                )?;
+            }
+        }
+    }
+
+    if let Some(ref path) = options.checkpoint {
+        if path.exists() {
+            std::fs::remove_file(path).context(error::VerifyCheckpointIo {
+                path: path.to_string_lossy().to_string(),
+            })?;
+        }
+    }
+    info!(
+        "{} files checked: {} compacted, {} not smaller, {} failed, {} bytes saved",
+        report.checked,
+        report.compacted,
+        report.not_smaller,
+        report.failed.len(),
+        report.bytes_saved
+    );
+    Ok(report)
+}
+
+/// Transcode a file back to the format of its original and restore the path of its record.
+async fn revert_media(
+    c_image: &Collection<Media>,
+    storage_dirs: &[PathBuf],
+    media: &Media,
+    original: &Original,
+    ffmpeg_path: &Path,
+) -> Result<i64, BoxError> {
+    let storage_dir = holding_dir(storage_dirs, &media.local_path)?;
+    let args = decode_args(&original.mime)?;
+    let transcoded = spawn_blocking({
+        let storage_dir = storage_dir.clone();
+        let from = media.local_path.clone();
+        let to = original.local_path.clone();
+        let ffmpeg_path = ffmpeg_path.to_owned();
+        move || transcode_file(&storage_dir, &from, &to, &args, &ffmpeg_path, None)
+    })
+    .await
+    .unwrap()?
+    .expect("written without a max size");
+    c_image
+        .update_one(
+            doc! { "_id": media._id },
+            doc! {
+                "$set": {
+                    "local_path": &transcoded.local_path,
+                    "size": transcoded.size,
+                    "mime": &original.mime,
+                    "sha256": &transcoded.sha256,
+                },
+                "$unset": { "compacted": "" },
+            },
+            None,
+        )
+        .await?;
+    if let Err(e) = tokio::fs::remove_file(storage_dir.join(&media.local_path)).await {
+        warn!("cannot remove {}: {}", media.local_path, e);
+    }
+    Ok(media.size - transcoded.size)
+}
+
+/// Transcode the compacted files back to the formats of their originals.
+///
+/// The pixels are kept if compacted losslessly, but the bytes differ from the originals.
+pub async fn revert(
+    db: &Database,
+    storage_dirs: &[PathBuf],
+    ffmpeg_path: &Path,
+) -> crate::Result<CompactReport> {
+    let c_image = db.collection::<Media>("pixiv_image");
+    let mut report = CompactReport::default();
+    let mut cur = c_image
+        .find(
+            doc! { "compacted": { "$exists": true } },
+            FindOptions::builder().sort(doc! { "_id": 1 }).build(),
+        )
+        .await
+        .context(error::MongoDb)?;
+    while let Some(media) = cur.try_next().await.context(error::MongoDb)? {
+        report.checked += 1;
+        let original = match &media.compacted {
+            Some(original) => original,
+            None => continue,
+        };
+        match revert_media(&c_image, storage_dirs, &media, original, ffmpeg_path).await {
+            Ok(saved) => {
+                report.compacted += 1;
+                report.bytes_saved += saved;
+            }
+            Err(e) => {
+                warn!("cannot revert {}: {}", media.local_path, e);
+                report.failed.push(media.local_path.clone());
+            }
+        }
+    }
+    info!(
+        "{} files reverted, {} failed",
+        report.compacted,
+        report.failed.len()
+    );
+    Ok(report)
+}
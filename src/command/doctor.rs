@@ -0,0 +1,279 @@
+use colored::Colorize;
+use std::{fmt, path::Path, process::Stdio, time::Duration};
+use tokio::{process::Command, time::timeout};
+
+use crate::{
+    command::migrate::{get_metadata, DB_VERSION},
+    config::{redact_mongodb_uri, Config, DownloadBackend},
+    sync::{check_dir_writable, check_proxy, configured_ffmpeg_path, open_db, test_db},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// Not checked because a check it depends on failed.
+    Skip,
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    /// How to fix it, for failed checks.
+    pub hint: Option<&'static str>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn skip(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Skip,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            CheckStatus::Pass => "PASS".bright_green(),
+            CheckStatus::Fail => "FAIL".bright_red(),
+            CheckStatus::Skip => "SKIP".bright_black(),
+        };
+        write!(f, "[{status}] {}: {}", self.name, self.detail)?;
+        if let Some(hint) = self.hint {
+            write!(f, "\n       {}", hint.bright_yellow())?;
+        }
+        Ok(())
+    }
+}
+
+/// The first line printed by `program version_arg`.
+async fn program_version(program: &Path, version_arg: &str) -> Result<String, String> {
+    let output = timeout(
+        TIMEOUT,
+        Command::new(program)
+            .arg(version_arg)
+            .stdin(Stdio::null())
+            .output(),
+    )
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map_err(|e| format!("{}: {e}", program.to_string_lossy()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().next().unwrap_or_default().to_string())
+}
+
+async fn check_mongodb(config: &Config) -> Vec<Check> {
+    if let Err(e) = test_db(config).await {
+        return vec![
+            Check::fail("mongodb", e.to_string(), e.hint()),
+            Check::skip("schema", "mongodb is not available"),
+        ];
+    }
+    let db = match open_db(config).await {
+        Ok(db) => db,
+        Err(e) => {
+            return vec![
+                Check::fail(
+                    "mongodb",
+                    e.to_string(),
+                    "check `mongodb.uri` in the config",
+                ),
+                Check::skip("schema", "mongodb is not available"),
+            ]
+        }
+    };
+    let connected = Check::pass(
+        "mongodb",
+        format!("connected to {}", redact_mongodb_uri(&config.mongodb.uri)),
+    );
+    let schema = match get_metadata(&db).await {
+        Ok(None) => Check::pass("schema", "new database, set up on first use"),
+        Ok(Some(m)) if m.version < DB_VERSION => Check::fail(
+            "schema",
+            format!("version {}, {} is required", m.version, DB_VERSION),
+            "back up the database and run `bowerbird migrate`",
+        ),
+        Ok(Some(m)) if m.version > DB_VERSION => Check::fail(
+            "schema",
+            format!("version {} is newer than {}", m.version, DB_VERSION),
+            "update bowerbird to the latest version",
+        ),
+        Ok(Some(m)) => Check::pass("schema", format!("version {}", m.version)),
+        Err(e) => Check::fail("schema", e.to_string(), "check the permissions of the user"),
+    };
+    vec![connected, schema]
+}
+
+async fn check_pixiv(config: &mut Config) -> Check {
+    if config.pixiv.refresh_token.is_empty() {
+        return Check::fail(
+            "pixiv",
+            "no refresh token",
+            "set `pixiv.refresh_token` in the config",
+        );
+    }
+    let mut client = config.http_client.apply(reqwest::ClientBuilder::new());
+    match config.pxoxy(&config.pixiv.proxy_api) {
+        Ok(Some(proxy)) => client = client.proxy(proxy),
+        Ok(None) => {}
+        Err(e) => return Check::skip("pixiv", format!("invalid proxy: {e}")),
+    }
+    let api = match pixivcrab::AppApi::new(
+        pixivcrab::AuthMethod::RefreshToken(config.pixiv.refresh_token.clone()),
+        &config.pixiv.language,
+        client,
+    ) {
+        Ok(api) => api,
+        Err(e) => return Check::fail("pixiv", e.to_string(), "check the proxy settings"),
+    };
+    match timeout(TIMEOUT, api.auth()).await {
+        Ok(Ok(r)) => {
+            // Saved like a sync does, in case pixiv rotates the token.
+            config.pixiv.refresh_token = r.refresh_token;
+            if config.config_path().is_some() {
+                let _ = config.save();
+            }
+            Check::pass(
+                "pixiv",
+                format!("logged in as {} ({})", r.user.name, r.user.id),
+            )
+        }
+        Ok(Err(e)) => Check::fail(
+            "pixiv",
+            e.to_string(),
+            "the refresh token may be expired, get a new one and update `pixiv.refresh_token`",
+        ),
+        Err(_) => Check::fail(
+            "pixiv",
+            format!("no response in {TIMEOUT:?}"),
+            "check the network, or set a proxy with `pixiv.proxy_api`",
+        ),
+    }
+}
+
+/// Check everything bowerbird depends on, continuing past failures.
+pub async fn doctor(
+    config_path: &Path,
+    proxy_override: Option<&str>,
+    ffmpeg_override: Option<&str>,
+    aria2_override: Option<&str>,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let mut config = match Config::from_file(config_path) {
+        Ok(config) => {
+            checks.push(Check::pass(
+                "config",
+                format!("loaded {}", config_path.to_string_lossy()),
+            ));
+            config
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "config",
+                e.to_string(),
+                "fix the config, or move it away to get a new one with the defaults",
+            ));
+            for name in ["storage", "mongodb", "ffmpeg", "aria2", "proxy", "pixiv"] {
+                checks.push(Check::skip(name, "the config cannot be loaded"));
+            }
+            return checks;
+        }
+    };
+    if let Some(proxy) = proxy_override {
+        if let Err(e) = config.set_proxy_override(proxy) {
+            checks.push(Check::fail("proxy", e.to_string(), "check `--proxy`"));
+        }
+    }
+    if let Err(e) = config.set_program_overrides(ffmpeg_override, aria2_override) {
+        checks.push(Check::fail(
+            "config",
+            e.to_string(),
+            "check `--ffmpeg-path` and `--aria2-path`",
+        ));
+    }
+
+    for dir in [config.root_dir(), config.sub_dir(&config.pixiv.storage_dir)] {
+        checks.push(match check_dir_writable(&dir) {
+            Ok(()) => Check::pass("storage", format!("{} is writable", dir.to_string_lossy())),
+            Err(e) => Check::fail(
+                "storage",
+                e.to_string(),
+                "check the permissions, or change `root_storage_dir` in the config",
+            ),
+        });
+    }
+
+    checks.extend(check_mongodb(&config).await);
+
+    checks.push(
+        match program_version(&configured_ffmpeg_path(&config), "-version").await {
+            Ok(version) => Check::pass("ffmpeg", version),
+            Err(e) => Check::fail(
+                "ffmpeg",
+                e,
+                "install ffmpeg or set `ffmpeg_path`, it is needed to transcode ugoira",
+            ),
+        },
+    );
+    checks.push(match config.download.backend {
+        DownloadBackend::Native => Check::skip("aria2", "not used by the native download backend"),
+        DownloadBackend::Aria2 => {
+            match program_version(Path::new(config.aria2_path()), "--version").await {
+                Ok(version) => Check::pass("aria2", version),
+                Err(e) => Check::fail(
+                    "aria2",
+                    e,
+                    "install aria2, set `aria2_path` or set `download.backend` to `native`",
+                ),
+            }
+        }
+    });
+
+    let mut proxies: Vec<String> = [&config.pixiv.proxy_api, &config.pixiv.proxy_download]
+        .into_iter()
+        .filter_map(|p| config.pxoxy_string(p))
+        .collect();
+    proxies.dedup();
+    if proxies.is_empty() {
+        checks.push(Check::pass("proxy", "not used"));
+    }
+    for proxy in proxies {
+        checks.push(match check_proxy(&proxy).await {
+            Ok(()) => Check::pass("proxy", "pixiv is reachable through the proxy"),
+            Err(e) => Check::fail(
+                "proxy",
+                e.to_string(),
+                "check the proxy settings, or start the proxy",
+            ),
+        });
+    }
+
+    checks.push(check_pixiv(&mut config).await);
+    checks
+}
@@ -0,0 +1,213 @@
+use futures::TryStreamExt;
+use log::info;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+    Database,
+};
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::Write,
+};
+
+use crate::{error, model::Tag};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Relaxed Extended JSON, readable but numbers and dates may lose their types.
+    Json,
+    /// Canonical Extended JSON, keeps the exact BSON types. Can be read by `mongoimport`.
+    Ejson,
+}
+
+pub fn to_json(d: Document, format: ExportFormat) -> serde_json::Value {
+    match format {
+        ExportFormat::Json => bson::Bson::Document(d).into_relaxed_extjson(),
+        ExportFormat::Ejson => bson::Bson::Document(d).into_canonical_extjson(),
+    }
+}
+
+/// Write every document in the collection as a line of JSON.
+pub async fn export(
+    db: &Database,
+    collection: &str,
+    format: ExportFormat,
+    mut out: impl Write,
+) -> crate::Result<u64> {
+    let mut cur = db
+        .collection::<Document>(collection)
+        .find(None, None)
+        .await
+        .context(error::MongoDb)?;
+    let mut count = 0;
+    while let Some(d) = cur.try_next().await.context(error::MongoDb)? {
+        serde_json::to_writer(&mut out, &to_json(d, format)).context(error::ExportJson)?;
+        out.write_all(b"\n").context(error::ExportIo)?;
+        count += 1;
+    }
+    out.flush().context(error::ExportIo)?;
+    info!("{} documents exported from {}", count, collection);
+    Ok(count)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub enum GraphFormat {
+    /// Edge list with the columns `Source,Target,Weight,Source Count,Target Count`.
+    Csv,
+    /// Nodes with the `count` attribute and edges with the `weight` attribute, e.g. for Gephi.
+    Graphml,
+}
+
+fn csv_field(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Write how many illusts every pair of tags appears in together.
+///
+/// Pairs in fewer than `min_count` illusts are left out. Tags in fewer illusts than that
+/// cannot be in such pairs, so they are dropped before counting to bound the memory.
+pub async fn export_tag_graph(
+    db: &Database,
+    format: GraphFormat,
+    min_count: u32,
+    mut out: impl Write,
+) -> crate::Result<u64> {
+    let c_illust = db.collection::<Document>("pixiv_illust");
+    let min_count = min_count.max(1);
+
+    let mut tag_counts: HashMap<ObjectId, u32> = HashMap::new();
+    let mut cur = c_illust
+        .aggregate(
+            [
+                doc! { "$unwind": "$tag_ids" },
+                doc! { "$group": { "_id": "$tag_ids", "count": { "$sum": 1 } } },
+                doc! { "$match": { "count": { "$gte": min_count } } },
+            ],
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+    while let Some(d) = cur.try_next().await.context(error::MongoDb)? {
+        tag_counts.insert(
+            d.get_object_id("_id").context(error::MongoValueAccess)?,
+            d.get_i32("count").context(error::MongoValueAccess)? as u32,
+        );
+    }
+    info!(
+        "{} tags in at least {} illusts",
+        tag_counts.len(),
+        min_count
+    );
+
+    let mut pairs: HashMap<(ObjectId, ObjectId), u32> = HashMap::new();
+    let mut cur = c_illust
+        .find(
+            doc! { "tag_ids.1": { "$exists": true } },
+            FindOptions::builder()
+                .projection(doc! { "tag_ids": 1 })
+                .build(),
+        )
+        .await
+        .context(error::MongoDb)?;
+    while let Some(d) = cur.try_next().await.context(error::MongoDb)? {
+        let mut tags: Vec<_> = d
+            .get_array("tag_ids")
+            .context(error::MongoValueAccess)?
+            .iter()
+            .filter_map(|t| t.as_object_id())
+            .filter(|t| tag_counts.contains_key(t))
+            .collect();
+        tags.sort();
+        tags.dedup();
+        for (i, a) in tags.iter().enumerate() {
+            for b in &tags[i + 1..] {
+                *pairs.entry((*a, *b)).or_default() += 1;
+            }
+        }
+    }
+    let mut edges: Vec<_> = pairs.into_iter().filter(|(_, n)| *n >= min_count).collect();
+    edges.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut names = HashMap::new();
+    let mut cur = db
+        .collection::<Tag>("pixiv_tag")
+        .find(
+            doc! { "_id": { "$in": tag_counts.keys().copied().collect::<Vec<_>>() } },
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+    while let Some(t) = cur.try_next().await.context(error::MongoDb)? {
+        if let (Some(id), Some(name)) = (t._id, t.alias.into_iter().next()) {
+            names.insert(id, name);
+        }
+    }
+    let name = |id: &ObjectId| names.get(id).cloned().unwrap_or_else(|| id.to_hex());
+
+    match format {
+        GraphFormat::Csv => {
+            writeln!(out, "Source,Target,Weight,Source Count,Target Count")
+                .context(error::ExportIo)?;
+            for ((a, b), n) in &edges {
+                writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    csv_field(&name(a)),
+                    csv_field(&name(b)),
+                    n,
+                    tag_counts[a],
+                    tag_counts[b]
+                )
+                .context(error::ExportIo)?;
+            }
+        }
+        GraphFormat::Graphml => {
+            let connected: BTreeSet<_> = edges.iter().flat_map(|((a, b), _)| [*a, *b]).collect();
+            writeln!(
+                out,
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="label" for="node" attr.name="label" attr.type="string"/>
+  <key id="count" for="node" attr.name="count" attr.type="int"/>
+  <key id="weight" for="edge" attr.name="weight" attr.type="int"/>
+  <graph edgedefault="undirected">"#
+            )
+            .context(error::ExportIo)?;
+            for id in &connected {
+                writeln!(
+                    out,
+                    r#"    <node id="{}"><data key="label">{}</data><data key="count">{}</data></node>"#,
+                    id.to_hex(),
+                    xml_escape(&name(id)),
+                    tag_counts[id]
+                )
+                .context(error::ExportIo)?;
+            }
+            for ((a, b), n) in &edges {
+                writeln!(
+                    out,
+                    r#"    <edge source="{}" target="{}"><data key="weight">{}</data></edge>"#,
+                    a.to_hex(),
+                    b.to_hex(),
+                    n
+                )
+                .context(error::ExportIo)?;
+            }
+            writeln!(out, "  </graph>\n</graphml>").context(error::ExportIo)?;
+        }
+    }
+    out.flush().context(error::ExportIo)?;
+    info!("{} tag pairs exported", edges.len());
+    Ok(edges.len() as u64)
+}
@@ -0,0 +1,123 @@
+use log::{info, warn};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::ReplaceOptions,
+    Database,
+};
+use snafu::ResultExt;
+use std::{io::BufRead, path::Path};
+
+use crate::error;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub inserted: u64,
+    /// Documents replacing existing ones with the same `_id`.
+    pub replaced: u64,
+    /// Documents with the same `_id` but different content in the database.
+    pub conflicts: u64,
+    /// Media records whose files cannot be found.
+    pub missing_files: u64,
+}
+
+/// Upsert the lines of JSON produced by `export` into the collection.
+///
+/// Both relaxed and canonical Extended JSON are accepted.
+/// If `media_dir` is set, `local_path` of the documents are checked against the files in it.
+pub async fn import(
+    db: &Database,
+    collection: &str,
+    input: impl BufRead,
+    dry_run: bool,
+    media_dir: Option<&Path>,
+) -> crate::Result<ImportReport> {
+    let c = db.collection::<Document>(collection);
+    let mut report = ImportReport::default();
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.context(error::ImportIo)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_str(&line).map_err(|e| {
+            error::ImportParse {
+                message: format!("line {}: {e}", i + 1),
+            }
+            .build()
+        })?;
+        let d = match Bson::try_from(value) {
+            Ok(Bson::Document(d)) => d,
+            Ok(_) => {
+                return error::ImportParse {
+                    message: format!("line {}: not a document", i + 1),
+                }
+                .fail()
+            }
+            Err(e) => {
+                return error::ImportParse {
+                    message: format!("line {}: {e}", i + 1),
+                }
+                .fail()
+            }
+        };
+
+        if let (Some(media_dir), Ok(local_path)) = (media_dir, d.get_str("local_path")) {
+            if !media_dir.join(local_path).exists() {
+                warn!("file of imported record not found: {}", local_path);
+                report.missing_files += 1;
+            }
+        }
+
+        let id = match d.get("_id") {
+            Some(id) => id.clone(),
+            None => {
+                if !dry_run {
+                    c.insert_one(&d, None).await.context(error::MongoDb)?;
+                }
+                report.inserted += 1;
+                continue;
+            }
+        };
+
+        if dry_run {
+            match c
+                .find_one(doc! { "_id": id.clone() }, None)
+                .await
+                .context(error::MongoDb)?
+            {
+                Some(existing) => {
+                    report.replaced += 1;
+                    if existing != d {
+                        report.conflicts += 1;
+                    }
+                }
+                None => report.inserted += 1,
+            }
+        } else {
+            let r = c
+                .replace_one(
+                    doc! { "_id": id.clone() },
+                    &d,
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await
+                .context(error::MongoDb)?;
+            if r.matched_count > 0 {
+                report.replaced += 1;
+            } else {
+                report.inserted += 1;
+            }
+        }
+    }
+
+    info!(
+        "{}{} inserted, {} replaced ({} conflicts), {} files missing in {}",
+        if dry_run { "dry run: " } else { "" },
+        report.inserted,
+        report.replaced,
+        report.conflicts,
+        report.missing_files,
+        collection
+    );
+    Ok(report)
+}
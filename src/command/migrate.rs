@@ -1,21 +1,23 @@
-use bson::{doc, to_bson};
+use bson::{doc, to_bson, Document};
 use futures::TryStreamExt;
 use log::info;
 use mongodb::Database;
+use regex::Regex;
 use serde::Deserialize;
 use snafu::ResultExt;
 
 use crate::{
     error,
-    model::{BowerbirdMetadata, Hsv, LocalMedia},
-    utils::rgb_to_hsv,
+    model::{pixiv::PixivIllust, BowerbirdMetadata, Hsv, LocalMedia, UgoiraMedia},
+    utils::{retry_db, rgb_to_hsv},
 };
 
-pub const DB_VERSION: i32 = 2;
+pub const DB_VERSION: i32 = 5;
 
 async fn update_version(db: &Database, version: i32) -> crate::Result<()> {
-    db.collection::<BowerbirdMetadata>("bowerbird_metadata")
-        .update_one(
+    let c_metadata = db.collection::<BowerbirdMetadata>("bowerbird_metadata");
+    retry_db("update version", || {
+        c_metadata.update_one(
             doc! {},
             doc! {
                 "$set": {
@@ -24,8 +26,9 @@ async fn update_version(db: &Database, version: i32) -> crate::Result<()> {
             },
             None,
         )
-        .await
-        .context(error::MongoDb)?;
+    })
+    .await
+    .context(error::MongoDb)?;
     Ok(())
 }
 
@@ -69,18 +72,108 @@ async fn operations(db: &Database, target_version: i32) -> crate::Result<()> {
                         Hsv { h, s, v }
                     })
                     .collect();
-                c_image
-                    .update_one(
-                        doc! {"_id": r._id},
-                        doc! {
-                        "$set": { "extension.palette_hsv": to_bson(&hsv_v).unwrap() },
-                        "$unset": { "extension.palette_rgb": "" }},
-                        None,
-                    )
+                let filter = doc! {"_id": r._id};
+                let update = doc! {
+                    "$set": { "extension.palette_hsv": to_bson(&hsv_v).unwrap() },
+                    "$unset": { "extension.palette_rgb": "" },
+                };
+                retry_db("migrate palette", || {
+                    c_image.update_one(filter.clone(), update.clone(), None)
+                })
+                .await
+                .context(error::MongoDb)?;
+            }
+            update_version(db, 2).await?;
+        }
+        3 => {
+            // Save the frame delay of ugoira to their zip files.
+            let re_ugoira_id = Regex::new(r"/(\d+)_ugoira").unwrap();
+            let c_image = db.collection::<Document>("pixiv_image");
+            let c_illust = db.collection::<PixivIllust>("pixiv_illust");
+            let mut cur = c_image
+                .find(
+                    doc! {
+                        "mime": "application/zip",
+                        "extension": { "$exists": false },
+                    },
+                    None,
+                )
+                .await
+                .context(error::MongoDb)?;
+            while let Some(r) = cur.try_next().await.context(error::MongoDb)? {
+                let illust_id = match r
+                    .get_str("url")
+                    .ok()
+                    .and_then(|url| re_ugoira_id.captures(url))
+                {
+                    Some(c) => c[1].to_string(),
+                    None => continue,
+                };
+                let frame_delay = c_illust
+                    .find_one(doc! { "source_id": &illust_id }, None)
+                    .await
+                    .context(error::MongoDb)?
+                    .and_then(|i| i.history.into_iter().last())
+                    .and_then(|h| h.extension)
+                    .and_then(|e| e.ugoira_delay);
+                if let Some(frame_delay) = frame_delay {
+                    let filter =
+                        doc! { "_id": r.get_object_id("_id").context(error::MongoValueAccess)? };
+                    let update = doc! { "$set": {
+                        "extension": to_bson(&UgoiraMedia::new(frame_delay))
+                            .context(error::BsonSerialize)?
+                    }};
+                    retry_db("migrate ugoira", || {
+                        c_image.update_one(filter.clone(), update.clone(), None)
+                    })
                     .await
                     .context(error::MongoDb)?;
+                }
             }
-            update_version(db, 2).await?;
+            update_version(db, 3).await?;
+        }
+        4 => {
+            // The creation time of the ObjectId is the best guess of when an item is first saved.
+            for name in ["pixiv_illust", "pixiv_novel"] {
+                let c_item = db.collection::<Document>(name);
+                retry_db("migrate first seen", || {
+                    c_item.update_many(
+                        doc! { "first_seen_at": { "$exists": false } },
+                        vec![doc! { "$set": {
+                            "first_seen_at": { "$toDate": "$_id" },
+                            "last_seen_at": { "$ifNull": ["$last_modified", { "$toDate": "$_id" }] },
+                        }}],
+                        None,
+                    )
+                })
+                .await
+                .context(error::MongoDb)?;
+            }
+            update_version(db, 4).await?;
+        }
+        5 => {
+            // Count the pages of the illusts from the urls of their latest history.
+            // Left unset without any url, e.g. saved while deleted, as the count is unknown.
+            let urls = doc! { "$ifNull": [
+                { "$arrayElemAt": ["$history.extension.image_urls", -1] },
+                [],
+            ]};
+            let c_illust = db.collection::<Document>("pixiv_illust");
+            retry_db("migrate page count", || {
+                c_illust.update_many(
+                    doc! {
+                        "extension.page_count": { "$exists": false },
+                        "$expr": { "$gt": [{ "$size": urls.clone() }, 0] },
+                    },
+                    vec![doc! { "$set": {
+                        "extension.page_count": { "$size": urls.clone() },
+                    }}],
+                    None,
+                )
+            })
+            .await
+            .context(error::MongoDb)?;
+            update_version(db, 5).await?;
         }
         _ => {
             panic!("Unknown target version: {}", target_version);
@@ -101,6 +194,32 @@ pub async fn migrate(db: &Database) -> crate::Result<()> {
     Ok(())
 }
 
+/// Make sure the database schema can be used by this version,
+/// and set up the metadata for a new database.
+pub async fn guard(db: &Database, fail_if_out_of_date: bool) -> crate::Result<()> {
+    if let Some(metadata) = get_metadata(db).await? {
+        if fail_if_out_of_date && metadata.version < DB_VERSION {
+            return error::MigrationRequired.fail();
+        }
+        if metadata.version > DB_VERSION {
+            return error::DatabaseIsNewer.fail();
+        }
+    } else {
+        db.collection("bowerbird_metadata")
+            .insert_one(
+                to_bson(&BowerbirdMetadata {
+                    version: DB_VERSION,
+                    ..Default::default()
+                })
+                .unwrap(),
+                None,
+            )
+            .await
+            .context(error::MongoDb)?;
+    }
+    Ok(())
+}
+
 pub async fn get_metadata(db: &Database) -> crate::Result<Option<BowerbirdMetadata>> {
     db.collection::<BowerbirdMetadata>("bowerbird_metadata")
         .find_one(None, None)
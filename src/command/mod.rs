@@ -1,2 +1,8 @@
+pub mod backup;
+pub mod compact;
+pub mod doctor;
+pub mod export;
+pub mod import;
 pub mod migrate;
 pub mod pixiv;
+pub mod verify;
@@ -1,8 +1,9 @@
 use chrono::{Duration, Utc};
+use futures::TryStreamExt;
 use log::{info, warn};
 use mongodb::{
-    bson::{doc, oid::ObjectId, to_bson, DateTime, Document},
-    options::{self, FindOneAndUpdateOptions, UpdateOptions},
+    bson::{doc, oid::ObjectId, to_bson, to_document, Bson, DateTime, Document},
+    options::{self, FindOneAndUpdateOptions, IndexOptions, UpdateOptions},
     Collection, Database, IndexModel,
 };
 use path_slash::PathBufExt;
@@ -11,19 +12,127 @@ use snafu::ResultExt;
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     path::{Path, PathBuf},
+    sync::Mutex,
 };
 
 use crate::{
-    command::pixiv::{download::download_other_images, TaskConfig},
-    downloader::Aria2Downloader,
+    command::pixiv::{
+        download::{download_other_images, original_profile_image_url},
+        utils::{call_api, ImageInfo},
+        TaskConfig,
+    },
+    config::{UgoiraFormat, WriteBatchConfig},
+    downloader::Downloader,
     error::{self, BoxError},
     model::{
-        pixiv::{self, NovelHistory, PixivIllust, PixivNovel, PixivUser, UserHistory},
-        History, Hsv, ImageMedia, LocalMedia,
+        pixiv::{
+            self, BookmarkVisibility, NovelHistory, PixivIllust, PixivNovel, PixivUser, UserHistory,
+        },
+        Derivative, History, ImageMedia, LocalMedia, UgoiraMedia,
     },
-    utils::try_skip,
+    utils::{retry_db, try_skip, Batcher},
 };
 
+/// An update queued to a `WriteBatch`.
+struct BatchedUpdate {
+    collection: String,
+    filter: Document,
+    update: Document,
+    upsert: bool,
+}
+
+impl BatchedUpdate {
+    fn statement(self) -> Document {
+        doc! { "q": self.filter, "u": self.update, "upsert": self.upsert }
+    }
+}
+
+/// Writes the updates after the downloads in batches,
+/// one `update` command for each run of updates to the same collection.
+pub struct WriteBatch {
+    batcher: Batcher<BatchedUpdate, String>,
+}
+
+impl WriteBatch {
+    pub fn new(db: Database, config: &WriteBatchConfig) -> Self {
+        let interval = std::time::Duration::from_millis(config.interval_millis);
+        Self {
+            batcher: Batcher::new(config.size, interval, move |updates| {
+                write_updates(db.clone(), updates)
+            }),
+        }
+    }
+
+    /// Write the queued updates. Updates after closing are written one by one.
+    pub async fn close(&self) {
+        self.batcher.close().await;
+    }
+}
+
+async fn write_updates(db: Database, updates: Vec<BatchedUpdate>) -> Result<(), String> {
+    let mut updates = updates.into_iter().peekable();
+    while let Some(first) = updates.next() {
+        let collection = first.collection.clone();
+        let mut statements = vec![first.statement()];
+        while let Some(u) = updates.next_if(|u| u.collection == collection) {
+            statements.push(u.statement());
+        }
+        let count = statements.len();
+        let command = doc! { "update": &collection, "updates": statements, "ordered": true };
+        // The updates are idempotent, safe to send again.
+        let r = retry_db("write batch", || db.run_command(command.clone(), None))
+            .await
+            .map_err(|e| e.to_string())?;
+        if let Ok(errors) = r.get_array("writeErrors") {
+            if !errors.is_empty() {
+                return Err(format!(
+                    "{} of {} updates of {} failed, the first: {}",
+                    errors.len(),
+                    count,
+                    collection,
+                    errors[0]
+                ));
+            }
+        }
+        if let Ok(e) = r.get_document("writeConcernError") {
+            return Err(format!("write concern error on {}: {}", collection, e));
+        }
+    }
+    Ok(())
+}
+
+/// Update a document, queued to `batch` if set.
+async fn update_one(
+    batch: Option<&WriteBatch>,
+    what: &str,
+    c: &Collection<Document>,
+    filter: Document,
+    update: Document,
+    upsert: bool,
+) -> crate::Result<()> {
+    if let Some(batch) = batch {
+        let queued = BatchedUpdate {
+            collection: c.name().to_string(),
+            filter: filter.clone(),
+            update: update.clone(),
+            upsert,
+        };
+        if let Some(r) = batch.batcher.write(queued).await {
+            return r.map_err(|message| error::MongoBatch { message }.build());
+        }
+    }
+    retry_db(what, || {
+        c.update_one(
+            filter.clone(),
+            update.clone(),
+            UpdateOptions::builder().upsert(upsert).build(),
+        )
+    })
+    .await
+    .context(error::MongoDb)?;
+    Ok(())
+}
+
 async fn update_users(
     users_map: BTreeMap<String, &pixivcrab::models::user::User>,
     users_need_update_set: &mut BTreeSet<String>,
@@ -32,8 +141,8 @@ async fn update_users(
     let mut users_to_oid = HashMap::new();
 
     for (user_id, user) in users_map {
-        let r = c_user
-            .find_one_and_update(
+        let r = retry_db("update user", || {
+            c_user.find_one_and_update(
                 doc! {"source_id": &user_id},
                 doc! {"$set": {
                     "source_inaccessible": false,
@@ -49,9 +158,10 @@ async fn update_users(
                     })
                     .build(),
             )
-            .await
-            .context(error::MongoDb)?
-            .ok_or(error::MongoNotMatch.build())?;
+        })
+        .await
+        .context(error::MongoDb)?
+        .ok_or(error::MongoNotMatch.build())?;
         let parent_id = r.get_object_id("_id").context(error::MongoValueAccess)?;
         users_to_oid.insert(user_id.clone(), parent_id);
 
@@ -94,9 +204,9 @@ async fn update_tags(
                 options: "i".to_string(),
             })
             .collect();
-        let r = c_tag
-            .find_one_and_update(
-                doc! { "alias": { "$in": regs }, "protected": false },
+        let r = retry_db("update tag", || {
+            c_tag.find_one_and_update(
+                doc! { "alias": { "$in": regs.clone() }, "protected": false },
                 doc! { "$addToSet": {"alias": { "$each": &alias } } },
                 FindOneAndUpdateOptions::builder()
                     .upsert(true)
@@ -104,9 +214,10 @@ async fn update_tags(
                     .projection(doc! {"_id": true})
                     .build(),
             )
-            .await
-            .context(error::MongoDb)?
-            .ok_or(error::MongoNotMatch.build())?;
+        })
+        .await
+        .context(error::MongoDb)?
+        .ok_or(error::MongoNotMatch.build())?;
         for t in alias {
             let oid = r.get_object_id("_id").context(error::MongoValueAccess)?;
             tags_to_oid.insert(t, oid);
@@ -135,8 +246,8 @@ fn insert_tags_to_alias(tags: &Vec<pixivcrab::models::Tag>, tags_set: &mut HashS
 
 async fn set_item_invisible(c_item: &Collection<Document>, source_id: &str) -> crate::Result<()> {
     warn!("pixiv: Works {} is invisible!", source_id);
-    c_item
-        .update_one(
+    retry_db("mark invisible", || {
+        c_item.update_one(
             doc! {
                 "source_id": source_id
             },
@@ -145,14 +256,15 @@ async fn set_item_invisible(c_item: &Collection<Document>, source_id: &str) -> c
             },
             UpdateOptions::builder().upsert(true).build(),
         )
-        .await
-        .context(error::MongoDb)?;
+    })
+    .await
+    .context(error::MongoDb)?;
     Ok(())
 }
 
 pub async fn update_user_id_set(
     api: &AppApi,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     c_user: &Collection<Document>,
     c_image: &Collection<Document>,
     users_need_update_set: BTreeSet<String>,
@@ -161,6 +273,9 @@ pub async fn update_user_id_set(
     let need_sleep = users_need_update_set.len() > 500;
     // Sleep for 1s to avoid 403 error
     for user_id in users_need_update_set {
+        if task_config.cancel.is_cancelled() {
+            break;
+        }
         try_skip!(
             update_user_detail(api, downloader, &user_id, c_user, c_image, task_config).await
         );
@@ -173,14 +288,14 @@ pub async fn update_user_id_set(
 
 async fn update_user_detail(
     api: &AppApi,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     user_id: &str,
     c_user: &Collection<Document>,
     c_image: &Collection<Document>,
     task_config: &TaskConfig,
 ) -> crate::Result<()> {
     info!("updating pixiv user data: {}", user_id);
-    let resp = api.user_detail(&user_id).await.context(error::PixivApi)?;
+    let resp = call_api(|| api.user_detail(&user_id)).await?;
     let user = PixivUser {
         last_modified: Some(DateTime::now()),
         extension: Some(pixiv::User {
@@ -195,14 +310,16 @@ async fn update_user_detail(
         }),
         ..Default::default()
     };
-    c_user
-        .update_one(
+    let user = to_bson(&user).context(error::BsonSerialize)?;
+    retry_db("update user detail", || {
+        c_user.update_one(
             doc! { "source_id": user_id },
-            doc! { "$set": &to_bson(&user).context(error::BsonSerialize)? },
+            doc! { "$set": &user },
             UpdateOptions::builder().upsert(true).build(),
         )
-        .await
-        .context(error::MongoDb)?;
+    })
+    .await
+    .context(error::MongoDb)?;
 
     fn filter_empty(s: &String) -> bool {
         !s.is_empty()
@@ -213,6 +330,14 @@ async fn update_user_detail(
         extension: Some(UserHistory {
             account: resp.user.account,
             name: resp.user.name,
+            profile_image_urls: Some(
+                original_profile_image_url(&resp.user.profile_image_urls.medium)
+                    .into_iter()
+                    .chain(Some(resp.user.profile_image_urls.medium.clone()))
+                    .filter(|u| !u.is_empty())
+                    .collect(),
+            )
+            .filter(|urls: &Vec<String>| !urls.is_empty()),
             avatar_url: Some(resp.user.profile_image_urls.medium),
             gender: Some(resp.profile.gender).filter(filter_empty),
             background_url: resp.profile.background_image_url.filter(filter_empty),
@@ -245,44 +370,37 @@ async fn update_user_detail(
         }),
     };
 
-    c_user
-        .update_one(
+    let history_extension =
+        to_bson(history.extension.as_ref().unwrap()).context(error::BsonSerialize)?;
+    let history_bson = to_bson(&history).context(error::BsonSerialize)?;
+    retry_db("push user history", || {
+        c_user.update_one(
             doc! {
                 "source_id": user_id,
-                "history.extension": {
-                    "$ne": to_bson(history.extension.as_ref().unwrap()).context(error::BsonSerialize)?
-                }
+                "history.extension": { "$ne": &history_extension }
             },
-            doc! { "$push": { "history": to_bson(&history).context(error::BsonSerialize)? } },
+            doc! { "$push": { "history": &history_bson } },
             None,
         )
-        .await
-        .context(error::MongoDb)?;
+    })
+    .await
+    .context(error::MongoDb)?;
 
     let ext = history.extension.unwrap();
 
-    if let Some(ref avatar_url) = ext.avatar_url {
-        download_other_images(downloader, c_image, avatar_url, "avatar", task_config).await?;
-    }
-    if let Some(ref background_url) = ext.background_url {
-        if !background_url.is_empty() {
+    // All the images of a user are under the dir of the user.
+    let images: [(&str, Vec<&String>); 3] = [
+        ("profile", ext.profile_image_urls.iter().flatten().collect()),
+        ("background", ext.background_url.iter().collect()),
+        ("workspace", ext.workspace_image_url.iter().collect()),
+    ];
+    for (kind, urls) in images {
+        for url in urls.into_iter().filter(|u| !u.is_empty()) {
             download_other_images(
                 downloader,
                 c_image,
-                background_url,
-                "background",
-                task_config,
-            )
-            .await?;
-        }
-    }
-    if let Some(ref workspace_image_url) = ext.workspace_image_url {
-        if !workspace_image_url.is_empty() {
-            download_other_images(
-                downloader,
-                c_image,
-                workspace_image_url,
-                "workspace",
+                url,
+                &format!("{user_id}/{kind}"),
                 task_config,
             )
             .await?;
@@ -293,90 +411,483 @@ async fn update_user_detail(
 
 pub async fn save_image(
     c_image: &Collection<Document>,
+    batch: Option<&WriteBatch>,
     size: i64,
-    (w, h): (i32, i32),
-    palette_hsv: Vec<Hsv>,
+    info: ImageInfo,
     url: String,
     image_path_db: String,
     image_path: impl AsRef<Path>,
+    derivative: Option<Derivative>,
 ) -> crate::Result<()> {
-    c_image
-        .update_one(
-            doc! {"url": &url},
-            doc! {
-                "$set": to_bson(&LocalMedia {
-                    _id: None,
-                    url: Some(url),
-                    local_path: image_path_db,
-                    mime: mime_guess::from_path(image_path).first().map(|x| x.to_string()),
-                    size,
-                    extension: Some(ImageMedia {
-                        width: w,
-                        height: h,
-                        palette_hsv,
-                    })
-                }).context(error::BsonSerialize)?
-            },
-            UpdateOptions::builder().upsert(true).build(),
-        )
-        .await
-        .context(error::MongoDb)?;
-    Ok(())
+    let (w, h) = info.dimensions;
+    let media = to_bson(&LocalMedia {
+        _id: None,
+        url: Some(url.clone()),
+        local_path: image_path_db,
+        mime: mime_guess::from_path(image_path)
+            .first()
+            .map(|x| x.to_string()),
+        size,
+        sha256: Some(info.sha256),
+        storage_dir: None,
+        extension: Some(ImageMedia {
+            width: w,
+            height: h,
+            palette_hsv: info.palette_hsv,
+            // Saved as the bits of i64, which is the largest integer of bson.
+            dhash: Some(info.dhash as i64),
+            derivative,
+        }),
+    })
+    .context(error::BsonSerialize)?;
+    update_one(
+        batch,
+        "save image",
+        c_image,
+        doc! {"url": &url},
+        // Downloaded again, so no longer the copy made by `compact`.
+        doc! { "$set": media, "$unset": { "compacted": "", "storage_dir": "" } },
+        true,
+    )
+    .await
 }
 
 pub async fn save_image_ugoira(
     c_image: &Collection<Document>,
+    batch: Option<&WriteBatch>,
     zip_url: String,
-    mut zip_path: PathBuf,
+    zip_path: PathBuf,
     zip_path_db: String,
     zip_size: i64,
-    with_mp4: bool,
+    frame_delay: Vec<i32>,
+    transcoded: &[UgoiraFormat],
 ) -> Result<(), BoxError> {
-    c_image
-        .update_one(
-            doc! {"url": &zip_url},
+    let media = to_bson(&LocalMedia {
+        _id: None,
+        url: Some(zip_url.clone()),
+        local_path: zip_path_db.clone(),
+        mime: Some("application/zip".to_string()),
+        size: zip_size,
+        sha256: None,
+        storage_dir: None,
+        extension: Some(UgoiraMedia {
+            videos: transcoded
+                .iter()
+                .map(|f| f.extension().to_string())
+                .collect(),
+            ..UgoiraMedia::new(frame_delay)
+        }),
+    })
+    .context(error::BsonSerialize)?;
+    update_one(
+        batch,
+        "save ugoira",
+        c_image,
+        doc! {"url": &zip_url},
+        doc! {
+            "$set": media,
+            "$unset": { "sha256": "", "storage_dir": "" },
+        },
+        true,
+    )
+    .await?;
+
+    for format in transcoded {
+        let mut video_path_db = PathBuf::from_slash(&zip_path_db);
+        video_path_db.set_extension(format.extension());
+        let video_path_db = video_path_db.to_slash_lossy();
+
+        let mut video_path = zip_path.clone();
+        video_path.set_extension(format.extension());
+
+        let media = to_bson(&LocalMedia {
+            _id: None,
+            url: None,
+            local_path: video_path_db.clone(),
+            mime: Some(format.mime().to_string()),
+            size: tokio::fs::metadata(&video_path)
+                .await?
+                .len()
+                .try_into()
+                .unwrap_or_default(),
+            sha256: None,
+            storage_dir: None,
+            extension: None::<ImageMedia>,
+        })
+        .context(error::BsonSerialize)?;
+        update_one(
+            batch,
+            "save ugoira video",
+            c_image,
+            doc! {"local_path": &video_path_db},
             doc! {
-                "$set": to_bson(&LocalMedia {
-                    _id: None,
-                    url: Some(zip_url),
-                    local_path: zip_path_db.clone(),
-                    mime: Some("application/zip".to_string()),
-                    size: zip_size,
-                    extension: None::<ImageMedia>
-                }).context(error::BsonSerialize)?
+                "$set": media,
+                "$unset": { "sha256": "", "storage_dir": "" },
             },
-            UpdateOptions::builder().upsert(true).build(),
+            true,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Record that the files of the illust are not downloaded for being too large.
+pub async fn mark_too_large(
+    c_illust: &Collection<Document>,
+    illust_id: &str,
+    size: u64,
+) -> crate::Result<()> {
+    retry_db("mark too large", || {
+        c_illust.update_one(
+            doc! { "source_id": illust_id },
+            doc! { "$set": {
+                "skipped_too_large": {
+                    "size": size as i64,
+                    "at": DateTime::now(),
+                }
+            }},
+            None,
         )
+    })
+    .await
+    .context(error::MongoDb)?;
+    Ok(())
+}
+
+/// Remove the mark of [`mark_too_large`], once the illust is downloaded.
+pub async fn clear_too_large(
+    c_illust: &Collection<Document>,
+    illust_id: &str,
+) -> crate::Result<()> {
+    retry_db("clear too large", || {
+        c_illust.update_one(
+            doc! { "source_id": illust_id, "skipped_too_large": { "$exists": true } },
+            doc! { "$unset": { "skipped_too_large": "" } },
+            None,
+        )
+    })
+    .await
+    .context(error::MongoDb)?;
+    Ok(())
+}
+
+/// Record that only the first `kept` pages of the illust are downloaded.
+pub async fn mark_truncated(
+    c_illust: &Collection<Document>,
+    illust_id: &str,
+    kept: usize,
+    total: usize,
+) -> crate::Result<()> {
+    retry_db("mark truncated", || {
+        c_illust.update_one(
+            doc! { "source_id": illust_id },
+            doc! { "$set": {
+                "truncated_pages": {
+                    "kept": kept as i32,
+                    "total": total as i32,
+                    "at": DateTime::now(),
+                }
+            }},
+            None,
+        )
+    })
+    .await
+    .context(error::MongoDb)?;
+    Ok(())
+}
+
+/// Record whether a page of the illust is downloaded. `page` is the index and the total.
+pub async fn mark_page(
+    c_illust: &Collection<Document>,
+    batch: Option<&WriteBatch>,
+    illust_id: &str,
+    (index, total): (usize, usize),
+    available: bool,
+) -> crate::Result<()> {
+    let (add, remove) = if available {
+        ("extension.pages.available", "extension.pages.missing")
+    } else {
+        ("extension.pages.missing", "extension.pages.available")
+    };
+    let index = index as i32;
+    update_one(
+        batch,
+        "mark page",
+        c_illust,
+        doc! { "source_id": illust_id },
+        doc! {
+            "$set": { "extension.pages.total": total as i32 },
+            "$addToSet": { add: index },
+            "$pull": { remove: index },
+        },
+        false,
+    )
+    .await
+}
+
+/// The illusts saved by a sync, whose incomplete ones it downloads again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncompleteScope {
+    /// The uploads of the user.
+    Uploads(String),
+    Bookmarks(BookmarkVisibility),
+}
+
+/// Whether the illust is in the database with all its pages downloaded,
+/// the first `kept` ones if truncated.
+pub async fn illust_downloaded(
+    c_illust: &Collection<Document>,
+    c_image: &Collection<Document>,
+    illust_id: &str,
+) -> crate::Result<bool> {
+    let illust = match c_illust
+        .find_one(doc! { "source_id": illust_id }, None)
+        .await
+        .context(error::MongoDb)?
+    {
+        Some(illust) => illust,
+        None => return Ok(false),
+    };
+    let missing = illust
+        .get_document("extension")
+        .and_then(|e| e.get_document("pages"))
+        .and_then(|p| p.get_array("missing"))
+        .map_or(false, |m| !m.is_empty());
+    if missing || illust.contains_key("skipped_too_large") {
+        return Ok(false);
+    }
+    let mut urls: Vec<&str> = illust
+        .get_array("history")
+        .ok()
+        .and_then(|h| h.last())
+        .and_then(|h| h.as_document())
+        .and_then(|h| h.get_document("extension").ok())
+        .and_then(|e| e.get_array("image_urls").ok())
+        .map(|u| u.iter().filter_map(|u| u.as_str()).collect())
+        .unwrap_or_default();
+    if let Ok(kept) = illust
+        .get_document("truncated_pages")
+        .and_then(|t| t.get_i32("kept"))
+    {
+        urls.truncate(kept.max(0) as usize);
+    }
+    urls.sort_unstable();
+    urls.dedup();
+    let saved = c_image
+        .count_documents(doc! { "url": { "$in": &urls } }, None)
         .await
         .context(error::MongoDb)?;
+    Ok(saved as usize >= urls.len())
+}
 
-    if with_mp4 {
-        let mut zip_path_db_slash = PathBuf::from_slash(zip_path_db);
-        zip_path_db_slash.set_extension("mp4");
-        let mp4_path_db = zip_path_db_slash.to_slash_lossy();
-
-        zip_path.set_extension("mp4");
-        let mp4_path = zip_path;
+/// Get the ids of the illusts in the scope with pages failed to download.
+pub async fn incomplete_illust_ids(
+    c_user: &Collection<Document>,
+    c_illust: &Collection<Document>,
+    scope: &IncompleteScope,
+) -> crate::Result<Vec<String>> {
+    let mut filter = doc! { "extension.pages.missing.0": { "$exists": true } };
+    match scope {
+        IncompleteScope::Uploads(user_id) => {
+            let user = c_user
+                .find_one(doc! { "source_id": user_id }, None)
+                .await
+                .context(error::MongoDb)?;
+            match user.and_then(|u| u.get_object_id("_id").ok()) {
+                Some(oid) => filter.insert("parent_id", oid),
+                None => return Ok(Vec::new()),
+            };
+        }
+        IncompleteScope::Bookmarks(visibility) => {
+            filter.insert(
+                "extension.bookmark_visibility",
+                to_bson(visibility).unwrap(),
+            );
+        }
+    }
+    let mut cur = c_illust
+        .find(
+            filter,
+            options::FindOptions::builder()
+                .projection(doc! { "source_id": 1 })
+                .build(),
+        )
+        .await
+        .context(error::MongoDb)?;
+    let mut ids = Vec::new();
+    while let Some(d) = cur.try_next().await.context(error::MongoDb)? {
+        if let Ok(id) = d.get_str("source_id") {
+            ids.push(id.to_string());
+        }
+    }
+    Ok(ids)
+}
 
-        c_image
-        .update_one(
-            doc! {"local_path": &mp4_path_db},
-            doc! {
-                "$set": to_bson(&LocalMedia {
-                    _id: None,
-                    url: None,
-                    local_path: mp4_path_db,
-                    mime: Some("video/mp4".to_string()),
-                    size: tokio::fs::metadata(&mp4_path).await?.len().try_into().unwrap_or_default(),
-                    extension: None::<ImageMedia>
-                }).context(error::BsonSerialize)?
-            },
-            UpdateOptions::builder().upsert(true).build(),
+/// The users among `user_ids` with any illust in the database.
+pub async fn users_with_illusts(
+    c_user: &Collection<Document>,
+    c_illust: &Collection<Document>,
+    user_ids: &[String],
+) -> crate::Result<HashSet<String>> {
+    let mut cur = c_user
+        .find(
+            doc! { "source_id": { "$in": user_ids } },
+            options::FindOptions::builder()
+                .projection(doc! { "source_id": 1 })
+                .build(),
         )
         .await
         .context(error::MongoDb)?;
+    let mut oid_to_user = HashMap::new();
+    while let Some(d) = cur.try_next().await.context(error::MongoDb)? {
+        if let (Ok(oid), Ok(id)) = (d.get_object_id("_id"), d.get_str("source_id")) {
+            oid_to_user.insert(oid, id.to_string());
+        }
+    }
+    if oid_to_user.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let oids: Vec<_> = oid_to_user.keys().copied().collect();
+    let parents = c_illust
+        .distinct("parent_id", doc! { "parent_id": { "$in": oids } }, None)
+        .await
+        .context(error::MongoDb)?;
+    Ok(parents
+        .into_iter()
+        .filter_map(|p| match p {
+            Bson::ObjectId(oid) => oid_to_user.remove(&oid),
+            _ => None,
+        })
+        .collect())
+}
+
+/// The tags of the bookmark of the logged in user on the illust.
+pub async fn bookmark_tags(api: &AppApi, illust_id: &str) -> crate::Result<Vec<String>> {
+    let detail = call_api(|| api.illust_bookmark_detail(illust_id)).await?;
+    Ok(detail
+        .bookmark_detail
+        .tags
+        .into_iter()
+        .filter(|t| t.is_registered)
+        .map(|t| t.name)
+        .collect())
+}
+
+/// The page to continue paging the works of a user from,
+/// saved by [`PendingPageToken`] once the downloads are finished.
+///
+/// The number of works examined since the first page is saved with it,
+/// so a sync continued by several runs reports the total.
+#[derive(Debug, Clone)]
+pub struct PageTokens {
+    /// `None` to only log the tokens without the database.
+    c_token: Option<Collection<Document>>,
+    key: String,
+    /// Examined by the runs before, from the first page to the loaded one.
+    examined_before: u32,
+}
+
+impl PageTokens {
+    pub fn new(db: Option<&Database>, kind: &str, user_id: &str) -> Self {
+        Self {
+            c_token: db.map(|db| db.collection("pixiv_page_token")),
+            key: format!("{kind}:{user_id}"),
+            examined_before: 0,
+        }
+    }
+
+    pub async fn load(&mut self) -> crate::Result<Option<String>> {
+        let c_token = match &self.c_token {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let saved = c_token
+            .find_one(doc! { "_id": &self.key }, None)
+            .await
+            .context(error::MongoDb)?;
+        let next_url = saved.as_ref().and_then(|d| d.get_str("next_url").ok());
+        if next_url.is_some() {
+            // Missing if saved by an older version.
+            self.examined_before = saved
+                .as_ref()
+                .and_then(|d| d.get_i64("examined").ok())
+                .unwrap_or_default() as u32;
+        }
+        Ok(next_url.map(|u| u.to_string()))
+    }
+
+    pub fn examined_before(&self) -> u32 {
+        self.examined_before
+    }
+
+    /// Save the URL of the page to continue from, `None` to start from the first page.
+    /// `examined` is the number of works examined by this run.
+    pub async fn save(&self, next_url: Option<&str>, examined: u32) -> crate::Result<()> {
+        let c_token = match &self.c_token {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let examined = (self.examined_before + examined) as i64;
+        match next_url {
+            Some(next_url) => retry_db("save page token", || {
+                c_token.update_one(
+                    doc! { "_id": &self.key },
+                    doc! { "$set": {
+                        "next_url": next_url,
+                        "examined": examined,
+                        "updated_at": DateTime::now(),
+                    }},
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+            })
+            .await
+            .map(|_| ()),
+            None => retry_db("clear page token", || {
+                c_token.delete_one(doc! { "_id": &self.key }, None)
+            })
+            .await
+            .map(|_| ()),
+        }
+        .context(error::MongoDb)
     }
-    Ok(())
+}
+
+/// The last page token of a sync, saved once its downloads are finished,
+/// so the works queued from the pages before are not skipped by the next run.
+#[derive(Debug, Default)]
+pub struct PendingPageToken(Mutex<Option<(PageTokens, Option<String>, u32)>>);
+
+impl PendingPageToken {
+    /// Replace the token to save, see [`PageTokens::save`].
+    pub fn set(&self, page_tokens: &PageTokens, next_url: Option<String>, examined: u32) {
+        *self.0.lock().unwrap() = Some((page_tokens.clone(), next_url, examined));
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    pub async fn save(&self) -> crate::Result<()> {
+        let pending = self.0.lock().unwrap().take();
+        match pending {
+            Some((page_tokens, next_url, examined)) => {
+                page_tokens.save(next_url.as_deref(), examined).await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Get the zip url and the frame delays of an ugoira.
+pub async fn ugoira_metadata(api: &AppApi, illust_id: &str) -> crate::Result<(String, Vec<i32>)> {
+    let ugoira = call_api(|| api.ugoira_metadata(illust_id)).await?;
+    let delay = ugoira
+        .ugoira_metadata
+        .frames
+        .iter()
+        .map(|frame| frame.delay)
+        .collect();
+    Ok((ugoira.ugoira_metadata.zip_urls.medium, delay))
 }
 
 pub async fn save_illusts(
@@ -387,6 +898,8 @@ pub async fn save_illusts(
     c_illust: &Collection<Document>,
     users_need_update_set: &mut BTreeSet<String>,
     ugoira_map: &mut HashMap<String, (String, Vec<i32>)>,
+    bookmark_visibility: Option<BookmarkVisibility>,
+    with_bookmark_tags: bool,
 ) -> crate::Result<()> {
     let mut tags_set = HashSet::new();
     let mut users_map = BTreeMap::new();
@@ -412,6 +925,20 @@ pub async fn save_illusts(
             .iter()
             .filter_map(|t| tags_to_oid.get(&t.name).map(|x| x.clone()))
             .collect();
+        let bookmark_tags = if with_bookmark_tags && i.is_bookmarked {
+            match bookmark_tags(api, &illust_id).await {
+                Ok(tags) => Some(tags),
+                Err(e) => {
+                    warn!(
+                        "cannot get the bookmark tags of illust {}: {}",
+                        illust_id, e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
         let illust = PixivIllust {
             parent_id: Some(
                 users_to_oid
@@ -422,26 +949,45 @@ pub async fn save_illusts(
             tag_ids: tag_ids,
             source_inaccessible: false,
             last_modified: Some(DateTime::now()),
+            last_seen_at: Some(DateTime::now()),
             extension: Some(pixiv::Works {
                 is_bookmarked: i.is_bookmarked,
                 total_bookmarks: i.total_bookmarks,
                 total_view: i.total_view,
+                bookmark_visibility,
+                bookmark_tags,
+                series: i.series.as_ref().map(|s| pixiv::Series {
+                    id: s.id.to_string(),
+                    title: s.title.clone(),
+                }),
+                pages: None,
+                page_count: Some(i.page_count as i32),
             }),
             ..Default::default()
         };
+        // Set the fields of the extension one by one,
+        // to keep the bookmark visibility when the work is saved from elsewhere.
+        let mut illust = to_document(&illust).context(error::BsonSerialize)?;
+        if let Some(Bson::Document(extension)) = illust.remove("extension") {
+            for (k, v) in extension {
+                illust.insert(format!("extension.{k}"), v);
+            }
+        }
 
-        c_illust
-            .update_one(
+        retry_db("save illust", || {
+            c_illust.update_one(
                 doc! {
                     "source_id": &illust_id,
                 },
                 doc! {
-                    "$set": &to_bson(&illust).context(error::BsonSerialize)?
+                    "$set": &illust,
+                    "$setOnInsert": { "first_seen_at": DateTime::now() },
                 },
                 UpdateOptions::builder().upsert(true).build(),
             )
-            .await
-            .context(error::MongoDb)?;
+        })
+        .await
+        .context(error::MongoDb)?;
 
         let mut history = History {
             last_modified: Some(DateTime::now()),
@@ -469,36 +1015,26 @@ pub async fn save_illusts(
             }),
         };
         if i.r#type == "ugoira" {
-            let ugoira = api
-                .ugoira_metadata(&illust_id)
-                .await
-                .context(error::PixivApi)?;
-            let delay: Vec<_> = ugoira
-                .ugoira_metadata
-                .frames
-                .iter()
-                .map(|frame| frame.delay)
-                .collect();
+            let (zip_url, delay) = ugoira_metadata(api, &illust_id).await?;
             history.extension.as_mut().unwrap().ugoira_delay = Some(delay.clone());
-            ugoira_map.insert(
-                illust_id.clone(),
-                (ugoira.ugoira_metadata.zip_urls.medium, delay),
-            );
+            ugoira_map.insert(illust_id.clone(), (zip_url, delay));
         }
 
-        c_illust
-                .update_one(
-                    doc! {
-                        "source_id": &illust_id,
-                        "history.extension": {
-                            "$ne": to_bson(history.extension.as_ref().unwrap()).context(error::BsonSerialize)?
-                        }
-                    },
-                    doc! {"$push": {"history": to_bson(&history).context(error::BsonSerialize)?}},
-                    None,
-                )
-                .await
-                .context(error::MongoDb)?;
+        let history_extension =
+            to_bson(history.extension.as_ref().unwrap()).context(error::BsonSerialize)?;
+        let history = to_bson(&history).context(error::BsonSerialize)?;
+        retry_db("push illust history", || {
+            c_illust.update_one(
+                doc! {
+                    "source_id": &illust_id,
+                    "history.extension": { "$ne": &history_extension }
+                },
+                doc! {"$push": {"history": &history}},
+                None,
+            )
+        })
+        .await
+        .context(error::MongoDb)?;
     }
     Ok(())
 }
@@ -548,35 +1084,44 @@ pub async fn save_novels(
         let novel_id = n.id.to_string();
         let novel = PixivNovel {
             last_modified: Some(DateTime::now()),
+            last_seen_at: Some(DateTime::now()),
             parent_id: Some(users_to_oid[&n.user.id.to_string()]),
             tag_ids,
             extension: Some(pixiv::Works {
                 is_bookmarked: n.is_bookmarked,
                 total_bookmarks: n.total_bookmarks,
                 total_view: n.total_view,
+                bookmark_visibility: None,
+                bookmark_tags: None,
+                series: None,
+                pages: None,
+                page_count: None,
             }),
             ..Default::default()
         };
 
-        let matched_count = c_novel
-            .update_one(
+        let novel = to_bson(&novel).context(error::BsonSerialize)?;
+        let matched_count = retry_db("save novel", || {
+            c_novel.update_one(
                 doc! {
                     "source_id": &novel_id,
                 },
                 doc! {
-                    "$set": &to_bson(&novel).context(error::BsonSerialize)?
+                    "$set": &novel,
+                    "$setOnInsert": { "first_seen_at": DateTime::now() },
                 },
                 UpdateOptions::builder().upsert(true).build(),
             )
-            .await
-            .context(error::MongoDb)?
-            .matched_count;
+        })
+        .await
+        .context(error::MongoDb)?
+        .matched_count;
         if matched_count != 0 && !update_exists {
             continue;
         }
 
         info!("pixiv: getting novel text of {}", novel_id);
-        let r = api.novel_text(&novel_id).await.context(error::PixivApi)?;
+        let r = call_api(|| api.novel_text(&novel_id)).await?;
 
         let history = History {
             extension: Some(NovelHistory {
@@ -590,19 +1135,21 @@ pub async fn save_novels(
             last_modified: Some(DateTime::now()),
         };
 
-        c_novel
-                .update_one(
-                    doc! {
-                        "source_id": &novel_id,
-                        "history.extension": {
-                            "$ne": to_bson(history.extension.as_ref().unwrap()).context(error::BsonSerialize)?
-                        }
-                    },
-                    doc! {"$push": {"history": to_bson(&history).context(error::BsonSerialize)?}},
-                    None,
-                )
-                .await
-                .context(error::MongoDb)?;
+        let history_extension =
+            to_bson(history.extension.as_ref().unwrap()).context(error::BsonSerialize)?;
+        let history = to_bson(&history).context(error::BsonSerialize)?;
+        retry_db("push novel history", || {
+            c_novel.update_one(
+                doc! {
+                    "source_id": &novel_id,
+                    "history.extension": { "$ne": &history_extension }
+                },
+                doc! {"$push": {"history": &history}},
+                None,
+            )
+        })
+        .await
+        .context(error::MongoDb)?;
     }
 
     Ok(())
@@ -620,12 +1167,64 @@ pub async fn create_indexes(db: &Database) -> crate::Result<()> {
         .map(|k| IndexModel::builder().keys(doc! { k: 1 }).build())
         .collect();
 
-    for c in [c_illust, c_novel] {
+    let seen_indexes: Vec<_> = ["first_seen_at", "last_seen_at"]
+        .into_iter()
+        .map(|k| IndexModel::builder().keys(doc! { k: -1 }).build())
+        .collect();
+
+    for c in [&c_illust, &c_novel] {
+        c.create_indexes(seen_indexes.clone(), None)
+            .await
+            .context(error::MongoDb)?;
         c.create_indexes(item_indexes.clone(), None)
             .await
             .context(error::MongoDb)?;
     }
 
+    c_illust
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "extension.series.id": 1 })
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+
+    c_illust
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "extension.bookmark_tags": 1 })
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+
+    c_illust
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "extension.page_count": 1 })
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+
+    // For paging the works of a user.
+    c_illust
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "parent_id": 1, "_id": -1 })
+                .build(),
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+
     c_user
         .create_indexes(item_indexes[..2].to_vec(), None)
         .await
@@ -635,6 +1234,45 @@ pub async fn create_indexes(db: &Database) -> crate::Result<()> {
         .create_index(IndexModel::builder().keys(doc! { "url": 1 }).build(), None)
         .await
         .context(error::MongoDb)?;
+    c_image
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "sha256": 1 })
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+    c_image
+        .create_index(
+            IndexModel::builder().keys(doc! { "local_path": 1 }).build(),
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+    // To not download again the originals replaced by `compact`.
+    c_image
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "compacted.local_path": 1 })
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
+
+    db.collection::<Document>("pixiv_image_embedding")
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "local_path": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await
+        .context(error::MongoDb)?;
 
     c_tag
         .create_index(
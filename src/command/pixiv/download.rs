@@ -1,26 +1,38 @@
 use aria2_ws::TaskOptions;
 use futures::FutureExt;
 use lazy_static::lazy_static;
-use log::warn;
+use log::{debug, info, warn};
 use mongodb::{
     bson::{doc, Document},
+    options::FindOneOptions,
     Collection,
 };
+use snafu::ResultExt;
 
-use regex::{Captures, Regex};
+use path_slash::PathBufExt;
+use regex::Regex;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 use tokio::task::spawn_blocking;
 
 use super::{
+    database::WriteBatch,
+    quota::Quota,
+    sidecar::{self, Sidecar},
+    transcode::TranscodeSlot,
     utils::{self, filename_from_url},
-    TaskConfig,
+    SyncStats, TaskConfig,
 };
 use crate::{
-    downloader::{Aria2Downloader, Task, TaskHooks},
-    error::{self, BoxError}, utils::try_skip,
+    command::verify::hash_file,
+    config::{CollisionPolicy, DerivativeConfig, DirectorySharding, SidecarFormat, UgoiraFormat},
+    downloader::{BoxFutureResult, Downloader, Pipeline, Task, TaskHooks},
+    error::{self, BoxError},
+    model::Derivative,
+    utils::{pace, try_skip, warn_throttled, CpuPool},
 };
 
 lazy_static! {
@@ -44,15 +56,93 @@ lazy_static! {
     /// __4__ `jpg`
     static ref RE_ILLUST_URL: Regex =
         Regex::new(r"/(\d{4}/\d{2}/\d{2}/\d{2}/\d{2}/\d{2})/((.*)\.(.*))$").unwrap();
+
+    /// Match the page index in the file name, e.g. `3` in `92187206_p3`.
+    static ref RE_PAGE_INDEX: Regex = Regex::new(r"_p(\d+)$").unwrap();
+
+    /// Match the resized profile image URL.
+    ///
+    /// # Example
+    ///
+    /// `https://i.pximg.net/user-profile/img/2021/01/02/03/04/05/20000000_abcdef_170.jpg`
+    /// is the 170px version of
+    /// `https://i.pximg.net/user-profile/img/2021/01/02/03/04/05/20000000_abcdef.jpg`
+    static ref RE_PROFILE_IMAGE_URL: Regex =
+        Regex::new(r"^(https://i\.pximg\.net/user-profile/img/.+)_\d+(\.\w+)$").unwrap();
 }
 
-fn get_captures(url: &str) -> crate::Result<Captures> {
-    RE_ILLUST_URL.captures(&url).ok_or(
-        error::PixivParse {
-            message: format!("cannot match url with regex: {url}"),
-        }
-        .build(),
-    )
+/// Get the original quality URL of a resized profile image.
+///
+/// Returns `None` if the URL is not a resized profile image,
+/// e.g. the default avatar of users without one.
+pub fn original_profile_image_url(url: &str) -> Option<String> {
+    let captures = RE_PROFILE_IMAGE_URL.captures(url)?;
+    Some(format!("{}{}", &captures[1], &captures[2]))
+}
+
+/// Build the path from the file name for URLs without the date,
+/// as `{illust_id}_p{page}.{ext}` or `{illust_id}/{filename}`.
+fn illust_path_without_date(
+    user_dir: &str,
+    illust_id: &str,
+    url: &str,
+    is_multi_page: bool,
+) -> crate::Result<String> {
+    let filename = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|u| u.rsplit('/').next())
+        .filter(|f| f.contains('.') && !f.starts_with('.'))
+        .ok_or(
+            error::PixivParse {
+                message: format!("cannot get file name from url: {url}"),
+            }
+            .build(),
+        )?;
+    debug!("pixiv: no date in url, using the file name: {url}");
+    if is_multi_page {
+        return Ok(format!("{user_dir}/{illust_id}/{filename}"));
+    }
+    let (stem, ext) = filename.rsplit_once('.').unwrap();
+    let page = RE_PAGE_INDEX
+        .captures(stem)
+        .map_or("0", |c| c.get(1).unwrap().as_str());
+    Ok(format!("{user_dir}/{illust_id}_p{page}.{ext}"))
+}
+
+/// Build the slash path of an illust image or ugoira zip, relative to the storage dir.
+///
+/// Single page works are saved as `{user_dir}/{illust_id}_p0_{date}.{ext}`,
+/// and the pages of multi-page works in `{user_dir}/{illust_id}_{date}/`.
+/// URLs without the date fall back to [`illust_path_without_date`], which are not sharded.
+fn illust_path(
+    user_dir: &str,
+    illust_id: &str,
+    url: &str,
+    is_multi_page: bool,
+    sharding: DirectorySharding,
+) -> crate::Result<String> {
+    let captures = match RE_ILLUST_URL.captures(url) {
+        Some(c) => c,
+        None => return illust_path_without_date(user_dir, illust_id, url, is_multi_page),
+    };
+    let date_slash = captures.get(1).unwrap().as_str();
+    let date = date_slash.replace('/', "");
+
+    let file_path_slash = if is_multi_page {
+        let filename = captures.get(2).unwrap().as_str();
+        format!("{illust_id}_{date}/{filename}")
+    } else {
+        let id_page = captures.get(3).unwrap().as_str();
+        let ext = captures.get(4).unwrap().as_str();
+        format!("{id_page}_{date}.{ext}")
+    };
+    let shard = match sharding {
+        DirectorySharding::None => String::new(),
+        DirectorySharding::Year => format!("{}/", &date_slash[..4]),
+        DirectorySharding::YearMonth => format!("{}/", &date_slash[..7]),
+    };
+    Ok(format!("{user_dir}/{shard}{file_path_slash}"))
 }
 
 fn file_exists(path: impl AsRef<Path>) -> bool {
@@ -65,49 +155,367 @@ fn file_exists(path: impl AsRef<Path>) -> bool {
     false
 }
 
-async fn on_success_ugoira(
+/// The existing file at `path_slash`, in `parent_dir` or else in the first storage tier holding it.
+fn existing_file(task_config: &TaskConfig, path_slash: &str) -> Option<PathBuf> {
+    let path = task_config.parent_dir.join(path_slash);
+    if file_exists(&path) {
+        return Some(path);
+    }
+    let path_db = task_config.db_path(path_slash);
+    task_config
+        .storage_tiers
+        .iter()
+        .map(|d| d.join(&path_db))
+        .find(|p| file_exists(p))
+}
+
+/// Whether the existing file is incomplete, e.g. left by a crash.
+///
+/// Empty files are always incomplete. The size and hash in `record` are checked if saved.
+async fn existing_file_broken(path: &Path, record: Option<&Document>) -> bool {
+    let len = match tokio::fs::metadata(path).await {
+        Ok(m) => m.len(),
+        Err(_) => return true,
+    };
+    if len == 0 {
+        return true;
+    }
+    let record = match record {
+        Some(record) => record,
+        None => return false,
+    };
+    if let Ok(size) = record.get_i64("size") {
+        if size > 0 && size as u64 != len {
+            return true;
+        }
+    }
+    if let Ok(sha256) = record.get_str("sha256") {
+        let path = path.to_owned();
+        return match spawn_blocking(move || hash_file(&path)).await.unwrap() {
+            Ok(hash) => hash != sha256,
+            Err(_) => true,
+        };
+    }
+    false
+}
+
+/// Skip the existing file of the same URL, or download it again with `replace`,
+/// or if it is incomplete and `verify_existing` is set.
+///
+/// The file is downloaded again to `parent_dir`, even if it exists in a storage tier.
+async fn skip_or_repair(
+    candidate: String,
+    existing: &Path,
+    record: Option<&Document>,
+    task_config: &TaskConfig,
+) -> Option<String> {
+    if task_config.replace {
+        Some(candidate)
+    } else if task_config.verify_existing && existing_file_broken(existing, record).await {
+        warn!("pixiv: {candidate} is incomplete, downloading it again");
+        Some(candidate)
+    } else {
+        None
+    }
+}
+
+/// Append a numeric suffix to the file name of a slash path.
+///
+/// `a/b.jpg` with `n = 1` becomes `a/b_1.jpg`.
+fn suffixed_path_slash(path_slash: &str, n: u32) -> String {
+    let (dir, filename) = match path_slash.rsplit_once('/') {
+        Some((dir, filename)) => (format!("{dir}/"), filename),
+        None => (String::new(), path_slash),
+    };
+    match filename.rsplit_once('.') {
+        Some((stem, ext)) => format!("{dir}{stem}_{n}.{ext}"),
+        None => format!("{dir}{filename}_{n}"),
+    }
+}
+
+/// Decide where to download `url` to, following the collision policy.
+///
+/// Returns `None` if the download should be skipped,
+/// which is the case if the file has been downloaded from the same URL.
+async fn resolve_path_slash(
+    c_image: &Collection<Document>,
+    url: &str,
+    path_slash: String,
+    task_config: &TaskConfig,
+) -> crate::Result<Option<String>> {
+    let mut candidate = path_slash.clone();
+    let mut n = 0;
+    loop {
+        let existing = match existing_file(task_config, &candidate) {
+            Some(existing) => existing,
+            None => {
+                if n == 0 && !task_config.no_db && !task_config.replace {
+                    let compacted = c_image
+                        .count_documents(
+                            doc! { "compacted.local_path": task_config.db_path(&candidate) },
+                            None,
+                        )
+                        .await
+                        .context(error::MongoDb)?;
+                    if compacted > 0 {
+                        // Replaced by a smaller copy with `compact`.
+                        return Ok(None);
+                    }
+                }
+                if n > 0 {
+                    warn!("pixiv: file name collision, saving {url} to {candidate}");
+                }
+                return Ok(Some(candidate));
+            }
+        };
+        if task_config.no_db {
+            // Nothing to compare with, keep the existing file.
+            return Ok(skip_or_repair(candidate, &existing, None, task_config).await);
+        }
+        let record = c_image
+            .find_one(
+                doc! { "local_path": task_config.db_path(&candidate) },
+                FindOneOptions::builder()
+                    .projection(doc! { "url": true, "size": true, "sha256": true })
+                    .build(),
+            )
+            .await
+            .context(error::MongoDb)?;
+        let stored_url = record
+            .as_ref()
+            .and_then(|r| r.get_str("url").ok().map(|u| u.to_string()));
+        match stored_url {
+            // Files without records are assumed to be from the same URL.
+            None => {
+                return Ok(skip_or_repair(candidate, &existing, record.as_ref(), task_config).await)
+            }
+            Some(stored_url) if stored_url == url => {
+                return Ok(skip_or_repair(candidate, &existing, record.as_ref(), task_config).await)
+            }
+            Some(stored_url) => match task_config.collision_policy {
+                CollisionPolicy::Skip => {
+                    warn!("pixiv: {candidate} was saved from {stored_url}, skipping {url}");
+                    return Ok(None);
+                }
+                CollisionPolicy::Overwrite => {
+                    warn!("pixiv: {candidate} was saved from {stored_url}, overwriting with {url}");
+                    return Ok(Some(candidate));
+                }
+                CollisionPolicy::Rename => {
+                    n += 1;
+                    candidate = suffixed_path_slash(&path_slash, n);
+                }
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+struct UgoiraContext {
     zip_url: String,
     zip_path: PathBuf,
     c_image: Collection<Document>,
+    write_batch: Option<Arc<WriteBatch>>,
+    path_slash: String,
+    frame_delay: Vec<i32>,
+    ffmpeg: utils::Ffmpeg,
+    formats: Vec<UgoiraFormat>,
+    transcoded: Vec<UgoiraFormat>,
+    zip_size: i64,
+}
+
+fn on_success_ugoira(
+    zip_url: String,
+    zip_path: PathBuf,
+    c_image: Collection<Document>,
+    write_batch: Option<Arc<WriteBatch>>,
     path_slash: String,
     ugoira_frame_delay: Vec<i32>,
-    ffmpeg_path: Option<PathBuf>,
-) -> Result<(), BoxError> {
-    let with_mp4 = ffmpeg_path.is_some();
-    if let Some(ffmpeg_path) = ffmpeg_path {
-        let zip_path = zip_path.clone();
-        spawn_blocking(move || utils::ugoira_to_mp4(&ffmpeg_path, &zip_path, ugoira_frame_delay))
-            .await
-            .unwrap()?;
+    ffmpeg: utils::Ffmpeg,
+    formats: Vec<UgoiraFormat>,
+    transcode_slot: Option<TranscodeSlot>,
+    no_db: bool,
+) -> BoxFutureResult {
+    let ugoira_context = UgoiraContext {
+        zip_url,
+        zip_path,
+        c_image,
+        write_batch,
+        path_slash,
+        frame_delay: ugoira_frame_delay,
+        ffmpeg,
+        formats,
+        transcoded: Vec::new(),
+        zip_size: 0,
+    };
+    let pipeline = Pipeline::new()
+        .then("transcode", |mut ctx: UgoiraContext| async move {
+            if let Some(slot) = transcode_slot {
+                ctx.transcoded = slot
+                    .transcode(
+                        ctx.ffmpeg.clone(),
+                        ctx.zip_path.clone(),
+                        ctx.frame_delay.clone(),
+                        ctx.formats.clone(),
+                    )
+                    .await;
+            }
+            Ok::<_, BoxError>(ctx)
+        })
+        .then("size", |mut ctx: UgoiraContext| async move {
+            ctx.zip_size = tokio::fs::metadata(&ctx.zip_path).await?.len().try_into()?;
+            Ok::<_, BoxError>(ctx)
+        });
+    if no_db {
+        return pipeline.into_hook(ugoira_context);
     }
-    let zip_size: i64 = tokio::fs::metadata(&zip_path).await?.len().try_into()?;
+    pipeline
+        .then("save", |ctx: UgoiraContext| async move {
+            super::database::save_image_ugoira(
+                &ctx.c_image,
+                ctx.write_batch.as_deref(),
+                ctx.zip_url.clone(),
+                ctx.zip_path.clone(),
+                ctx.path_slash.clone(),
+                ctx.zip_size,
+                ctx.frame_delay.clone(),
+                &ctx.transcoded,
+            )
+            .await?;
+            Ok::<_, BoxError>(ctx)
+        })
+        .into_hook(ugoira_context)
+}
 
-    super::database::save_image_ugoira(&c_image, zip_url, zip_path, path_slash, zip_size, with_mp4)
-        .await?;
+#[derive(Clone)]
+struct IllustContext {
+    url: String,
+    image_path: PathBuf,
+    c_image: Collection<Document>,
+    write_batch: Option<Arc<WriteBatch>>,
+    path_slash: String,
+    size: i64,
+    info: utils::ImageInfo,
+    ffmpeg: utils::Ffmpeg,
+    derivative_config: Option<DerivativeConfig>,
+    cpu: CpuPool,
+    derivative: Option<Derivative>,
+    #[cfg(feature = "embedding")]
+    embedding: Option<super::embedding::EmbeddingTask>,
+}
 
+/// Save the embedding of the image. Failures are not fatal, like the derivatives.
+#[cfg(feature = "embedding")]
+async fn save_embedding(ctx: &IllustContext) -> Result<(), BoxError> {
+    let task = match ctx.embedding.clone() {
+        Some(task) => task,
+        None => return Ok(()),
+    };
+    let image_path = ctx.image_path.clone();
+    let embedder = task.embedder.clone();
+    let r = ctx
+        .cpu
+        .run(move || -> Result<Vec<f32>, BoxError> { embedder.embed(&image::open(image_path)?) })
+        .await?;
+    match r {
+        Ok(vector) => task.save(&ctx.path_slash, vector).await?,
+        Err(e) => warn_throttled(
+            "embedding failed",
+            format!("cannot get embedding of {:?}: {}", ctx.image_path, e),
+        ),
+    }
     Ok(())
 }
 
-async fn on_success_illust(
+fn on_success_illust(
     url: String,
     image_path: PathBuf,
     c_image: Collection<Document>,
     path_slash: String,
-) -> Result<(), BoxError> {
-    let size: i64 = tokio::fs::metadata(&image_path).await?.len().try_into()?;
-    let ((w, h), hsv_v) = {
-        let image_path = image_path.clone();
-        spawn_blocking(move || utils::get_palette(image_path))
-    }
-    .await
-    .unwrap()?;
-    super::database::save_image(&c_image, size, (w, h), hsv_v, url, path_slash, image_path).await?;
-
-    Ok(())
+    task_config: &TaskConfig,
+) -> BoxFutureResult {
+    Pipeline::new()
+        .then("size", |mut ctx: IllustContext| async move {
+            ctx.size = tokio::fs::metadata(&ctx.image_path)
+                .await?
+                .len()
+                .try_into()?;
+            Ok::<_, BoxError>(ctx)
+        })
+        .then("info", |mut ctx: IllustContext| async move {
+            let image_path = ctx.image_path.clone();
+            ctx.info = ctx
+                .cpu
+                .run(move || utils::get_image_info(image_path))
+                .await??;
+            Ok::<_, BoxError>(ctx)
+        })
+        .then("derivative", |mut ctx: IllustContext| async move {
+            let config = match ctx.derivative_config.clone() {
+                Some(config) => config,
+                None => return Ok(ctx),
+            };
+            let ffmpeg = ctx.ffmpeg.clone();
+            let image_path = ctx.image_path.clone();
+            let format = config.format;
+            // Failing to make the derivative is not fatal, the original is still served.
+            match ctx
+                .cpu
+                .run(move || utils::make_derivative(&ffmpeg, image_path, &config))
+                .await?
+            {
+                Ok((path, (width, height))) => {
+                    ctx.derivative = Some(Derivative {
+                        local_path: utils::derivative_path(&ctx.path_slash, format)
+                            .to_slash_lossy(),
+                        size: tokio::fs::metadata(&path).await?.len().try_into()?,
+                        mime: format.mime().to_string(),
+                        width,
+                        height,
+                    });
+                }
+                Err(e) => warn_throttled(
+                    "derivative failed",
+                    format!("cannot make derivative of {:?}: {}", ctx.image_path, e),
+                ),
+            }
+            Ok::<_, BoxError>(ctx)
+        })
+        .then("save", |ctx: IllustContext| async move {
+            super::database::save_image(
+                &ctx.c_image,
+                ctx.write_batch.as_deref(),
+                ctx.size,
+                ctx.info.clone(),
+                ctx.url.clone(),
+                ctx.path_slash.clone(),
+                &ctx.image_path,
+                ctx.derivative.clone(),
+            )
+            .await?;
+            #[cfg(feature = "embedding")]
+            save_embedding(&ctx).await?;
+            Ok::<_, BoxError>(ctx)
+        })
+        .into_hook(IllustContext {
+            url,
+            image_path,
+            c_image,
+            write_batch: task_config.write_batch.clone(),
+            path_slash,
+            size: 0,
+            info: utils::ImageInfo::default(),
+            ffmpeg: task_config.ffmpeg.clone(),
+            derivative_config: task_config.derivative.clone(),
+            cpu: task_config.cpu.clone(),
+            derivative: None,
+            #[cfg(feature = "embedding")]
+            embedding: task_config.embedding.clone(),
+        })
 }
 
 pub async fn download_other_images(
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     c_image: &Collection<Document>,
     url: &str,
     parent_dir: &str,
@@ -115,24 +523,36 @@ pub async fn download_other_images(
 ) -> crate::Result<()> {
     let filename = filename_from_url(&url)?;
 
-    let path_slash = format!("{parent_dir}/{filename}");
+    let path_slash = match resolve_path_slash(
+        c_image,
+        url,
+        format!("{parent_dir}/{filename}"),
+        task_config,
+    )
+    .await?
+    {
+        Some(path_slash) => path_slash,
+        None => {
+            task_config.stats.skip_existing_file();
+            return Ok(());
+        }
+    };
     let path = task_config.parent_dir.join(&path_slash);
 
-    if file_exists(&path) {
-        return Ok(());
-    }
-
     let task = Task {
         hooks: Some(TaskHooks {
-            on_success: Some(
-                on_success_illust(
+            on_success: Some(size_hook(
+                Some(on_success_illust(
                     url.to_string(),
                     path.clone(),
                     c_image.clone(),
-                    path_slash.clone(),
-                )
-                .boxed(),
-            ),
+                    task_config.db_path(&path_slash),
+                    task_config,
+                )),
+                task_config.quota.clone(),
+                task_config.stats.clone(),
+                path,
+            )),
             ..Default::default()
         }),
         options: Some(TaskOptions {
@@ -143,18 +563,215 @@ pub async fn download_other_images(
             ..Default::default()
         }),
         url: url.to_string(),
+        ..Default::default()
     };
-    downloader.add_task(task).await
+    downloader.add_task(task).await?;
+    task_config.stats.queue();
+    Ok(())
+}
+
+/// URLs added to the downloader in a sync, to avoid adding the same URL twice.
+#[derive(Debug, Default)]
+pub struct SeenUrls {
+    urls: HashSet<String>,
+    duplicates: u32,
+}
+
+impl SeenUrls {
+    /// Returns `false` and counts a duplicate if the URL has been seen.
+    fn insert(&mut self, url: &str) -> bool {
+        if self.urls.contains(url) {
+            self.duplicates += 1;
+            false
+        } else {
+            self.urls.insert(url.to_string());
+            true
+        }
+    }
+
+    pub fn duplicates(&self) -> u32 {
+        self.duplicates
+    }
+}
+
+/// Whether the file of `url` is going to be downloaded, i.e. not seen in this sync
+/// and not existing in the storage, sharded or not, unless replaced.
+fn will_download(
+    seen_urls: &SeenUrls,
+    url: &str,
+    user_dir: &str,
+    illust_id: &str,
+    is_multi_page: bool,
+    task_config: &TaskConfig,
+) -> bool {
+    if seen_urls.urls.contains(url) {
+        return false;
+    }
+    if task_config.replace {
+        return true;
+    }
+    let exists = |sharding| {
+        illust_path(user_dir, illust_id, url, is_multi_page, sharding)
+            .map_or(false, |p| existing_file(task_config, &p).is_some())
+    };
+    !exists(task_config.directory_sharding) && !exists(DirectorySharding::None)
+}
+
+/// The file downloaded to replace the existing one at `path`.
+fn replacement_path(path_slash: &str) -> String {
+    format!("{path_slash}.replace")
+}
+
+/// Move the replacement over the existing file before running the hook,
+/// so the existing file is kept if the download fails.
+///
+/// A failed replacement is left with its `.aria2` control file to be resumed next time.
+fn replace_hook(
+    hook: Option<BoxFutureResult>,
+    replacement: PathBuf,
+    path: PathBuf,
+) -> BoxFutureResult {
+    async move {
+        tokio::fs::rename(&replacement, &path).await?;
+        debug!("pixiv: replaced {:?}", path);
+        if let Some(hook) = hook {
+            hook.await?;
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Count the size of the downloaded file towards the stats and the quota,
+/// even if the hook fails.
+fn size_hook(
+    hook: Option<BoxFutureResult>,
+    quota: Option<Arc<Quota>>,
+    stats: Arc<SyncStats>,
+    path: PathBuf,
+) -> BoxFutureResult {
+    async move {
+        let r = match hook {
+            Some(hook) => hook.await,
+            None => Ok(()),
+        };
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            stats.add_bytes(metadata.len());
+            if let Some(quota) = quota {
+                // The file is saved anyway, so the task is not failed for it.
+                if let Err(e) = quota.add(metadata.len()).await {
+                    warn_throttled(
+                        "quota failed",
+                        format!("cannot count {path:?} towards the quota: {e}"),
+                    );
+                }
+            }
+        }
+        r
+    }
+    .boxed()
+}
+
+/// Write the sidecar once the file is in place.
+fn sidecar_hook(
+    hook: Option<BoxFutureResult>,
+    sidecar: Sidecar,
+    format: SidecarFormat,
+    path: PathBuf,
+) -> BoxFutureResult {
+    async move {
+        if let Some(hook) = hook {
+            hook.await?;
+        }
+        sidecar::write(&sidecar, format, &path).await?;
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Update the sidecar of an existing file, e.g. after the tags are changed.
+async fn sync_sidecar(sidecar: Option<&Sidecar>, url: &str, path: &Path, task_config: &TaskConfig) {
+    if let (Some(sidecar), Some(format)) = (sidecar, task_config.sidecar) {
+        if let Err(e) = sidecar::write(&sidecar.for_file(url), format, path).await {
+            warn_throttled(
+                "sidecar failed",
+                format!("cannot write sidecar of {path:?}: {e}"),
+            );
+        }
+    }
+}
+
+/// Record the page of an existing file as available, e.g. one missing before
+/// and downloaded by another sync since.
+async fn mark_existing_page(
+    c_illust: &Collection<Document>,
+    illust_id: &str,
+    page: Option<(usize, usize)>,
+    task_config: &TaskConfig,
+) -> crate::Result<()> {
+    match page {
+        Some(page) if !task_config.no_db => {
+            super::database::mark_page(
+                c_illust,
+                task_config.write_batch.as_deref(),
+                illust_id,
+                page,
+                true,
+            )
+            .await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Record the availability of the page after the download.
+fn page_hook(
+    hook: Option<BoxFutureResult>,
+    c_illust: Collection<Document>,
+    write_batch: Option<Arc<WriteBatch>>,
+    illust_id: String,
+    page: (usize, usize),
+    available: bool,
+) -> BoxFutureResult {
+    async move {
+        if let Some(hook) = hook {
+            if let Err(e) = hook.await {
+                super::database::mark_page(
+                    &c_illust,
+                    write_batch.as_deref(),
+                    &illust_id,
+                    page,
+                    false,
+                )
+                .await?;
+                return Err(e);
+            }
+        }
+        super::database::mark_page(
+            &c_illust,
+            write_batch.as_deref(),
+            &illust_id,
+            page,
+            available,
+        )
+        .await?;
+        Ok(())
+    }
+    .boxed()
 }
 
 async fn download_illust(
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     c_image: &Collection<Document>,
+    c_illust: &Collection<Document>,
+    seen_urls: &mut SeenUrls,
     url: Option<String>,
-    user_id: &str,
+    user_dir: &str,
     illust_id: &str,
     is_multi_page: bool,
     ugoira_frame_delay: Option<Vec<i32>>,
+    page: Option<(usize, usize)>,
+    sidecar: Option<&Sidecar>,
     task_config: &TaskConfig,
 ) -> crate::Result<()> {
     let url = url.ok_or(
@@ -163,76 +780,242 @@ async fn download_illust(
         }
         .build(),
     )?;
+    if !seen_urls.insert(&url) {
+        return Ok(());
+    }
 
-    let captures = get_captures(&url)?;
-    let date = captures.get(1).unwrap().as_str().replace("/", "");
-
-    let path_slash = if is_multi_page {
-        let filename = captures.get(2).unwrap().as_str();
-        format!("{user_id}/{illust_id}_{date}/{filename}")
+    let path_slash = illust_path(
+        user_dir,
+        illust_id,
+        &url,
+        is_multi_page,
+        task_config.directory_sharding,
+    )?;
+    let path_slash = if task_config.directory_sharding != DirectorySharding::None {
+        let unsharded = illust_path(
+            user_dir,
+            illust_id,
+            &url,
+            is_multi_page,
+            DirectorySharding::None,
+        )?;
+        match existing_file(task_config, &unsharded) {
+            // Downloaded before sharding is enabled.
+            Some(_) if task_config.replace => unsharded,
+            Some(existing) => {
+                sync_sidecar(sidecar, &url, &existing, task_config).await;
+                mark_existing_page(c_illust, illust_id, page, task_config).await?;
+                task_config.stats.skip_existing_file();
+                return Ok(());
+            }
+            None => path_slash,
+        }
     } else {
-        let id_page = captures.get(3).unwrap().as_str();
-        let ext = captures.get(4).unwrap().as_str();
-        format!("{user_id}/{id_page}_{date}.{ext}")
+        path_slash
     };
 
+    let path_slash =
+        match resolve_path_slash(c_image, &url, path_slash.clone(), task_config).await? {
+            Some(path_slash) => path_slash,
+            None => {
+                // Not found if replaced by a smaller copy with `compact`.
+                if let Some(existing) = existing_file(task_config, &path_slash) {
+                    sync_sidecar(sidecar, &url, &existing, task_config).await;
+                }
+                mark_existing_page(c_illust, illust_id, page, task_config).await?;
+                task_config.stats.skip_existing_file();
+                return Ok(());
+            }
+        };
     let path = task_config.parent_dir.join(&path_slash);
-
-    if file_exists(&path) {
-        return Ok(());
-    }
+    // Replaced, overwritten or repaired, but only once the new download succeeds.
+    let out = if file_exists(&path) {
+        Some(replacement_path(&path_slash))
+    } else {
+        None
+    };
 
     let on_success_hook = if let Some(ugoira_frame_delay) = ugoira_frame_delay {
         // The task is an ugoira zip.
-        on_success_ugoira(
+        let transcode_slot = match task_config.transcode {
+            // Waits while the queue of the transcode workers is full.
+            Some(ref pool) => Some(pool.reserve().await),
+            None => None,
+        };
+        Some(on_success_ugoira(
             url.clone(),
             path.clone(),
             c_image.clone(),
-            path_slash.clone(),
+            task_config.write_batch.clone(),
+            task_config.db_path(&path_slash),
             ugoira_frame_delay,
-            task_config.ffmpeg_path.clone(),
-        )
-        .boxed()
+            task_config.ffmpeg.clone(),
+            task_config.ugoira_formats.clone(),
+            transcode_slot,
+            task_config.no_db,
+        ))
+    } else if task_config.no_db {
+        None
     } else {
-        on_success_illust(
+        Some(on_success_illust(
             url.clone(),
             path.clone(),
             c_image.clone(),
-            path_slash.clone(),
-        )
-        .boxed()
+            task_config.db_path(&path_slash),
+            task_config,
+        ))
     };
 
-    let task = Task {
-        hooks: Some(TaskHooks {
-            on_success: Some(on_success_hook),
+    let on_success_hook = match out {
+        Some(ref out) => Some(replace_hook(
+            on_success_hook,
+            task_config.parent_dir.join(out),
+            path.clone(),
+        )),
+        None => on_success_hook,
+    };
+    let on_success_hook = match (sidecar, task_config.sidecar) {
+        (Some(sidecar), Some(format)) => Some(sidecar_hook(
+            on_success_hook,
+            sidecar.for_file(&url),
+            format,
+            path.clone(),
+        )),
+        _ => on_success_hook,
+    };
+    let on_success_hook = Some(size_hook(
+        on_success_hook,
+        task_config.quota.clone(),
+        task_config.stats.clone(),
+        path.clone(),
+    ));
+
+    let hooks = match page {
+        Some(page) if !task_config.no_db => TaskHooks {
+            on_success: Some(page_hook(
+                on_success_hook,
+                c_illust.clone(),
+                task_config.write_batch.clone(),
+                illust_id.to_string(),
+                page,
+                true,
+            )),
+            on_error: Some(page_hook(
+                None,
+                c_illust.clone(),
+                task_config.write_batch.clone(),
+                illust_id.to_string(),
+                page,
+                false,
+            )),
+        },
+        _ => TaskHooks {
+            on_success: on_success_hook,
             ..Default::default()
-        }),
+        },
+    };
+    let task = Task {
+        hooks: Some(hooks),
         options: Some(TaskOptions {
             header: Some(vec!["Referer: https://app-api.pixiv.net/".to_string()]),
             all_proxy: task_config.proxy.clone(),
-            out: Some(path_slash),
+            out: Some(out.unwrap_or(path_slash)),
             dir: Some(task_config.parent_dir.to_string_lossy().to_string()),
             ..Default::default()
         }),
         url,
+        ..Default::default()
     };
-    downloader.add_task(task).await
+    downloader.add_task(task).await?;
+    task_config.stats.queue();
+    Ok(())
+}
+
+/// Sum the sizes of the files with `HEAD` requests. Unknown sizes are counted as 0.
+async fn total_size(client: &reqwest::Client, urls: &[&str]) -> u64 {
+    let mut total = 0;
+    for url in urls {
+        pace().await;
+        let r = client
+            .head(*url)
+            .header(reqwest::header::REFERER, "https://app-api.pixiv.net/")
+            .send()
+            .await;
+        match r {
+            // The body of HEAD is empty, so the header is read instead of `content_length()`.
+            Ok(r) => {
+                total += r
+                    .headers()
+                    .get(reqwest::header::CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(0)
+            }
+            Err(e) => debug!("pixiv: cannot get size of {url}: {e}"),
+        }
+    }
+    total
 }
 
 pub async fn download_illusts(
     illusts: &Vec<pixivcrab::models::illust::Illust>,
     ugoira_map: &mut HashMap<String, (String, Vec<i32>)>,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     c_image: &Collection<Document>,
+    c_illust: &Collection<Document>,
+    seen_urls: &mut SeenUrls,
     items_sent: &mut u32,
     limit: Option<u32>,
     task_config: &TaskConfig,
 ) -> crate::Result<()> {
     for i in illusts {
-        if super::limit_reached(limit, *items_sent) {
+        if super::limit_reached(limit, *items_sent) || task_config.cancel.is_cancelled() {
             break;
         }
+        if let Some(ref quota) = task_config.quota {
+            if quota.exceeded() {
+                warn_throttled(
+                    "quota exceeded",
+                    format!(
+                        "quota exceeded: {} of {} bytes downloaded, no more downloads are started",
+                        quota.used(),
+                        quota.max_bytes()
+                    ),
+                );
+                break;
+            }
+        }
+        if !task_config.date_allowed(&i.create_date) {
+            debug!(
+                "pixiv: skipping illust {} created on {}",
+                i.id, i.create_date
+            );
+            task_config.stats.filter_work();
+            continue;
+        }
+        let filtered = if !task_config.tags_allowed(&i.tags) {
+            Some("filtered by tags".to_string())
+        } else if !task_config
+            .page_range
+            .map_or(true, |r| r.contains(i.page_count as usize))
+        {
+            Some(format!("with {} pages", i.page_count))
+        } else if task_config
+            .min_bookmarks
+            .map_or(false, |min| i.total_bookmarks < min as i64)
+        {
+            Some(format!("with {} bookmarks", i.total_bookmarks))
+        } else {
+            None
+        };
+        if let Some(reason) = filtered {
+            debug!("pixiv: skipping illust {} {}", i.id, reason);
+            task_config.stats.filter_work();
+            if task_config.count_filtered {
+                *items_sent += 1;
+            }
+            continue;
+        }
         *items_sent += 1;
 
         if !i.visible {
@@ -240,19 +1023,113 @@ pub async fn download_illusts(
         }
         let illust_id = i.id.to_string();
         let is_ugoira = i.r#type == "ugoira";
+        let sidecar = task_config.sidecar.map(|_| Sidecar::from_illust(i));
+        let user_dir = match task_config.route_dir(i.tags.iter().map(|t| t.name.as_str())) {
+            Some(dir) => format!("{dir}/{}", i.user.id),
+            None => i.user.id.to_string(),
+        };
+        let ugoira = if is_ugoira {
+            // get higher resolution images
+            ugoira_map
+                .remove(&illust_id)
+                .map(|(zip_url, delay)| (zip_url.replace("600x600", "1920x1080"), delay))
+        } else {
+            None
+        };
+
+        let pages = match task_config.max_pages {
+            Some(max) if i.meta_pages.len() > max => {
+                info!(
+                    "pixiv: only downloading {} of {} pages of illust {}",
+                    max,
+                    i.meta_pages.len(),
+                    illust_id
+                );
+                if !task_config.no_db {
+                    if let Err(e) = super::database::mark_truncated(
+                        c_illust,
+                        &illust_id,
+                        max,
+                        i.meta_pages.len(),
+                    )
+                    .await
+                    {
+                        warn!("{}", e);
+                    }
+                }
+                &i.meta_pages[..max]
+            }
+            _ => &i.meta_pages[..],
+        };
+
+        if let Some(ref size_guard) = task_config.size_guard {
+            // With whether each is in the directory of a multi page illust.
+            let files: Vec<(&str, bool)> = ugoira
+                .iter()
+                .map(|(zip_url, _)| (zip_url.as_str(), true))
+                .chain(if i.page_count == 1 {
+                    i.meta_single_page
+                        .original_image_url
+                        .as_deref()
+                        .map(|url| (url, is_ugoira))
+                        .into_iter()
+                        .collect()
+                } else {
+                    pages
+                        .iter()
+                        .filter_map(|p| p.image_urls.original.as_deref())
+                        .map(|url| (url, true))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            // Only the files to download count, so the illusts downloaded before the
+            // limit, or partially, are not skipped nor requested again.
+            let urls: Vec<&str> = files
+                .into_iter()
+                .filter(|(url, is_multi_page)| {
+                    will_download(
+                        seen_urls,
+                        url,
+                        &user_dir,
+                        &illust_id,
+                        *is_multi_page,
+                        task_config,
+                    )
+                })
+                .map(|(url, _)| url)
+                .collect();
+            let size = total_size(&size_guard.client, &urls).await;
+            if size > size_guard.max_bytes {
+                info!(
+                    "pixiv: skipping illust {} of {} bytes, larger than {} bytes",
+                    illust_id, size, size_guard.max_bytes
+                );
+                task_config.stats.filter_work();
+                if !task_config.no_db {
+                    try_skip!(super::database::mark_too_large(c_illust, &illust_id, size).await);
+                }
+                continue;
+            }
+            if !task_config.no_db {
+                // Skipped before with a lower limit.
+                try_skip!(super::database::clear_too_large(c_illust, &illust_id).await);
+            }
+        }
 
         if is_ugoira {
-            if let Some((zip_url, delay)) = ugoira_map.remove(&illust_id) {
-                let zip_url = zip_url.replace("600x600", "1920x1080");
+            if let Some((zip_url, delay)) = ugoira {
                 if let Err(err) = download_illust(
                     downloader,
                     c_image,
-                    // get higher resolution images
+                    c_illust,
+                    seen_urls,
                     Some(zip_url.clone()),
-                    &i.user.id.to_string(),
+                    &user_dir,
                     &illust_id,
                     true,
                     Some(delay),
+                    None,
+                    sidecar.as_ref(),
                     task_config,
                 )
                 .await
@@ -267,26 +1144,34 @@ pub async fn download_illusts(
                 download_illust(
                     downloader,
                     c_image,
+                    c_illust,
+                    seen_urls,
                     i.meta_single_page.original_image_url.clone(),
-                    &i.user.id.to_string(),
+                    &user_dir,
                     &illust_id,
                     is_ugoira,
                     None,
+                    Some((0, 1)),
+                    sidecar.as_ref(),
                     task_config
                 )
                 .await
             );
         } else {
-            for img in &i.meta_pages {
+            for (index, img) in pages.iter().enumerate() {
                 try_skip!(
                     download_illust(
                         downloader,
                         c_image,
+                        c_illust,
+                        seen_urls,
                         img.image_urls.original.clone(),
-                        &i.user.id.to_string(),
+                        &user_dir,
                         &illust_id,
                         true,
                         None,
+                        Some((index, i.meta_pages.len())),
+                        sidecar.as_ref(),
                         task_config
                     )
                     .await
@@ -296,3 +1181,268 @@ pub async fn download_illusts(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn illust_paths() {
+        let cases = [
+            (
+                "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/92187206_p0.jpg",
+                false,
+                DirectorySharding::None,
+                "100/92187206_p0_20210822220333.jpg",
+            ),
+            (
+                "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/92187206_p3.png",
+                true,
+                DirectorySharding::None,
+                "100/92187206_20210822220333/92187206_p3.png",
+            ),
+            (
+                "https://i.pximg.net/img-zip-ugoira/img/2021/08/22/22/03/33/92187206_ugoira1920x1080.zip",
+                true,
+                DirectorySharding::None,
+                "100/92187206_20210822220333/92187206_ugoira1920x1080.zip",
+            ),
+            (
+                "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/92187206_p0.jpg",
+                false,
+                DirectorySharding::Year,
+                "100/2021/92187206_p0_20210822220333.jpg",
+            ),
+            (
+                "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/92187206_p1.jpg",
+                true,
+                DirectorySharding::YearMonth,
+                "100/2021/08/92187206_20210822220333/92187206_p1.jpg",
+            ),
+        ];
+        for (url, is_multi_page, sharding, expected) in cases {
+            assert_eq!(
+                illust_path("100", "92187206", url, is_multi_page, sharding).unwrap(),
+                expected,
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn original_profile_image_urls() {
+        assert_eq!(
+            original_profile_image_url(
+                "https://i.pximg.net/user-profile/img/2021/01/02/03/04/05/20000000_abcdef_170.jpg"
+            )
+            .as_deref(),
+            Some("https://i.pximg.net/user-profile/img/2021/01/02/03/04/05/20000000_abcdef.jpg")
+        );
+        assert_eq!(
+            original_profile_image_url("https://s.pximg.net/common/images/no_profile.png"),
+            None
+        );
+    }
+
+    #[test]
+    fn illust_paths_without_date() {
+        let cases = [
+            (
+                "https://i.pximg.net/img-original/img/92187206_p2.jpg",
+                false,
+                "100/92187206_p2.jpg",
+            ),
+            (
+                "https://i.pximg.net/img-original/img/2021/08/22/92187206_p0.png",
+                false,
+                "100/92187206_p0.png",
+            ),
+            (
+                "https://example.com/92187206.jpg?1629637413",
+                false,
+                "100/92187206_p0.jpg",
+            ),
+            (
+                "https://i.pximg.net/img-original/img/92187206_p1.jpg",
+                true,
+                "100/92187206/92187206_p1.jpg",
+            ),
+        ];
+        for (url, is_multi_page, expected) in cases {
+            assert_eq!(
+                illust_path(
+                    "100",
+                    "92187206",
+                    url,
+                    is_multi_page,
+                    DirectorySharding::Year
+                )
+                .unwrap(),
+                expected,
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn illust_path_malformed() {
+        for url in ["", "https://i.pximg.net/", "https://i.pximg.net/img/.jpg"] {
+            assert!(
+                illust_path("100", "92187206", url, false, DirectorySharding::None).is_err(),
+                "{url}"
+            );
+        }
+    }
+
+    fn no_db_config(parent_dir: PathBuf) -> TaskConfig {
+        TaskConfig {
+            ffmpeg: utils::Ffmpeg::new(PathBuf::from("ffmpeg"), false),
+            proxy: None,
+            parent_dir,
+            db_path_prefix: String::new(),
+            storage_tiers: Vec::new(),
+            collision_policy: CollisionPolicy::default(),
+            directory_sharding: DirectorySharding::None,
+            tag_routes: Vec::new(),
+            include_tags: Vec::new(),
+            exclude_tags: vec!["skipped".to_string()],
+            page_range: None,
+            min_bookmarks: None,
+            count_filtered: false,
+            since: None,
+            until: None,
+            ugoira_formats: Vec::new(),
+            sidecar: Some(SidecarFormat::Json),
+            transcode: None,
+            derivative: None,
+            #[cfg(feature = "embedding")]
+            embedding: None,
+            cpu: CpuPool::new(1),
+            prefetch_pages: false,
+            partial_policy: Default::default(),
+            replace: false,
+            verify_existing: false,
+            only_new_users: false,
+            bookmark_tags: false,
+            size_guard: None,
+            max_pages: None,
+            write_batch: None,
+            quota: None,
+            no_db: true,
+            cancel: Default::default(),
+            stats: Default::default(),
+            page_token: Default::default(),
+        }
+    }
+
+    /// An illust as returned by the app API.
+    fn illust(id: u64, tag: &str, pages: &[&str]) -> pixivcrab::models::illust::Illust {
+        let image_urls = |url: &str| {
+            serde_json::json!({
+                "square_medium": url, "medium": url, "large": url, "original": url,
+            })
+        };
+        let (single, meta_pages) = match pages {
+            [url] => (serde_json::json!({ "original_image_url": url }), Vec::new()),
+            _ => (
+                serde_json::json!({}),
+                pages
+                    .iter()
+                    .map(|url| serde_json::json!({ "image_urls": image_urls(url) }))
+                    .collect(),
+            ),
+        };
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "title": "title",
+            "type": "illust",
+            "image_urls": image_urls(pages[0]),
+            "caption": "",
+            "restrict": 0,
+            "user": {
+                "id": 100,
+                "name": "user",
+                "account": "user",
+                "profile_image_urls": { "medium": "" },
+                "is_followed": false,
+            },
+            "tags": [{ "name": tag, "translated_name": null }],
+            "tools": [],
+            "create_date": "2021-08-22T22:03:33+09:00",
+            "page_count": pages.len(),
+            "width": 100,
+            "height": 100,
+            "sanity_level": 2,
+            "x_restrict": 0,
+            "series": null,
+            "meta_single_page": single,
+            "meta_pages": meta_pages,
+            "total_view": 0,
+            "total_bookmarks": 0,
+            "is_bookmarked": false,
+            "visible": true,
+            "is_muted": false,
+            "total_comments": 0,
+            "illust_ai_type": 0,
+            "illust_book_style": 0,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn download_illusts_without_db() {
+        let dir = std::env::temp_dir().join(format!("bowerbird-no-db-{}", std::process::id()));
+        let illust_dir = dir.join("100/92187206_20210822220333");
+        std::fs::create_dir_all(&illust_dir).unwrap();
+        // Downloaded before, so only its sidecar is written.
+        std::fs::write(illust_dir.join("92187206_p1.png"), b"png").unwrap();
+
+        let client = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap();
+        let c_image = client.database("test").collection("pixiv_image");
+        let c_illust = client.database("test").collection("pixiv_illust");
+        let task_config = no_db_config(dir.clone());
+        let downloader = crate::downloader::MemoryDownloader::new();
+        let p0 = "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/92187206_p0.png";
+        let p1 = "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/92187206_p1.png";
+        let filtered = "https://i.pximg.net/img-original/img/2021/08/22/22/03/33/92187207_p0.png";
+        let illusts = vec![
+            illust(92187206, "tag", &[p0, p1]),
+            illust(92187207, "skipped", &[filtered]),
+        ];
+
+        let mut items_sent = 0;
+        download_illusts(
+            &illusts,
+            &mut HashMap::new(),
+            &downloader,
+            &c_image,
+            &c_illust,
+            &mut SeenUrls::default(),
+            &mut items_sent,
+            None,
+            &task_config,
+        )
+        .await
+        .unwrap();
+
+        let tasks = downloader.tasks();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].url, p0);
+        assert_eq!(tasks[0].path, Some(illust_dir.join("92187206_p0.png")));
+        // The hooks of the task ran without the database.
+        assert_eq!(downloader.failed_tasks(), 0);
+        assert!(illust_dir.join("92187206_p0.png.json").exists());
+        assert!(illust_dir.join("92187206_p1.png.json").exists());
+
+        assert_eq!(items_sent, 1);
+        let mut result = crate::command::pixiv::SyncResult::default();
+        task_config.stats.fill(&mut result);
+        assert_eq!(
+            (result.queued, result.works_filtered, result.files_existing),
+            (1, 1, 1)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
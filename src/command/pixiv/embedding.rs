@@ -0,0 +1,104 @@
+use bson::{doc, Document};
+use image::{imageops::FilterType, DynamicImage};
+use mongodb::{options::UpdateOptions, Collection};
+use ndarray::{Array4, CowArray};
+use ort::{Environment, GraphOptimizationLevel, Session, SessionBuilder, Value};
+use snafu::ResultExt;
+use std::{fmt, path::Path, sync::Arc};
+
+use crate::{
+    config::EmbeddingConfig,
+    error::{self, BoxError},
+    utils::retry_db,
+};
+
+// The normalization used to train CLIP.
+const MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+const STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+/// Computes the embeddings of images with an ONNX model taking a `1x3xNxN` image.
+pub struct Embedder {
+    session: Session,
+    input_size: u32,
+}
+
+impl fmt::Debug for Embedder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Embedder")
+            .field("input_size", &self.input_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Embedder {
+    pub fn new(config: &EmbeddingConfig) -> crate::Result<Self> {
+        let model_path = Path::new(&config.model_path);
+        let session = Environment::builder()
+            .with_name("bowerbird")
+            .build()
+            .map(|env| env.into_arc())
+            .and_then(|env| {
+                SessionBuilder::new(&env)?
+                    .with_optimization_level(GraphOptimizationLevel::Level3)?
+                    .with_model_from_file(model_path)
+            })
+            .map_err(|e| {
+                error::EmbeddingModel {
+                    path: config.model_path.clone(),
+                    message: e.to_string(),
+                }
+                .build()
+            })?;
+        Ok(Self {
+            session,
+            input_size: config.input_size,
+        })
+    }
+
+    /// The embedding of the image, normalized to unit length.
+    pub fn embed(&self, image: &DynamicImage) -> Result<Vec<f32>, BoxError> {
+        let n = self.input_size;
+        let rgb = image.resize_to_fill(n, n, FilterType::Triangle).to_rgb8();
+        let input = Array4::from_shape_fn((1, 3, n as usize, n as usize), |(_, c, y, x)| {
+            let v = rgb.get_pixel(x as u32, y as u32)[c] as f32 / 255.0;
+            (v - MEAN[c]) / STD[c]
+        });
+        let input = CowArray::from(input.into_dyn());
+        let outputs = self
+            .session
+            .run(vec![Value::from_array(self.session.allocator(), &input)?])?;
+        let output = outputs
+            .first()
+            .ok_or("the model has no output")?
+            .try_extract::<f32>()?;
+        let mut v: Vec<f32> = output.view().iter().copied().collect();
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            v.iter_mut().for_each(|x| *x /= norm);
+        }
+        Ok(v)
+    }
+}
+
+/// Save the embeddings of the downloaded images to `pixiv_image_embedding`.
+#[derive(Debug, Clone)]
+pub struct EmbeddingTask {
+    pub embedder: Arc<Embedder>,
+    pub c_embedding: Collection<Document>,
+}
+
+impl EmbeddingTask {
+    /// Keyed by the local path, like the image media.
+    pub async fn save(&self, local_path: &str, vector: Vec<f32>) -> crate::Result<()> {
+        retry_db("save embedding", || {
+            self.c_embedding.update_one(
+                doc! { "local_path": local_path },
+                doc! { "$set": { "vector": vector.clone() } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+        })
+        .await
+        .context(error::MongoDb)?;
+        Ok(())
+    }
+}
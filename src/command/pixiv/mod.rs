@@ -1,16 +1,38 @@
-use log::info;
+use chrono::NaiveDate;
+use futures::StreamExt;
+use log::{info, warn};
 use mongodb::{bson::Document, Database};
-use pixivcrab::AppApi;
+use pixivcrab::{AppApi, NextUrl};
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeSet, HashMap, HashSet},
     path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
 };
+use tokio_util::sync::CancellationToken;
 
-use crate::downloader::Aria2Downloader;
+use crate::{
+    config::{
+        CollisionPolicy, DerivativeConfig, DirectorySharding, PartialPolicy, SidecarFormat,
+        TagRoute, UgoiraFormat,
+    },
+    downloader::Downloader,
+    error,
+    model::pixiv::BookmarkVisibility,
+    utils::CpuPool,
+};
+use transcode::TranscodePool;
 
 pub mod database;
 mod download;
-mod utils;
+#[cfg(feature = "embedding")]
+pub mod embedding;
+pub mod quota;
+mod sidecar;
+pub mod transcode;
+pub(crate) mod utils;
 
 fn limit_reached<T>(limit: Option<T>, items_sent: T) -> bool
 where
@@ -23,21 +45,386 @@ where
     }
 }
 
+/// Where to start paging the works of a user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageStart {
+    First,
+    /// Continue from the page saved by the last sync of the user.
+    Saved,
+    /// The URL of a page, logged when a sync stops early.
+    Token(String),
+}
+
+/// The rankings of pixiv.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub enum RankingMode {
+    Daily,
+    Weekly,
+    Monthly,
+    /// Weekly, of the users new to pixiv.
+    Rookie,
+    /// Weekly, of the original works.
+    Original,
+    /// Daily, popular among men.
+    Male,
+    /// Daily, popular among women.
+    Female,
+}
+
+impl RankingMode {
+    /// The `mode` of the API.
+    pub fn api_value(self) -> &'static str {
+        match self {
+            Self::Daily => "day",
+            Self::Weekly => "week",
+            Self::Monthly => "month",
+            Self::Rookie => "week_rookie",
+            Self::Original => "week_original",
+            Self::Male => "day_male",
+            Self::Female => "day_female",
+        }
+    }
+}
+
+/// The order of the results of a search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum SearchSort {
+    DateDesc,
+    DateAsc,
+    /// Only for premium accounts.
+    PopularDesc,
+}
+
+impl SearchSort {
+    pub fn api_value(self) -> &'static str {
+        match self {
+            Self::DateDesc => "date_desc",
+            Self::DateAsc => "date_asc",
+            Self::PopularDesc => "popular_desc",
+        }
+    }
+}
+
+/// What the words of a search are matched against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum SearchTarget {
+    /// Tags containing the words.
+    Partial,
+    /// Tags equal to the words.
+    Exact,
+    /// The title and the caption.
+    Title,
+}
+
+impl SearchTarget {
+    pub fn api_value(self) -> &'static str {
+        match self {
+            Self::Partial => "partial_match_for_tags",
+            Self::Exact => "exact_match_for_tags",
+            Self::Title => "title_and_caption",
+        }
+    }
+}
+
+impl Default for PageStart {
+    fn default() -> Self {
+        Self::First
+    }
+}
+
+/// Check the token is a page of the works of the user, not of someone else.
+fn check_page_token(token: &str, user_id: &str) -> crate::Result<()> {
+    let valid = url::Url::parse(token)
+        .ok()
+        .filter(|u| u.host_str() == Some("app-api.pixiv.net"))
+        .map_or(false, |u| {
+            u.query_pairs().any(|(k, v)| k == "user_id" && v == user_id)
+        });
+    if !valid {
+        return Err(error::PageTokenInvalid {
+            token: token.to_string(),
+            user_id: user_id.to_string(),
+        }
+        .build());
+    }
+    Ok(())
+}
+
+/// What happened in a sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncResult {
+    /// Number of works examined, counting towards the limit.
+    pub examined: u32,
+    /// Number of downloads added.
+    pub queued: u32,
+    /// Number of works filtered out, by date, tags, pages, bookmarks or size.
+    pub works_filtered: u32,
+    /// Number of files not downloaded for being downloaded before.
+    pub files_existing: u32,
+    /// Number of downloads failed, including those failed in the hooks.
+    pub failed: usize,
+    /// Total size of the files downloaded, read after the downloads.
+    pub bytes_estimate: u64,
+    /// Number of users skipped by `only_new_users` for having works in the database.
+    pub known_users_skipped: usize,
+}
+
+/// Counted by the downloads of a sync, shared with their hooks.
+#[derive(Debug, Default)]
+pub struct SyncStats {
+    queued: AtomicU32,
+    works_filtered: AtomicU32,
+    files_existing: AtomicU32,
+    bytes: AtomicU64,
+}
+
+impl SyncStats {
+    pub fn queue(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn filter_work(&self) {
+        self.works_filtered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skip_existing_file(&self) {
+        self.files_existing.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Copy the counts to the result, once the downloads are finished.
+    pub fn fill(&self, result: &mut SyncResult) {
+        result.queued = self.queued.load(Ordering::Relaxed);
+        result.works_filtered = self.works_filtered.load(Ordering::Relaxed);
+        result.files_existing = self.files_existing.load(Ordering::Relaxed);
+        result.bytes_estimate = self.bytes.load(Ordering::Relaxed);
+    }
+}
+
+/// Keeps the works of the users without any illust in the database before the sync.
+///
+/// Users are checked once, so their works saved by this sync do not make them known.
+#[derive(Debug, Default)]
+struct NewUserFilter {
+    known: HashSet<String>,
+    new: HashSet<String>,
+}
+
+impl NewUserFilter {
+    async fn retain(
+        &mut self,
+        c_user: &mongodb::Collection<Document>,
+        c_illust: &mongodb::Collection<Document>,
+        illusts: &mut Vec<pixivcrab::models::illust::Illust>,
+    ) -> crate::Result<()> {
+        let unchecked: BTreeSet<_> = illusts
+            .iter()
+            .map(|i| i.user.id.to_string())
+            .filter(|id| !self.known.contains(id) && !self.new.contains(id))
+            .collect();
+        if !unchecked.is_empty() {
+            let unchecked: Vec<_> = unchecked.into_iter().collect();
+            let known = database::users_with_illusts(c_user, c_illust, &unchecked).await?;
+            for id in unchecked {
+                if known.contains(&id) {
+                    self.known.insert(id);
+                } else {
+                    self.new.insert(id);
+                }
+            }
+        }
+        illusts.retain(|i| self.new.contains(&i.user.id.to_string()));
+        Ok(())
+    }
+}
+
+/// Skip the illusts whose files are larger than `max_bytes` in total.
+#[derive(Debug, Clone)]
+pub struct SizeGuard {
+    pub max_bytes: u64,
+    /// Used to get the sizes with `HEAD` requests.
+    pub client: reqwest::Client,
+}
+
+/// The number of pages of the illusts to download, e.g. `1`, `2-`, `-10` or `2-10`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRange {
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+
+impl PageRange {
+    pub fn contains(&self, pages: usize) -> bool {
+        self.min.map_or(true, |min| pages >= min) && self.max.map_or(true, |max| pages <= max)
+    }
+}
+
+impl std::str::FromStr for PageRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| {
+            let n = n.trim();
+            if n.is_empty() {
+                return Ok(None);
+            }
+            n.parse()
+                .map(Some)
+                .map_err(|e| format!("invalid page range {s}: {e}"))
+        };
+        let range = match s.split_once('-') {
+            Some((min, max)) => Self {
+                min: parse(min)?,
+                max: parse(max)?,
+            },
+            None => {
+                let count = parse(s)?;
+                Self {
+                    min: count,
+                    max: count,
+                }
+            }
+        };
+        match range {
+            Self {
+                min: None,
+                max: None,
+            } => Err(format!("invalid page range {s}: no page count")),
+            Self {
+                min: Some(min),
+                max: Some(max),
+            } if min > max => Err(format!("invalid page range {s}: {min} is more than {max}")),
+            _ => Ok(range),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TaskConfig {
-    pub ffmpeg_path: Option<PathBuf>,
+    pub ffmpeg: utils::Ffmpeg,
     pub proxy: Option<String>,
     pub parent_dir: PathBuf,
+    /// Prepended to the paths saved to the database,
+    /// so files downloaded outside the storage dir can still be located.
+    pub db_path_prefix: String,
+    /// Searched after `parent_dir` for the existing files, which are not downloaded again.
+    pub storage_tiers: Vec<PathBuf>,
+    pub collision_policy: CollisionPolicy,
+    pub directory_sharding: DirectorySharding,
+    pub tag_routes: Vec<TagRoute>,
+    /// Only download works with any of these tags, if not empty.
+    pub include_tags: Vec<String>,
+    /// Never download works with any of these tags, taking precedence over `include_tags`.
+    pub exclude_tags: Vec<String>,
+    /// Only download the illusts with this number of pages.
+    pub page_range: Option<PageRange>,
+    /// Only download the illusts with at least this number of bookmarks.
+    pub min_bookmarks: Option<u32>,
+    /// Count the illusts skipped by the filters above towards the limit.
+    pub count_filtered: bool,
+    /// Only the works created on or after this day in Japan.
+    pub since: Option<NaiveDate>,
+    /// Only the works created on or before this day in Japan.
+    pub until: Option<NaiveDate>,
+    /// Videos transcoded from ugoira, only if ffmpeg is available.
+    pub ugoira_formats: Vec<UgoiraFormat>,
+    /// Write the metadata next to the downloaded files, and update it for the existing ones.
+    pub sidecar: Option<SidecarFormat>,
+    /// Transcodes the ugoira to `ugoira_formats`, if any.
+    pub transcode: Option<TranscodePool>,
+    /// Save a smaller copy of every image if set.
+    pub derivative: Option<DerivativeConfig>,
+    /// Save the embedding of every image if set.
+    #[cfg(feature = "embedding")]
+    pub embedding: Option<embedding::EmbeddingTask>,
+    /// Runs the CPU heavy work in the hooks, e.g. making derivatives.
+    pub cpu: CpuPool,
+    /// Get the next page of works while processing the current one.
+    pub prefetch_pages: bool,
+    pub partial_policy: PartialPolicy,
+    /// Download the existing files again, replacing them only if the download succeeds.
+    pub replace: bool,
+    /// Download the existing files again if they are incomplete.
+    pub verify_existing: bool,
+    /// Only process the illusts of the users without any illust in the database.
+    pub only_new_users: bool,
+    /// Save the bookmark tags of the illusts synced from the bookmarks of the logged in user.
+    pub bookmark_tags: bool,
+    pub size_guard: Option<SizeGuard>,
+    /// Only download the first pages of the illusts with more pages.
+    pub max_pages: Option<usize>,
+    /// Write the updates after the downloads in batches.
+    pub write_batch: Option<Arc<database::WriteBatch>>,
+    /// Stop adding downloads once the quota is reached.
+    pub quota: Option<Arc<quota::Quota>>,
+    /// Only download the files without writing to the database.
+    pub no_db: bool,
+    /// Stops paging and adding new tasks when cancelled.
+    pub cancel: CancellationToken,
+    pub stats: Arc<SyncStats>,
+    /// The page to continue from, saved after the downloads.
+    pub page_token: Arc<database::PendingPageToken>,
+}
+
+impl TaskConfig {
+    pub fn db_path(&self, path_slash: &str) -> String {
+        format!("{}{path_slash}", self.db_path_prefix)
+    }
+
+    /// Check the tags against `include_tags` and `exclude_tags`.
+    /// Both the original and translated names are matched.
+    pub fn tags_allowed(&self, tags: &[pixivcrab::models::Tag]) -> bool {
+        let names: HashSet<_> = tags
+            .iter()
+            .flat_map(|t| std::iter::once(t.name.as_str()).chain(t.translated_name.as_deref()))
+            .collect();
+        if self.exclude_tags.iter().any(|t| names.contains(t.as_str())) {
+            return false;
+        }
+        self.include_tags.is_empty() || self.include_tags.iter().any(|t| names.contains(t.as_str()))
+    }
+
+    /// Whether the work is created between `since` and `until`.
+    pub fn date_allowed<Tz: chrono::TimeZone>(&self, created: &chrono::DateTime<Tz>) -> bool {
+        let date = created.naive_local().date();
+        self.since.map_or(true, |since| date >= since)
+            && self.until.map_or(true, |until| date <= until)
+    }
+
+    /// Whether the work is created before `since`, so are the rest of a newest first list.
+    pub fn before_since<Tz: chrono::TimeZone>(&self, created: &chrono::DateTime<Tz>) -> bool {
+        self.since
+            .map_or(false, |since| created.naive_local().date() < since)
+    }
+
+    /// The directory of the first route in the config matching any of the tags.
+    pub fn route_dir<'a>(&self, tags: impl Iterator<Item = &'a str>) -> Option<&str> {
+        let tags: HashSet<_> = tags.collect();
+        self.tag_routes
+            .iter()
+            .find(|r| tags.contains(r.tag.as_str()))
+            .map(|r| r.dir.trim_matches('/'))
+    }
 }
 
 async fn illusts(
     db: &Database,
     api: &AppApi,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     mut pager: pixivcrab::Pager<pixivcrab::models::illust::Response>,
+    newest_first: bool,
     limit: Option<u32>,
+    bookmark_visibility: Option<BookmarkVisibility>,
+    page_tokens: Option<&database::PageTokens>,
+    mut current_page: Option<String>,
+    incomplete_scope: Option<database::IncompleteScope>,
     task_config: &TaskConfig,
-) -> crate::Result<()> {
+) -> crate::Result<SyncResult> {
     let c_illust = db.collection::<Document>("pixiv_illust");
     let c_user = db.collection::<Document>("pixiv_user");
     let c_tag = db.collection::<Document>("pixiv_tag");
@@ -45,37 +432,168 @@ async fn illusts(
 
     let mut users_need_update_set = BTreeSet::new();
     let mut ugoira_map = HashMap::new();
+    let mut seen_urls = download::SeenUrls::default();
+
+    match incomplete_scope {
+        Some(ref scope)
+            if task_config.partial_policy == PartialPolicy::Strict && !task_config.no_db =>
+        {
+            requeue_incomplete(
+                api,
+                downloader,
+                &c_image,
+                &c_illust,
+                &c_user,
+                scope,
+                &mut seen_urls,
+                task_config,
+            )
+            .await?;
+        }
+        _ => {}
+    }
+
+    let mut user_filter = task_config.only_new_users.then(NewUserFilter::default);
 
     let mut items_sent = 0;
-    while let Some(r) = {
-        info!("getting illusts with offset: {}", items_sent);
-        utils::retry_pager(&mut pager, 3).await?
-    } {
-        database::save_illusts(
-            &r.illusts,
-            api,
-            &c_tag,
-            &c_user,
-            &c_illust,
-            &mut users_need_update_set,
-            &mut ugoira_map,
-        )
-        .await?;
-        download::download_illusts(
-            &r.illusts,
-            &mut ugoira_map,
-            downloader,
-            &c_image,
-            &mut items_sent,
-            limit,
-            task_config,
-        )
-        .await?;
+    info!("getting illusts with offset: {}", items_sent);
+    let mut next = utils::retry_pager(&mut pager, 3).await?;
+    while let Some(mut r) = next.take() {
+        if let Some(filter) = user_filter.as_mut() {
+            filter.retain(&c_user, &c_illust, &mut r.illusts).await?;
+        }
+        let past_since = newest_first
+            && r.illusts
+                .last()
+                .map_or(false, |i| task_config.before_since(&i.create_date));
+        // Every illust counts once at most, so the limit cannot be reached with fewer.
+        let may_reach_limit = limit_reached(limit, items_sent + r.illusts.len() as u32);
+        let last_page = past_since || may_reach_limit || task_config.cancel.is_cancelled();
+        let process = async {
+            if task_config.no_db {
+                for i in r
+                    .illusts
+                    .iter()
+                    .filter(|i| i.visible && i.r#type == "ugoira")
+                {
+                    let illust_id = i.id.to_string();
+                    let metadata = database::ugoira_metadata(api, &illust_id).await?;
+                    ugoira_map.insert(illust_id, metadata);
+                }
+            } else {
+                database::save_illusts(
+                    &r.illusts,
+                    api,
+                    &c_tag,
+                    &c_user,
+                    &c_illust,
+                    &mut users_need_update_set,
+                    &mut ugoira_map,
+                    bookmark_visibility,
+                    task_config.bookmark_tags && bookmark_visibility.is_some(),
+                )
+                .await?;
+            }
+            download::download_illusts(
+                &r.illusts,
+                &mut ugoira_map,
+                downloader,
+                &c_image,
+                &c_illust,
+                &mut seen_urls,
+                &mut items_sent,
+                limit,
+                task_config,
+            )
+            .await
+        };
+        // Get the next page while processing the current one,
+        // unless the walk may stop after this page and the next one would be got in vain.
+        let (processed, prefetched) = if task_config.prefetch_pages && !last_page {
+            let prefetch = async {
+                tokio::select! {
+                    r = utils::retry_pager(&mut pager, 3) => Some(r),
+                    // Stopped once cancelled, as the page would be thrown away.
+                    _ = task_config.cancel.cancelled() => None,
+                }
+            };
+            tokio::join!(process, prefetch)
+        } else {
+            (process.await, None)
+        };
+        processed?;
+        if let Some(page_tokens) = page_tokens {
+            // Continue from this page if some of it is not processed.
+            let next_page = r.next_url();
+            let page_done = !limit_reached(limit, items_sent) && !task_config.cancel.is_cancelled();
+            let resume_from = if page_done {
+                next_page.clone()
+            } else {
+                current_page.take()
+            };
+            // Once cancelled, the downloads of the pages before may be unfinished.
+            if let (false, false, Some(token)) =
+                (page_done, task_config.cancel.is_cancelled(), &resume_from)
+            {
+                info!("to continue from this page: --start-page-token '{}'", token);
+            }
+            task_config
+                .page_token
+                .set(page_tokens, resume_from, items_sent);
+            current_page = next_page;
+        }
         if limit_reached(limit, items_sent) {
             break;
         }
+        if past_since {
+            info!("reached the illusts created before --since, stop getting illusts");
+            if let Some(page_tokens) = page_tokens {
+                // The pages after are all before --since, start over next time.
+                task_config.page_token.set(page_tokens, None, items_sent);
+            }
+            break;
+        }
+        if task_config.cancel.is_cancelled() {
+            info!("sync cancelled, stop getting illusts");
+            break;
+        }
+        info!("getting illusts with offset: {}", items_sent);
+        let fetched = match prefetched {
+            Some(prefetched) => prefetched,
+            None => utils::retry_pager(&mut pager, 3).await,
+        };
+        next = match fetched {
+            // Keep what is processed, e.g. the first pages of a search by popularity.
+            Err(error::Error::PixivPremiumRequired { source }) => {
+                warn!(
+                    "the next pages need a pixiv premium account, stop getting illusts: {}",
+                    source
+                );
+                break;
+            }
+            r => r?,
+        };
     }
     info!("{} illusts processed", items_sent);
+    if let Some(page_tokens) = page_tokens.filter(|p| p.examined_before() > 0) {
+        info!(
+            "{} illusts processed with the runs before",
+            page_tokens.examined_before() + items_sent
+        );
+    }
+    if seen_urls.duplicates() > 0 {
+        info!("{} duplicated urls skipped", seen_urls.duplicates());
+    }
+    let known_users_skipped = user_filter.map_or(0, |f| f.known.len());
+    if task_config.only_new_users {
+        info!("{} already known users skipped", known_users_skipped);
+    }
+    if task_config.no_db {
+        return Ok(SyncResult {
+            examined: items_sent,
+            ..Default::default()
+        });
+    }
 
     database::update_user_id_set(
         api,
@@ -87,45 +605,432 @@ async fn illusts(
     )
     .await?;
 
-    Ok(())
+    Ok(SyncResult {
+        examined: items_sent,
+        known_users_skipped,
+        ..Default::default()
+    })
+}
+
+/// Load the page to start from, checking the token is of the user.
+async fn start_page(
+    page_tokens: &mut database::PageTokens,
+    start: &PageStart,
+    user_id: &str,
+) -> crate::Result<Option<String>> {
+    Ok(match start {
+        PageStart::First => None,
+        PageStart::Saved => {
+            let saved = page_tokens.load().await?;
+            match saved {
+                Some(_) => info!(
+                    "continuing the last sync, {} works examined before",
+                    page_tokens.examined_before()
+                ),
+                None => info!(
+                    "no saved page for user {}, starting from the first page",
+                    user_id
+                ),
+            }
+            saved
+        }
+        PageStart::Token(token) => {
+            check_page_token(token, user_id)?;
+            Some(token.clone())
+        }
+    })
 }
 
 pub async fn illust_uploads(
     api: &pixivcrab::AppApi,
     db: &mongodb::Database,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     user_id: &str,
+    start: &PageStart,
     limit: Option<u32>,
     task_config: &TaskConfig,
-) -> crate::Result<()> {
-    let pager = api.illust_uploads(user_id);
+) -> crate::Result<SyncResult> {
+    let mut page_tokens =
+        database::PageTokens::new((!task_config.no_db).then(|| db), "illust_uploads", user_id);
+    let start_page = start_page(&mut page_tokens, start, user_id).await?;
+    let mut pager = api.illust_uploads(user_id);
+    if let Some(ref start_page) = start_page {
+        info!("starting from page: {}", start_page);
+        pager.set_next_url(start_page.clone());
+    }
 
-    illusts(db, api, downloader, pager, limit, task_config).await
+    illusts(
+        db,
+        api,
+        downloader,
+        pager,
+        true,
+        limit,
+        None,
+        Some(&page_tokens),
+        start_page,
+        Some(database::IncompleteScope::Uploads(user_id.to_string())),
+        task_config,
+    )
+    .await
 }
 
 pub async fn illust_bookmarks(
     api: &pixivcrab::AppApi,
     db: &mongodb::Database,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     user_id: &str,
     private: bool,
+    start: &PageStart,
+    limit: Option<u32>,
+    task_config: &TaskConfig,
+) -> crate::Result<SyncResult> {
+    let kind = if private {
+        "illust_bookmarks_private"
+    } else {
+        "illust_bookmarks"
+    };
+    let mut page_tokens =
+        database::PageTokens::new((!task_config.no_db).then(|| db), kind, user_id);
+    let start_page = start_page(&mut page_tokens, start, user_id).await?;
+    let mut pager = api.illust_bookmarks(user_id, private);
+    if let Some(ref start_page) = start_page {
+        info!("starting from page: {}", start_page);
+        pager.set_next_url(start_page.clone());
+    }
+
+    illusts(
+        db,
+        api,
+        downloader,
+        pager,
+        false,
+        limit,
+        Some(BookmarkVisibility::from_private(private)),
+        Some(&page_tokens),
+        start_page,
+        Some(database::IncompleteScope::Bookmarks(
+            BookmarkVisibility::from_private(private),
+        )),
+        task_config,
+    )
+    .await
+}
+
+/// Save the illusts in a ranking like the bookmarks, and download them.
+///
+/// `date` is the last day of the ranking, the latest one if `None`.
+pub async fn illust_ranking(
+    api: &pixivcrab::AppApi,
+    db: &mongodb::Database,
+    downloader: &dyn Downloader,
+    mode: RankingMode,
+    date: Option<NaiveDate>,
+    limit: Option<u32>,
+    task_config: &TaskConfig,
+) -> crate::Result<SyncResult> {
+    let date = date.map(|d| d.format("%Y-%m-%d").to_string());
+    info!(
+        "getting the {} ranking of {}",
+        mode.api_value(),
+        date.as_deref().unwrap_or("the latest day")
+    );
+    let pager = api.illust_ranking(mode.api_value(), date.as_deref());
+
+    illusts(
+        db,
+        api,
+        downloader,
+        pager,
+        false,
+        limit,
+        None,
+        None,
+        None,
+        None,
+        task_config,
+    )
+    .await
+}
+
+/// Save the illusts found by a search like the bookmarks, and download them.
+pub async fn illust_search(
+    api: &pixivcrab::AppApi,
+    db: &mongodb::Database,
+    downloader: &dyn Downloader,
+    word: &str,
+    sort: SearchSort,
+    target: SearchTarget,
     limit: Option<u32>,
     task_config: &TaskConfig,
+) -> crate::Result<SyncResult> {
+    info!("searching illusts for {}", word);
+    let pager = api.search_illust(word, sort.api_value(), target.api_value());
+
+    let newest_first = sort == SearchSort::DateDesc;
+    let r = illusts(
+        db,
+        api,
+        downloader,
+        pager,
+        newest_first,
+        limit,
+        None,
+        None,
+        None,
+        None,
+        task_config,
+    )
+    .await;
+    match r {
+        Err(error::Error::PixivPremiumRequired { .. }) if sort == SearchSort::PopularDesc => {
+            warn!(
+                "sorting the search by popularity needs a pixiv premium account, \
+                 search with `--sort date_desc` instead"
+            );
+            Ok(SyncResult::default())
+        }
+        r => r,
+    }
+}
+
+/// What happened to an illust imported by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdImportStatus {
+    /// Saved and sent to the downloader, whose downloads may still fail.
+    Queued,
+    /// Already in the database with all its pages downloaded, not fetched again.
+    Exists,
+    /// Deleted, made private or never existed on pixiv.
+    /// Marked as inaccessible if in the database and still returned by pixiv.
+    Invisible,
+    Failed(String),
+}
+
+/// Read illust ids from lines of text. Empty lines and everything after `#` are ignored.
+pub fn parse_ids(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|l| l.split('#').next().unwrap_or_default().trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect()
+}
+
+const ID_BATCH_SIZE: usize = 30;
+
+/// Get the details of the illusts, a few at a time.
+async fn fetch_illusts<'a>(
+    api: &AppApi,
+    ids: &'a [String],
+) -> Vec<(
+    &'a String,
+    Result<pixivcrab::models::illust::Illust, pixivcrab::error::Error>,
+)> {
+    futures::stream::iter(ids)
+        .map(|id| async move {
+            crate::utils::pace().await;
+            (id, api.illust_detail(id).await.map(|r| r.illust))
+        })
+        .buffered(4)
+        .collect()
+        .await
+}
+
+/// Download the missing pages of the illusts in the scope failed partially before.
+///
+/// The pages already downloaded are skipped as existing files.
+async fn requeue_incomplete(
+    api: &AppApi,
+    downloader: &dyn Downloader,
+    c_image: &mongodb::Collection<Document>,
+    c_illust: &mongodb::Collection<Document>,
+    c_user: &mongodb::Collection<Document>,
+    scope: &database::IncompleteScope,
+    seen_urls: &mut download::SeenUrls,
+    task_config: &TaskConfig,
 ) -> crate::Result<()> {
-    let pager = api.illust_bookmarks(user_id, private);
+    let ids = database::incomplete_illust_ids(c_user, c_illust, scope).await?;
+    if ids.is_empty() {
+        return Ok(());
+    }
+    info!("downloading the missing pages of {} illusts", ids.len());
+    for batch in ids.chunks(ID_BATCH_SIZE) {
+        let mut illusts = Vec::with_capacity(batch.len());
+        for (id, r) in fetch_illusts(api, batch).await {
+            match r {
+                Ok(illust) => illusts.push(illust),
+                Err(e) => warn!("cannot get incomplete illust {}: {}", id, e),
+            }
+        }
+        download::download_illusts(
+            &illusts,
+            &mut HashMap::new(),
+            downloader,
+            c_image,
+            c_illust,
+            seen_urls,
+            &mut 0,
+            None,
+            task_config,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Fetch the illusts by id and download them like the other illust syncs.
+///
+/// Every id gets a status, so one failure does not stop the whole import.
+/// The illusts in the database with all their pages downloaded are skipped unless `update_exists`.
+pub async fn illust_ids(
+    db: &Database,
+    api: &AppApi,
+    downloader: &dyn Downloader,
+    ids: Vec<String>,
+    update_exists: bool,
+    task_config: &TaskConfig,
+) -> crate::Result<Vec<(String, IdImportStatus)>> {
+    let c_illust = db.collection::<Document>("pixiv_illust");
+    let c_user = db.collection::<Document>("pixiv_user");
+    let c_tag = db.collection::<Document>("pixiv_tag");
+    let c_image = db.collection::<Document>("pixiv_image");
+
+    let mut users_need_update_set = BTreeSet::new();
+    let mut ugoira_map = HashMap::new();
+    let mut seen_urls = download::SeenUrls::default();
+    let mut items_sent = 0;
 
-    illusts(db, api, downloader, pager, limit, task_config).await
+    let mut report = Vec::with_capacity(ids.len());
+    let mut pending = Vec::new();
+    let mut seen_ids = HashSet::new();
+    for id in ids {
+        if !seen_ids.insert(id.clone()) {
+            continue;
+        }
+        if id.parse::<u64>().is_err() {
+            report.push((id, IdImportStatus::Failed("invalid illust id".to_string())));
+            continue;
+        }
+        let exists = !task_config.no_db
+            && !task_config.replace
+            && !update_exists
+            && database::illust_downloaded(&c_illust, &c_image, &id).await?;
+        if exists {
+            report.push((id, IdImportStatus::Exists));
+        } else {
+            pending.push(id);
+        }
+    }
+
+    for batch in pending.chunks(ID_BATCH_SIZE) {
+        if task_config.cancel.is_cancelled() {
+            info!("import cancelled, stop getting illusts");
+            break;
+        }
+        info!("getting {} illusts by id", batch.len());
+        for (id, r) in fetch_illusts(api, batch).await {
+            let illust = match r {
+                Ok(illust) => illust,
+                Err(e)
+                    if utils::api_error_kind(&e.to_string()) == utils::ApiErrorKind::NotFound =>
+                {
+                    warn!("illust {} is deleted or does not exist", id);
+                    report.push((id.clone(), IdImportStatus::Invisible));
+                    continue;
+                }
+                Err(e) => {
+                    warn!("cannot get illust {}: {}", id, e);
+                    report.push((id.clone(), IdImportStatus::Failed(e.to_string())));
+                    continue;
+                }
+            };
+            let visible = illust.visible;
+            if !visible {
+                warn!("illust {} is deleted or private", id);
+            }
+            // One at a time for the status of each. The invisible ones are saved as inaccessible.
+            let illusts = vec![illust];
+            let processed = async {
+                if task_config.no_db {
+                    for i in illusts.iter().filter(|i| i.visible && i.r#type == "ugoira") {
+                        let illust_id = i.id.to_string();
+                        let metadata = database::ugoira_metadata(api, &illust_id).await?;
+                        ugoira_map.insert(illust_id, metadata);
+                    }
+                } else {
+                    database::save_illusts(
+                        &illusts,
+                        api,
+                        &c_tag,
+                        &c_user,
+                        &c_illust,
+                        &mut users_need_update_set,
+                        &mut ugoira_map,
+                        None,
+                        false,
+                    )
+                    .await?;
+                }
+                download::download_illusts(
+                    &illusts,
+                    &mut ugoira_map,
+                    downloader,
+                    &c_image,
+                    &c_illust,
+                    &mut seen_urls,
+                    &mut items_sent,
+                    None,
+                    task_config,
+                )
+                .await
+            }
+            .await;
+            let status = match processed {
+                Ok(()) if visible => IdImportStatus::Queued,
+                Ok(()) => IdImportStatus::Invisible,
+                Err(e) => {
+                    warn!("cannot import illust {}: {}", id, e);
+                    if visible {
+                        IdImportStatus::Failed(e.to_string())
+                    } else {
+                        IdImportStatus::Invisible
+                    }
+                }
+            };
+            report.push((id.clone(), status));
+        }
+    }
+
+    if !task_config.no_db {
+        database::update_user_id_set(
+            api,
+            downloader,
+            &c_user,
+            &c_image,
+            users_need_update_set,
+            task_config,
+        )
+        .await?;
+    }
+    Ok(report)
 }
 
 async fn novels<'a>(
     db: &Database,
     api: &AppApi,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     mut pager: pixivcrab::Pager<pixivcrab::models::novel::Response>,
+    newest_first: bool,
     limit: Option<u32>,
     update_exists: bool,
     task_config: &TaskConfig,
-) -> crate::Result<()> {
+) -> crate::Result<SyncResult> {
+    if task_config.no_db {
+        return error::NoDbUnsupported {
+            message: "novels are only saved to the database",
+        }
+        .fail();
+    }
     let c_user = db.collection::<Document>("pixiv_user");
     let c_tag = db.collection::<Document>("pixiv_tag");
     let c_novel = db.collection::<Document>("pixiv_novel");
@@ -135,11 +1040,22 @@ async fn novels<'a>(
     let mut items_sent = 0;
 
     while let Some(r) = {
+        if task_config.cancel.is_cancelled() {
+            info!("sync cancelled, stop getting novels");
+            break;
+        }
         info!("getting novels with offset: {}", items_sent);
         utils::retry_pager(&mut pager, 3).await?
     } {
+        let mut novels = r.novels;
+        // Novels are only saved, so those out of the range are not saved at all.
+        let past_since = newest_first
+            && novels
+                .last()
+                .map_or(false, |n| task_config.before_since(&n.create_date));
+        novels.retain(|n| task_config.date_allowed(&n.create_date));
         database::save_novels(
-            r.novels,
+            novels,
             api,
             &c_user,
             &c_tag,
@@ -153,6 +1069,10 @@ async fn novels<'a>(
         if limit_reached(limit, items_sent) {
             break;
         }
+        if past_since {
+            info!("reached the novels created before --since, stop getting novels");
+            break;
+        }
     }
     info!("{} novels processed", items_sent);
 
@@ -166,25 +1086,29 @@ async fn novels<'a>(
     )
     .await?;
 
-    Ok(())
+    Ok(SyncResult {
+        examined: items_sent,
+        ..Default::default()
+    })
 }
 
 pub async fn novel_bookmarks(
     api: &pixivcrab::AppApi,
     db: &mongodb::Database,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     update_exists: bool,
     user_id: &str,
     private: bool,
     limit: Option<u32>,
     task_config: &TaskConfig,
-) -> crate::Result<()> {
+) -> crate::Result<SyncResult> {
     let pager = api.novel_bookmarks(user_id, private);
     novels(
         db,
         api,
         downloader,
         pager,
+        false,
         limit,
         update_exists,
         task_config,
@@ -195,21 +1119,62 @@ pub async fn novel_bookmarks(
 pub async fn novel_uploads(
     api: &pixivcrab::AppApi,
     db: &mongodb::Database,
-    downloader: &Aria2Downloader,
+    downloader: &dyn Downloader,
     update_exists: bool,
     user_id: &str,
     limit: Option<u32>,
     task_config: &TaskConfig,
-) -> crate::Result<()> {
+) -> crate::Result<SyncResult> {
     let pager = api.novel_uploads(user_id);
     novels(
         db,
         api,
         downloader,
         pager,
+        true,
         limit,
         update_exists,
         task_config,
     )
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_tokens() {
+        let token = "https://app-api.pixiv.net/v1/user/illusts?user_id=100&type=illust&offset=30";
+        assert!(check_page_token(token, "100").is_ok());
+        assert!(check_page_token(token, "10").is_err());
+        assert!(check_page_token(
+            "https://example.com/v1/user/illusts?user_id=100&offset=30",
+            "100"
+        )
+        .is_err());
+        assert!(
+            check_page_token("https://app-api.pixiv.net/v1/user/illusts?offset=30", "100").is_err()
+        );
+        assert!(check_page_token("not a url", "100").is_err());
+    }
+
+    #[test]
+    fn page_ranges() {
+        let range = |s: &str| s.parse::<PageRange>();
+        let bounds = |s: &str| range(s).map(|r| (r.min, r.max));
+        assert_eq!(bounds("1"), Ok((Some(1), Some(1))));
+        assert_eq!(bounds("-10"), Ok((None, Some(10))));
+        assert_eq!(bounds("2-"), Ok((Some(2), None)));
+        assert_eq!(bounds(" 2 - 10 "), Ok((Some(2), Some(10))));
+        assert!(range("3-1").is_err());
+        assert!(range("").is_err());
+        assert!(range("-").is_err());
+        assert!(range("a-2").is_err());
+
+        let r = range("2-").unwrap();
+        assert!(!r.contains(1) && r.contains(2) && r.contains(200));
+        let r = range("-10").unwrap();
+        assert!(r.contains(1) && r.contains(10) && !r.contains(11));
+    }
+}
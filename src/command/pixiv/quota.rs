@@ -0,0 +1,91 @@
+use bson::doc;
+use log::{info, warn};
+use mongodb::{options::UpdateOptions, Collection, Database};
+use snafu::ResultExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{command::migrate::DB_VERSION, config::QuotaConfig, error, model::BowerbirdMetadata};
+
+/// The bytes downloaded by all the runs, persisted in `bowerbird_metadata`.
+///
+/// Checked before adding the tasks, so the running downloads may exceed the limit a bit.
+#[derive(Debug)]
+pub struct Quota {
+    c_metadata: Collection<BowerbirdMetadata>,
+    config: QuotaConfig,
+    used: AtomicU64,
+}
+
+impl Quota {
+    pub async fn load(db: &Database, config: QuotaConfig) -> crate::Result<Self> {
+        let c_metadata = db.collection::<BowerbirdMetadata>("bowerbird_metadata");
+        let used = c_metadata
+            .find_one(None, None)
+            .await
+            .context(error::MongoDb)?
+            .map_or(0, |m| m.downloaded_bytes.max(0) as u64);
+        let quota = Self {
+            c_metadata,
+            config,
+            used: AtomicU64::new(used),
+        };
+        info!(
+            "quota: {} bytes downloaded, limit {}",
+            used,
+            match quota.config.max_bytes {
+                0 => "none".to_string(),
+                max => format!("{max} bytes"),
+            }
+        );
+        if quota.over_warning(used) {
+            warn!(
+                "quota: {} bytes downloaded, over the warning threshold",
+                used
+            );
+        }
+        Ok(quota)
+    }
+
+    fn over_warning(&self, used: u64) -> bool {
+        self.config.warn_bytes != 0 && used >= self.config.warn_bytes
+    }
+
+    /// Count the bytes of a finished download.
+    pub async fn add(&self, bytes: u64) -> crate::Result<()> {
+        let before = self.used.fetch_add(bytes, Ordering::Relaxed);
+        if !self.over_warning(before) && self.over_warning(before + bytes) {
+            warn!(
+                "quota: {} bytes downloaded, over the warning threshold of {} bytes",
+                before + bytes,
+                self.config.warn_bytes
+            );
+        }
+        // Not retried, an increment applied twice would count the bytes twice.
+        // Created if missing, so the bytes are never lost.
+        self.c_metadata
+            .update_one(
+                doc! {},
+                doc! {
+                    "$inc": { "downloaded_bytes": bytes as i64 },
+                    "$setOnInsert": { "version": DB_VERSION },
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .context(error::MongoDb)?;
+        Ok(())
+    }
+
+    /// Whether no more downloads should be started.
+    pub fn exceeded(&self) -> bool {
+        self.config.max_bytes != 0 && self.used.load(Ordering::Relaxed) >= self.config.max_bytes
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.config.max_bytes
+    }
+}
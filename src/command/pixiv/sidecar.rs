@@ -0,0 +1,158 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::config::SidecarFormat;
+
+/// The metadata of a work, written next to its files for the tools without the database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sidecar {
+    pub illust_id: String,
+    pub title: String,
+    pub user_id: String,
+    pub user_name: String,
+    pub tags: Vec<String>,
+    /// The page of the work on pixiv.
+    pub source_url: String,
+    /// The URL the file is downloaded from.
+    pub file_url: String,
+    /// RFC 3339.
+    pub create_date: String,
+}
+
+impl Sidecar {
+    pub fn from_illust(i: &pixivcrab::models::illust::Illust) -> Self {
+        Self {
+            illust_id: i.id.to_string(),
+            title: i.title.clone(),
+            user_id: i.user.id.to_string(),
+            user_name: i.user.name.clone(),
+            tags: i.tags.iter().map(|t| t.name.clone()).collect(),
+            source_url: format!("https://www.pixiv.net/artworks/{}", i.id),
+            file_url: String::new(),
+            create_date: i.create_date.to_rfc3339(),
+        }
+    }
+
+    /// The sidecar of one of the files of the work.
+    pub fn for_file(&self, url: &str) -> Self {
+        Self {
+            file_url: url.to_string(),
+            ..self.clone()
+        }
+    }
+
+    pub fn render(&self, format: SidecarFormat) -> String {
+        match format {
+            SidecarFormat::Json => serde_json::to_string_pretty(self).unwrap(),
+            SidecarFormat::Xmp => self.xmp(),
+        }
+    }
+
+    /// Dublin Core in an XMP packet, with the tags as the subjects.
+    fn xmp(&self) -> String {
+        let tags: String = self
+            .tags
+            .iter()
+            .map(|t| format!("     <rdf:li>{}</rdf:li>\n", escape_xml(t)))
+            .collect();
+        format!(
+            r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/">
+   <dc:title>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">{title}</rdf:li>
+    </rdf:Alt>
+   </dc:title>
+   <dc:creator>
+    <rdf:Seq>
+     <rdf:li>{user_name}</rdf:li>
+    </rdf:Seq>
+   </dc:creator>
+   <dc:subject>
+    <rdf:Bag>
+{tags}    </rdf:Bag>
+   </dc:subject>
+   <dc:source>{source_url}</dc:source>
+   <dc:identifier>{file_url}</dc:identifier>
+   <xmp:CreateDate>{create_date}</xmp:CreateDate>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#,
+            title = escape_xml(&self.title),
+            user_name = escape_xml(&self.user_name),
+            tags = tags,
+            source_url = escape_xml(&self.source_url),
+            file_url = escape_xml(&self.file_url),
+            create_date = escape_xml(&self.create_date),
+        )
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `{file}.json` or `{file}.xmp`.
+pub fn sidecar_path(path: &Path, format: SidecarFormat) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(format.extension());
+    PathBuf::from(sidecar)
+}
+
+/// Write the sidecar of the file at `path`, unless it is unchanged.
+pub async fn write(sidecar: &Sidecar, format: SidecarFormat, path: &Path) -> std::io::Result<()> {
+    let sidecar_path = sidecar_path(path, format);
+    let content = sidecar.render(format);
+    let unchanged = tokio::fs::read_to_string(&sidecar_path)
+        .await
+        .map_or(false, |c| c == content);
+    if unchanged {
+        return Ok(());
+    }
+    tokio::fs::write(&sidecar_path, content).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xmp_escaped() {
+        let sidecar = Sidecar {
+            illust_id: "1".to_string(),
+            title: "a <b> & 'c'".to_string(),
+            user_id: "2".to_string(),
+            user_name: "d\"e".to_string(),
+            tags: vec!["R-18".to_string(), "f&g".to_string()],
+            source_url: "https://www.pixiv.net/artworks/1".to_string(),
+            file_url: String::new(),
+            create_date: "2021-08-22T22:03:33+09:00".to_string(),
+        };
+        let xmp = sidecar.render(SidecarFormat::Xmp);
+        assert!(xmp.contains(">a &lt;b&gt; &amp; &apos;c&apos;</rdf:li>"));
+        assert!(xmp.contains("<rdf:li>d&quot;e</rdf:li>"));
+        assert!(xmp.contains("<rdf:li>f&amp;g</rdf:li>"));
+        assert_eq!(
+            sidecar_path(Path::new("a/1_p0.jpg"), SidecarFormat::Xmp),
+            Path::new("a/1_p0.jpg.xmp")
+        );
+    }
+}
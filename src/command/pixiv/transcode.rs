@@ -0,0 +1,169 @@
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    sync::{
+        mpsc::{self, OwnedPermit},
+        oneshot, Mutex,
+    },
+    task::spawn_blocking,
+};
+
+use super::utils::{self, Ffmpeg};
+use crate::{config::UgoiraFormat, utils::warn_throttled};
+
+struct Job {
+    ffmpeg: Ffmpeg,
+    zip_path: PathBuf,
+    frame_delay: Vec<i32>,
+    formats: Vec<UgoiraFormat>,
+    done: oneshot::Sender<Vec<UgoiraFormat>>,
+}
+
+/// Transcodes the downloaded ugoira with a fixed number of workers.
+///
+/// A place in the queue is reserved before an ugoira is downloaded,
+/// so the downloads wait when ffmpeg falls behind instead of piling up.
+#[derive(Debug, Clone)]
+pub struct TranscodePool {
+    tx: mpsc::Sender<Job>,
+    workers: usize,
+}
+
+/// A place in the queue of a pool, freed if dropped without transcoding, e.g. the download failed.
+pub struct TranscodeSlot(OwnedPermit<Job>);
+
+impl TranscodePool {
+    /// `0` workers for one per CPU.
+    pub fn new(workers: usize, queue_depth: usize) -> Self {
+        let workers = if workers == 0 {
+            num_cpus::get()
+        } else {
+            workers
+        };
+        let (tx, rx) = mpsc::channel::<Job>(queue_depth.max(1));
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..workers {
+            let rx = rx.clone();
+            tokio::spawn(async move {
+                loop {
+                    // Only one worker waits for the next job at a time,
+                    // and the lock is released before running it.
+                    let job = match rx.lock().await.recv().await {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let transcoded = run(&job).await;
+                    let _ = job.done.send(transcoded);
+                }
+            });
+        }
+        Self { tx, workers }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Wait for a place in the queue.
+    pub async fn reserve(&self) -> TranscodeSlot {
+        let permit = self.tx.clone().reserve_owned().await;
+        TranscodeSlot(permit.expect("the transcode workers are stopped"))
+    }
+}
+
+impl TranscodeSlot {
+    /// Transcode the zip to the formats, returning those succeeded.
+    pub async fn transcode(
+        self,
+        ffmpeg: Ffmpeg,
+        zip_path: PathBuf,
+        frame_delay: Vec<i32>,
+        formats: Vec<UgoiraFormat>,
+    ) -> Vec<UgoiraFormat> {
+        let (done, transcoded) = oneshot::channel();
+        self.0.send(Job {
+            ffmpeg,
+            zip_path,
+            frame_delay,
+            formats,
+            done,
+        });
+        transcoded.await.unwrap_or_default()
+    }
+}
+
+/// Failing to transcode is not fatal, the zip is still saved.
+async fn run(job: &Job) -> Vec<UgoiraFormat> {
+    let mut transcoded = Vec::new();
+    for &format in &job.formats {
+        let ffmpeg = job.ffmpeg.clone();
+        let zip_path = job.zip_path.clone();
+        let frame_delay = job.frame_delay.clone();
+        let r = spawn_blocking(move || {
+            let ffmpeg_path = match ffmpeg.path() {
+                Some(p) => p,
+                None => return Ok(false),
+            };
+            let r = utils::ugoira_transcode(ffmpeg_path, &zip_path, frame_delay, format);
+            if let Err(e) = &r {
+                if e.is::<utils::FfmpegSpawnError>() {
+                    ffmpeg.mark_missing();
+                }
+            }
+            r.map(|_| true)
+        })
+        .await
+        .unwrap();
+        match r {
+            Ok(true) => transcoded.push(format),
+            Ok(false) => {}
+            Err(e) => warn_throttled(
+                "ugoira transcode failed",
+                format!(
+                    "cannot transcode ugoira to {}, only the zip is saved: {:?}: {}",
+                    format.extension(),
+                    job.zip_path,
+                    e
+                ),
+            ),
+        }
+    }
+    transcoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn reserved(pool: &TranscodePool) -> Option<TranscodeSlot> {
+        tokio::time::timeout(Duration::from_millis(50), pool.reserve())
+            .await
+            .ok()
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        let pool = TranscodePool::new(1, 2);
+        let first = reserved(&pool).await.unwrap();
+        let _second = reserved(&pool).await.unwrap();
+        assert!(reserved(&pool).await.is_none());
+
+        // E.g. the download failed.
+        drop(first);
+        let third = reserved(&pool).await.unwrap();
+        assert!(reserved(&pool).await.is_none());
+
+        // Without ffmpeg nothing is transcoded, and the place is freed once a worker takes it.
+        let ffmpeg = Ffmpeg::new(PathBuf::from("ffmpeg"), false);
+        let transcoded = third
+            .transcode(
+                ffmpeg,
+                PathBuf::from("a.zip"),
+                vec![100],
+                vec![UgoiraFormat::Mp4],
+            )
+            .await;
+        assert!(transcoded.is_empty());
+        assert!(reserved(&pool).await.is_some());
+    }
+}
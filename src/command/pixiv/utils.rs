@@ -1,34 +1,143 @@
 use futures::TryStreamExt;
-use image::GenericImageView;
-use log::warn;
+use image::{imageops::FilterType::Lanczos3, GenericImageView, ImageOutputFormat};
+use log::{info, warn};
 use pixivcrab::Pager;
 use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 use snafu::ResultExt;
 use std::{
     fs::File,
+    future::Future,
+    io::{Cursor, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
-    time::Duration,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 use url::Url;
 
 use crate::{
+    config::{DerivativeConfig, DerivativeFormat, UgoiraFormat},
     error::{self, BoxError},
     model::Hsv,
-    utils::rgb_to_hsv,
+    utils::{pace, pause_pacing, rgb_to_hsv, warn_throttled},
 };
 
-pub fn ugoira_to_mp4(
+/// How long to wait before checking a missing ffmpeg again.
+const FFMPEG_REPROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct FfmpegState {
+    available: bool,
+    checked_at: Instant,
+}
+
+/// The ffmpeg binary shared by all tasks.
+///
+/// Once it fails to start, it is considered missing
+/// and probed again at most once a minute, so a reinstalled binary is picked up.
+#[derive(Debug, Clone)]
+pub struct Ffmpeg {
+    path: PathBuf,
+    state: Arc<Mutex<FfmpegState>>,
+}
+
+impl Ffmpeg {
+    pub fn new(path: PathBuf, available: bool) -> Self {
+        Self {
+            path,
+            state: Arc::new(Mutex::new(FfmpegState {
+                available,
+                checked_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Get the path if ffmpeg can be used. May block to probe it.
+    pub fn path(&self) -> Option<&Path> {
+        let mut state = self.state.lock().unwrap();
+        if !state.available && state.checked_at.elapsed() >= FFMPEG_REPROBE_INTERVAL {
+            state.checked_at = Instant::now();
+            state.available = Command::new(&self.path)
+                .arg("-version")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok();
+            if state.available {
+                info!("ffmpeg is available again: {}", self.path.to_string_lossy());
+            }
+        }
+        if state.available {
+            Some(&self.path)
+        } else {
+            None
+        }
+    }
+
+    pub fn mark_missing(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.available = false;
+        state.checked_at = Instant::now();
+    }
+}
+
+fn ugoira_codec_args(format: UgoiraFormat) -> &'static [&'static str] {
+    match format {
+        UgoiraFormat::Mp4 => &[
+            "-c:v",
+            "libx264",
+            "-preset",
+            "slow",
+            "-crf",
+            "22",
+            "-pix_fmt",
+            "yuv420p",
+            "-vf",
+            "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+        ],
+        UgoiraFormat::Webm => &[
+            "-c:v",
+            "libvpx-vp9",
+            "-crf",
+            "30",
+            "-b:v",
+            "0",
+            "-pix_fmt",
+            "yuv420p",
+        ],
+        UgoiraFormat::Gif => &["-vf", "split[a][b];[a]palettegen[p];[b][p]paletteuse"],
+    }
+}
+
+/// ffmpeg cannot be started, the binary is probably missing.
+#[derive(Debug)]
+pub struct FfmpegSpawnError(pub std::io::Error);
+
+impl std::fmt::Display for FfmpegSpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cannot start ffmpeg: {}", self.0)
+    }
+}
+
+impl std::error::Error for FfmpegSpawnError {}
+
+/// Transcode the ugoira zip to a file next to it, with the extension of the format.
+pub fn ugoira_transcode(
     ffmpeg_path: impl AsRef<Path>,
     zip_path: impl AsRef<Path>,
     frame_delay: Vec<i32>,
+    format: UgoiraFormat,
 ) -> Result<PathBuf, BoxError> {
     let zip_path = zip_path.as_ref();
-    let mut mp4_path = PathBuf::from(zip_path);
+    let mut video_path = PathBuf::from(zip_path);
 
     let mut file = File::open(zip_path)?;
     let mut zip_file = zip::ZipArchive::new(&mut file)?;
-    mp4_path.set_extension("mp4");
+    video_path.set_extension(format.extension());
 
     let mut ffmpeg = Command::new(ffmpeg_path.as_ref())
         .args([
@@ -42,20 +151,12 @@ pub fn ugoira_to_mp4(
             "60",
             "-i",
             "-",
-            "-c:v",
-            "libx264",
-            "-preset",
-            "slow",
-            "-crf",
-            "22",
-            "-pix_fmt",
-            "yuv420p",
-            "-vf",
-            "pad=ceil(iw/2)*2:ceil(ih/2)*2",
         ])
-        .arg(mp4_path.as_os_str())
+        .args(ugoira_codec_args(format))
+        .arg(video_path.as_os_str())
         .stdin(Stdio::piped())
-        .spawn()?;
+        .spawn()
+        .map_err(FfmpegSpawnError)?;
     let mut stdin = ffmpeg.stdin.take().unwrap();
 
     let mut t: f32 = 0.0; // video length in milliseconds
@@ -78,12 +179,43 @@ pub fn ugoira_to_mp4(
     if !status.success() {
         Err(format!("FFmpeg exited with status {status}"))?
     }
-    Ok(mp4_path)
+    Ok(video_path)
+}
+
+/// A 64-bit difference hash of the image, close for visually similar images.
+pub fn dhash(img: &image::DynamicImage) -> u64 {
+    let small = img
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
 }
 
-pub fn get_palette(image_path: impl AsRef<Path>) -> Result<((i32, i32), Vec<Hsv>), BoxError> {
+/// What is saved to the database about a downloaded image.
+#[derive(Debug, Clone, Default)]
+pub struct ImageInfo {
+    pub dimensions: (i32, i32),
+    pub palette_hsv: Vec<Hsv>,
+    pub dhash: u64,
+    pub sha256: String,
+}
+
+pub fn get_image_info(image_path: impl AsRef<Path>) -> Result<ImageInfo, BoxError> {
+    let image_path = image_path.as_ref();
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(image_path)?, &mut hasher)?;
+
     let img = image::open(image_path)?;
     let (w, h) = img.dimensions();
+    let dhash = dhash(&img);
     let thumbnail = img.thumbnail(512, 512).to_rgba8();
     drop(img);
 
@@ -95,33 +227,231 @@ pub fn get_palette(image_path: impl AsRef<Path>) -> Result<((i32, i32), Vec<Hsv>
         })
         .collect();
     // Convert to i32 here to save to bson.
-    Ok(((w as i32, h as i32), hsv_v))
+    Ok(ImageInfo {
+        dimensions: (w as i32, h as i32),
+        palette_hsv: hsv_v,
+        dhash,
+        sha256: hex::encode(hasher.finalize()),
+    })
+}
+
+/// The derivative of `image_path` is saved next to it, e.g. `123_p0.jpg` to `123_p0_derivative.webp`.
+pub fn derivative_path(image_path: impl AsRef<Path>, format: DerivativeFormat) -> PathBuf {
+    let image_path = image_path.as_ref();
+    let stem = image_path.file_stem().unwrap_or_default().to_string_lossy();
+    image_path.with_file_name(format!("{stem}_derivative.{}", format.extension()))
+}
+
+/// Save a copy of the image scaled down to fit in the max dimension.
+///
+/// Returns the path and the dimensions of the copy.
+pub fn make_derivative(
+    ffmpeg: &Ffmpeg,
+    image_path: impl AsRef<Path>,
+    config: &DerivativeConfig,
+) -> Result<(PathBuf, (i32, i32)), BoxError> {
+    let path = derivative_path(&image_path, config.format);
+    let mut img = image::open(image_path)?;
+    let (w, h) = img.dimensions();
+    if w > config.max_dimension || h > config.max_dimension {
+        img = img.resize(config.max_dimension, config.max_dimension, Lanczos3);
+    }
+    let (w, h) = img.dimensions();
+    let quality = config.quality.clamp(1, 100);
+    match config.format {
+        DerivativeFormat::Jpeg => {
+            let mut b = Cursor::new(Vec::new());
+            img.write_to(&mut b, ImageOutputFormat::Jpeg(quality))?;
+            std::fs::write(&path, b.into_inner())?;
+        }
+        DerivativeFormat::Webp => {
+            // The image crate cannot encode lossy WebP, so pipe the scaled image to ffmpeg.
+            let ffmpeg_path = ffmpeg.path().ok_or("ffmpeg is not available")?;
+            let mut b = Cursor::new(Vec::new());
+            img.write_to(&mut b, ImageOutputFormat::Png)?;
+            drop(img);
+            let mut child = Command::new(ffmpeg_path)
+                .args([
+                    "-y",
+                    "-hide_banner",
+                    "-loglevel",
+                    "error",
+                    "-f",
+                    "image2pipe",
+                    "-i",
+                    "-",
+                    "-quality",
+                    &quality.to_string(),
+                ])
+                .arg(path.as_os_str())
+                .stdin(Stdio::piped())
+                .spawn()
+                .map_err(|e| {
+                    ffmpeg.mark_missing();
+                    FfmpegSpawnError(e)
+                })?;
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(b.get_ref())?;
+            drop(stdin);
+            let status = child.wait()?;
+            if !status.success() {
+                Err(format!("FFmpeg exited with status {status}"))?
+            }
+        }
+    }
+    Ok((path, (w as i32, h as i32)))
+}
+
+static RATE_LIMIT_COOLDOWN_SECS: AtomicU64 = AtomicU64::new(300);
+static RATE_LIMIT_MAX_COOLDOWNS: AtomicU32 = AtomicU32::new(6);
+
+pub fn set_rate_limit_cooldown(cooldown: Duration, max_cooldowns: u32) {
+    RATE_LIMIT_COOLDOWN_SECS.store(cooldown.as_secs(), SeqCst);
+    RATE_LIMIT_MAX_COOLDOWNS.store(max_cooldowns, SeqCst);
 }
 
+/// What a failed request to the pixiv API is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorKind {
+    /// Too many requests, which goes away after a while.
+    RateLimited,
+    /// The token is rejected, which only a new refresh token fixes.
+    Auth,
+    /// Only for premium accounts, e.g. sorting the search by popularity.
+    PremiumRequired,
+    /// The work is deleted or never existed.
+    NotFound,
+    Other,
+}
+
+/// The HTTP status and the JSON body of a failed request, found in the message of the error.
+///
+/// The bodies of the API are like `{"error": {"message": "Rate Limit", "reason": "", ...}}`,
+/// and the ones of the OAuth endpoint like `{"has_error": true, "errors": {"system": {...}}}`.
+fn parse_api_error(message: &str) -> (Option<u16>, Option<serde_json::Value>) {
+    let status = message
+        .find("HTTP status")
+        .and_then(|i| message[i..].split_once('('))
+        .and_then(|(_, s)| s.get(..3))
+        .and_then(|s| s.parse().ok());
+    let body = message.find('{').and_then(|i| {
+        serde_json::Deserializer::from_str(&message[i..])
+            .into_iter::<serde_json::Value>()
+            .next()?
+            .ok()
+    });
+    (status, body)
+}
+
+/// Tell the kind of an error by the HTTP status and the fields of the body from pixiv.
+///
+/// Only the fields are matched, not the whole message,
+/// which also has the url of the request, e.g. with the words searched.
+pub fn api_error_kind(message: &str) -> ApiErrorKind {
+    let (status, body) = parse_api_error(message);
+    let field = |pointer: &str| {
+        body.as_ref()
+            .and_then(|b| b.pointer(pointer))
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_lowercase()
+    };
+    let error_message = field("/error/message");
+    let oauth_error = body
+        .as_ref()
+        .map_or(false, |b| b.pointer("/errors/system").is_some());
+    if status == Some(429) || error_message == "rate limit" {
+        ApiErrorKind::RateLimited
+    } else if status == Some(401)
+        || oauth_error
+        || matches!(field("/error").as_str(), "invalid_grant" | "invalid_token")
+        || error_message.contains("oauth process")
+    {
+        ApiErrorKind::Auth
+    } else if ["/error/message", "/error/user_message", "/error/reason"]
+        .iter()
+        .any(|p| field(p).contains("premium"))
+    {
+        ApiErrorKind::PremiumRequired
+    } else if status == Some(404) {
+        ApiErrorKind::NotFound
+    } else {
+        ApiErrorKind::Other
+    }
+}
+
+/// Pause all the requests for the cooldown if the rate limit is reached,
+/// so the failed request can be sent again. Fails with the error otherwise.
+fn cool_down_or_fail(source: pixivcrab::error::Error, cooldowns: &mut u32) -> crate::Result<()> {
+    match api_error_kind(&source.to_string()) {
+        ApiErrorKind::RateLimited => {
+            let max_cooldowns = RATE_LIMIT_MAX_COOLDOWNS.load(SeqCst);
+            if *cooldowns >= max_cooldowns {
+                return Err(error::Error::PixivRateLimited {
+                    cooldowns: *cooldowns,
+                    source,
+                });
+            }
+            *cooldowns += 1;
+            let cooldown = Duration::from_secs(RATE_LIMIT_COOLDOWN_SECS.load(SeqCst));
+            warn!(
+                "pixiv rate limit reached, pausing the requests for {}s (cooldown {}/{})",
+                cooldown.as_secs(),
+                cooldowns,
+                max_cooldowns
+            );
+            pause_pacing(cooldown);
+            Ok(())
+        }
+        ApiErrorKind::Auth => Err(error::Error::PixivAuth { source }),
+        ApiErrorKind::PremiumRequired => Err(error::Error::PixivPremiumRequired { source }),
+        ApiErrorKind::NotFound | ApiErrorKind::Other => Err(error::Error::PixivApi { source }),
+    }
+}
+
+/// Send a request to the pixiv API, again after the cooldowns while the rate limit is reached.
+pub async fn call_api<T, F, Fut>(mut request: F) -> crate::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, pixivcrab::error::Error>>,
+{
+    let mut cooldowns = 0;
+    loop {
+        pace().await;
+        match request().await {
+            Ok(r) => return Ok(r),
+            Err(source) => cool_down_or_fail(source, &mut cooldowns)?,
+        }
+    }
+}
+
+/// Get the next page, retrying on HTTP errors.
+///
+/// When the rate limit is reached, the same page is requested again after the cooldowns.
 pub async fn retry_pager<T>(pager: &mut Pager<T>, max_tries: i32) -> crate::Result<Option<T>>
 where
     T: DeserializeOwned + pixivcrab::NextUrl + Send,
 {
     let mut tries = 0;
+    let mut cooldowns = 0;
     loop {
         tries += 1;
-        match pager.try_next().await.context(error::PixivApi) {
-            Ok(r) => {
-                return Ok(r);
-            }
-            Err(e) => {
-                if let error::Error::PixivApi { source, .. } = &e {
-                    if let pixivcrab::error::Error::HTTP { .. } = source {
-                        if tries <= max_tries {
-                            warn!("retrying on pixiv api error: {:?} :{}", pager, e);
-                            tokio::time::sleep(Duration::from_secs(2)).await;
-                            continue;
-                        }
-                    }
-                }
-                return Err(e);
+        pace().await;
+        let source = match pager.try_next().await {
+            Ok(r) => return Ok(r),
+            Err(source) => source,
+        };
+        if let pixivcrab::error::Error::HTTP { .. } = source {
+            if tries <= max_tries && api_error_kind(&source.to_string()) == ApiErrorKind::Other {
+                warn_throttled(
+                    "retrying on pixiv api error",
+                    format!("retrying on pixiv api error: {:?} :{}", pager, source),
+                );
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                continue;
             }
         }
+        cool_down_or_fail(source, &mut cooldowns)?;
     }
 }
 
@@ -138,3 +468,47 @@ pub fn filename_from_url(url: &str) -> crate::Result<String> {
         .build()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_error_kinds() {
+        let kind = |body: &str| {
+            api_error_kind(&format!(
+                "https://app-api.pixiv.net/v1/search/illust?word=premium: {body}"
+            ))
+        };
+        assert_eq!(
+            kind(r#"{"error":{"user_message":"","message":"Rate Limit","reason":""}}"#),
+            ApiErrorKind::RateLimited
+        );
+        assert_eq!(
+            kind(r#"{"error":{"message":"Error occurred at the OAuth process. invalid_grant"}}"#),
+            ApiErrorKind::Auth
+        );
+        assert_eq!(
+            kind(r#"{"has_error":true,"errors":{"system":{"message":"Invalid refresh token"}}}"#),
+            ApiErrorKind::Auth
+        );
+        assert_eq!(
+            kind(r#"{"error":{"message":"","reason":"Premium membership is required"}}"#),
+            ApiErrorKind::PremiumRequired
+        );
+        // Only in the url.
+        assert_eq!(
+            kind(r#"{"error":{"message":"","user_message":"Invalid request"}}"#),
+            ApiErrorKind::Other
+        );
+        assert_eq!(
+            api_error_kind("HTTP status client error (429 Too Many Requests) for url (/v1/)"),
+            ApiErrorKind::RateLimited
+        );
+        assert_eq!(
+            api_error_kind("HTTP status client error (404 Not Found) for url (/v1/)"),
+            ApiErrorKind::NotFound
+        );
+        assert_eq!(kind("not a body"), ApiErrorKind::Other);
+    }
+}
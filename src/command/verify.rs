@@ -0,0 +1,278 @@
+use bson::{doc, oid::ObjectId};
+use futures::{StreamExt, TryStreamExt};
+use log::{info, warn};
+use mongodb::{options::FindOptions, Database};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{sync::Semaphore, task::spawn_blocking};
+
+use crate::{error, utils::find_in_storage_dirs};
+
+/// Save the progress after this number of files.
+const CHECKPOINT_INTERVAL: u64 = 1000;
+
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    /// Only check about this percentage of the files.
+    /// The files are chosen by their ids, so a resumed run checks the same ones.
+    pub sample_percent: Option<f64>,
+    /// Save the progress to this file, and continue from it if it exists.
+    pub checkpoint: Option<PathBuf>,
+    /// Number of files checked at the same time.
+    pub io_concurrency: usize,
+    /// Save the hashes of the files without one, to be checked next time.
+    pub save_hashes: bool,
+}
+
+/// The files failing the checks, by their paths in the database.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub checked: u64,
+    pub missing: Vec<String>,
+    pub size_mismatch: Vec<String>,
+    pub hash_mismatch: Vec<String>,
+    pub hashes_saved: u64,
+    /// Records updated with the storage tier holding the file.
+    #[serde(default)]
+    pub storage_dirs_saved: u64,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.size_mismatch.is_empty() && self.hash_mismatch.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Files are checked in the order of their ids.
+    last_id: ObjectId,
+    sample_percent: Option<f64>,
+    report: VerifyReport,
+}
+
+#[derive(Debug, Deserialize)]
+struct Media {
+    _id: ObjectId,
+    local_path: String,
+    size: i64,
+    sha256: Option<String>,
+    /// The storage tier holding the file, none for the storage dir.
+    storage_dir: Option<String>,
+}
+
+enum Check {
+    Ok,
+    Missing,
+    SizeMismatch,
+    HashMismatch,
+    /// The file has no hash in the database.
+    NewHash(String),
+}
+
+fn sampled(id: &ObjectId, percent: f64) -> bool {
+    (crc32fast::hash(&id.bytes()) % 10000) < (percent * 100.0) as u32
+}
+
+pub(crate) fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// `path` is the file in the first storage dir holding it.
+async fn check_media(
+    path: Option<PathBuf>,
+    media: &Media,
+    save_hashes: bool,
+    cpu: &Semaphore,
+) -> Check {
+    let path = match path {
+        Some(path) => path,
+        None => return Check::Missing,
+    };
+    let size = match tokio::fs::metadata(&path).await {
+        Ok(m) => m.len(),
+        Err(_) => return Check::Missing,
+    };
+    if size as i64 != media.size {
+        return Check::SizeMismatch;
+    }
+    if media.sha256.is_none() && !save_hashes {
+        return Check::Ok;
+    }
+    let _permit = cpu.acquire().await.unwrap();
+    let hash = match spawn_blocking(move || hash_file(&path)).await.unwrap() {
+        Ok(hash) => hash,
+        Err(_) => return Check::Missing,
+    };
+    match &media.sha256 {
+        Some(expected) if *expected == hash => Check::Ok,
+        Some(_) => Check::HashMismatch,
+        None => Check::NewHash(hash),
+    }
+}
+
+pub(crate) fn load_checkpoint<T: DeserializeOwned>(path: &Path) -> crate::Result<Option<T>> {
+    let context = || error::VerifyCheckpointIo {
+        path: path.to_string_lossy().to_string(),
+    };
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(context()),
+    };
+    serde_json::from_str(&text)
+        .map(Some)
+        .context(error::VerifyCheckpointJson {
+            path: path.to_string_lossy().to_string(),
+        })
+}
+
+pub(crate) fn save_checkpoint<T: Serialize>(path: &Path, checkpoint: &T) -> crate::Result<()> {
+    let context = || error::VerifyCheckpointIo {
+        path: path.to_string_lossy().to_string(),
+    };
+    // Write to a temporary file first, so an interrupted write does not lose the progress.
+    let tmp = path.with_extension("tmp");
+    let text = serde_json::to_string(checkpoint).context(error::VerifyCheckpointJson {
+        path: path.to_string_lossy().to_string(),
+    })?;
+    std::fs::write(&tmp, text).context(context())?;
+    std::fs::rename(&tmp, path).context(context())?;
+    Ok(())
+}
+
+/// Check that the media files exist with the sizes and hashes in the database.
+///
+/// The files are searched in `storage_dirs` in order, and the storage tier holding each file
+/// is saved in its record. The checkpoint is removed after all the files are checked.
+pub async fn verify(
+    db: &Database,
+    storage_dirs: &[PathBuf],
+    options: &VerifyOptions,
+) -> crate::Result<VerifyReport> {
+    let c_image = db.collection::<Media>("pixiv_image");
+
+    let mut report = VerifyReport::default();
+    let mut filter = doc! {};
+    if let Some(ref path) = options.checkpoint {
+        match load_checkpoint::<Checkpoint>(path)? {
+            Some(c) if c.sample_percent == options.sample_percent => {
+                info!(
+                    "resuming from checkpoint: {} files checked",
+                    c.report.checked
+                );
+                filter = doc! { "_id": { "$gt": c.last_id } };
+                report = c.report;
+            }
+            Some(_) => warn!("checkpoint is for a different sample, starting over"),
+            None => {}
+        }
+    }
+
+    let cpu = Arc::new(Semaphore::new(num_cpus::get()));
+    let sample_percent = options.sample_percent;
+    let save_hashes = options.save_hashes;
+    let storage_dirs: Arc<[PathBuf]> = storage_dirs.into();
+    let mut checks = c_image
+        .find(
+            filter,
+            FindOptions::builder()
+                .sort(doc! { "_id": 1 })
+                .projection(doc! {
+                    "_id": 1, "local_path": 1, "size": 1, "sha256": 1, "storage_dir": 1,
+                })
+                .build(),
+        )
+        .await
+        .context(error::MongoDb)?
+        .map(|r| r.context(error::MongoDb))
+        .try_filter(move |m| {
+            futures::future::ready(sample_percent.map_or(true, |p| sampled(&m._id, p)))
+        })
+        .map_ok(|m| {
+            let cpu = cpu.clone();
+            let storage_dirs = storage_dirs.clone();
+            async move {
+                let found = find_in_storage_dirs(&storage_dirs, &m.local_path);
+                let tier = found.as_ref().map(|(i, _)| *i);
+                let check = check_media(found.map(|(_, p)| p), &m, save_hashes, &cpu).await;
+                Ok((m, check, tier))
+            }
+        })
+        .try_buffered(options.io_concurrency.max(1));
+
+    let mut since_checkpoint = 0;
+    while let Some((media, check, tier)) = checks.try_next().await? {
+        report.checked += 1;
+        if let Some(tier) = tier {
+            let storage_dir = (tier > 0).then(|| storage_dirs[tier].to_string_lossy().to_string());
+            if storage_dir != media.storage_dir {
+                let update = match storage_dir {
+                    Some(dir) => doc! { "$set": { "storage_dir": dir } },
+                    None => doc! { "$unset": { "storage_dir": "" } },
+                };
+                db.collection::<Media>("pixiv_image")
+                    .update_one(doc! { "_id": media._id }, update, None)
+                    .await
+                    .context(error::MongoDb)?;
+                report.storage_dirs_saved += 1;
+            }
+        }
+        match check {
+            Check::Ok => {}
+            Check::Missing => report.missing.push(media.local_path),
+            Check::SizeMismatch => report.size_mismatch.push(media.local_path),
+            Check::HashMismatch => report.hash_mismatch.push(media.local_path),
+            Check::NewHash(hash) => {
+                db.collection::<Media>("pixiv_image")
+                    .update_one(
+                        doc! { "_id": media._id },
+                        doc! { "$set": { "sha256": hash } },
+                        None,
+                    )
+                    .await
+                    .context(error::MongoDb)?;
+                report.hashes_saved += 1;
+            }
+        }
+        since_checkpoint += 1;
+        if since_checkpoint >= CHECKPOINT_INTERVAL {
+            since_checkpoint = 0;
+            info!("{} files checked", report.checked);
+            if let Some(ref path) = options.checkpoint {
+                save_checkpoint(
+                    path,
+                    &Checkpoint {
+                        last_id: media._id,
+                        sample_percent,
+                        report: report.clone(),
+                    },
+                )?;
+            }
+        }
+    }
+
+    if let Some(ref path) = options.checkpoint {
+        if path.exists() {
+            std::fs::remove_file(path).context(error::VerifyCheckpointIo {
+                path: path.to_string_lossy().to_string(),
+            })?;
+        }
+    }
+    info!(
+        "{} files checked: {} missing, {} size mismatched, {} hash mismatched",
+        report.checked,
+        report.missing.len(),
+        report.size_mismatch.len(),
+        report.hash_mismatch.len()
+    );
+    Ok(report)
+}
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Local, SecondsFormat, Utc};
 use log::info;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
@@ -5,6 +6,7 @@ use std::{
     fs::{File, OpenOptions},
     net::SocketAddr,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use crate::error;
@@ -14,28 +16,90 @@ use crate::error;
 pub struct Config {
     #[serde(skip)]
     config_path: Option<PathBuf>,
+    /// Set by the `--proxy` flag, takes precedence over all the proxies in the config file.
+    #[serde(skip)]
+    proxy_override: Option<String>,
+    /// Set by `--ffmpeg-path`, takes precedence over `ffmpeg_path`.
+    #[serde(skip)]
+    ffmpeg_path_override: Option<String>,
+    /// Set by `--aria2-path`, takes precedence over `aria2_path`.
+    #[serde(skip)]
+    aria2_path_override: Option<String>,
 
     pub root_storage_dir: String,
     pub proxy_all: String,
+    /// Credentials for proxies without them in the url.
+    pub proxy_username: String,
+    pub proxy_password: String,
     pub ffmpeg_path: String,
     pub aria2_path: String,
+    /// Shut down aria2 after no download for this number of seconds.
+    /// It is started again when needed.
+    pub aria2_idle_timeout_secs: Option<u64>,
+    pub download: DownloadConfig,
+    pub mongodump_path: String,
+    /// Threads analyzing the downloaded images, e.g. palettes, hashes and derivatives.
+    /// `0` for one per CPU.
+    pub analysis_threads: usize,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub pacing: PacingConfig,
+    pub quota: QuotaConfig,
+    pub http_client: HttpClientConfig,
+    /// Identical warnings in this number of seconds are collapsed into a count.
+    pub warning_dedup_window_secs: u64,
+    /// Overridden by the environment variable `BOWERBIRD_LOG_TIME`.
+    pub log_time_format: LogTimeFormat,
     pub mongodb: MongoDBConfig,
     pub pixiv: PixivConfig,
     pub server: ServerConfig,
 }
 
+/// Retry the database operations failed for network errors or elections of the replica set.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DbRetryConfig {
+    /// `0` to fail at the first error.
+    pub retries: u32,
+    /// Doubled after every retry.
+    pub backoff_millis: u64,
+}
+
+impl Default for DbRetryConfig {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff_millis: 200,
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             config_path: None,
+            proxy_override: None,
+            ffmpeg_path_override: None,
+            aria2_path_override: None,
             root_storage_dir: dirs::home_dir()
                 .unwrap_or_default()
                 .join(".bowerbird")
                 .to_string_lossy()
                 .to_string(),
             proxy_all: "".to_string(),
+            proxy_username: "".to_string(),
+            proxy_password: "".to_string(),
             ffmpeg_path: "".to_string(),
             aria2_path: "aria2c".to_string(),
+            aria2_idle_timeout_secs: None,
+            download: DownloadConfig::default(),
+            mongodump_path: "mongodump".to_string(),
+            analysis_threads: 0,
+            warning_dedup_window_secs: 60,
+            log_time_format: LogTimeFormat::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            pacing: PacingConfig::default(),
+            quota: QuotaConfig::default(),
+            http_client: HttpClientConfig::default(),
             mongodb: MongoDBConfig::default(),
             pixiv: PixivConfig::default(),
             server: ServerConfig::default(),
@@ -43,11 +107,287 @@ impl Default for Config {
     }
 }
 
+/// How the time of a log line is written.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTimeFormat {
+    /// RFC 3339 in the local timezone, e.g. `2023-01-02T15:04:05+08:00`.
+    Rfc3339,
+    /// RFC 3339 in UTC, e.g. `2023-01-02T07:04:05Z`.
+    Utc,
+    /// `2023-01-02 15:04:05` in the local timezone.
+    Human,
+}
+
+impl Default for LogTimeFormat {
+    fn default() -> Self {
+        Self::Rfc3339
+    }
+}
+
+impl std::str::FromStr for LogTimeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rfc3339" => Ok(Self::Rfc3339),
+            "utc" => Ok(Self::Utc),
+            "human" => Ok(Self::Human),
+            _ => Err(format!("unknown log time format: {s}")),
+        }
+    }
+}
+
+impl LogTimeFormat {
+    pub fn format(self, time: DateTime<Local>) -> String {
+        match self {
+            Self::Rfc3339 => time.to_rfc3339_opts(SecondsFormat::Secs, false),
+            Self::Utc => time
+                .with_timezone(&Utc)
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            Self::Human => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+}
+
+/// Options of the HTTP clients built by bowerbird.
+///
+/// The defaults of reqwest are kept if unset. Files downloaded by aria2 are not affected.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpClientConfig {
+    /// Use HTTP/2 without negotiating, only if the server is known to support it.
+    pub http2_prior_knowledge: bool,
+    /// Close idle connections after this number of seconds. Defaults to 90.
+    pub pool_idle_timeout_secs: Option<u64>,
+    /// Keep at most this number of idle connections per host. Unlimited by default.
+    pub pool_max_idle_per_host: Option<usize>,
+}
+
+impl HttpClientConfig {
+    pub fn apply(&self, mut builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(secs) = self.pool_idle_timeout_secs {
+            builder = builder.pool_idle_timeout(std::time::Duration::from_secs(secs));
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        builder
+    }
+}
+
+/// How the downloads connect to the servers, with either backend.
+///
+/// The defaults of aria2 and reqwest are kept if unset. If the first bytes of the downloads
+/// take tens of seconds on a network with broken IPv6, `disable_ipv6` fixes it.
+/// Otherwise a short `connect_timeout_secs` gives up on an unreachable address sooner
+/// and tries the next one, like happy eyeballs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Only connect over IPv4.
+    pub disable_ipv6: bool,
+    /// Give up connecting to an address after this number of seconds.
+    /// Defaults to 60 with aria2, and to none with the native backend.
+    pub connect_timeout_secs: Option<u64>,
+    /// Resolve the hosts with these DNS servers instead of those of the system,
+    /// e.g. `["1.1.1.1", "8.8.8.8"]`.
+    pub dns_servers: Vec<String>,
+}
+
+impl NetworkConfig {
+    /// Apply the options to the client of the native backend.
+    pub fn apply(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> crate::Result<reqwest::ClientBuilder> {
+        if let Some(secs) = self.connect_timeout_secs {
+            builder = builder.connect_timeout(std::time::Duration::from_secs(secs));
+        }
+        if self.disable_ipv6 {
+            // Bound to an IPv4 address, only IPv4 addresses can be connected to.
+            builder = builder.local_address(std::net::IpAddr::from([0, 0, 0, 0]));
+        }
+        if !self.dns_servers.is_empty() {
+            let servers = self
+                .dns_servers
+                .iter()
+                .map(|s| {
+                    s.parse().map_err(|_| {
+                        error::ConfigInvalid {
+                            message: format!("invalid dns server: {s}"),
+                        }
+                        .build()
+                    })
+                })
+                .collect::<crate::Result<Vec<_>>>()?;
+            let resolver = crate::downloader::DnsResolver::new(&servers, self.disable_ipv6)
+                .map_err(|e| {
+                    error::ConfigInvalid {
+                        message: format!("cannot use the dns servers: {e}"),
+                    }
+                    .build()
+                })?;
+            builder = builder.dns_resolver(std::sync::Arc::new(resolver));
+        }
+        Ok(builder)
+    }
+
+    /// The command line options of aria2.
+    pub fn aria2_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.disable_ipv6 {
+            args.push("--disable-ipv6=true".to_string());
+        }
+        if let Some(secs) = self.connect_timeout_secs {
+            args.push(format!("--connect-timeout={secs}"));
+        }
+        if !self.dns_servers.is_empty() {
+            args.push("--async-dns=true".to_string());
+            args.push(format!("--async-dns-server={}", self.dns_servers.join(",")));
+        }
+        args
+    }
+}
+
+/// What downloads the files of the syncs.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadBackend {
+    /// Downloads with HTTP requests from bowerbird itself, without aria2.
+    Native,
+    Aria2,
+}
+
+impl Default for DownloadBackend {
+    fn default() -> Self {
+        Self::Aria2
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DownloadConfig {
+    /// `aria2_path` is not needed with `native`.
+    pub backend: DownloadBackend,
+    /// Number of the files downloaded at the same time by the native backend.
+    pub native_concurrency: usize,
+    /// Restart the downloads of the native backend receiving nothing for this number of
+    /// seconds. `0` to wait forever.
+    pub stall_timeout_secs: u64,
+    /// Fail a download after it is restarted for stalling this number of times.
+    pub stall_restarts: u32,
+    pub network: NetworkConfig,
+    /// Retry the downloads of the native backend failed for reset connections,
+    /// timeouts or `5xx` this number of times. `0` to fail at the first error.
+    pub retries: u32,
+    /// Doubled after every retry.
+    pub retry_backoff_millis: u64,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            backend: DownloadBackend::default(),
+            native_concurrency: 5,
+            stall_timeout_secs: 60,
+            stall_restarts: 3,
+            network: NetworkConfig::default(),
+            retries: 3,
+            retry_backoff_millis: 1000,
+        }
+    }
+}
+
+/// Space out the requests to pixiv, both to the API and for downloads.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PacingConfig {
+    /// Minimum milliseconds between two requests. `0` disables pacing.
+    pub interval_millis: u64,
+    /// Up to this number of milliseconds is randomly added to every interval.
+    pub jitter_millis: u64,
+    /// Seconds to pause the requests when pixiv says the rate limit is reached,
+    /// the downloads from the pixiv CDN included. Requests already waiting are not held.
+    pub rate_limit_cooldown_secs: u64,
+    /// Fail a request still rate limited after this number of cooldowns in a row.
+    pub rate_limit_max_cooldowns: u32,
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        Self {
+            interval_millis: 300,
+            jitter_millis: 200,
+            rate_limit_cooldown_secs: 300,
+            rate_limit_max_cooldowns: 6,
+        }
+    }
+}
+
+/// Limit the total bytes downloaded by all the runs, counted in the database.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// No new download is started after this number of bytes. `0` for no limit.
+    pub max_bytes: u64,
+    /// Warn once this number of bytes is downloaded. `0` to disable.
+    pub warn_bytes: u64,
+}
+
+/// Pause downloading when most of the recent downloads fail.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    /// Number of the latest finished downloads to compute the error rate.
+    pub window: usize,
+    /// Trip when this percentage of the window fails.
+    pub error_percent: u32,
+    /// Stop adding downloads for this number of seconds after tripping.
+    pub cooldown_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 20,
+            error_percent: 80,
+            cooldown_secs: 60,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(default)]
 pub struct MongoDBConfig {
     pub uri: String,
     pub database_name: String,
+    pub retry: DbRetryConfig,
+    pub write_batch: WriteBatchConfig,
+}
+
+/// Batch the database writes after downloads, e.g. to smooth the load of many small downloads.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct WriteBatchConfig {
+    /// Most writes in a batch. `1` to write each at once.
+    pub size: usize,
+    /// Write a batch this long after its first write even if it is not full.
+    pub interval_millis: u64,
+}
+
+impl Default for WriteBatchConfig {
+    fn default() -> Self {
+        Self {
+            size: 1,
+            interval_millis: 200,
+        }
+    }
 }
 
 impl Default for MongoDBConfig {
@@ -55,6 +395,8 @@ impl Default for MongoDBConfig {
         Self {
             database_name: "bowerbird".to_string(),
             uri: "mongodb://localhost/bowerbird".to_string(),
+            retry: DbRetryConfig::default(),
+            write_batch: WriteBatchConfig::default(),
         }
     }
 }
@@ -63,10 +405,234 @@ impl Default for MongoDBConfig {
 #[serde(default)]
 pub struct PixivConfig {
     pub storage_dir: String,
+    /// More storage dirs searched in order after `storage_dir` for the existing files,
+    /// e.g. a slower disk the old works are moved to. Downloads always go to `storage_dir`.
+    pub storage_tiers: Vec<String>,
     pub proxy_api: String,
     pub proxy_download: String,
     pub refresh_token: String,
     pub language: String,
+    pub collision_policy: CollisionPolicy,
+    pub directory_sharding: DirectorySharding,
+    /// Download works with these tags to the directories, instead of the storage dir.
+    /// The first matching route is used.
+    pub tag_routes: Vec<TagRoute>,
+    /// Get the next page from pixiv while processing the current one.
+    pub prefetch_pages: bool,
+    pub partial_policy: PartialPolicy,
+    /// Only download the first pages of the illusts with more pages. Unlimited if unset.
+    pub max_pages: Option<usize>,
+    /// Download the existing files again if they are empty, or differ from the size
+    /// or hash in the database. Costs a hash of every existing file with a saved hash.
+    pub verify_existing: bool,
+    /// Save the tags given to your own bookmarks when syncing them.
+    /// Costs one more API request for every bookmarked illust.
+    pub bookmark_tags: bool,
+    /// Videos transcoded from ugoira with ffmpeg.
+    pub ugoira_formats: Vec<UgoiraFormat>,
+    /// Number of ugoira transcoded at the same time. `0` for one per CPU.
+    pub transcode_workers: usize,
+    /// Ugoira queued, downloading or waiting for a transcode worker at most,
+    /// as their place is reserved before the download. Those being transcoded do not count.
+    /// More ugoira are only queued when there is room.
+    pub transcode_queue_depth: usize,
+    pub derivative: DerivativeConfig,
+    pub embedding: EmbeddingConfig,
+    /// Write the metadata of the works next to the downloaded files, for other tools.
+    /// Disabled if unset.
+    pub sidecar: Option<SidecarFormat>,
+}
+
+/// Image embeddings for finding similar images, requires the `embedding` feature.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    /// The ONNX model, e.g. the image encoder of CLIP. Disabled if empty.
+    pub model_path: String,
+    /// Images are resized to this size before being passed to the model.
+    pub input_size: u32,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            model_path: "".to_string(),
+            input_size: 224,
+        }
+    }
+}
+
+/// A smaller copy of every downloaded image saved next to it, served instead of the original.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DerivativeConfig {
+    pub enabled: bool,
+    /// Images are scaled down to fit in this size, and never scaled up.
+    pub max_dimension: u32,
+    pub format: DerivativeFormat,
+    /// From 1 to 100.
+    pub quality: u8,
+}
+
+impl Default for DerivativeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_dimension: 2048,
+            format: DerivativeFormat::Webp,
+            quality: 80,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DerivativeFormat {
+    /// Encoded with ffmpeg.
+    Webp,
+    Jpeg,
+}
+
+impl DerivativeFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Webp => "webp",
+            Self::Jpeg => "jpg",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Webp => "image/webp",
+            Self::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// The format of the metadata files written next to the downloaded files.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SidecarFormat {
+    /// `{file}.json`.
+    Json,
+    /// `{file}.xmp`, read by most image managers.
+    Xmp,
+}
+
+impl SidecarFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Xmp => "xmp",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, clap::ArgEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum UgoiraFormat {
+    Mp4,
+    Webm,
+    Gif,
+}
+
+impl UgoiraFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Webm => "webm",
+            Self::Gif => "gif",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "video/mp4",
+            Self::Webm => "video/webm",
+            Self::Gif => "image/gif",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TagRoute {
+    pub tag: String,
+    /// Relative to the storage dir, e.g. `landscapes` for `landscapes/{user_id}/...`.
+    pub dir: String,
+}
+
+impl TagRoute {
+    fn validate(&self) -> crate::Result<()> {
+        let dir = Path::new(&self.dir);
+        if self.dir.trim_matches('/').is_empty()
+            || dir.is_absolute()
+            || dir
+                .components()
+                .any(|c| !matches!(c, std::path::Component::Normal(_)))
+        {
+            return error::ConfigInvalid {
+                message: format!("invalid dir of tag route {}: {}", self.tag, self.dir),
+            }
+            .fail();
+        }
+        Ok(())
+    }
+}
+
+/// Split the directory of each user into subdirectories
+/// by the upload date of the works.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectorySharding {
+    /// `{user_id}/...`
+    None,
+    /// `{user_id}/{year}/...`
+    Year,
+    /// `{user_id}/{year}/{month}/...`
+    YearMonth,
+}
+
+impl Default for DirectorySharding {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// What to do with illusts having some pages failed to download.
+///
+/// The available pages are recorded either way.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, clap::ArgEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum PartialPolicy {
+    /// Download the missing pages again in the next sync of the uploads or the bookmarks
+    /// saving the illust.
+    Strict,
+    /// Keep the illust with the available pages.
+    Lenient,
+}
+
+impl Default for PartialPolicy {
+    fn default() -> Self {
+        Self::Lenient
+    }
+}
+
+/// What to do when a file to download already exists but was saved from another URL.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    /// Keep the existing file and skip the download.
+    Skip,
+    /// Download to a new file name with a numeric suffix.
+    Rename,
+    /// Replace the existing file.
+    Overwrite,
+}
+
+impl Default for CollisionPolicy {
+    fn default() -> Self {
+        Self::Skip
+    }
 }
 
 impl Default for PixivConfig {
@@ -75,8 +641,70 @@ impl Default for PixivConfig {
             proxy_api: "".to_string(),
             proxy_download: "".to_string(),
             storage_dir: "pixiv".to_string(),
+            storage_tiers: Vec::new(),
             refresh_token: "".to_string(),
             language: "en".to_string(),
+            collision_policy: CollisionPolicy::default(),
+            directory_sharding: DirectorySharding::default(),
+            tag_routes: Vec::new(),
+            prefetch_pages: true,
+            partial_policy: PartialPolicy::default(),
+            max_pages: None,
+            verify_existing: false,
+            bookmark_tags: false,
+            ugoira_formats: vec![UgoiraFormat::Mp4],
+            transcode_workers: 2,
+            transcode_queue_depth: 8,
+            derivative: DerivativeConfig::default(),
+            embedding: EmbeddingConfig::default(),
+            sidecar: None,
+        }
+    }
+}
+
+/// Where the server listens: `host:port`, `[::1]:port` or `unix:/path/to.sock`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, e.g. for a reverse proxy on the same host.
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("empty socket path".to_string()),
+            Some(path) => Ok(Self::Unix(expand_home(path))),
+            None => s
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|e| format!("invalid listen address {s}: {e}")),
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.to_string_lossy()),
         }
     }
 }
@@ -84,26 +712,138 @@ impl Default for PixivConfig {
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(default)]
 pub struct ServerConfig {
-    pub listen_addr: SocketAddr,
+    pub listen_addr: ListenAddr,
+    /// Permissions of the Unix socket in octal, e.g. `660` to let the group of a reverse proxy connect.
+    pub unix_socket_mode: String,
     pub thumbnail_jpeg_quality: u8,
+    /// Disable all the endpoints that write to the database or trigger downloads.
+    pub read_only: bool,
+    /// Bearer token for the admin endpoints. They are disabled if empty.
+    pub admin_token: String,
+    /// Server side time limit (`maxTimeMS`) of the database queries of a request.
+    /// Unrelated to the connection timeouts of MongoDB. 0 to disable.
+    pub query_timeout_millis: u64,
+    pub query_guard: QueryGuardConfig,
+    pub cache_control: CacheControlConfig,
+    /// How long the color histograms are reused, as they go through all the images. 0 to disable.
+    pub color_histogram_ttl_secs: u64,
+    /// Total size in MiB of the animated GIFs made from the ugoira zips kept in memory.
+    pub ugoira_cache_mib: u64,
+}
+
+/// What to do with a query scanning a whole collection.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryGuardMode {
+    Off,
+    /// Log it, and return at most `capped_limit` results.
+    Warn,
+    /// Fail the request.
+    Reject,
+}
+
+/// Check the plan of the filters of the API before running them.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct QueryGuardConfig {
+    pub mode: QueryGuardMode,
+    /// Smaller collections are not checked.
+    pub min_documents: u64,
+    pub capped_limit: u32,
+}
+
+impl Default for QueryGuardConfig {
+    fn default() -> Self {
+        Self {
+            mode: QueryGuardMode::Warn,
+            min_documents: 50_000,
+            capped_limit: 200,
+        }
+    }
+}
+
+/// `Cache-Control` of the successful responses of each group of endpoints.
+/// Empty to send none.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CacheControlConfig {
+    /// The stored files and the ugoira zips.
+    /// Not `immutable` by default, as `--replace` overwrites them.
+    /// The animated GIFs and the archives made from them are always revalidated instead.
+    pub media: String,
+    /// The thumbnails and the palettes.
+    pub thumbnail: String,
+    /// The results of the searches and the lists, changed by every sync.
+    pub list: String,
+    /// The version and the admin endpoints.
+    pub stats: String,
+}
+
+impl Default for CacheControlConfig {
+    fn default() -> Self {
+        Self {
+            media: "public, max-age=31536000".to_string(),
+            thumbnail: "public, max-age=604800, immutable".to_string(),
+            list: "no-cache".to_string(),
+            stats: "private, max-age=10".to_string(),
+        }
+    }
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
-            listen_addr: "127.0.0.1:5000".parse().unwrap(),
+            listen_addr: ListenAddr::Tcp("127.0.0.1:5000".parse().unwrap()),
+            unix_socket_mode: "660".to_string(),
             thumbnail_jpeg_quality: 85,
+            read_only: false,
+            admin_token: "".to_string(),
+            query_timeout_millis: 10_000,
+            query_guard: QueryGuardConfig::default(),
+            cache_control: CacheControlConfig::default(),
+            color_histogram_ttl_secs: 600,
+            ugoira_cache_mib: 256,
         }
     }
 }
 
+impl ServerConfig {
+    pub fn unix_socket_mode(&self) -> Result<u32, String> {
+        u32::from_str_radix(self.unix_socket_mode.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|m| *m <= 0o777)
+            .ok_or_else(|| format!("invalid unix_socket_mode {}", self.unix_socket_mode))
+    }
+
+    pub fn query_timeout(&self) -> Option<Duration> {
+        (self.query_timeout_millis > 0).then(|| Duration::from_millis(self.query_timeout_millis))
+    }
+}
+
+/// Replace the leading `~` of the path with the home dir.
+fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => dirs::home_dir()
+            .unwrap_or_default()
+            .join(rest.trim_start_matches(['/', '\\'])),
+        _ => PathBuf::from(path),
+    }
+}
+
 impl Config {
+    /// The defaults, to be saved to `path`.
+    pub fn default_at(path: impl AsRef<Path>) -> Config {
+        Config {
+            config_path: Some(path.as_ref().to_owned()),
+            ..Default::default()
+        }
+    }
+
     pub fn from_file(path: impl AsRef<Path>) -> crate::Result<Config> {
         let path = path.as_ref();
         if !path.exists() {
             info!("creating config file: {}", path.to_string_lossy());
-            let mut defaults = Config::default();
-            defaults.config_path = Some(path.to_owned());
+            let defaults = Config::default_at(path);
             defaults.save()?;
             Ok(defaults)
         } else {
@@ -111,11 +851,19 @@ impl Config {
             let mut config_loaded: Config =
                 serde_json::from_reader(file).context(error::ConfigJson)?;
             config_loaded.config_path = Some(PathBuf::from(path));
+            for r in &config_loaded.pixiv.tag_routes {
+                r.validate()?;
+            }
             config_loaded.save()?;
             Ok(config_loaded)
         }
     }
 
+    /// The path the config is loaded from.
+    pub fn config_path(&self) -> Option<&Path> {
+        self.config_path.as_deref()
+    }
+
     pub fn save(&self) -> crate::Result<()> {
         let path = self
             .config_path
@@ -133,37 +881,196 @@ impl Config {
         serde_json::to_writer_pretty(file, &self).context(error::ConfigJson)
     }
 
+    /// The root storage dir, with `~` expanded to the home dir.
+    ///
+    /// A relative path is resolved against the directory of the config file,
+    /// so the config works wherever it is moved with the storage.
+    pub fn root_dir(&self) -> PathBuf {
+        let root = expand_home(&self.root_storage_dir);
+        if root.is_relative() {
+            if let Some(config_dir) = self.config_path.as_deref().and_then(Path::parent) {
+                return config_dir.join(root);
+            }
+        }
+        root
+    }
+
+    /// Create the root storage dir if needed, so a wrong path fails early.
+    pub fn ensure_root_dir(&self) -> crate::Result<PathBuf> {
+        let root = self.root_dir();
+        std::fs::create_dir_all(&root).context(error::StorageRootIo {
+            path: root.to_string_lossy().to_string(),
+        })?;
+        Ok(root)
+    }
+
     pub fn sub_dir(&self, dir: impl AsRef<Path>) -> PathBuf {
         let dir = dir.as_ref();
+        let dir = expand_home(&dir.to_string_lossy());
         if dir.is_relative() {
-            PathBuf::from(&self.root_storage_dir).join(dir)
+            self.root_dir().join(dir)
         } else {
-            dir.to_owned()
+            dir
+        }
+    }
+
+    /// `pixiv.storage_dir` then `pixiv.storage_tiers`, in the order the files are searched.
+    pub fn pixiv_storage_dirs(&self) -> Vec<PathBuf> {
+        std::iter::once(&self.pixiv.storage_dir)
+            .chain(&self.pixiv.storage_tiers)
+            .map(|d| self.sub_dir(d))
+            .collect()
+    }
+
+    /// Use `url` as the proxy for all requests of this run.
+    /// The override is not saved to the config file.
+    pub fn set_proxy_override(&mut self, url: &str) -> crate::Result<()> {
+        let parsed = url::Url::parse(url).map_err(|_| {
+            error::ProxyInvalid {
+                message: format!("cannot parse proxy url: {url}"),
+            }
+            .build()
+        })?;
+        match parsed.scheme() {
+            "http" | "https" | "socks5" | "socks5h" => {}
+            scheme => {
+                return error::ProxyInvalid {
+                    message: format!("unsupported proxy scheme: {scheme}"),
+                }
+                .fail()
+            }
         }
+        reqwest::Proxy::all(url).context(error::ProxyParse)?;
+        self.proxy_override = Some(url.to_string());
+        Ok(())
+    }
+
+    /// Use these programs for this run, e.g. to try another build.
+    /// The overrides are not saved to the config file.
+    ///
+    /// A path to a file must exist. A bare name is searched in `PATH` when started.
+    pub fn set_program_overrides(
+        &mut self,
+        ffmpeg: Option<&str>,
+        aria2: Option<&str>,
+    ) -> crate::Result<()> {
+        for (name, path) in [("ffmpeg", ffmpeg), ("aria2", aria2)] {
+            if let Some(path) = path {
+                if path.contains(std::path::is_separator) && !expand_home(path).is_file() {
+                    return error::ProgramPathInvalid { name, path }.fail();
+                }
+            }
+        }
+        self.ffmpeg_path_override = ffmpeg.map(|p| expand_home(p).to_string_lossy().to_string());
+        self.aria2_path_override = aria2.map(|p| expand_home(p).to_string_lossy().to_string());
+        Ok(())
+    }
+
+    /// Empty for `ffmpeg` in `PATH`.
+    pub fn ffmpeg_path(&self) -> &str {
+        self.ffmpeg_path_override
+            .as_deref()
+            .unwrap_or(&self.ffmpeg_path)
+    }
+
+    pub fn aria2_path(&self) -> &str {
+        self.aria2_path_override
+            .as_deref()
+            .unwrap_or(&self.aria2_path)
     }
 
     pub fn pxoxy(&self, url: &str) -> crate::Result<Option<reqwest::Proxy>> {
-        use reqwest::Proxy;
-        if !url.is_empty() {
-            Ok(Some(Proxy::all(url).context(error::ProxyParse)?))
-        } else if !self.proxy_all.is_empty() {
-            Ok(Some(
-                Proxy::all(&self.proxy_all).context(error::ProxyParse)?,
-            ))
-        } else {
-            Ok(None)
+        // Credentials in the url are used by reqwest as basic auth.
+        match self.pxoxy_string(url) {
+            Some(proxy) => Ok(Some(
+                reqwest::Proxy::all(&proxy).context(error::ProxyParse)?,
+            )),
+            None => Ok(None),
         }
     }
 
+    /// Get the proxy url with the credentials in the config filled in.
     pub fn pxoxy_string(&self, url: &str) -> Option<String> {
-        if url.is_empty() {
+        let proxy = if let Some(ref proxy) = self.proxy_override {
+            proxy.clone()
+        } else if url.is_empty() {
             if self.proxy_all.is_empty() {
-                None
+                return None;
             } else {
-                Some(self.proxy_all.clone())
+                self.proxy_all.clone()
             }
         } else {
-            Some(self.proxy_all.clone())
+            url.to_string()
+        };
+        Some(self.with_proxy_credentials(proxy))
+    }
+
+    fn with_proxy_credentials(&self, proxy: String) -> String {
+        if self.proxy_username.is_empty() {
+            return proxy;
+        }
+        match url::Url::parse(&proxy) {
+            Ok(mut parsed) if parsed.username().is_empty() => {
+                if parsed.set_username(&self.proxy_username).is_err()
+                    || parsed
+                        .set_password(Some(self.proxy_password.as_str()).filter(|p| !p.is_empty()))
+                        .is_err()
+                {
+                    return proxy;
+                }
+                parsed.to_string()
+            }
+            _ => proxy,
         }
     }
 }
+
+const INVALID_PROXY: &str = "<invalid proxy url>";
+
+/// Hide the password in the proxy url for logging.
+pub fn redact_proxy(proxy: &str) -> String {
+    match url::Url::parse(proxy) {
+        Ok(mut parsed) => {
+            if parsed.password().is_some() {
+                let _ = parsed.set_password(Some("***"));
+            }
+            parsed.to_string()
+        }
+        Err(_) => INVALID_PROXY.to_string(),
+    }
+}
+
+/// The password in the userinfo of a MongoDB connection string, if any.
+fn mongodb_password(uri: &str) -> Option<&str> {
+    let rest = &uri[uri.find("://")? + 3..];
+    let authority = &rest[..rest.find(['/', '?']).unwrap_or(rest.len())];
+    let userinfo = &authority[..authority.rfind('@')?];
+    let password = &userinfo[userinfo.find(':')? + 1..];
+    (!password.is_empty()).then(|| password)
+}
+
+/// Hide the password in `text`, e.g. an error message, taken from the MongoDB connection string.
+pub fn redact_mongodb_password(text: &str, uri: &str) -> String {
+    match mongodb_password(uri) {
+        Some(password) => text.replace(password, "***"),
+        None => text.to_string(),
+    }
+}
+
+/// Hide the password in the MongoDB connection string for logging.
+pub fn redact_mongodb_uri(uri: &str) -> String {
+    redact_mongodb_password(uri, uri)
+}
+
+/// Keep only the scheme, host and port of the proxy url for logging.
+pub fn proxy_host(proxy: &str) -> String {
+    match url::Url::parse(proxy) {
+        Ok(parsed) => format!(
+            "{}://{}{}",
+            parsed.scheme(),
+            parsed.host_str().unwrap_or_default(),
+            parsed.port().map_or_else(String::new, |p| format!(":{p}"))
+        ),
+        Err(_) => INVALID_PROXY.to_string(),
+    }
+}
@@ -1,36 +1,67 @@
 use aria2_ws::Client;
 use futures::{future::BoxFuture, FutureExt};
 use log::{debug, warn};
+use reqwest::Method;
 use snafu::ResultExt;
-use std::time::{Duration, Instant};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
     process::{Child, Command},
+    sync::{Mutex, OnceCell},
     time::timeout,
 };
+use tokio_util::sync::CancellationToken;
 
-use super::Task;
+use super::{
+    CircuitBreaker, Downloader, ProgressEvent, ProgressWriter, QueueState, Task, Ticket,
+    HOOK_GRACE_PERIOD,
+};
 use crate::{
-    error,
-    utils::{get_available_port, WaitGroup},
+    config::{CircuitBreakerConfig, NetworkConfig},
+    error::{self, BoxError},
+    utils::{flush_throttled, get_available_port, pace, warn_throttled, WaitGroup},
 };
 
 pub use reqwest::header::HeaderMap;
 
 pub struct Aria2Downloader {
+    aria2_path: String,
+    /// Passed to aria2 every time it is started.
+    aria2_args: Vec<String>,
+    /// `None` if aria2 is not started or shut down for being idle.
+    aria2: Arc<Mutex<Option<Aria2Process>>>,
+    last_active: Arc<StdMutex<Instant>>,
+    waitgroup: WaitGroup,
+    /// The hooks running, waited for after cancelling.
+    hooks_running: WaitGroup,
+    progress: Option<ProgressWriter>,
+    cancel: CancellationToken,
+    breaker: Arc<CircuitBreaker>,
+    finished: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+}
+
+/// A spawned aria2 and the RPC connection to it.
+struct Aria2Process {
     client: Client,
     child: Child,
-    waitgroup: WaitGroup,
 }
 
-impl Drop for Aria2Downloader {
+impl Drop for Aria2Process {
     fn drop(&mut self) {
         let r = self.child.start_kill();
         debug!("tried to kill aria2: {:?}", r);
     }
 }
 
-impl Aria2Downloader {
-    pub async fn new(aria2_path: &str) -> crate::Result<Self> {
+impl Aria2Process {
+    async fn spawn(aria2_path: &str, extra_args: &[String]) -> crate::Result<Self> {
         let token = "bowerbird";
         let ra = 30311..30400;
         let port = get_available_port(ra.clone()).ok_or(
@@ -43,12 +74,23 @@ impl Aria2Downloader {
             .args(&[
                 "--no-conf",
                 "--auto-file-renaming=false",
+                // Existing files are checked before adding tasks.
+                "--allow-overwrite=true",
+                // Restart from the beginning instead of appending to the partial file
+                // if the server does not support ranged requests.
+                "--always-resume=false",
+                "--max-resume-failure-tries=0",
                 "--enable-rpc",
                 "--rpc-listen-port",
                 &port.to_string(),
                 "--rpc-secret",
                 token,
+                // Do not outlive bowerbird if it crashes.
+                "--stop-with-process",
+                &std::process::id().to_string(),
             ])
+            .args(extra_args)
+            .kill_on_drop(true)
             .spawn()
             .context(error::Aria2StartUpIo)?;
         match timeout(Duration::from_millis(100), child.wait()).await {
@@ -63,47 +105,378 @@ impl Aria2Downloader {
         let client = Client::connect(&format!("ws://127.0.0.1:{port}/jsonrpc"), Some(token))
             .await
             .context(error::Aria2)?;
+        debug!("aria2 started on port {}", port);
+        Ok(Self { client, child })
+    }
+
+    /// Ask aria2 to exit, and kill it if it does not in time.
+    async fn shutdown(&mut self) {
+        let r = timeout(Duration::from_secs(1), self.client.force_shutdown()).await;
+        debug!("tried to force shutdown aria2: {:?}", r);
+        match timeout(Duration::from_secs(5), self.child.wait()).await {
+            Ok(r) => debug!("aria2 exited: {:?}", r),
+            Err(_) => {
+                let r = self.child.kill().await;
+                debug!("aria2 did not exit in time, killed: {:?}", r);
+            }
+        }
+    }
+}
+
+impl Downloader for Aria2Downloader {
+    fn add_task(&self, task: Task) -> BoxFuture<'_, crate::Result<()>> {
+        Aria2Downloader::add_task(self, task).boxed()
+    }
+
+    fn wait_shutdown(&self) -> BoxFuture<'_, ()> {
+        Aria2Downloader::wait_shutdown(self).boxed()
+    }
+
+    fn failed_tasks(&self) -> usize {
+        Aria2Downloader::failed_tasks(self)
+    }
+
+    fn queue_state(&self) -> BoxFuture<'_, crate::Result<QueueState>> {
+        Aria2Downloader::queue_state(self).boxed()
+    }
+}
+
+/// Make sure the size of the downloaded file matches the size reported by aria2.
+///
+/// The file is removed on mismatch, so it will be downloaded again next time.
+async fn verify_size(client: &Client, gid: &OnceCell<String>, path: &Path) -> Result<(), BoxError> {
+    let gid = match gid.get() {
+        Some(gid) => gid,
+        None => return Ok(()),
+    };
+    let expected = client.tell_status(gid).await?.total_length;
+    if expected == 0 {
+        // The size is unknown.
+        return Ok(());
+    }
+    let actual = tokio::fs::metadata(path).await?.len();
+    if actual != expected {
+        let _ = tokio::fs::remove_file(path).await;
+        return Err(error::FileSizeMismatch {
+            path: path.to_string_lossy().to_string(),
+            expected,
+            actual,
+        }
+        .build()
+        .into());
+    }
+    Ok(())
+}
+
+/// Build the error of a failed download with the reason from aria2.
+async fn download_error(
+    client: &Client,
+    gid: &OnceCell<String>,
+    url: &str,
+    path: Option<&Path>,
+) -> error::Error {
+    let (code, message) = match gid.get() {
+        Some(gid) => match client.tell_status(gid).await {
+            Ok(status) => (
+                status.error_code.unwrap_or_default(),
+                status.error_message.unwrap_or_default(),
+            ),
+            Err(err) => (
+                String::new(),
+                format!("cannot get status from aria2: {err}"),
+            ),
+        },
+        None => (String::new(), "task is not added to aria2".to_string()),
+    };
+    error::Aria2Download {
+        url,
+        path: path.map_or_else(String::new, |p| p.to_string_lossy().to_string()),
+        gid: gid.get().cloned().unwrap_or_default(),
+        code,
+        message,
+    }
+    .build()
+}
+
+impl Aria2Downloader {
+    pub async fn new(aria2_path: &str, network: &NetworkConfig) -> crate::Result<Self> {
+        let aria2_args = network.aria2_args();
+        let aria2 = Aria2Process::spawn(aria2_path, &aria2_args).await?;
         Ok(Self {
-            client,
-            child,
+            aria2_path: aria2_path.to_string(),
+            aria2_args,
+            aria2: Arc::new(Mutex::new(Some(aria2))),
+            last_active: Arc::new(StdMutex::new(Instant::now())),
             waitgroup: WaitGroup::new(),
+            hooks_running: WaitGroup::new(),
+            progress: None,
+            cancel: CancellationToken::new(),
+            breaker: Arc::new(CircuitBreaker::new(CircuitBreakerConfig::default())),
+            finished: Arc::new(AtomicUsize::new(0)),
+            failed: Arc::new(AtomicUsize::new(0)),
         })
     }
 
-    fn map_hook(&self, hook: Option<super::BoxFutureResult>) -> BoxFuture<'static, ()> {
+    /// Shut down aria2 when no task is running for `idle`.
+    ///
+    /// It is started again by the next task, so a long-living downloader does not keep it running.
+    pub fn with_idle_timeout(self, idle: Duration) -> Self {
+        let aria2 = Arc::downgrade(&self.aria2);
+        let last_active = self.last_active.clone();
+        let waitgroup = self.waitgroup.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep((idle / 2).max(Duration::from_secs(1))).await;
+                let aria2 = match aria2.upgrade() {
+                    Some(aria2) => aria2,
+                    None => break,
+                };
+                let mut aria2 = aria2.lock().await;
+                // Tasks are added with the lock held, so none can start while shutting down.
+                let idle_for = last_active.lock().unwrap().elapsed();
+                if waitgroup.pending() == 0 && idle_for >= idle {
+                    if let Some(mut process) = aria2.take() {
+                        debug!("aria2 is idle for {:?}, shutting down", idle_for);
+                        process.shutdown().await;
+                    }
+                }
+            }
+        });
+        self
+    }
+
+    /// Stop waiting for the tasks and shut down aria2 when `cancel` is cancelled.
+    ///
+    /// The unfinished downloads are resumed next time with their `.aria2` control files.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Pause adding tasks when most of the recent tasks fail.
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker = Arc::new(CircuitBreaker::new(config));
+        self
+    }
+
+    /// Report the progress of every task to `progress`.
+    pub fn with_progress(mut self, progress: ProgressWriter) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn map_hook(
+        &self,
+        client: Client,
+        hook: Option<super::BoxFutureResult>,
+        succeeded: bool,
+        url: String,
+        path: Option<PathBuf>,
+        gid: Arc<OnceCell<String>>,
+        ticket: Ticket,
+    ) -> BoxFuture<'static, ()> {
         let waitgroup = self.waitgroup.clone();
-        if let Some(hook) = hook {
-            async move {
+        let hooks_running = self.hooks_running.clone();
+        let progress = self.progress.clone();
+        let breaker = self.breaker.clone();
+        let last_active = self.last_active.clone();
+        let finished = self.finished.clone();
+        let failed = self.failed.clone();
+        async move {
+            hooks_running.add(1);
+            breaker.record(ticket, succeeded);
+            let mut hook_error = None;
+            if !succeeded {
+                let err = download_error(&client, &gid, &url, path.as_deref()).await;
+                warn_throttled("download failed", &err);
+                hook_error = Some(err.to_string());
+            }
+            if let Some(hook) = hook {
                 let i = Instant::now();
                 if let Err(err) = hook.await {
-                    warn!("error on hook: {}", err);
+                    warn_throttled("error on hook", format!("error on hook: {}", err));
+                    hook_error.get_or_insert(err.to_string());
                 }
                 debug!("hook took {:?}", i.elapsed());
-                waitgroup.done();
             }
-            .boxed()
-        } else {
-            async move { waitgroup.done() }.boxed()
+            if succeeded && hook_error.is_none() {
+                finished.fetch_add(1, Ordering::Relaxed);
+            } else {
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+            if let Some(progress) = progress {
+                let path = path.as_deref();
+                if succeeded && hook_error.is_none() {
+                    let bytes = match path {
+                        Some(path) => tokio::fs::metadata(path).await.ok().map(|m| m.len()),
+                        None => None,
+                    };
+                    progress.emit(&ProgressEvent::TaskCompleted {
+                        url: &url,
+                        path,
+                        bytes,
+                    });
+                } else {
+                    progress.emit(&ProgressEvent::TaskFailed {
+                        url: &url,
+                        path,
+                        error: hook_error,
+                    });
+                }
+            }
+            *last_active.lock().unwrap() = Instant::now();
+            hooks_running.done();
+            waitgroup.done();
         }
+        .boxed()
     }
 
     pub async fn add_task(&self, task: Task) -> crate::Result<()> {
-        let hooks = task.hooks.map(|hooks| aria2_ws::TaskHooks {
-            on_complete: Some(self.map_hook(hooks.on_success)),
-            on_error: Some(self.map_hook(hooks.on_error)),
+        if task.method != Method::GET || task.body.is_some() {
+            return error::Aria2UnsupportedRequest {
+                message: format!(
+                    "{} {} with body: {}",
+                    task.method,
+                    task.url,
+                    task.body.is_some()
+                ),
+            }
+            .fail();
+        }
+        let ticket = tokio::select! {
+            ticket = self.breaker.acquire() => ticket,
+            _ = self.cancel.cancelled() => return Ok(()),
+        };
+        pace().await;
+        let path = task.options.as_ref().and_then(|o| match (&o.dir, &o.out) {
+            (Some(dir), Some(out)) => Some(PathBuf::from(dir).join(out)),
+            _ => None,
         });
-        self.client
-            .add_uri(vec![task.url], task.options, None, hooks)
+        let mut aria2 = self.aria2.lock().await;
+        let client = match &mut *aria2 {
+            Some(process) => process.client.clone(),
+            None => {
+                let process = Aria2Process::spawn(&self.aria2_path, &self.aria2_args).await?;
+                aria2.insert(process).client.clone()
+            }
+        };
+        self.touch();
+        let gid = Arc::new(OnceCell::new());
+        let hooks = task.hooks.unwrap_or_default();
+        let on_success = match path {
+            Some(ref path) => {
+                let client = client.clone();
+                let gid = gid.clone();
+                let path = path.clone();
+                let hook = hooks.on_success;
+                Some(
+                    async move {
+                        verify_size(&client, &gid, &path).await?;
+                        if let Some(hook) = hook {
+                            hook.await?;
+                        }
+                        Ok::<(), BoxError>(())
+                    }
+                    .boxed(),
+                )
+            }
+            None => hooks.on_success,
+        };
+        let hooks = aria2_ws::TaskHooks {
+            on_complete: Some(self.map_hook(
+                client.clone(),
+                on_success,
+                true,
+                task.url.clone(),
+                path.clone(),
+                gid.clone(),
+                ticket,
+            )),
+            on_error: Some(self.map_hook(
+                client.clone(),
+                hooks.on_error,
+                false,
+                task.url.clone(),
+                path.clone(),
+                gid.clone(),
+                ticket,
+            )),
+        };
+        if let Some(ref progress) = self.progress {
+            progress.emit(&ProgressEvent::TaskStarted {
+                url: &task.url,
+                path: path.as_deref(),
+            });
+        }
+        let r = client
+            .add_uri(vec![task.url], task.options, None, Some(hooks))
             .await
             .context(error::Aria2)?;
+        let _ = gid.set(r);
         self.waitgroup.add(1);
+        drop(aria2);
         Ok(())
     }
 
-    pub async fn wait_shutdown(self) {
-        self.waitgroup.clone().await;
-        // let r = self.client.force_shutdown().await;
-        // debug!("tried to force shutdown aria2: {:?}", r);
-        tokio::time::sleep(Duration::from_millis(100)).await;
+    /// Wait for all the tasks, or until cancelled, then shut down aria2.
+    ///
+    /// Once cancelled, the hooks already running are waited for up to [`HOOK_GRACE_PERIOD`].
+    pub async fn wait_shutdown(&self) {
+        tokio::select! {
+            _ = self.waitgroup.clone() => {}
+            _ = self.cancel.cancelled() => {
+                debug!("cancelled, waiting for the running hooks");
+                if timeout(HOOK_GRACE_PERIOD, self.hooks_running.clone()).await.is_err() {
+                    warn!("hooks still running after {:?}, shutting down", HOOK_GRACE_PERIOD);
+                }
+                debug!("cancelled, shutting down aria2");
+            }
+        }
+        flush_throttled();
+        if let Some(ref progress) = self.progress {
+            progress.emit(&ProgressEvent::Finished);
+        }
+        self.shutdown().await;
+    }
+
+    /// Number of the tasks failed so far, including those failed in the hooks.
+    pub fn failed_tasks(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// The counts of the tasks, and the urls being downloaded by aria2.
+    pub async fn queue_state(&self) -> crate::Result<QueueState> {
+        let client = self.aria2.lock().await.as_ref().map(|p| p.client.clone());
+        let active = match client {
+            Some(client) => client.tell_active().await.context(error::Aria2)?,
+            // Shut down for being idle.
+            None => Vec::new(),
+        };
+        let running = active.len() + self.hooks_running.pending();
+        Ok(QueueState {
+            // Waiting in aria2.
+            pending: self.waitgroup.pending().saturating_sub(running),
+            running,
+            finished: self.finished.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            active: active
+                .into_iter()
+                .filter_map(|s| s.files.into_iter().flat_map(|f| f.uris).next())
+                .map(|u| u.uri)
+                .collect(),
+        })
+    }
+
+    /// Shut down aria2 and wait for it to exit. The unfinished tasks are stopped.
+    ///
+    /// aria2 is started again if more tasks are added.
+    pub async fn shutdown(&self) {
+        if let Some(mut process) = self.aria2.lock().await.take() {
+            process.shutdown().await;
+        }
     }
 }
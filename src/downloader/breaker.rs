@@ -0,0 +1,207 @@
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::CircuitBreakerConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed,
+    Open {
+        until: Instant,
+    },
+    /// One task is let through to test if the downloads recover.
+    HalfOpen {
+        probe: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    recent: VecDeque<bool>,
+    next_probe: u64,
+}
+
+/// Given by [`CircuitBreaker::acquire`] and passed back to [`CircuitBreaker::record`],
+/// so only the probe decides if the half-open breaker closes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ticket {
+    probe: Option<u64>,
+}
+
+/// Pause new tasks when most of the recent tasks fail, e.g. during an outage of pixiv.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                recent: VecDeque::new(),
+                next_probe: 0,
+            }),
+        }
+    }
+
+    /// Wait until a new task is allowed.
+    pub async fn acquire(&self) -> Ticket {
+        if !self.config.enabled {
+            return Ticket::default();
+        }
+        loop {
+            let wait = {
+                let mut inner = self.inner.lock().unwrap();
+                match inner.state {
+                    State::Closed => return Ticket::default(),
+                    State::Open { until } => {
+                        let now = Instant::now();
+                        if now >= until {
+                            info!("circuit breaker half-open, testing with one task");
+                            let probe = inner.next_probe;
+                            inner.next_probe += 1;
+                            inner.state = State::HalfOpen { probe };
+                            return Ticket { probe: Some(probe) };
+                        }
+                        until - now
+                    }
+                    State::HalfOpen { .. } => Duration::from_secs(1),
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Record the result of a finished task, with the ticket it is allowed with.
+    pub fn record(&self, ticket: Ticket, succeeded: bool) {
+        if !self.config.enabled {
+            return;
+        }
+        let cooldown = Duration::from_secs(self.config.cooldown_secs);
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed => {
+                inner.recent.push_back(succeeded);
+                while inner.recent.len() > self.config.window {
+                    inner.recent.pop_front();
+                }
+                let failed = inner.recent.iter().filter(|s| !**s).count();
+                if inner.recent.len() >= self.config.window
+                    && failed * 100 >= self.config.error_percent as usize * inner.recent.len()
+                {
+                    warn!(
+                        "circuit breaker tripped: {} of the last {} tasks failed, pausing for {:?}",
+                        failed,
+                        inner.recent.len(),
+                        cooldown
+                    );
+                    inner.recent.clear();
+                    inner.state = State::Open {
+                        until: Instant::now() + cooldown,
+                    };
+                }
+            }
+            // Decided by the probe, not the tasks added before tripping.
+            State::HalfOpen { probe } if ticket.probe == Some(probe) => {
+                if succeeded {
+                    info!("circuit breaker closed, downloads recovered");
+                    inner.state = State::Closed;
+                } else {
+                    warn!("circuit breaker tripped again, pausing for {:?}", cooldown);
+                    inner.state = State::Open {
+                        until: Instant::now() + cooldown,
+                    };
+                }
+            }
+            // Tasks added before tripping.
+            State::HalfOpen { .. } | State::Open { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cooldown_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            enabled: true,
+            window: 4,
+            error_percent: 50,
+            cooldown_secs,
+        })
+    }
+
+    fn state(breaker: &CircuitBreaker) -> State {
+        breaker.inner.lock().unwrap().state
+    }
+
+    async fn acquired(breaker: &CircuitBreaker) -> Option<Ticket> {
+        tokio::time::timeout(Duration::from_millis(50), breaker.acquire())
+            .await
+            .ok()
+    }
+
+    #[tokio::test]
+    async fn transitions() {
+        let breaker = breaker(0);
+        let closed = Ticket::default();
+        for succeeded in [true, false, true] {
+            breaker.record(closed, succeeded);
+        }
+        // Not until the window is full.
+        assert_eq!(state(&breaker), State::Closed);
+        breaker.record(closed, false);
+        assert!(matches!(state(&breaker), State::Open { .. }));
+        // Finished after tripping.
+        breaker.record(closed, true);
+        assert!(matches!(state(&breaker), State::Open { .. }));
+
+        // The cooldown is over, one task is let through.
+        let probe = acquired(&breaker).await.unwrap();
+        assert_eq!(state(&breaker), State::HalfOpen { probe: 0 });
+        assert!(acquired(&breaker).await.is_none());
+        // Added before tripping, the probe decides.
+        breaker.record(closed, true);
+        assert_eq!(state(&breaker), State::HalfOpen { probe: 0 });
+        breaker.record(probe, false);
+        assert!(matches!(state(&breaker), State::Open { .. }));
+
+        let probe = acquired(&breaker).await.unwrap();
+        assert_eq!(state(&breaker), State::HalfOpen { probe: 1 });
+        breaker.record(closed, false);
+        breaker.record(probe, true);
+        assert_eq!(state(&breaker), State::Closed);
+        assert!(breaker.inner.lock().unwrap().recent.is_empty());
+        assert_eq!(acquired(&breaker).await, Some(closed));
+    }
+
+    #[tokio::test]
+    async fn open_waits_for_cooldown() {
+        let breaker = breaker(60);
+        for _ in 0..4 {
+            breaker.record(Ticket::default(), false);
+        }
+        assert!(acquired(&breaker).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            enabled: false,
+            window: 1,
+            ..Default::default()
+        });
+        breaker.record(Ticket::default(), false);
+        assert_eq!(state(&breaker), State::Closed);
+        assert!(acquired(&breaker).await.is_some());
+    }
+}
@@ -0,0 +1,159 @@
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use super::{Downloader, QueueState, Task};
+
+/// A task added to a [`MemoryDownloader`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedTask {
+    pub url: String,
+    /// `dir` joined with `out` of the options, if both are set.
+    pub path: Option<PathBuf>,
+}
+
+/// Records the tasks instead of downloading them, for testing the commands without aria2.
+///
+/// The tasks succeed unless their url is set to fail, and their hooks run before `add_task`
+/// returns. Nothing is written, so the hooks reading the files fail.
+#[derive(Debug, Default)]
+pub struct MemoryDownloader {
+    tasks: Mutex<Vec<RecordedTask>>,
+    failing_urls: HashSet<String>,
+    failed: AtomicUsize,
+}
+
+impl MemoryDownloader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail the tasks of this url, running their `on_error`.
+    pub fn with_failing_url(mut self, url: impl Into<String>) -> Self {
+        self.failing_urls.insert(url.into());
+        self
+    }
+
+    /// The tasks added so far, in order.
+    pub fn tasks(&self) -> Vec<RecordedTask> {
+        self.tasks.lock().unwrap().clone()
+    }
+}
+
+impl Downloader for MemoryDownloader {
+    fn add_task(&self, task: Task) -> BoxFuture<'_, crate::Result<()>> {
+        async move {
+            let path = task.options.as_ref().and_then(|o| match (&o.dir, &o.out) {
+                (Some(dir), Some(out)) => Some(PathBuf::from(dir).join(out)),
+                _ => None,
+            });
+            self.tasks.lock().unwrap().push(RecordedTask {
+                url: task.url.clone(),
+                path,
+            });
+            let hooks = task.hooks.unwrap_or_default();
+            let succeeded = !self.failing_urls.contains(&task.url);
+            let hook = if succeeded {
+                hooks.on_success
+            } else {
+                hooks.on_error
+            };
+            let hook_ok = match hook {
+                Some(hook) => hook.await.is_ok(),
+                None => true,
+            };
+            if !succeeded || !hook_ok {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn wait_shutdown(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn failed_tasks(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// The tasks are finished once added.
+    fn queue_state(&self) -> BoxFuture<'_, crate::Result<QueueState>> {
+        let failed = self.failed_tasks();
+        let finished = self.tasks.lock().unwrap().len() - failed;
+        async move {
+            Ok(QueueState {
+                finished,
+                failed,
+                ..Default::default()
+            })
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{downloader::TaskHooks, error::BoxError};
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    fn task(url: &str, ran: &Arc<AtomicBool>, fail_hook: bool) -> Task {
+        let ran = ran.clone();
+        Task {
+            url: url.to_string(),
+            hooks: Some(TaskHooks {
+                on_success: Some(
+                    async move {
+                        ran.store(true, Ordering::SeqCst);
+                        if fail_hook {
+                            return Err::<(), BoxError>("hook failed".into());
+                        }
+                        Ok(())
+                    }
+                    .boxed(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_and_failures() {
+        let downloader = MemoryDownloader::new().with_failing_url("https://a/fail");
+        let ok = Arc::new(AtomicBool::new(false));
+        let fail = Arc::new(AtomicBool::new(false));
+        let hook_fail = Arc::new(AtomicBool::new(false));
+        downloader
+            .add_task(task("https://a/ok", &ok, false))
+            .await
+            .unwrap();
+        downloader
+            .add_task(task("https://a/fail", &fail, false))
+            .await
+            .unwrap();
+        downloader
+            .add_task(task("https://a/hook", &hook_fail, true))
+            .await
+            .unwrap();
+
+        assert!(ok.load(Ordering::SeqCst));
+        assert!(!fail.load(Ordering::SeqCst));
+        assert!(hook_fail.load(Ordering::SeqCst));
+        assert_eq!(downloader.failed_tasks(), 2);
+        let state = downloader.queue_state().await.unwrap();
+        assert_eq!((state.pending, state.finished, state.failed), (0, 1, 2));
+        let tasks = downloader.tasks();
+        assert!(tasks.iter().all(|t| t.path.is_none()));
+        let urls: Vec<_> = tasks.into_iter().map(|t| t.url).collect();
+        assert_eq!(urls, ["https://a/ok", "https://a/fail", "https://a/hook"]);
+    }
+}
@@ -1,17 +1,132 @@
+use bytes::Bytes;
 use futures::future::BoxFuture;
+use reqwest::Method;
+use serde::Serialize;
+use std::time::Duration;
 
 use crate::error::BoxError;
 
 pub use aria2::Aria2Downloader;
+pub use breaker::{CircuitBreaker, Ticket};
+#[cfg(test)]
+pub use memory::MemoryDownloader;
+pub use native::{NativeDownloader, RequestBuilderFn};
+pub use pipeline::Pipeline;
+pub use progress::{ProgressEvent, ProgressWriter};
+pub use resolver::DnsResolver;
+pub use tracker::ProgressTracker;
+pub(crate) use tracker::TaskCounters;
 
 mod aria2;
+mod breaker;
+#[cfg(test)]
+mod memory;
+mod native;
+mod pipeline;
+mod progress;
+mod resolver;
+mod tracker;
 
+/// How long the hooks already running are waited for after cancelling,
+/// so the downloaded files are saved to the database.
+pub(crate) const HOOK_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Runs the download tasks and their hooks.
+pub trait Downloader: Send + Sync {
+    /// Queue the task, returning once it is accepted. The hooks run when it is finished.
+    fn add_task(&self, task: Task) -> BoxFuture<'_, crate::Result<()>>;
+    /// Wait for all the tasks and their hooks, or until cancelled.
+    ///
+    /// Once cancelled, the hooks already running are waited for up to [`HOOK_GRACE_PERIOD`].
+    fn wait_shutdown(&self) -> BoxFuture<'_, ()>;
+    /// Number of the tasks failed so far, including those failed in the hooks.
+    fn failed_tasks(&self) -> usize;
+    /// The progress of the running and the last finished tasks, in order.
+    /// Empty if it is not tracked.
+    fn progress(&self) -> Vec<TaskProgress> {
+        Vec::new()
+    }
+    /// The counts of the tasks and the urls being downloaded.
+    fn queue_state(&self) -> BoxFuture<'_, crate::Result<QueueState>>;
+}
+
+/// A snapshot of the tasks of a downloader.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct QueueState {
+    /// Added but not started yet.
+    pub pending: usize,
+    /// Downloading, or running the hooks.
+    pub running: usize,
+    /// Completed since the downloader is created.
+    pub finished: usize,
+    /// Failed since the downloader is created, including those failed in the hooks.
+    pub failed: usize,
+    /// The urls being downloaded.
+    pub active: Vec<String>,
+}
+
+/// A snapshot of the progress of a task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskProgress {
+    /// Numbered from 0 in the order the tasks are added.
+    pub id: u64,
+    pub url: String,
+    /// Bytes written so far.
+    pub downloaded: u64,
+    /// `None` until the server reports the size, or if it does not.
+    pub total: Option<u64>,
+    pub status: TaskStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// Downloading, or running the hooks.
+    Running,
+    Completed,
+    /// The download or the hooks failed.
+    Failed,
+}
+
+#[derive(Default)]
 pub struct Task {
     pub url: String,
+    /// Only `GET` is supported by aria2.
+    pub method: Method,
+    pub body: Option<Bytes>,
     pub options: Option<aria2_ws::TaskOptions>,
     pub hooks: Option<TaskHooks>,
 }
 
+impl Task {
+    /// Build a task sending `body` with `POST`.
+    ///
+    /// Non-idempotent requests are never resumed or retried partially.
+    pub fn post(
+        url: impl Into<String>,
+        body: impl Into<Bytes>,
+        options: Option<aria2_ws::TaskOptions>,
+        hooks: Option<TaskHooks>,
+    ) -> Self {
+        Self {
+            url: url.into(),
+            method: Method::POST,
+            body: Some(body.into()),
+            options,
+            hooks,
+        }
+    }
+
+    /// Whether the request can be safely sent again,
+    /// e.g. to resolve the file name or to resume the download.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self.method,
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+        )
+    }
+}
+
 pub type BoxFutureResult = BoxFuture<'static, Result<(), BoxError>>;
 #[derive(Default)]
 pub struct TaskHooks {
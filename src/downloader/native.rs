@@ -0,0 +1,645 @@
+use futures::{future::BoxFuture, Future, FutureExt};
+use log::{debug, warn};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use snafu::ResultExt;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Semaphore, time::timeout};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    CircuitBreaker, Downloader, ProgressEvent, ProgressTracker, ProgressWriter, QueueState, Task,
+    TaskCounters, TaskHooks, TaskProgress, TaskStatus, HOOK_GRACE_PERIOD,
+};
+use crate::{
+    config::CircuitBreakerConfig,
+    error,
+    utils::{flush_throttled, pace, warn_throttled, WaitGroup},
+};
+
+/// Changes every request before sending, e.g. to set the headers needed by the server.
+pub type RequestBuilderFn = Arc<dyn Fn(RequestBuilder) -> RequestBuilder + Send + Sync>;
+
+/// Restarts the downloads receiving nothing for `stall`, e.g. stuck on a dead connection.
+///
+/// Unlike a timeout of the whole download, slow downloads are never restarted.
+#[derive(Debug, Clone, Copy)]
+struct Watchdog {
+    stall: Duration,
+    /// Fail the download after this number of restarts.
+    max_restarts: u32,
+}
+
+/// Retries the downloads failed for transient errors, e.g. reset connections and `5xx`.
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+    retries: u32,
+    /// Doubled after every retry.
+    backoff: Duration,
+}
+
+/// Downloads the files with reqwest, so aria2 is not needed.
+///
+/// Of the aria2 options of the tasks, only `dir` and `out` are used.
+/// The headers and the proxy are set with the request builder and the client instead.
+pub struct NativeDownloader {
+    client: Client,
+    request_builder: RequestBuilderFn,
+    /// Limits the downloads running at the same time.
+    slots: Arc<Semaphore>,
+    waitgroup: WaitGroup,
+    /// The hooks running, waited for after cancelling.
+    hooks_running: WaitGroup,
+    progress: Option<ProgressWriter>,
+    cancel: CancellationToken,
+    breaker: Arc<CircuitBreaker>,
+    /// The tasks waiting for a slot in `add_task`.
+    pending: AtomicUsize,
+    finished: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+    tracker: ProgressTracker,
+    watchdog: Option<Watchdog>,
+    retry: Option<Retry>,
+}
+
+/// Counts a task waiting for a slot until dropped, including when `add_task` is dropped.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(pending: &'a AtomicUsize) -> Self {
+        pending.fetch_add(1, Ordering::Relaxed);
+        Self(pending)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl NativeDownloader {
+    /// `0` concurrency for one download at a time.
+    pub fn new(client: Client, concurrency: usize) -> Self {
+        Self {
+            client,
+            request_builder: Arc::new(|builder| builder),
+            slots: Arc::new(Semaphore::new(concurrency.max(1))),
+            waitgroup: WaitGroup::new(),
+            hooks_running: WaitGroup::new(),
+            progress: None,
+            cancel: CancellationToken::new(),
+            breaker: Arc::new(CircuitBreaker::new(CircuitBreakerConfig::default())),
+            pending: AtomicUsize::new(0),
+            finished: Arc::new(AtomicUsize::new(0)),
+            failed: Arc::new(AtomicUsize::new(0)),
+            tracker: ProgressTracker::new(),
+            watchdog: None,
+            retry: None,
+        }
+    }
+
+    pub fn with_request_builder(
+        mut self,
+        f: impl Fn(RequestBuilder) -> RequestBuilder + Send + Sync + 'static,
+    ) -> Self {
+        self.request_builder = Arc::new(f);
+        self
+    }
+
+    /// Stop waiting for the tasks and the running downloads when `cancel` is cancelled.
+    ///
+    /// The unfinished downloads are started over next time.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Pause adding tasks when most of the recent tasks fail.
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker = Arc::new(CircuitBreaker::new(config));
+        self
+    }
+
+    /// Report the progress of every task to `progress`.
+    pub fn with_progress(mut self, progress: ProgressWriter) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Track the progress of the tasks with `tracker`, shared with those polling it.
+    pub fn with_progress_tracker(mut self, tracker: ProgressTracker) -> Self {
+        self.tracker = tracker;
+        self
+    }
+
+    /// Restart the downloads receiving no bytes for `stall`, up to `max_restarts` times.
+    ///
+    /// The requests not idempotent are failed instead of restarted.
+    pub fn with_stall_watchdog(mut self, stall: Duration, max_restarts: u32) -> Self {
+        self.watchdog = Some(Watchdog {
+            stall,
+            max_restarts,
+        });
+        self
+    }
+
+    /// Retry the downloads failed for transient errors up to `retries` times,
+    /// waiting `backoff` doubled after every retry.
+    ///
+    /// The requests not idempotent are never retried.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retry = Some(Retry { retries, backoff });
+        self
+    }
+
+    pub async fn add_task(&self, task: Task) -> crate::Result<()> {
+        let waiting = Waiting::new(&self.pending);
+        let ticket = tokio::select! {
+            ticket = self.breaker.acquire() => ticket,
+            _ = self.cancel.cancelled() => return Ok(()),
+        };
+        // Waits while all the slots are taken, like the tasks waiting in aria2.
+        let slot = tokio::select! {
+            slot = self.slots.clone().acquire_owned() => slot.expect("the slots are never closed"),
+            _ = self.cancel.cancelled() => return Ok(()),
+        };
+        drop(waiting);
+        pace().await;
+        let path = task_path(&task);
+        let idempotent = task.is_idempotent();
+        if let Some(ref progress) = self.progress {
+            progress.emit(&ProgressEvent::TaskStarted {
+                url: &task.url,
+                path: Some(&path),
+            });
+        }
+        let mut request = self.client.request(task.method.clone(), &task.url);
+        if let Some(body) = task.body.clone() {
+            request = request.body(body);
+        }
+        let request = (self.request_builder)(request);
+        let hooks = task.hooks.unwrap_or_default();
+        let url = task.url;
+        let TaskCounters {
+            id,
+            downloaded,
+            total,
+        } = self.tracker.add(&url);
+        let tracker = self.tracker.clone();
+        let watchdog = self.watchdog.map(|w| Watchdog {
+            max_restarts: if idempotent { w.max_restarts } else { 0 },
+            ..w
+        });
+        let retry = self.retry.filter(|_| idempotent);
+        let breaker = self.breaker.clone();
+        let progress = self.progress.clone();
+        let cancel = self.cancel.clone();
+        let finished = self.finished.clone();
+        let failed = self.failed.clone();
+        let waitgroup = self.waitgroup.clone();
+        let hooks_running = self.hooks_running.clone();
+        self.waitgroup.add(1);
+        tokio::spawn(async move {
+            let r = tokio::select! {
+                r = download_restarting(request, &url, &path, &downloaded, &total, watchdog, retry) => r,
+                _ = cancel.cancelled() => {
+                    let _ = tokio::fs::remove_file(part_path(&path)).await;
+                    tracker.finish(id, TaskStatus::Failed);
+                    waitgroup.done();
+                    return;
+                }
+            };
+            drop(slot);
+            hooks_running.add(1);
+            breaker.record(ticket, r.is_ok());
+            let bytes = r.as_ref().ok().copied();
+            let error = run_hooks(r, hooks, &url).await;
+            hooks_running.done();
+            match error {
+                None => finished.fetch_add(1, Ordering::Relaxed),
+                Some(_) => failed.fetch_add(1, Ordering::Relaxed),
+            };
+            tracker.finish(
+                id,
+                match error {
+                    None => TaskStatus::Completed,
+                    Some(_) => TaskStatus::Failed,
+                },
+            );
+            if let Some(progress) = progress {
+                progress.emit(&match error {
+                    None => ProgressEvent::TaskCompleted {
+                        url: &url,
+                        path: Some(&path),
+                        bytes,
+                    },
+                    Some(error) => ProgressEvent::TaskFailed {
+                        url: &url,
+                        path: Some(&path),
+                        error: Some(error),
+                    },
+                });
+            }
+            waitgroup.done();
+        });
+        Ok(())
+    }
+
+    /// Wait for all the tasks, or until cancelled.
+    ///
+    /// Once cancelled, the hooks already running are waited for up to [`HOOK_GRACE_PERIOD`].
+    pub async fn wait_shutdown(&self) {
+        tokio::select! {
+            _ = self.waitgroup.clone() => {}
+            _ = self.cancel.cancelled() => {
+                debug!("cancelled, waiting for the running hooks");
+                if timeout(HOOK_GRACE_PERIOD, self.hooks_running.clone()).await.is_err() {
+                    warn!("hooks still running after {:?}, stop waiting", HOOK_GRACE_PERIOD);
+                }
+            }
+        }
+        flush_throttled();
+        if let Some(ref progress) = self.progress {
+            progress.emit(&ProgressEvent::Finished);
+        }
+    }
+
+    /// Number of the tasks failed so far, including those failed in the hooks.
+    pub fn failed_tasks(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// The progress of the running and the last finished tasks, in order.
+    pub fn progress(&self) -> Vec<TaskProgress> {
+        self.tracker.snapshot()
+    }
+
+    /// The counts of the tasks and the urls being downloaded.
+    ///
+    /// The urls are read from the progress tracker, so they include the tasks running the hooks.
+    pub fn queue_state(&self) -> QueueState {
+        QueueState {
+            pending: self.pending.load(Ordering::Relaxed),
+            running: self.waitgroup.pending(),
+            finished: self.finished.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            active: self
+                .tracker
+                .snapshot()
+                .into_iter()
+                .filter(|t| t.status == TaskStatus::Running)
+                .map(|t| t.url)
+                .collect(),
+        }
+    }
+}
+
+impl Downloader for NativeDownloader {
+    fn add_task(&self, task: Task) -> BoxFuture<'_, crate::Result<()>> {
+        NativeDownloader::add_task(self, task).boxed()
+    }
+
+    fn wait_shutdown(&self) -> BoxFuture<'_, ()> {
+        NativeDownloader::wait_shutdown(self).boxed()
+    }
+
+    fn failed_tasks(&self) -> usize {
+        NativeDownloader::failed_tasks(self)
+    }
+
+    fn progress(&self) -> Vec<TaskProgress> {
+        NativeDownloader::progress(self)
+    }
+
+    fn queue_state(&self) -> BoxFuture<'_, crate::Result<QueueState>> {
+        let state = NativeDownloader::queue_state(self);
+        async move { Ok(state) }.boxed()
+    }
+}
+
+/// `dir` joined with `out`, named after the url if `out` is not set.
+fn task_path(task: &Task) -> PathBuf {
+    let options = task.options.as_ref();
+    let dir = options.and_then(|o| o.dir.as_deref()).unwrap_or(".");
+    let out = match options.and_then(|o| o.out.clone()) {
+        Some(out) => out,
+        None => url::Url::parse(&task.url)
+            .ok()
+            .and_then(|u| u.path_segments()?.last().map(|s| s.to_string()))
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| "index.html".to_string()),
+    };
+    PathBuf::from(dir).join(out)
+}
+
+/// The file written during the download, moved to `path` once completed.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Wait for `f`, or `None` if it is not ready in `stall`.
+async fn unless_stalled<F: Future>(stall: Option<Duration>, f: F) -> Option<F::Output> {
+    match stall {
+        Some(stall) => timeout(stall, f).await.ok(),
+        None => Some(f.await),
+    }
+}
+
+/// Whether the download may succeed if tried again.
+fn is_transient(err: &error::Error) -> bool {
+    match err {
+        error::Error::NativeDownload { source, .. } => match source.status() {
+            Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            None => {
+                source.is_connect()
+                    || source.is_timeout()
+                    || source.is_request()
+                    || source.is_body()
+            }
+        },
+        // Cut off before the end.
+        error::Error::FileSizeMismatch { .. } => true,
+        _ => false,
+    }
+}
+
+/// Download to `path`, starting over if it stalls or fails for a transient error.
+async fn download_restarting(
+    request: RequestBuilder,
+    url: &str,
+    path: &Path,
+    downloaded: &AtomicU64,
+    total: &AtomicU64,
+    watchdog: Option<Watchdog>,
+    retry: Option<Retry>,
+) -> crate::Result<u64> {
+    let mut restarts = 0;
+    let mut retries = 0;
+    loop {
+        let attempt = match request.try_clone() {
+            Some(attempt) => attempt,
+            // The body is a stream, which cannot be sent again.
+            None => return download(request, url, path, downloaded, total, watchdog).await,
+        };
+        match download(attempt, url, path, downloaded, total, watchdog).await {
+            Err(error::Error::DownloadStalled { secs, .. })
+                if watchdog.map_or(false, |w| restarts < w.max_restarts) =>
+            {
+                restarts += 1;
+                warn!(
+                    "no progress downloading {} for {} seconds, restarting ({}/{})",
+                    url,
+                    secs,
+                    restarts,
+                    watchdog.unwrap().max_restarts
+                );
+                downloaded.store(0, Ordering::Relaxed);
+            }
+            Err(e) if is_transient(&e) && retry.map_or(false, |r| retries < r.retries) => {
+                let retry = retry.unwrap();
+                let delay = retry.backoff * 2u32.saturating_pow(retries);
+                retries += 1;
+                warn_throttled(
+                    "download retried",
+                    format!("{}, retry {}/{} in {:?}", e, retries, retry.retries, delay),
+                );
+                downloaded.store(0, Ordering::Relaxed);
+                tokio::time::sleep(delay).await;
+            }
+            r => return r,
+        }
+    }
+}
+
+/// Download to `path`, returning the size of the file.
+async fn download(
+    request: RequestBuilder,
+    url: &str,
+    path: &Path,
+    downloaded: &AtomicU64,
+    total: &AtomicU64,
+    watchdog: Option<Watchdog>,
+) -> crate::Result<u64> {
+    let io_context = || error::NativeDownloadIo {
+        path: path.to_string_lossy().to_string(),
+    };
+    let stall = watchdog.map(|w| w.stall);
+    let stalled = || {
+        error::DownloadStalled {
+            url,
+            secs: stall.unwrap_or_default().as_secs(),
+        }
+        .build()
+    };
+    let mut response = unless_stalled(stall, request.send())
+        .await
+        .ok_or_else(stalled)?
+        .and_then(|r| r.error_for_status())
+        .context(error::NativeDownload { url })?;
+    let expected = response.content_length();
+    total.store(expected.unwrap_or(0), Ordering::Relaxed);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|_| io_context())?;
+    }
+    let part = part_path(path);
+    let mut file = File::create(&part).await.with_context(|_| io_context())?;
+    let mut actual = 0;
+    while let Some(chunk) = unless_stalled(stall, response.chunk())
+        .await
+        .ok_or_else(stalled)?
+        .context(error::NativeDownload { url })?
+    {
+        file.write_all(&chunk)
+            .await
+            .with_context(|_| io_context())?;
+        actual += chunk.len() as u64;
+        downloaded.store(actual, Ordering::Relaxed);
+    }
+    file.flush().await.with_context(|_| io_context())?;
+    drop(file);
+    if let Some(expected) = expected.filter(|e| *e != actual) {
+        let _ = tokio::fs::remove_file(&part).await;
+        return error::FileSizeMismatch {
+            path: path.to_string_lossy().to_string(),
+            expected,
+            actual,
+        }
+        .fail();
+    }
+    tokio::fs::rename(&part, path)
+        .await
+        .with_context(|_| io_context())?;
+    Ok(actual)
+}
+
+/// Run the hook of the result, returning the error of the download or the hook.
+async fn run_hooks(r: crate::Result<u64>, hooks: TaskHooks, url: &str) -> Option<String> {
+    let mut hook_error = None;
+    let hook = match r {
+        Ok(_) => hooks.on_success,
+        Err(err) => {
+            warn_throttled("download failed", &err);
+            hook_error = Some(err.to_string());
+            hooks.on_error
+        }
+    };
+    if let Some(hook) = hook {
+        let i = Instant::now();
+        if let Err(err) = hook.await {
+            warn_throttled("error on hook", format!("error on hook: {}", err));
+            hook_error.get_or_insert(err.to_string());
+        }
+        debug!("hook of {} took {:?}", url, i.elapsed());
+    }
+    hook_error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    fn task(url: &str, dir: Option<&str>, out: Option<&str>) -> Task {
+        Task {
+            url: url.to_string(),
+            options: Some(aria2_ws::TaskOptions {
+                dir: dir.map(|d| d.to_string()),
+                out: out.map(|o| o.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn paths() {
+        let t = task("https://a/b/1_p0.jpg?x=1", Some("/d"), Some("u/1.jpg"));
+        assert_eq!(task_path(&t), Path::new("/d/u/1.jpg"));
+        let t = task("https://a/b/1_p0.jpg?x=1", Some("/d"), None);
+        assert_eq!(task_path(&t), Path::new("/d/1_p0.jpg"));
+        let t = task("https://a/", None, None);
+        assert_eq!(task_path(&t), Path::new("./index.html"));
+        assert_eq!(
+            part_path(Path::new("/d/1_p0.jpg")),
+            Path::new("/d/1_p0.jpg.part")
+        );
+    }
+
+    /// Answer every connection with `responses` in turn, the last one repeated.
+    async fn serve(responses: Vec<&'static str>) -> String {
+        serve_recording(responses).await.0
+    }
+
+    /// Like `serve`, also sending the requests received.
+    async fn serve_recording(
+        responses: Vec<&'static str>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut i = 0;
+            loop {
+                let (mut stream, _): (TcpStream, _) = listener.accept().await.unwrap();
+                let response = responses[i.min(responses.len() - 1)];
+                i += 1;
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let _ = tx.send(String::from_utf8_lossy(&buf[..n]).to_string());
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        (format!("http://{addr}/file.txt"), rx)
+    }
+
+    #[tokio::test]
+    async fn download_retrying() {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello",
+        ])
+        .await;
+        let dir = std::env::temp_dir().join(format!("bowerbird-native-{}", std::process::id()));
+        let downloader = NativeDownloader::new(Client::new(), 1).with_retries(2, Duration::ZERO);
+        downloader
+            .add_task(task(&url, dir.to_str(), Some("a/file.txt")))
+            .await
+            .unwrap();
+        downloader.wait_shutdown().await;
+        assert_eq!(downloader.failed_tasks(), 0);
+        let path = dir.join("a/file.txt");
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "hello");
+        assert!(!part_path(&path).exists());
+        let progress = downloader.progress();
+        assert_eq!(progress[0].status, TaskStatus::Completed);
+        assert_eq!(progress[0].downloaded, 5);
+        assert_eq!(
+            downloader.queue_state(),
+            QueueState {
+                finished: 1,
+                ..Default::default()
+            }
+        );
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn download_failed_without_retries() {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello",
+        ])
+        .await;
+        let dir = std::env::temp_dir().join(format!("bowerbird-native-f{}", std::process::id()));
+        let downloader = NativeDownloader::new(Client::new(), 1);
+        downloader
+            .add_task(task(&url, dir.to_str(), Some("file.txt")))
+            .await
+            .unwrap();
+        downloader.wait_shutdown().await;
+        assert_eq!(downloader.failed_tasks(), 1);
+        assert!(!dir.join("file.txt").exists());
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn post_not_retried() {
+        let (url, mut requests) = serve_recording(vec![
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello",
+        ])
+        .await;
+        let dir = std::env::temp_dir().join(format!("bowerbird-native-p{}", std::process::id()));
+        let downloader = NativeDownloader::new(Client::new(), 1).with_retries(2, Duration::ZERO);
+        let t = task(&url, dir.to_str(), Some("file.txt"));
+        downloader
+            .add_task(Task::post(url, "q=1", t.options, None))
+            .await
+            .unwrap();
+        downloader.wait_shutdown().await;
+        assert_eq!(downloader.failed_tasks(), 1);
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("POST "), "{request}");
+        assert!(
+            request.to_lowercase().contains("content-length: 3"),
+            "{request}"
+        );
+        assert!(requests.try_recv().is_err());
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
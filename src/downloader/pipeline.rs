@@ -0,0 +1,177 @@
+use futures::{future::BoxFuture, Future, FutureExt};
+use log::{debug, warn};
+use std::time::Instant;
+
+use super::BoxFutureResult;
+use crate::error::BoxError;
+
+type StepFn<C> = Box<dyn FnOnce(C) -> BoxFuture<'static, Result<C, BoxError>> + Send>;
+
+struct Step<C> {
+    name: &'static str,
+    abort_on_error: bool,
+    f: StepFn<C>,
+}
+
+/// An ordered list of async post-processing steps passing a context along.
+///
+/// # Example
+///
+/// ```ignore
+/// let hook = Pipeline::new()
+///     .then("transcode", |ctx: Ctx| async move { Ok(ctx) })
+///     .then_optional("thumbnail", |ctx: Ctx| async move { Ok(ctx) })
+///     .then("save", |ctx: Ctx| async move { Ok(ctx) })
+///     .into_hook(ctx);
+/// ```
+pub struct Pipeline<C> {
+    steps: Vec<Step<C>>,
+}
+
+impl<C> Default for Pipeline<C>
+where
+    C: Clone + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> Pipeline<C>
+where
+    C: Clone + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self { steps: Vec::new() }
+    }
+
+    fn push<F, Fut>(mut self, name: &'static str, abort_on_error: bool, f: F) -> Self
+    where
+        F: FnOnce(C) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<C, BoxError>> + Send + 'static,
+    {
+        self.steps.push(Step {
+            name,
+            abort_on_error,
+            f: Box::new(move |ctx| f(ctx).boxed()),
+        });
+        self
+    }
+
+    /// Add a step. The following steps are skipped if it fails.
+    pub fn then<F, Fut>(self, name: &'static str, f: F) -> Self
+    where
+        F: FnOnce(C) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<C, BoxError>> + Send + 'static,
+    {
+        self.push(name, true, f)
+    }
+
+    /// Add a step whose failure is logged,
+    /// and the following steps run with the context before it.
+    pub fn then_optional<F, Fut>(self, name: &'static str, f: F) -> Self
+    where
+        F: FnOnce(C) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<C, BoxError>> + Send + 'static,
+    {
+        self.push(name, false, f)
+    }
+
+    pub async fn run(self, mut ctx: C) -> Result<C, BoxError> {
+        for step in self.steps {
+            let i = Instant::now();
+            if step.abort_on_error {
+                ctx = (step.f)(ctx)
+                    .await
+                    .map_err(|err| format!("{}: {}", step.name, err))?;
+            } else {
+                match (step.f)(ctx.clone()).await {
+                    Ok(c) => ctx = c,
+                    Err(err) => warn!("{}: {}", step.name, err),
+                }
+            }
+            debug!("step {} took {:?}", step.name, i.elapsed());
+        }
+        Ok(ctx)
+    }
+
+    /// Turn the pipeline into a task hook.
+    pub fn into_hook(self, ctx: C) -> BoxFutureResult {
+        async move { self.run(ctx).await.map(|_| ()) }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    type Steps = Vec<&'static str>;
+
+    fn step(
+        name: &'static str,
+    ) -> impl FnOnce(Steps) -> BoxFuture<'static, Result<Steps, BoxError>> {
+        move |mut ran: Steps| {
+            async move {
+                ran.push(name);
+                Ok(ran)
+            }
+            .boxed()
+        }
+    }
+
+    fn failing(_: Steps) -> BoxFuture<'static, Result<Steps, BoxError>> {
+        async { Err("failed".into()) }.boxed()
+    }
+
+    #[tokio::test]
+    async fn steps_in_order() {
+        let ran = Pipeline::new()
+            .then("a", step("a"))
+            .then_optional("b", step("b"))
+            .then("c", step("c"))
+            .run(Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(ran, ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn optional_failure_continues() {
+        let ran = Pipeline::new()
+            .then("a", step("a"))
+            .then_optional("optional", failing)
+            .then("c", step("c"))
+            .run(Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(ran, ["a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn failure_aborts() {
+        let ran_after = Arc::new(AtomicBool::new(false));
+        let err = Pipeline::new()
+            .then("a", step("a"))
+            .then("required", failing)
+            .then("c", {
+                let ran_after = ran_after.clone();
+                move |ran: Steps| {
+                    ran_after.store(true, Ordering::SeqCst);
+                    step("c")(ran)
+                }
+            })
+            .run(Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "required: failed");
+        assert!(!ran_after.load(Ordering::SeqCst));
+        let hook = Pipeline::new()
+            .then("required", failing)
+            .into_hook(Vec::new());
+        assert!(hook.await.is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use log::warn;
+use serde::Serialize;
+use std::{
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+};
+
+/// Version of the progress event schema.
+/// Bump it when fields are removed or their meaning changes.
+pub const PROGRESS_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Serialize, Default)]
+pub struct ProgressCounts {
+    pub queued: u64,
+    pub completed: u64,
+    pub failed: u64,
+    pub bytes: u64,
+}
+
+/// A progress event, written as a single line of JSON.
+///
+/// # Example
+///
+/// ```json
+/// {"version":1,"event":"task_completed","url":"https://i.pximg.net/...","path":"/path/to/file.jpg","bytes":1024,"counts":{"queued":3,"completed":1,"failed":0,"bytes":1024}}
+/// ```
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent<'a> {
+    TaskStarted {
+        url: &'a str,
+        path: Option<&'a Path>,
+    },
+    TaskCompleted {
+        url: &'a str,
+        path: Option<&'a Path>,
+        bytes: Option<u64>,
+    },
+    TaskFailed {
+        url: &'a str,
+        path: Option<&'a Path>,
+        error: Option<String>,
+    },
+    Finished,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: &'a ProgressEvent<'a>,
+    counts: ProgressCounts,
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Writes newline-delimited JSON progress events for frontends.
+#[derive(Clone)]
+pub struct ProgressWriter {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    counters: Arc<Counters>,
+}
+
+impl std::fmt::Debug for ProgressWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ProgressWriter")
+            .field("counts", &self.counts())
+            .finish()
+    }
+}
+
+impl ProgressWriter {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(Box::new(out))),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Write to the file descriptor `fd` left open by the parent process.
+    /// `1` is always the stdout.
+    ///
+    /// The stdin and the stderr holding the logs are refused. A copy of `fd` is written to,
+    /// so the descriptor is never closed by us.
+    pub fn from_fd(fd: i32) -> std::io::Result<Self> {
+        if fd == 1 {
+            return Ok(Self::new(std::io::stdout()));
+        }
+        if fd <= 0 || fd == 2 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "progress file descriptor cannot be the stdin or the stderr",
+            ));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::FromRawFd;
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                // Not open.
+                return Err(std::io::Error::last_os_error());
+            }
+            let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) };
+            if copy == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // The copy is only owned by us.
+            let file = unsafe { std::fs::File::from_raw_fd(copy) };
+            Ok(Self::new(file))
+        }
+        #[cfg(not(unix))]
+        {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "progress file descriptors other than 1 are only supported on unix",
+            ))
+        }
+    }
+
+    pub fn counts(&self) -> ProgressCounts {
+        ProgressCounts {
+            queued: self.counters.queued.load(SeqCst),
+            completed: self.counters.completed.load(SeqCst),
+            failed: self.counters.failed.load(SeqCst),
+            bytes: self.counters.bytes.load(SeqCst),
+        }
+    }
+
+    pub fn emit(&self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::TaskStarted { .. } => {
+                self.counters.queued.fetch_add(1, SeqCst);
+            }
+            ProgressEvent::TaskCompleted { bytes, .. } => {
+                self.counters.completed.fetch_add(1, SeqCst);
+                self.counters
+                    .bytes
+                    .fetch_add(bytes.unwrap_or_default(), SeqCst);
+            }
+            ProgressEvent::TaskFailed { .. } => {
+                self.counters.failed.fetch_add(1, SeqCst);
+            }
+            ProgressEvent::Finished => {}
+        }
+        let line = Line {
+            version: PROGRESS_SCHEMA_VERSION,
+            event,
+            counts: self.counts(),
+        };
+        let mut out = self.out.lock().unwrap();
+        let r = serde_json::to_writer(&mut *out, &line)
+            .map_err(std::io::Error::from)
+            .and_then(|_| out.write_all(b"\n"))
+            .and_then(|_| out.flush());
+        if let Err(err) = r {
+            warn!("cannot write progress event: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuse_fds() {
+        assert!(ProgressWriter::from_fd(0).is_err());
+        assert!(ProgressWriter::from_fd(2).is_err());
+        assert!(ProgressWriter::from_fd(-1).is_err());
+        // Not open.
+        #[cfg(unix)]
+        assert!(ProgressWriter::from_fd(i32::MAX).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fd_left_open() {
+        use std::{io::Read, os::unix::io::AsRawFd};
+
+        let path = std::env::temp_dir().join(format!("bowerbird-progress-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        let fd = file.as_raw_fd();
+        let progress = ProgressWriter::from_fd(fd).unwrap();
+        progress.emit(&ProgressEvent::Finished);
+        drop(progress);
+        // Still open after the writer is dropped.
+        file.write_all(b"end").unwrap();
+        drop(file);
+
+        let mut written = String::new();
+        std::fs::File::open(&path)
+            .unwrap()
+            .read_to_string(&mut written)
+            .unwrap();
+        assert!(written.starts_with(r#"{"version":1,"event":"finished""#));
+        assert!(written.ends_with("}\nend"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,43 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use trust_dns_resolver::{
+    config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::ResolveError,
+    TokioAsyncResolver,
+};
+
+/// Resolves the hosts of the native downloads with the DNS servers in the config,
+/// instead of those of the system.
+pub struct DnsResolver(Arc<TokioAsyncResolver>);
+
+impl DnsResolver {
+    /// Only the IPv4 addresses are looked up with `ipv4_only`.
+    pub fn new(servers: &[IpAddr], ipv4_only: bool) -> Result<Self, ResolveError> {
+        let config = ResolverConfig::from_parts(
+            None,
+            Vec::new(),
+            NameServerConfigGroup::from_ips_clear(servers, 53, true),
+        );
+        let mut opts = ResolverOpts::default();
+        if ipv4_only {
+            opts.ip_strategy = LookupIpStrategy::Ipv4Only;
+        }
+        Ok(Self(Arc::new(TokioAsyncResolver::tokio(config, opts)?)))
+    }
+}
+
+impl Resolve for DnsResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            let lookup = resolver.lookup_ip(name.as_str()).await?;
+            // The port is replaced with the one of the URL.
+            let addrs: Vec<_> = lookup.iter().map(|ip| SocketAddr::new(ip, 0)).collect();
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
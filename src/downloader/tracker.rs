@@ -0,0 +1,144 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use super::{TaskProgress, TaskStatus};
+
+/// The finished tasks kept in the snapshots, the older ones are dropped.
+const KEEP_FINISHED: usize = 100;
+
+/// A task being tracked. The sizes are updated without the lock of the tasks.
+struct TrackedTask {
+    url: String,
+    downloaded: Arc<AtomicU64>,
+    /// `0` if unknown.
+    total: Arc<AtomicU64>,
+    status: TaskStatus,
+}
+
+#[derive(Default)]
+struct Tasks {
+    next_id: u64,
+    tasks: BTreeMap<u64, TrackedTask>,
+    /// The ids of the finished tasks, oldest first.
+    finished: VecDeque<u64>,
+}
+
+/// The sizes of a tracked task, updated by the downloader.
+pub(crate) struct TaskCounters {
+    pub id: u64,
+    pub downloaded: Arc<AtomicU64>,
+    pub total: Arc<AtomicU64>,
+}
+
+/// The progress of the tasks of a downloader, shared with those polling it,
+/// e.g. to show the progress of a sync in a UI.
+///
+/// The running tasks and the last finished ones are kept,
+/// so a long sync does not grow it without limit.
+#[derive(Clone, Default)]
+pub struct ProgressTracker {
+    tasks: Arc<RwLock<Tasks>>,
+}
+
+impl fmt::Debug for ProgressTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressTracker")
+            .field("tasks", &self.tasks.read().unwrap().tasks.len())
+            .finish()
+    }
+}
+
+impl ProgressTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn add(&self, url: &str) -> TaskCounters {
+        let mut tasks = self.tasks.write().unwrap();
+        let id = tasks.next_id;
+        tasks.next_id += 1;
+        let downloaded = Arc::new(AtomicU64::new(0));
+        let total = Arc::new(AtomicU64::new(0));
+        tasks.tasks.insert(
+            id,
+            TrackedTask {
+                url: url.to_string(),
+                downloaded: downloaded.clone(),
+                total: total.clone(),
+                status: TaskStatus::Running,
+            },
+        );
+        TaskCounters {
+            id,
+            downloaded,
+            total,
+        }
+    }
+
+    pub(crate) fn finish(&self, id: u64, status: TaskStatus) {
+        let mut tasks = self.tasks.write().unwrap();
+        if let Some(task) = tasks.tasks.get_mut(&id) {
+            task.status = status;
+            tasks.finished.push_back(id);
+        }
+        while tasks.finished.len() > KEEP_FINISHED {
+            let oldest = tasks.finished.pop_front().unwrap();
+            tasks.tasks.remove(&oldest);
+        }
+    }
+
+    /// The progress of the tasks, in the order they are added.
+    pub fn snapshot(&self) -> Vec<TaskProgress> {
+        self.tasks
+            .read()
+            .unwrap()
+            .tasks
+            .iter()
+            .map(|(id, t)| TaskProgress {
+                id: *id,
+                url: t.url.clone(),
+                downloaded: t.downloaded.load(Ordering::Relaxed),
+                total: Some(t.total.load(Ordering::Relaxed)).filter(|t| *t != 0),
+                status: t.status,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_finished() {
+        let tracker = ProgressTracker::new();
+        let first = tracker.add("https://a/0");
+        first.downloaded.store(3, Ordering::Relaxed);
+        first.total.store(10, Ordering::Relaxed);
+        for i in 1..=KEEP_FINISHED + 1 {
+            let t = tracker.add(&format!("https://a/{i}"));
+            tracker.finish(t.id, TaskStatus::Completed);
+        }
+        let snapshot = tracker.snapshot();
+        // The running task and the last finished ones.
+        assert_eq!(snapshot.len(), KEEP_FINISHED + 1);
+        assert_eq!(
+            snapshot[0],
+            TaskProgress {
+                id: 0,
+                url: "https://a/0".to_string(),
+                downloaded: 3,
+                total: Some(10),
+                status: TaskStatus::Running,
+            }
+        );
+        assert_eq!(snapshot[1].id, 2);
+        assert_eq!(snapshot[KEEP_FINISHED].status, TaskStatus::Completed);
+    }
+}
@@ -15,16 +15,67 @@ pub enum Error {
     ConfigIo {
         source: std::io::Error,
     },
+    #[snafu(display("invalid config: {message}"))]
+    ConfigInvalid {
+        message: String,
+    },
+    #[snafu(display("cannot create the root storage dir {path}: {source}"))]
+    StorageRootIo {
+        path: String,
+        source: std::io::Error,
+    },
     #[snafu(display("try to save config without path"))]
     ConfigPathNotSet,
     #[snafu(display("cannot parse proxy in config file: {source}"))]
     ProxyParse {
         source: reqwest::Error,
     },
+    #[snafu(display("cannot build the http client: {source}"))]
+    HttpClientBuild {
+        source: reqwest::Error,
+    },
+    #[snafu(display("invalid proxy: {message}"))]
+    ProxyInvalid {
+        message: String,
+    },
+    #[snafu(display("--{name}-path {path} does not exist"))]
+    ProgramPathInvalid {
+        name: String,
+        path: String,
+    },
+    #[snafu(display("proxy {proxy} is not usable: {message}"))]
+    ProxyUnreachable {
+        proxy: String,
+        message: String,
+    },
+    #[snafu(display("cannot find pixiv user {input}, sync the user by id first"))]
+    UserUnresolved {
+        input: String,
+    },
+    #[snafu(display("cannot log in to pixiv, check the refresh token: {source}"))]
+    PixivAuth {
+        source: pixivcrab::error::Error,
+    },
     #[snafu(display("pixiv api error: {source}"))]
     PixivApi {
         source: pixivcrab::error::Error,
     },
+    #[snafu(display(
+        "pixiv rate limit still reached after {cooldowns} cooldowns, try again later: {source}"
+    ))]
+    PixivRateLimited {
+        cooldowns: u32,
+        source: pixivcrab::error::Error,
+    },
+    #[snafu(display("this needs a pixiv premium account: {source}"))]
+    PixivPremiumRequired {
+        source: pixivcrab::error::Error,
+    },
+    #[snafu(display("page token is not a page of the works of user {user_id}: {token}"))]
+    PageTokenInvalid {
+        token: String,
+        user_id: String,
+    },
     #[snafu(display("cannot parse infomation from pixiv: {message}"))]
     PixivParse {
         message: String,
@@ -42,6 +93,10 @@ pub enum Error {
     },
     #[snafu(display("data struct cannot be parsed from mongodb"))]
     MongoNotMatch,
+    #[snafu(display("cannot write the batch to mongodb: {message}"))]
+    MongoBatch {
+        message: String,
+    },
     #[snafu(display("error while serializing to bson: {source}"))]
     BsonSerialize {
         source: mongodb::bson::ser::Error,
@@ -50,6 +105,39 @@ pub enum Error {
     Aria2 {
         source: aria2_ws::Error,
     },
+    #[snafu(display(
+        "aria2 failed to download {url} to {path} (gid {gid}, code {code}): {message}"
+    ))]
+    Aria2Download {
+        url: String,
+        path: String,
+        gid: String,
+        code: String,
+        message: String,
+    },
+    #[snafu(display("request not supported by aria2: {message}"))]
+    Aria2UnsupportedRequest {
+        message: String,
+    },
+    #[snafu(display("fail to download {url}: {source}"))]
+    NativeDownload {
+        url: String,
+        source: reqwest::Error,
+    },
+    #[snafu(display("no progress downloading {url} for {secs} seconds"))]
+    DownloadStalled {
+        url: String,
+        secs: u64,
+    },
+    #[snafu(display("io error downloading to {path}: {source}"))]
+    NativeDownloadIo {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("ffmpeg is required to transcode ugoira to {format}"))]
+    UgoiraFormatNoFfmpeg {
+        format: String,
+    },
     #[snafu(display("aria2 startup error: {source}"))]
     Aria2StartUpIo {
         source: std::io::Error,
@@ -62,10 +150,74 @@ pub enum Error {
     Aria2ExitIo {
         source: std::io::Error,
     },
+    #[snafu(display("size of {path} is {actual}, expected {expected}"))]
+    FileSizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
     #[snafu(display("fail to find avalible port: {message}"))]
     NoAvaliablePort {
         message: String,
     },
+    #[snafu(display("output directory is not writable: {path}: {source}"))]
+    OutputDirIo {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("cannot open progress file descriptor {fd}: {source}"))]
+    ProgressIo {
+        fd: i32,
+        source: std::io::Error,
+    },
+    #[snafu(display("io error while exporting: {source}"))]
+    ExportIo {
+        source: std::io::Error,
+    },
+    #[snafu(display("json error while exporting: {source}"))]
+    ExportJson {
+        source: serde_json::Error,
+    },
+    #[snafu(display("io error while importing: {source}"))]
+    ImportIo {
+        source: std::io::Error,
+    },
+    #[snafu(display("cannot parse imported data: {message}"))]
+    ImportParse {
+        message: String,
+    },
+    #[snafu(display("not supported without the database: {message}"))]
+    NoDbUnsupported {
+        message: String,
+    },
+    #[snafu(display("io error while backing up {path}: {source}"))]
+    BackupIo {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("database dump failed: {message}"))]
+    BackupDump {
+        message: String,
+    },
+    #[snafu(display("cannot write the media manifest: {source}"))]
+    BackupManifest {
+        source: serde_json::Error,
+    },
+    #[snafu(display("io error with checkpoint {path}: {source}"))]
+    VerifyCheckpointIo {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("invalid checkpoint {path}: {source}"))]
+    VerifyCheckpointJson {
+        path: String,
+        source: serde_json::Error,
+    },
+    #[snafu(display("cannot load the embedding model {path}: {message}"))]
+    EmbeddingModel {
+        path: String,
+        message: String,
+    },
     #[snafu(display("fail to start server: {source}"))]
     ServerIo {
         source: std::io::Error,
@@ -1,12 +1,16 @@
 pub mod cli;
 mod command;
-mod config;
+pub mod config;
 mod downloader;
 mod error;
 pub mod model;
 mod server;
+pub mod sync;
 mod utils;
 
 pub(crate) type Result<T> = std::result::Result<T, error::Error>;
 
+pub use downloader::{
+    Downloader, NativeDownloader, ProgressTracker, QueueState, TaskProgress, TaskStatus,
+};
 pub use error::Error;
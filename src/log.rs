@@ -1,9 +1,11 @@
+use bowerbird::config::LogTimeFormat;
 use chrono::Local;
 use colored::Colorize;
 use log4rs::{
-    append::console::ConsoleAppender,
-    config::{Appender, Config, Logger, Root},
+    append::console::{ConsoleAppender, Target},
+    config::{Appender, Config, Root},
     encode::Encode,
+    filter::{Filter, Response},
 };
 #[derive(Debug)]
 struct Encoder;
@@ -18,9 +20,8 @@ impl Encode for Encoder {
         let level = record.level();
         let msg = record.args().to_string();
 
-        let date = Local::now()
-            .format("%Y-%m-%d %H:%M:%S")
-            .to_string()
+        let date = bowerbird::cli::log_time_format()
+            .format(Local::now())
             .bright_black();
 
         let (level, msg) = match level {
@@ -40,18 +41,77 @@ impl Encode for Encoder {
     }
 }
 
+/// Write each record as a line of JSON.
+///
+/// Records with the target `bowerbird::config` carry a JSON object,
+/// which is embedded as `config` instead of a string.
+#[derive(Debug)]
+struct JsonEncoder;
+
+impl Encode for JsonEncoder {
+    fn encode(
+        &self,
+        w: &mut dyn log4rs::encode::Write,
+        record: &log::Record,
+    ) -> anyhow::Result<()> {
+        let msg = record.args().to_string();
+        // Always parsable, the human format is only for the console.
+        let time_format = match bowerbird::cli::log_time_format() {
+            LogTimeFormat::Human => LogTimeFormat::Rfc3339,
+            f => f,
+        };
+        let mut line = serde_json::json!({
+            "time": time_format.format(Local::now()),
+            "level": record.level().as_str(),
+            "target": record.target(),
+        });
+        match serde_json::from_str::<serde_json::Value>(&msg) {
+            Ok(config) if record.target() == "bowerbird::config" => line["config"] = config,
+            _ => line["message"] = msg.into(),
+        }
+        writeln!(w, "{line}")?;
+        Ok(())
+    }
+}
+
+/// Drop the records below the level of their modules, set by `-v` and `--log`.
+#[derive(Debug)]
+struct LevelsFilter;
+
+impl Filter for LevelsFilter {
+    fn filter(&self, record: &log::Record) -> Response {
+        if bowerbird::cli::log_enabled(record.target(), record.level()) {
+            Response::Neutral
+        } else {
+            Response::Reject
+        }
+    }
+}
+
 pub fn init_log4rs() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Logs go to stderr, leaving stdout for machine-readable output.
+    let encoder: Box<dyn Encode> = if bowerbird::cli::json_log_enabled() {
+        Box::new(JsonEncoder)
+    } else {
+        Box::new(Encoder)
+    };
     let console_out = ConsoleAppender::builder()
-        .encoder(Box::new(Encoder))
+        .target(Target::Stderr)
+        .encoder(encoder)
         .build();
     let config = Config::builder()
-        .appender(Appender::builder().build("console", Box::new(console_out)))
-        .logger(Logger::builder().build("bowerbird", log::LevelFilter::Debug))
+        .appender(
+            Appender::builder()
+                .filter(Box::new(LevelsFilter))
+                .build("console", Box::new(console_out)),
+        )
         .build(
             Root::builder()
                 .appender("console")
-                .build(log::LevelFilter::Warn),
+                .build(log::LevelFilter::Trace),
         )?;
     log4rs::init_config(config)?;
+    // Only the levels of the default filter until the command line is parsed.
+    log::set_max_level(log::LevelFilter::Debug);
     Ok(())
 }
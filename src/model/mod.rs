@@ -17,6 +17,12 @@ pub struct Item<E, H> {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_modified: Option<DateTime>,
+    /// When the item was first saved by a sync.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen_at: Option<DateTime>,
+    /// When the item was last returned by pixiv in a sync.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen_at: Option<DateTime>,
 
     pub history: Vec<History<H>>,
 
@@ -45,6 +51,12 @@ pub struct LocalMedia<E> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mime: Option<String>,
     pub local_path: String,
+    /// Hex SHA-256 of the file, saved on download or by `verify --save-hashes`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The storage tier holding the file, saved by `verify`. None for `pixiv.storage_dir`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_dir: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extension: Option<E>,
 }
@@ -61,6 +73,52 @@ pub struct ImageMedia {
     pub width: i32,
     pub height: i32,
     pub palette_hsv: Vec<Hsv>,
+    /// The 64-bit difference hash to find similar images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dhash: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub derivative: Option<Derivative>,
+}
+
+/// A smaller copy of an image for display.
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Derivative {
+    pub local_path: String,
+    pub size: i64,
+    pub mime: String,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct UgoiraMedia {
+    /// Delay of each frame in milliseconds.
+    pub frame_delay: Vec<i32>,
+    /// Total duration in milliseconds.
+    pub duration: i64,
+    /// Extensions of the videos transcoded from the zip.
+    /// Empty if only the zip is kept, e.g. without ffmpeg or when transcoding failed.
+    #[serde(default)]
+    pub videos: Vec<String>,
+}
+
+impl UgoiraMedia {
+    pub fn new(frame_delay: Vec<i32>) -> Self {
+        let duration = frame_delay.iter().map(|d| *d as i64).sum();
+        Self {
+            frame_delay,
+            duration,
+            videos: Vec::new(),
+        }
+    }
+}
+
+/// Extension of any media in the image collection.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum MediaExtension {
+    Image(ImageMedia),
+    Ugoira(UgoiraMedia),
 }
 
 #[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -82,4 +140,7 @@ pub struct Collection {
 #[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct BowerbirdMetadata {
     pub version: i32,
+    /// Total bytes downloaded, for the quota.
+    #[serde(default)]
+    pub downloaded_bytes: i64,
 }
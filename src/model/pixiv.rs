@@ -48,6 +48,29 @@ pub struct UserHistory {
     pub background_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar_url: Option<String>,
+    /// The sizes of the profile image, the original first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile_image_urls: Option<Vec<String>>,
+}
+
+impl UserHistory {
+    /// The URLs of the images of the user downloaded with the user data, without duplicates.
+    pub fn image_urls(&self) -> Vec<&String> {
+        let all = self
+            .profile_image_urls
+            .iter()
+            .flatten()
+            .chain(&self.avatar_url)
+            .chain(&self.background_url)
+            .chain(&self.workspace_image_url);
+        let mut urls: Vec<&String> = Vec::new();
+        for url in all {
+            if !url.is_empty() && !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        urls
+    }
 }
 
 #[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -55,6 +78,54 @@ pub struct Works {
     pub total_bookmarks: i64,
     pub total_view: i64,
     pub is_bookmarked: bool,
+    /// Set when the work is saved from the bookmarks of the user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bookmark_visibility: Option<BookmarkVisibility>,
+    /// Tags the user gave to the bookmark, unrelated to the tags of the work.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bookmark_tags: Option<Vec<String>>,
+    /// The series or manga the illust belongs to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series: Option<Series>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pages: Option<PageAvailability>,
+    /// The number of pages of the illust on pixiv, however many are downloaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_count: Option<i32>,
+}
+
+/// Which pages of an illust are downloaded, by their indices.
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PageAvailability {
+    pub total: i32,
+    #[serde(default)]
+    pub available: Vec<i32>,
+    /// Failed to download. The illust is incomplete if not empty.
+    #[serde(default)]
+    pub missing: Vec<i32>,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Series {
+    pub id: String,
+    pub title: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BookmarkVisibility {
+    Public,
+    Private,
+}
+
+impl BookmarkVisibility {
+    pub fn from_private(private: bool) -> Self {
+        if private {
+            Self::Private
+        } else {
+            Self::Public
+        }
+    }
 }
 
 #[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -0,0 +1,208 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json},
+    HttpRequest, HttpResponse,
+};
+use log::{info, warn};
+use mongodb::{bson::Document, options::CountOptions, Database};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::Semaphore;
+
+use super::{
+    error::{Error, MongoErrorExt, ServerErrorExt},
+    export::{filter_after, ndjson_stream, parse_after},
+    utils::{cached_image_thumbnail, check_admin, check_writable, ThumbnailCache},
+    Result,
+};
+use crate::{
+    command::export::ExportFormat,
+    config::Config,
+    downloader::{Downloader, QueueState},
+};
+
+/// Progress of regenerating the thumbnails after the cache is purged.
+#[derive(Debug, Default)]
+pub struct ThumbnailWarmup {
+    running: AtomicBool,
+    total: AtomicUsize,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+#[derive(Debug, Serialize)]
+struct ThumbnailCacheStatus {
+    entries: usize,
+    warming: bool,
+    warm_total: usize,
+    warm_done: usize,
+    warm_failed: usize,
+}
+
+fn cache_status(cache: &Mutex<ThumbnailCache>, warmup: &ThumbnailWarmup) -> ThumbnailCacheStatus {
+    ThumbnailCacheStatus {
+        entries: cache.lock().unwrap().len(),
+        warming: warmup.running.load(Ordering::Relaxed),
+        warm_total: warmup.total.load(Ordering::Relaxed),
+        warm_done: warmup.done.load(Ordering::Relaxed),
+        warm_failed: warmup.failed.load(Ordering::Relaxed),
+    }
+}
+
+#[get("/thumbnail-cache")]
+async fn thumbnail_cache_status(
+    req: HttpRequest,
+    config: Data<Config>,
+    cache: Data<Mutex<ThumbnailCache>>,
+    warmup: Data<ThumbnailWarmup>,
+) -> Result<Json<ThumbnailCacheStatus>> {
+    check_admin(&req, &config)?;
+    Ok(Json(cache_status(&cache, &warmup)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RebuildThumbnailCacheQuery {
+    /// Regenerate the thumbnails that were cached before purging.
+    #[serde(default)]
+    warm: bool,
+}
+/// Purge the thumbnail cache, and optionally regenerate it in the background.
+#[post("/thumbnail-cache/rebuild")]
+async fn rebuild_thumbnail_cache(
+    req: HttpRequest,
+    query: web::Query<RebuildThumbnailCacheQuery>,
+    config: Data<Config>,
+    cache: Data<Mutex<ThumbnailCache>>,
+    semaphore: Data<Semaphore>,
+    warmup: Data<ThumbnailWarmup>,
+) -> Result<Json<ThumbnailCacheStatus>> {
+    check_admin(&req, &config)?;
+    check_writable(&config)?;
+
+    let keys: Vec<_> = cache.lock().unwrap().drain().map(|(k, _)| k).collect();
+    info!("thumbnail cache purged: {} entries", keys.len());
+
+    if query.warm && !keys.is_empty() && !warmup.running.swap(true, Ordering::SeqCst) {
+        warmup.total.store(keys.len(), Ordering::Relaxed);
+        warmup.done.store(0, Ordering::Relaxed);
+        warmup.failed.store(0, Ordering::Relaxed);
+        let quality = config.server.thumbnail_jpeg_quality;
+        let (cache, semaphore, warmup) = (cache.clone(), semaphore.clone(), warmup.clone());
+        // The thumbnail future is not `Send`, run it on the worker of this request.
+        actix_web::rt::spawn(async move {
+            for k in keys {
+                // Goes through the same semaphore as requests, so serving is not starved.
+                if let Err(e) = cached_image_thumbnail(
+                    &k.local_path,
+                    k.size,
+                    &cache,
+                    &semaphore,
+                    quality,
+                    k.target_ratio.map(|t| t as f32 / 100.0),
+                )
+                .await
+                {
+                    warn!("cannot regenerate thumbnail {:?}: {}", k.local_path, e);
+                    warmup.failed.fetch_add(1, Ordering::Relaxed);
+                }
+                warmup.done.fetch_add(1, Ordering::Relaxed);
+            }
+            warmup.running.store(false, Ordering::SeqCst);
+            info!("thumbnail cache warmed up");
+        });
+    }
+
+    Ok(Json(cache_status(&cache, &warmup)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ExportQuery {
+    /// Continue after the document with this `_id`, the `last_id` of the previous export.
+    after: Option<String>,
+    /// Stop after this many documents, to export in chunks. `0` for no limit.
+    limit: Option<u64>,
+    format: Option<ExportFormat>,
+    /// Count the documents left before sending them, a scan of the collection.
+    #[serde(default)]
+    count: bool,
+}
+/// Export a collection as lines of JSON, in the order of `_id`.
+///
+/// The last line is `{"$export": {...}}` with the count and the `_id` to resume from.
+/// `X-Export-Total` estimates the size of the collection. With `count`,
+/// `X-Export-Remaining` tells how many documents are left before this request.
+#[get("/export/{collection}")]
+async fn export_collection(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<ExportQuery>,
+    config: Data<Config>,
+    db: Data<Database>,
+) -> Result<HttpResponse> {
+    check_admin(&req, &config)?;
+    let name = path.into_inner().0;
+    if !db
+        .list_collection_names(None)
+        .await
+        .with_interal()?
+        .contains(&name)
+    {
+        return Err(Error::not_found());
+    }
+    let after = match query.after.as_deref() {
+        Some(after) => Some(
+            parse_after(after)
+                .ok_or_else(|| Error::with_msg(StatusCode::BAD_REQUEST, "invalid after"))?,
+        ),
+        None => None,
+    };
+
+    let collection = db.collection::<Document>(&name);
+    let total = collection
+        .estimated_document_count(None)
+        .await
+        .with_interal()?;
+    let mut res = HttpResponse::Ok();
+    res.content_type("application/x-ndjson")
+        .append_header(("X-Export-Total", total));
+    if query.count {
+        let remaining = collection
+            .count_documents(
+                filter_after(after.as_ref()),
+                CountOptions::builder()
+                    .max_time(config.server.query_timeout())
+                    .build(),
+            )
+            .await
+            .with_query()?;
+        res.append_header(("X-Export-Remaining", remaining));
+    }
+    Ok(res.streaming(ndjson_stream(
+        collection,
+        after,
+        query.limit.filter(|l| *l != 0),
+        query.format.unwrap_or(ExportFormat::Json),
+    )))
+}
+
+/// What the downloader held by the server is doing.
+#[get("/download-queue")]
+async fn download_queue(
+    req: HttpRequest,
+    config: Data<Config>,
+    downloader: Data<Option<Arc<dyn Downloader>>>,
+) -> Result<Json<QueueState>> {
+    check_admin(&req, &config)?;
+    let downloader = downloader.get_ref().as_ref().ok_or_else(|| {
+        Error::with_msg(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the server does not hold a downloader",
+        )
+    })?;
+    Ok(Json(downloader.queue_state().await.with_interal()?))
+}
@@ -0,0 +1,174 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::Stream;
+use std::{io, path::PathBuf};
+use tokio::{io::AsyncReadExt, sync::mpsc};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+// Bit 3: sizes and crc are in the data descriptor, bit 11: names are UTF-8.
+const FLAGS: u16 = 0x0008 | 0x0800;
+// 1980-01-01 00:00:00 in MS-DOS format.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+struct CentralEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "archive is too large without zip64")
+}
+
+/// Stream the files as an uncompressed zip archive.
+///
+/// Sizes and checksums are written after the data of each file,
+/// so no file is read twice and only one chunk is kept in memory.
+pub fn zip_stream(entries: Vec<(String, PathBuf)>) -> impl Stream<Item = io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        if let Err(e) = write_zip(entries, &tx).await {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|b| (b, rx)) })
+}
+
+async fn write_zip(
+    entries: Vec<(String, PathBuf)>,
+    tx: &mpsc::Sender<io::Result<Bytes>>,
+) -> io::Result<()> {
+    let closed = |_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected");
+    let mut offset: u64 = 0;
+    let mut central = Vec::with_capacity(entries.len());
+
+    for (name, path) in entries {
+        let mut file = tokio::fs::File::open(&path).await?;
+
+        let mut header = BytesMut::with_capacity(30 + name.len());
+        header.put_u32_le(0x04034b50);
+        header.put_u16_le(20);
+        header.put_u16_le(FLAGS);
+        header.put_u16_le(0); // stored
+        header.put_u16_le(DOS_TIME);
+        header.put_u16_le(DOS_DATE);
+        header.put_u32_le(0);
+        header.put_u32_le(0);
+        header.put_u32_le(0);
+        header.put_u16_le(name.len() as u16);
+        header.put_u16_le(0);
+        header.put_slice(name.as_bytes());
+        let header_offset = u32::try_from(offset).map_err(|_| too_large())?;
+        offset += header.len() as u64;
+        tx.send(Ok(header.freeze())).await.map_err(closed)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size: u64 = 0;
+        loop {
+            let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+            if file.read_buf(&mut buf).await? == 0 {
+                break;
+            }
+            hasher.update(&buf);
+            size += buf.len() as u64;
+            tx.send(Ok(buf.freeze())).await.map_err(closed)?;
+        }
+        let size = u32::try_from(size).map_err(|_| too_large())?;
+        let crc = hasher.finalize();
+
+        let mut descriptor = BytesMut::with_capacity(16);
+        descriptor.put_u32_le(0x08074b50);
+        descriptor.put_u32_le(crc);
+        descriptor.put_u32_le(size);
+        descriptor.put_u32_le(size);
+        offset += size as u64 + descriptor.len() as u64;
+        tx.send(Ok(descriptor.freeze())).await.map_err(closed)?;
+
+        central.push(CentralEntry {
+            name,
+            crc,
+            size,
+            offset: header_offset,
+        });
+    }
+
+    let cd_offset = u32::try_from(offset).map_err(|_| too_large())?;
+    let count = u16::try_from(central.len()).map_err(|_| too_large())?;
+    let mut cd = BytesMut::new();
+    for e in &central {
+        cd.put_u32_le(0x02014b50);
+        cd.put_u16_le(20);
+        cd.put_u16_le(20);
+        cd.put_u16_le(FLAGS);
+        cd.put_u16_le(0);
+        cd.put_u16_le(DOS_TIME);
+        cd.put_u16_le(DOS_DATE);
+        cd.put_u32_le(e.crc);
+        cd.put_u32_le(e.size);
+        cd.put_u32_le(e.size);
+        cd.put_u16_le(e.name.len() as u16);
+        cd.put_u16_le(0);
+        cd.put_u16_le(0);
+        cd.put_u16_le(0);
+        cd.put_u16_le(0);
+        cd.put_u32_le(0);
+        cd.put_u32_le(e.offset);
+        cd.put_slice(e.name.as_bytes());
+    }
+    let cd_size = cd.len() as u32;
+    cd.put_u32_le(0x06054b50);
+    cd.put_u16_le(0);
+    cd.put_u16_le(0);
+    cd.put_u16_le(count);
+    cd.put_u16_le(count);
+    cd.put_u32_le(cd_size);
+    cd.put_u32_le(cd_offset);
+    cd.put_u16_le(0);
+    tx.send(Ok(cd.freeze())).await.map_err(closed)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn read_back() {
+        let dir = std::env::temp_dir().join(format!("bowerbird-zip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let large: Vec<u8> = (0..CHUNK_SIZE * 2 + 7).map(|i| i as u8).collect();
+        let files = [
+            ("1/a.jpg", b"jpeg".to_vec()),
+            ("1/empty.txt", Vec::new()),
+            ("1/大きい.png", large),
+        ];
+        let mut entries = Vec::new();
+        for (i, (name, content)) in files.iter().enumerate() {
+            let path = dir.join(i.to_string());
+            std::fs::write(&path, content).unwrap();
+            entries.push((name.to_string(), path));
+        }
+
+        let chunks: Vec<Bytes> = zip_stream(entries).try_collect().await.unwrap();
+        let mut zip = zip::ZipArchive::new(io::Cursor::new(chunks.concat())).unwrap();
+        assert_eq!(zip.len(), files.len());
+        for (i, (name, content)) in files.iter().enumerate() {
+            let mut file = zip.by_index(i).unwrap();
+            assert_eq!(file.name(), *name);
+            let mut read = Vec::new();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(&read, content, "{name}");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_file() {
+        let entries = vec![("a".to_string(), PathBuf::from("/nonexistent/bowerbird"))];
+        let r: io::Result<Vec<Bytes>> = zip_stream(entries).try_collect().await;
+        assert!(r.is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderValue, CACHE_CONTROL},
+        StatusCode,
+    },
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::sync::Arc;
+
+use crate::config::CacheControlConfig;
+
+/// The groups of endpoints of `server.cache_control`.
+///
+/// A handler can put one in the extensions of its response to override the group of its scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheGroup {
+    Media,
+    Thumbnail,
+    List,
+    Stats,
+}
+
+/// The parsed `server.cache_control`, `None` for the groups without one.
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    media: Option<HeaderValue>,
+    thumbnail: Option<HeaderValue>,
+    list: Option<HeaderValue>,
+    stats: Option<HeaderValue>,
+}
+
+impl CachePolicy {
+    pub fn new(config: &CacheControlConfig) -> Result<Self, String> {
+        let parse = |name: &str, value: &str| {
+            if value.is_empty() {
+                return Ok(None);
+            }
+            HeaderValue::from_str(value)
+                .map(Some)
+                .map_err(|_| format!("invalid server.cache_control.{name}: {value}"))
+        };
+        Ok(Self {
+            media: parse("media", &config.media)?,
+            thumbnail: parse("thumbnail", &config.thumbnail)?,
+            list: parse("list", &config.list)?,
+            stats: parse("stats", &config.stats)?,
+        })
+    }
+
+    fn get(&self, group: CacheGroup) -> Option<&HeaderValue> {
+        match group {
+            CacheGroup::Media => self.media.as_ref(),
+            CacheGroup::Thumbnail => self.thumbnail.as_ref(),
+            CacheGroup::List => self.list.as_ref(),
+            CacheGroup::Stats => self.stats.as_ref(),
+        }
+    }
+}
+
+/// Set the `Cache-Control` of the group on the responses without one.
+///
+/// Only the successful responses are cached, so a file missing now is not missing
+/// in the caches once downloaded.
+pub struct CacheControl {
+    policy: Arc<CachePolicy>,
+    group: CacheGroup,
+}
+
+impl CacheControl {
+    pub fn new(policy: Arc<CachePolicy>, group: CacheGroup) -> Self {
+        Self { policy, group }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CacheControl
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = CacheControlMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CacheControlMiddleware {
+            service,
+            policy: self.policy.clone(),
+            group: self.group,
+        }))
+    }
+}
+
+pub struct CacheControlMiddleware<S> {
+    service: S,
+    policy: Arc<CachePolicy>,
+    group: CacheGroup,
+}
+
+impl<S, B> Service<ServiceRequest> for CacheControlMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let policy = self.policy.clone();
+        let group = self.group;
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            // Marked with the innermost group, so the outer scopes keep it.
+            let group = {
+                let mut extensions = res.response_mut().extensions_mut();
+                match extensions.get::<CacheGroup>().copied() {
+                    Some(inner) => inner,
+                    None => {
+                        extensions.insert(group);
+                        group
+                    }
+                }
+            };
+            let status = res.status();
+            let cacheable = status.is_success() || status == StatusCode::NOT_MODIFIED;
+            if cacheable && !res.headers().contains_key(CACHE_CONTROL) {
+                if let Some(value) = policy.get(group) {
+                    res.headers_mut().insert(CACHE_CONTROL, value.clone());
+                }
+            }
+            Ok(res)
+        })
+    }
+}
@@ -1,5 +1,5 @@
 use actix_web::http::StatusCode;
-use log::error;
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 use std::fmt::{self, Debug, Display};
 
@@ -8,6 +8,9 @@ use crate::error::BoxError;
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Error {
     pub message: String,
+    /// Machine readable kind of the error. The response is JSON if it is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
     #[serde(skip)]
     pub status: StatusCode,
     #[serde(skip)]
@@ -30,6 +33,7 @@ impl Error {
     ) -> Error {
         Error {
             status,
+            code: None,
             message: if print_source {
                 if !message.is_empty() {
                     format!("{message}: {source}")
@@ -46,6 +50,7 @@ impl Error {
     pub fn with_msg(status: StatusCode, message: &str) -> Error {
         Error {
             status,
+            code: None,
             message: message.to_string(),
             source: None,
         }
@@ -54,10 +59,39 @@ impl Error {
     pub fn not_found() -> Error {
         Error::with_msg(StatusCode::NOT_FOUND, "not found in database")
     }
+
+    pub fn read_only() -> Error {
+        Error::with_msg(StatusCode::METHOD_NOT_ALLOWED, "server is read-only")
+    }
+
+    pub fn query_too_expensive(documents: u64) -> Error {
+        Error {
+            code: Some("query_too_expensive".to_string()),
+            ..Error::with_msg(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                &format!("the query scans all the {documents} documents, add a filter or an index"),
+            )
+        }
+    }
+
+    pub fn query_timeout() -> Error {
+        Error {
+            code: Some("query_timeout".to_string()),
+            ..Error::with_msg(
+                StatusCode::GATEWAY_TIMEOUT,
+                "the query took too long, try a narrower filter",
+            )
+        }
+    }
 }
 impl actix_web::error::ResponseError for Error {
     fn error_response(&self) -> actix_web::HttpResponse {
-        actix_web::HttpResponse::build(self.status_code()).body(self.message.clone())
+        let mut res = actix_web::HttpResponse::build(self.status_code());
+        if self.code.is_some() {
+            res.json(self)
+        } else {
+            res.body(self.message.clone())
+        }
     }
 
     fn status_code(&self) -> StatusCode {
@@ -107,6 +141,33 @@ where
     }
 }
 
+/// Error code of MongoDB when `maxTimeMS` is exceeded.
+const MAX_TIME_MS_EXPIRED: i32 = 50;
+
+pub trait MongoErrorExt<T> {
+    /// Like `with_interal`, but a query killed by `maxTimeMS` is a 504.
+    fn with_query(self) -> Result<T, Error>;
+}
+
+fn is_query_timeout(err: &mongodb::error::Error) -> bool {
+    matches!(&*err.kind, mongodb::error::ErrorKind::Command(e) if e.code == MAX_TIME_MS_EXPIRED)
+}
+
+impl<T> MongoErrorExt<T> for Result<T, mongodb::error::Error> {
+    fn with_query(self) -> Result<T, Error> {
+        match self {
+            Err(err) if is_query_timeout(&err) => {
+                warn!("query timed out: {}", err);
+                Err(Error {
+                    source: Some(Box::new(err)),
+                    ..Error::query_timeout()
+                })
+            }
+            r => r.with_interal(),
+        }
+    }
+}
+
 pub struct StrErr(pub &'static str);
 impl Display for StrErr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -0,0 +1,136 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Stream, TryStreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::Serialize;
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    command::export::{to_json, ExportFormat},
+    error::BoxError,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Send what is buffered at least this often, so a slow cursor still shows progress.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The last line of an export, as `{"$export": Trailer}`.
+#[derive(Debug, Serialize)]
+struct Trailer {
+    /// Documents sent by this request.
+    count: u64,
+    /// The `_id` in relaxed extended JSON, e.g. `{"$oid": "..."}`.
+    /// Pass it as `after` to continue the export.
+    last_id: Option<serde_json::Value>,
+    /// False if stopped by the limit or an error.
+    /// An export stopped exactly at the last document is only complete in the next request.
+    complete: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Parse the `last_id` of an export, or the hex of an `ObjectId`.
+/// Anything else not JSON is a string `_id`.
+pub fn parse_after(after: &str) -> Option<Bson> {
+    if let Ok(id) = ObjectId::parse_str(after) {
+        return Some(Bson::ObjectId(id));
+    }
+    match serde_json::from_str::<serde_json::Value>(after) {
+        Ok(value) => Bson::try_from(value).ok(),
+        Err(_) => Some(Bson::String(after.to_string())),
+    }
+}
+
+/// The documents after `after` in the order of `_id`.
+///
+/// Only the `_id`s of the same type as `after` are compared by MongoDB,
+/// so a collection with several types of `_id` is exported one type at a time.
+pub fn filter_after(after: Option<&Bson>) -> Document {
+    match after {
+        Some(id) => doc! { "_id": { "$gt": id } },
+        None => doc! {},
+    }
+}
+
+/// Stream the documents after `after` as lines of JSON, read from the cursor while sending.
+pub fn ndjson_stream(
+    collection: Collection<Document>,
+    after: Option<Bson>,
+    limit: Option<u64>,
+    format: ExportFormat,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let mut trailer = Trailer {
+            count: 0,
+            last_id: after.clone().map(Bson::into_relaxed_extjson),
+            complete: false,
+            error: None,
+        };
+        if let Err(e) = write_ndjson(collection, after, limit, format, &tx, &mut trailer).await {
+            trailer.error = Some(e.to_string());
+        }
+        let mut line =
+            serde_json::to_vec(&serde_json::json!({ "$export": trailer })).unwrap_or_default();
+        line.push(b'\n');
+        let _ = tx.send(Ok(line.into())).await;
+    });
+    futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|b| (b, rx)) })
+}
+
+async fn send(tx: &mpsc::Sender<io::Result<Bytes>>, buf: &mut BytesMut) -> Result<(), BoxError> {
+    tx.send(Ok(buf.split().freeze()))
+        .await
+        .map_err(|_| "client disconnected")?;
+    Ok(())
+}
+
+/// Only the lines already sent are counted in the trailer,
+/// so the export can be resumed from it after an error.
+async fn write_ndjson(
+    collection: Collection<Document>,
+    after: Option<Bson>,
+    limit: Option<u64>,
+    format: ExportFormat,
+    tx: &mpsc::Sender<io::Result<Bytes>>,
+    trailer: &mut Trailer,
+) -> Result<(), BoxError> {
+    let options = FindOptions::builder()
+        .sort(doc! { "_id": 1 })
+        .limit(limit.map(|l| l as i64))
+        .build();
+    let mut cur = collection
+        .find(filter_after(after.as_ref()), options)
+        .await?;
+    let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+    let mut pending = 0;
+    let mut pending_last_id = None;
+    let mut flushed_at = Instant::now();
+    while let Some(d) = cur.try_next().await? {
+        pending_last_id = d.get("_id").cloned();
+        serde_json::to_writer((&mut buf).writer(), &to_json(d, format))?;
+        buf.put_u8(b'\n');
+        pending += 1;
+        if buf.len() >= CHUNK_SIZE || flushed_at.elapsed() >= FLUSH_INTERVAL {
+            send(tx, &mut buf).await?;
+            trailer.count += pending;
+            trailer.last_id = pending_last_id.clone().map(Bson::into_relaxed_extjson);
+            pending = 0;
+            flushed_at = Instant::now();
+        }
+    }
+    if pending > 0 {
+        send(tx, &mut buf).await?;
+        trailer.count += pending;
+        trailer.last_id = pending_last_id.map(Bson::into_relaxed_extjson);
+    }
+    trailer.complete = limit.map_or(true, |l| trailer.count < l);
+    Ok(())
+}
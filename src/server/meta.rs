@@ -0,0 +1,28 @@
+use actix_web::{get, web::Data, web::Json};
+use mongodb::Database;
+use serde::Serialize;
+
+use super::{error::ServerErrorExt, Result};
+use crate::command::migrate::{get_metadata, DB_VERSION};
+
+#[derive(Debug, Serialize)]
+struct Version {
+    version: &'static str,
+    /// The database version required by this build.
+    db_version: i32,
+    /// The version stored in the database, `None` if it is not initialized.
+    db_stored_version: Option<i32>,
+    migration_pending: bool,
+}
+
+/// The versions of the server and the database, to check if a client is compatible.
+#[get("/version")]
+async fn version(db: Data<Database>) -> Result<Json<Version>> {
+    let stored = get_metadata(&db).await.with_interal()?.map(|m| m.version);
+    Ok(Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        db_version: DB_VERSION,
+        db_stored_version: stored,
+        migration_pending: stored.map_or(false, |v| v < DB_VERSION),
+    }))
+}
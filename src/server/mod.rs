@@ -6,59 +6,202 @@ use actix_web::{
 use log::info;
 use mongodb::Database;
 use snafu::ResultExt;
-use std::{path::PathBuf, sync::Mutex};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 use tokio::sync::Semaphore;
 
-use crate::config::Config;
-use utils::ThumbnailCache;
+use crate::{
+    config::{proxy_host, Config, ListenAddr},
+    downloader::Downloader,
+};
+use cache_control::{CacheControl, CacheGroup, CachePolicy};
+use utils::{ThumbnailCache, UgoiraCache};
 
+mod admin;
+mod archive;
+mod cache_control;
 mod error;
+mod export;
+mod meta;
 mod pixiv;
+mod query_guard;
 mod utils;
 
 type Result<T> = std::result::Result<T, error::Error>;
 
 #[derive(Debug, Clone)]
 struct PixivConfig {
-    storage_dir: PathBuf,
+    /// The storage dir then the storage tiers.
+    storage_dirs: Vec<PathBuf>,
+}
+
+impl PixivConfig {
+    /// The file in the first storage dir holding it, or in the storage dir if none does.
+    fn path(&self, local_path: &str) -> PathBuf {
+        crate::utils::find_in_storage_dirs(&self.storage_dirs, local_path)
+            .map_or_else(|| self.storage_dirs[0].join(local_path), |(_, p)| p)
+    }
+}
+
+/// The effective config without secrets, to be logged at startup.
+fn config_summary(config: &Config) -> serde_json::Value {
+    let proxy = |url: &str| config.pxoxy_string(url).map(|p| proxy_host(&p));
+    serde_json::json!({
+        "listen_addr": config.server.listen_addr,
+        "read_only": config.server.read_only,
+        "config_path": config.config_path(),
+        "root_storage_dir": config.root_dir(),
+        "pixiv_storage_dir": config.sub_dir(&config.pixiv.storage_dir),
+        "pixiv_storage_tiers": &config.pixiv_storage_dirs()[1..],
+        "proxy_api": proxy(&config.pixiv.proxy_api),
+        "proxy_download": proxy(&config.pixiv.proxy_download),
+        "database_name": config.mongodb.database_name,
+    })
 }
 
-pub async fn run(db: Database, config: Config) -> crate::Result<()> {
+/// `downloader` is held by the server and shown by the admin endpoints,
+/// `None` if it cannot be created, e.g. aria2 is not installed.
+pub async fn run(
+    db: Database,
+    config: Config,
+    downloader: Option<Arc<dyn Downloader>>,
+) -> crate::Result<()> {
     let thumbnail_cache = Data::new(Mutex::new(ThumbnailCache::new()));
+    let ugoira_cache = Data::new(Mutex::new(UgoiraCache::new(
+        config.server.ugoira_cache_mib * 1024 * 1024,
+    )));
+    let color_histogram_cache = Data::new(Mutex::new(pixiv::ColorHistogramCache::new()));
+    let thumbnail_warmup = Data::new(admin::ThumbnailWarmup::default());
     let pixiv_config = Data::new(PixivConfig {
-        storage_dir: config.sub_dir(&config.pixiv.storage_dir),
+        storage_dirs: config.pixiv_storage_dirs(),
     });
     let db = Data::new(db);
+    let downloader = Data::new(downloader);
 
     let cpu_workers_sem = Data::new(Semaphore::new(num_cpus::get()));
+    let cache_policy = Arc::new(
+        CachePolicy::new(&config.server.cache_control)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            .context(crate::error::ServerIo)?,
+    );
 
-    info!("server listening on http://{}", config.server.listen_addr);
-    HttpServer::new({
+    if crate::cli::json_log_enabled() {
+        info!(target: "bowerbird::config", "{}", config_summary(&config));
+    }
+    if config.server.read_only {
+        info!("server is read-only, endpoints writing data are disabled");
+    }
+    let server = HttpServer::new({
         let config = Data::new(config.clone());
         move || {
+            let cache_control = |group| CacheControl::new(cache_policy.clone(), group);
+            // Not found in a storage dir, the next one is tried.
+            let files = pixiv_config
+                .storage_dirs
+                .iter()
+                .rev()
+                .fold(None, |next: Option<Files>, dir| {
+                    let files = Files::new("", dir.clone());
+                    Some(match next {
+                        Some(next) => files.default_handler(next),
+                        None => files,
+                    })
+                })
+                .unwrap();
+            let scope_storage = web::scope("/storage")
+                .wrap(cache_control(CacheGroup::Media))
+                .service(files);
             let scope_pixiv = web::scope("/pixiv")
-                .service(Files::new("/storage", pixiv_config.storage_dir.clone()))
+                .wrap(cache_control(CacheGroup::List))
+                .service(scope_storage)
                 .service(pixiv::thumbnail)
                 .service(pixiv::find_illust)
+                .service(pixiv::illust_neighbors)
                 .service(pixiv::find_tag)
                 .service(pixiv::media_by_url)
                 .service(pixiv::find_user)
-                .service(pixiv::find_image_media);
+                .service(pixiv::user_illusts)
+                .service(pixiv::find_image_media)
+                .service(pixiv::color_histogram)
+                .service(pixiv::find_media_by_hash)
+                .service(pixiv::find_media_by_file)
+                .service(pixiv::find_similar_media)
+                .service(pixiv::illust_archive)
+                .service(pixiv::illust_media)
+                .service(pixiv::illust_palette)
+                .service(pixiv::illust_ugoira)
+                .service(pixiv::illust_ugoira_zip)
+                .service(pixiv::series);
 
-            let scope_v1 = web::scope("/api/v1").service(scope_pixiv);
+            let scope_admin = web::scope("/admin")
+                .service(admin::thumbnail_cache_status)
+                .service(admin::rebuild_thumbnail_cache)
+                .service(admin::export_collection)
+                .service(admin::download_queue);
+
+            let scope_v1 = web::scope("/api/v1")
+                .wrap(cache_control(CacheGroup::Stats))
+                .service(meta::version)
+                .service(scope_pixiv)
+                .service(scope_admin);
 
             App::new()
                 .app_data(db.clone())
                 .app_data(thumbnail_cache.clone())
+                .app_data(ugoira_cache.clone())
+                .app_data(color_histogram_cache.clone())
+                .app_data(thumbnail_warmup.clone())
                 .app_data(pixiv_config.clone())
+                .app_data(downloader.clone())
                 .app_data(cpu_workers_sem.clone())
+                // For the images uploaded to search.
+                .app_data(web::PayloadConfig::new(64 * 1024 * 1024))
                 .app_data(config.clone())
                 .service(scope_v1)
         }
-    })
-    .bind(config.server.listen_addr)
-    .context(crate::error::ServerIo)?
-    .run()
-    .await
-    .context(crate::error::ServerIo)
+    });
+    let server = match &config.server.listen_addr {
+        ListenAddr::Tcp(addr) => {
+            let server = server.bind(addr).context(crate::error::ServerIo)?;
+            info!("server listening on http://{}", addr);
+            server
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            use std::os::unix::{
+                fs::{FileTypeExt, PermissionsExt},
+                net::UnixStream,
+            };
+            let mode = config
+                .server
+                .unix_socket_mode()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+                .context(crate::error::ServerIo)?;
+            // Left by a server not stopped cleanly. A socket still accepting is kept,
+            // and binding fails as the address is in use.
+            let stale = std::fs::symlink_metadata(path)
+                .map_or(false, |m| m.file_type().is_socket())
+                && UnixStream::connect(path).is_err();
+            if stale {
+                std::fs::remove_file(path).context(crate::error::ServerIo)?;
+            }
+            let server = server.bind_uds(path).context(crate::error::ServerIo)?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .context(crate::error::ServerIo)?;
+            info!("server listening on unix:{}", path.to_string_lossy());
+            server
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            ))
+            .context(crate::error::ServerIo)
+        }
+    };
+    server.run().await.context(crate::error::ServerIo)
 }
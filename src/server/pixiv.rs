@@ -1,36 +1,48 @@
+use actix_files::NamedFile;
 use actix_web::{
     get,
     http::{
-        header::{self, CacheDirective, ContentType},
+        header::{self, ContentType},
         StatusCode,
     },
     post,
     web::{self, Data, Json},
     HttpRequest, HttpResponse,
 };
-use bson::{doc, oid::ObjectId, to_document, Document};
+use bson::{doc, oid::ObjectId, to_bson, to_document, Bson, Document};
 use chrono::{DateTime, Utc};
 use futures::TryStreamExt;
 use indexmap::IndexMap;
+use lazy_static::lazy_static;
 use log::debug;
 use mongodb::{
-    options::{FindOneOptions, FindOptions},
-    Database,
+    options::{AggregateOptions, CountOptions, FindOneOptions, FindOptions},
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
-use serde::Deserialize;
-use std::sync::Mutex;
 use tokio::sync::Semaphore;
 
 use super::{
+    archive::zip_stream,
+    cache_control::CacheGroup,
     error::*,
-    utils::{build_search_regex, cached_image_thumbnail, ThumbnailCache},
+    query_guard::guard_query,
+    utils::{
+        build_search_regex, cached_image_thumbnail, cached_ugoira_gif, file_validators, is_fresh,
+        ApiJson, ThumbnailCache, UgoiraCache,
+    },
     PixivConfig, Result,
 };
 use crate::{
     config::Config,
     model::{
-        pixiv::{PixivIllust, PixivUser},
-        ImageMedia, LocalMedia, Tag,
+        pixiv::{BookmarkVisibility, PixivIllust, PixivUser, Series},
+        LocalMedia, MediaExtension, Tag,
     },
 };
 
@@ -75,9 +87,7 @@ async fn thumbnail(
     if req.headers().get(header::RANGE).is_some() {
         return Ok(HttpResponse::NotImplemented().finish());
     }
-    let path = pixiv_config
-        .storage_dir
-        .join(path.0.replace("../", "").replace("..\\", ""));
+    let path = pixiv_config.path(&path.0.replace("../", "").replace("..\\", ""));
 
     let img = cached_image_thumbnail(
         path,
@@ -93,16 +103,18 @@ async fn thumbnail(
     )
     .await?;
 
-    Ok(HttpResponse::Ok()
+    let res = HttpResponse::Ok()
         .content_type(ContentType::jpeg())
-        .append_header(header::CacheControl(vec![CacheDirective::MaxAge(604800)]))
-        .body(img))
+        .body(img);
+    res.extensions_mut().insert(CacheGroup::Thumbnail);
+    Ok(res)
 }
 
 async fn media_redirect(
     db: &Database,
     filter: Document,
     to_thumbnail: bool,
+    original: bool,
     query: &str,
 ) -> Result<HttpResponse> {
     if let Some(r) = db
@@ -110,13 +122,21 @@ async fn media_redirect(
         .find_one(
             filter,
             FindOneOptions::builder()
-                .projection(doc! {"local_path": true})
+                .projection(doc! {"local_path": true, "extension.derivative.local_path": true})
                 .build(),
         )
         .await
         .with_interal()?
     {
-        let path = r.get_str("local_path").with_interal()?;
+        // Serve the derivative unless the original is asked for.
+        let derivative = r
+            .get_document("extension")
+            .and_then(|e| e.get_document("derivative"))
+            .and_then(|d| d.get_str("local_path"));
+        let path = match derivative {
+            Ok(path) if !original => path,
+            _ => r.get_str("local_path").with_interal()?,
+        };
         let url = if to_thumbnail {
             format!("thumbnail/{path}?{query}")
         } else {
@@ -134,6 +154,9 @@ async fn media_redirect(
 struct MediaByUrlQuery {
     url: String,
     size: Option<u32>,
+    /// Redirect to the original file even if it has a derivative.
+    #[serde(default)]
+    original: bool,
 }
 #[get("/media-by-url")]
 async fn media_by_url(
@@ -145,6 +168,7 @@ async fn media_by_url(
         db.as_ref(),
         doc! { "url": &query.url },
         query.size.is_some(),
+        query.original,
         req.query_string(),
     )
     .await
@@ -159,8 +183,9 @@ struct FindImageMediaForm {
 #[post("/find/media/image")]
 async fn find_image_media(
     db: Data<Database>,
+    config: Data<Config>,
     form: Json<FindImageMediaForm>,
-) -> Result<Json<Vec<LocalMedia<ImageMedia>>>> {
+) -> Result<ApiJson<Vec<LocalMedia<MediaExtension>>>> {
     let mut m = Document::new();
 
     if let Some(h_range) = form.h_range {
@@ -181,12 +206,458 @@ async fn find_image_media(
 
     let cur = db
         .collection("pixiv_image")
-        .find(m, FindOptions::builder().sort(doc! {"_id": -1}).build())
+        .find(
+            m,
+            FindOptions::builder()
+                .sort(doc! {"_id": -1})
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
         .await
-        .with_interal()?;
+        .with_query()?;
+
+    let rv = cur.try_collect().await.with_query()?;
+    Ok(ApiJson(rv))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ColorHistogramForm {
+    /// The number of hue bins, 12 by default.
+    bins: Option<u32>,
+    /// The colors less saturated or darker are counted as achromatic.
+    min_s: Option<f32>,
+    min_v: Option<f32>,
+    /// Only the images of these illusts. All the images if empty.
+    #[serde(flatten)]
+    filter: IllustFilter,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct ColorBin {
+    hue_start: f32,
+    hue_end: f32,
+    /// The color at the middle of the bin, e.g. `#ff8000`.
+    color: String,
+    count: i64,
+}
+/// The main colors of the images by hue, ordered by hue.
+#[derive(Debug, Clone, Serialize)]
+pub struct ColorHistogram {
+    bins: Vec<ColorBin>,
+    achromatic: i64,
+    total: i64,
+    computed_at: DateTime<Utc>,
+}
+/// The histograms computed before, keyed by the form.
+pub type ColorHistogramCache = HashMap<String, (Instant, ColorHistogram)>;
+/// Count the main colors of the images by hue.
+///
+/// It goes through every image, so the results are kept for `server.color_histogram_ttl_secs`.
+#[post("/stats/color-histogram")]
+async fn color_histogram(
+    db: Data<Database>,
+    config: Data<Config>,
+    cache: Data<Mutex<ColorHistogramCache>>,
+    form: Json<ColorHistogramForm>,
+) -> Result<ApiJson<ColorHistogram>> {
+    let form = form.into_inner();
+    let key = format!("{:?}", form);
+    let ttl = Duration::from_secs(config.server.color_histogram_ttl_secs);
+    if let Some((at, histogram)) = cache.lock().unwrap().get(&key) {
+        if at.elapsed() < ttl {
+            return Ok(ApiJson(histogram.clone()));
+        }
+    }
+
+    let bins = form.bins.unwrap_or(12).clamp(1, 360);
+    let bin_width = 360.0 / bins as f64;
+    let filter = form.filter.into_document(&config);
+    // The main color of each image, from the illusts matching the filter if any.
+    let (collection, mut pipeline) = if filter.is_empty() {
+        (
+            "pixiv_image",
+            vec![doc! { "$project": {
+                "color": { "$arrayElemAt": ["$extension.palette_hsv", 0] },
+            }}],
+        )
+    } else {
+        (
+            "pixiv_illust",
+            vec![
+                doc! { "$match": filter },
+                doc! { "$project": {
+                    "url": { "$arrayElemAt": ["$history.extension.image_urls", -1] },
+                }},
+                doc! { "$unwind": "$url" },
+                doc! { "$lookup": {
+                    "from": "pixiv_image",
+                    "localField": "url",
+                    "foreignField": "url",
+                    "as": "image",
+                }},
+                doc! { "$project": {
+                    "color": { "$arrayElemAt": [
+                        { "$arrayElemAt": ["$image.extension.palette_hsv", 0] },
+                        0,
+                    ]},
+                }},
+            ],
+        )
+    };
+    pipeline.extend([
+        doc! { "$match": { "color": { "$exists": true } } },
+        doc! { "$group": {
+            "_id": { "$cond": [
+                { "$or": [
+                    { "$lt": ["$color.s", form.min_s.unwrap_or(0.2)] },
+                    { "$lt": ["$color.v", form.min_v.unwrap_or(0.2)] },
+                ]},
+                -1,
+                { "$mod": [{ "$floor": { "$divide": ["$color.h", bin_width] } }, bins] },
+            ]},
+            "count": { "$sum": 1 },
+        }},
+    ]);
+    let counts: Vec<Document> = db
+        .collection::<Document>(collection)
+        .aggregate(
+            pipeline,
+            AggregateOptions::builder()
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?
+        .try_collect()
+        .await
+        .with_query()?;
+
+    let mut histogram = ColorHistogram {
+        bins: (0..bins)
+            .map(|i| {
+                let hue_start = (i as f64 * bin_width) as f32;
+                let hue_end = ((i + 1) as f64 * bin_width) as f32;
+                let (r, g, b) = crate::utils::hsv_to_rgb((hue_start + hue_end) / 2.0, 1.0, 1.0);
+                ColorBin {
+                    hue_start,
+                    hue_end,
+                    color: format!("#{r:02x}{g:02x}{b:02x}"),
+                    count: 0,
+                }
+            })
+            .collect(),
+        achromatic: 0,
+        total: 0,
+        computed_at: Utc::now(),
+    };
+    for d in counts {
+        // The numbers may be of any type, depending on the server.
+        let as_i64 = |b: Option<&Bson>| match b {
+            Some(Bson::Int32(i)) => Some(*i as i64),
+            Some(Bson::Int64(i)) => Some(*i),
+            Some(Bson::Double(f)) => Some(*f as i64),
+            _ => None,
+        };
+        let (bin, count) = match (as_i64(d.get("_id")), as_i64(d.get("count"))) {
+            (Some(bin), Some(count)) => (bin, count),
+            _ => continue,
+        };
+        histogram.total += count;
+        match usize::try_from(bin)
+            .ok()
+            .and_then(|i| histogram.bins.get_mut(i))
+        {
+            Some(bin) => bin.count += count,
+            None => histogram.achromatic += count,
+        }
+    }
+
+    if !ttl.is_zero() {
+        let mut cache = cache.lock().unwrap();
+        cache.retain(|_, (at, _)| at.elapsed() < ttl);
+        cache.insert(key, (Instant::now(), histogram.clone()));
+    }
+    Ok(ApiJson(histogram))
+}
+
+lazy_static! {
+    /// Match the illust id in a pximg URL, e.g. `/92187206_p0.jpg` or `/92187206_ugoira1920x1080.zip`.
+    static ref RE_ILLUST_ID: regex::Regex = regex::Regex::new(r"/(\d+)_(?:p\d+|ugoira)").unwrap();
+}
+
+#[derive(Debug, Serialize)]
+struct HashMatch {
+    media: LocalMedia<MediaExtension>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    illust: Option<PixivIllust>,
+    /// Number of different bits of the dhash, only for similar matches.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<u32>,
+}
+
+const DEFAULT_MAX_DISTANCE: u32 = 10;
+const MAX_SIMILAR_MATCHES: usize = 50;
+
+/// Every query is stopped after `timeout`.
+async fn hash_matches(
+    db: &Database,
+    sha256: Option<&str>,
+    dhash: Option<(u64, u32)>,
+    timeout: Option<Duration>,
+) -> Result<Vec<HashMatch>> {
+    let find_one = || FindOneOptions::builder().max_time(timeout).build();
+    let c_image = db.collection::<LocalMedia<MediaExtension>>("pixiv_image");
+    let mut found: Vec<(LocalMedia<MediaExtension>, Option<u32>)> = Vec::new();
+    if let Some(sha256) = sha256 {
+        let mut cur = c_image
+            .find(
+                doc! { "sha256": sha256.to_ascii_lowercase() },
+                FindOptions::builder().max_time(timeout).build(),
+            )
+            .await
+            .with_query()?;
+        while let Some(m) = cur.try_next().await.with_query()? {
+            found.push((m, None));
+        }
+    }
+    if let Some((dhash, max_distance)) = dhash {
+        // Hamming distance cannot be queried, so scan the hashes only.
+        let mut cur = db
+            .collection::<Document>("pixiv_image")
+            .find(
+                doc! { "extension.dhash": { "$exists": true } },
+                FindOptions::builder()
+                    .projection(doc! { "_id": 1, "extension.dhash": 1 })
+                    .max_time(timeout)
+                    .build(),
+            )
+            .await
+            .with_query()?;
+        let mut near = Vec::new();
+        while let Some(d) = cur.try_next().await.with_query()? {
+            let other = d
+                .get_document("extension")
+                .and_then(|e| e.get_i64("dhash"))
+                .with_interal()?;
+            let distance = (dhash ^ other as u64).count_ones();
+            if distance <= max_distance {
+                near.push((distance, d.get_object_id("_id").with_interal()?));
+            }
+        }
+        near.sort_by_key(|(distance, _)| *distance);
+        near.truncate(MAX_SIMILAR_MATCHES);
+        for (distance, id) in near {
+            if let Some(m) = c_image
+                .find_one(doc! { "_id": id }, find_one())
+                .await
+                .with_query()?
+            {
+                found.push((m, Some(distance)));
+            }
+        }
+    }
+
+    let c_illust = db.collection::<PixivIllust>("pixiv_illust");
+    let mut rv = Vec::with_capacity(found.len());
+    for (media, distance) in found {
+        let illust_id = media
+            .url
+            .as_deref()
+            .and_then(|url| RE_ILLUST_ID.captures(url))
+            .map(|c| c[1].to_string());
+        let illust = match illust_id {
+            Some(id) => c_illust
+                .find_one(doc! { "source_id": id }, find_one())
+                .await
+                .with_query()?,
+            None => None,
+        };
+        rv.push(HashMatch {
+            media,
+            illust,
+            distance,
+        });
+    }
+    Ok(rv)
+}
+
+fn parse_dhash(dhash: &str) -> Result<u64> {
+    u64::from_str_radix(dhash, 16).with_msg(StatusCode::BAD_REQUEST, "dhash must be 16 hex digits")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FindMediaByHashForm {
+    sha256: Option<String>,
+    /// In hex.
+    dhash: Option<String>,
+    max_distance: Option<u32>,
+}
+/// Find the media with the same SHA-256, or with a similar dhash.
+#[post("/find/media/hash")]
+async fn find_media_by_hash(
+    db: Data<Database>,
+    config: Data<Config>,
+    form: Json<FindMediaByHashForm>,
+) -> Result<ApiJson<Vec<HashMatch>>> {
+    let dhash = match form.dhash {
+        Some(ref dhash) => Some((
+            parse_dhash(dhash)?,
+            form.max_distance.unwrap_or(DEFAULT_MAX_DISTANCE),
+        )),
+        None => None,
+    };
+    if form.sha256.is_none() && dhash.is_none() {
+        return Err(Error::with_msg(
+            StatusCode::BAD_REQUEST,
+            "sha256 or dhash is required",
+        ));
+    }
+    Ok(ApiJson(
+        hash_matches(
+            &db,
+            form.sha256.as_deref(),
+            dhash,
+            config.server.query_timeout(),
+        )
+        .await?,
+    ))
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum HashMode {
+    Exact,
+    Similar,
+}
+#[derive(Debug, Clone, Deserialize)]
+struct FindMediaByFileQuery {
+    mode: HashMode,
+    max_distance: Option<u32>,
+}
+/// Find the media matching the file in the request body.
+#[post("/find/media/file")]
+async fn find_media_by_file(
+    db: Data<Database>,
+    config: Data<Config>,
+    query: web::Query<FindMediaByFileQuery>,
+    semaphore: Data<Semaphore>,
+    body: web::Bytes,
+) -> Result<ApiJson<Vec<HashMatch>>> {
+    let rv = match query.mode {
+        HashMode::Exact => {
+            use sha2::{Digest, Sha256};
+            let sha256 = hex::encode(Sha256::digest(&body));
+            hash_matches(&db, Some(&sha256), None, config.server.query_timeout()).await?
+        }
+        HashMode::Similar => {
+            let _permit = semaphore.acquire().await.with_interal()?;
+            let dhash = actix_web::rt::task::spawn_blocking(move || {
+                image::load_from_memory(&body).map(|img| crate::command::pixiv::utils::dhash(&img))
+            })
+            .await
+            .with_interal()?
+            .with_msg_source(StatusCode::BAD_REQUEST, "cannot decode image")?;
+            let max_distance = query.max_distance.unwrap_or(DEFAULT_MAX_DISTANCE);
+            hash_matches(
+                &db,
+                None,
+                Some((dhash, max_distance)),
+                config.server.query_timeout(),
+            )
+            .await?
+        }
+    };
+    Ok(ApiJson(rv))
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageEmbedding {
+    local_path: String,
+    vector: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FindSimilarMediaForm {
+    media_id: ObjectId,
+    limit: Option<usize>,
+}
+#[derive(Debug, Serialize)]
+struct SimilarMedia {
+    media: LocalMedia<MediaExtension>,
+    /// Cosine similarity of the embeddings, 1 for the same image.
+    score: f32,
+}
+const DEFAULT_SIMILAR_LIMIT: usize = 20;
+
+/// Find the media similar to the given one by the embeddings saved on download.
+///
+/// All the embeddings are compared, there is no index for nearest neighbors.
+#[post("/find/media/similar")]
+async fn find_similar_media(
+    db: Data<Database>,
+    config: Data<Config>,
+    form: Json<FindSimilarMediaForm>,
+) -> Result<ApiJson<Vec<SimilarMedia>>> {
+    let limit = form
+        .limit
+        .unwrap_or(DEFAULT_SIMILAR_LIMIT)
+        .min(MAX_SIMILAR_MATCHES);
+    let c_image = db.collection::<LocalMedia<MediaExtension>>("pixiv_image");
+    let c_embedding = db.collection::<ImageEmbedding>("pixiv_image_embedding");
+    let media = c_image
+        .find_one(doc! { "_id": form.media_id }, None)
+        .await
+        .with_interal()?
+        .ok_or_else(Error::not_found)?;
+    let target = c_embedding
+        .find_one(doc! { "local_path": &media.local_path }, None)
+        .await
+        .with_interal()?
+        .ok_or_else(|| Error::with_msg(StatusCode::NOT_FOUND, "the media has no embedding"))?;
+
+    let mut cur = c_embedding
+        .find(
+            doc! { "local_path": { "$ne": &media.local_path } },
+            FindOptions::builder()
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?;
+    let mut scores = Vec::new();
+    while let Some(e) = cur.try_next().await.with_query()? {
+        if e.vector.len() != target.vector.len() {
+            continue;
+        }
+        // The vectors are normalized when saved.
+        let score: f32 = e
+            .vector
+            .iter()
+            .zip(&target.vector)
+            .map(|(a, b)| a * b)
+            .sum();
+        scores.push((score, e.local_path));
+    }
+    scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scores.truncate(limit);
 
-    let rv = cur.try_collect().await.with_interal()?;
-    Ok(Json(rv))
+    let paths: Vec<&str> = scores.iter().map(|(_, p)| p.as_str()).collect();
+    let mut found: HashMap<String, LocalMedia<MediaExtension>> = c_image
+        .find(doc! { "local_path": { "$in": paths } }, None)
+        .await
+        .with_interal()?
+        .map_ok(|m| (m.local_path.clone(), m))
+        .try_collect()
+        .await
+        .with_interal()?;
+    let rv = scores
+        .into_iter()
+        .filter_map(|(score, path)| {
+            found
+                .remove(&path)
+                .map(|media| SimilarMedia { media, score })
+        })
+        .collect();
+    Ok(ApiJson(rv))
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -200,8 +671,19 @@ struct FindUserForm {
     skip: u32,
     limit: u32,
 }
+#[derive(Debug, Serialize)]
+struct UserWithMedia {
+    #[serde(flatten)]
+    user: PixivUser,
+    /// The downloaded images of the latest history, e.g. the profile and workspace images.
+    media: Vec<LocalMedia<MediaExtension>>,
+}
 #[post("/find/user")]
-async fn find_user(db: Data<Database>, form: Json<FindUserForm>) -> Result<Json<Vec<PixivUser>>> {
+async fn find_user(
+    db: Data<Database>,
+    config: Data<Config>,
+    form: Json<FindUserForm>,
+) -> Result<ApiJson<Vec<UserWithMedia>>> {
     let form = form.into_inner();
 
     sort_by_guard(&form.sort_by)?;
@@ -233,21 +715,136 @@ async fn find_user(db: Data<Database>, form: Json<FindUserForm>) -> Result<Json<
         }
     }
 
-    let rv = db
+    let users: Vec<PixivUser> = db
         .collection("pixiv_user")
         .find(
             filter,
             FindOptions::builder()
                 .sort(parse_sort_by(form.sort_by))
                 .limit(form.limit as i64)
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?
+        .try_collect()
+        .await
+        .with_query()?;
+
+    let latest_urls = |user: &PixivUser| -> Vec<String> {
+        user.history
+            .last()
+            .and_then(|h| h.extension.as_ref())
+            .map_or_else(Vec::new, |h| h.image_urls().into_iter().cloned().collect())
+    };
+    let urls: Vec<String> = users.iter().flat_map(latest_urls).collect();
+    let mut media: HashMap<String, LocalMedia<MediaExtension>> = HashMap::new();
+    if !urls.is_empty() {
+        let mut cursor = db
+            .collection::<LocalMedia<MediaExtension>>("pixiv_image")
+            .find(
+                doc! { "url": { "$in": urls } },
+                FindOptions::builder()
+                    .max_time(config.server.query_timeout())
+                    .build(),
+            )
+            .await
+            .with_query()?;
+        while let Some(m) = cursor.try_next().await.with_query()? {
+            if let Some(url) = m.url.clone() {
+                media.insert(url, m);
+            }
+        }
+    }
+    let rv = users
+        .into_iter()
+        .map(|user| UserWithMedia {
+            media: latest_urls(&user)
+                .iter()
+                .filter_map(|u| media.get(u).cloned())
+                .collect(),
+            user,
+        })
+        .collect();
+    Ok(ApiJson(rv))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UserIllustsForm {
+    /// The pixiv id of the user.
+    user_id: String,
+    sort_by: Option<SortBy>,
+    skip: u32,
+    /// At most [`MAX_USER_ILLUSTS`], which `0` also means.
+    limit: u32,
+}
+const MAX_USER_ILLUSTS: u32 = 100;
+#[derive(Debug, Serialize)]
+struct UserIllusts {
+    total: u64,
+    has_more: bool,
+    illusts: Vec<PixivIllust>,
+}
+#[post("/find/user/illusts")]
+async fn user_illusts(
+    db: Data<Database>,
+    config: Data<Config>,
+    form: Json<UserIllustsForm>,
+) -> Result<ApiJson<UserIllusts>> {
+    let form = form.into_inner();
+
+    sort_by_guard(&form.sort_by)?;
+
+    let user = db
+        .collection::<Document>("pixiv_user")
+        .find_one(
+            doc! { "source_id": &form.user_id },
+            FindOneOptions::builder()
+                .projection(doc! {"_id": true})
                 .build(),
         )
         .await
         .with_interal()?
+        .ok_or_else(Error::not_found)?;
+    let mut filter = doc! { "parent_id": user.get_object_id("_id").with_interal()? };
+    if let Some(visibility) = visibility_filter(None, &config) {
+        filter.insert("extension.bookmark_visibility", visibility);
+    }
+    let limit = match form.limit {
+        0 => MAX_USER_ILLUSTS,
+        limit => limit.min(MAX_USER_ILLUSTS),
+    };
+
+    let c_illust = db.collection::<PixivIllust>("pixiv_illust");
+    let total = c_illust
+        .count_documents(
+            filter.clone(),
+            CountOptions::builder()
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?;
+    let illusts: Vec<_> = c_illust
+        .find(
+            filter,
+            FindOptions::builder()
+                .sort(parse_sort_by(form.sort_by))
+                .skip(form.skip as u64)
+                .limit(limit as i64)
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?
         .try_collect()
         .await
-        .with_interal()?;
-    Ok(Json(rv))
+        .with_query()?;
+    Ok(ApiJson(UserIllusts {
+        total,
+        has_more: (form.skip as u64 + illusts.len() as u64) < total,
+        illusts,
+    }))
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -258,7 +855,11 @@ struct FindTagForm {
     limit: u32,
 }
 #[post("/find/tag")]
-async fn find_tag(db: Data<Database>, form: Json<FindTagForm>) -> Result<Json<Vec<Tag>>> {
+async fn find_tag(
+    db: Data<Database>,
+    config: Data<Config>,
+    form: Json<FindTagForm>,
+) -> Result<ApiJson<Vec<Tag>>> {
     let form = form.into_inner();
     let mut filter = doc! {};
     if let Some(search) = form.search {
@@ -274,103 +875,772 @@ async fn find_tag(db: Data<Database>, form: Json<FindTagForm>) -> Result<Json<Ve
         .collection::<Tag>("pixiv_tag")
         .find(
             filter,
-            FindOptions::builder().limit(form.limit as i64).build(),
+            FindOptions::builder()
+                .limit(form.limit as i64)
+                .max_time(config.server.query_timeout())
+                .build(),
         )
         .await
-        .with_interal()?;
+        .with_query()?;
 
-    let rv = cur.try_collect().await.with_interal()?;
-    Ok(Json(rv))
+    let rv = cur.try_collect().await.with_query()?;
+    Ok(ApiJson(rv))
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
-struct FindIllustForm {
+struct IllustFilter {
     tags: Option<Vec<ObjectId>>,
     search: Option<String>, // Search in title and caption
     date_range: Option<(Option<DateTime<Utc>>, Option<DateTime<Utc>>)>,
+    first_seen_range: Option<(Option<DateTime<Utc>>, Option<DateTime<Utc>>)>,
+    last_seen_range: Option<(Option<DateTime<Utc>>, Option<DateTime<Utc>>)>,
     bookmarks_range: Option<(u32, u32)>,
+    /// Exactly this number of pages.
+    page_count: Option<u32>,
+    /// The minimum and maximum number of pages, `0` for no maximum.
+    page_count_range: Option<(u32, u32)>,
+    is_multi_page: Option<bool>,
     sort_by: Option<SortBy>,
     source_inaccessible: Option<bool>,
+    /// Defaults to hiding private bookmarks if the server is read-only.
+    visibility: Option<BookmarkVisibility>,
+    /// Tags of your own bookmarks, all of them must match.
+    bookmark_tags: Option<Vec<String>>,
+    series_id: Option<String>,
     parent_ids: Option<Vec<ObjectId>>,
+}
+
+impl IllustFilter {
+    /// The sort, with `_id` to break ties so every illust has a single position.
+    fn sort(&self) -> Document {
+        let mut sort = parse_sort_by(self.sort_by.clone());
+        if !sort.contains_key("_id") {
+            let direction = sort.values().last().and_then(Bson::as_i32).unwrap_or(-1);
+            sort.insert("_id", direction);
+        }
+        sort
+    }
+
+    fn into_document(self, config: &Config) -> Document {
+        let mut filter = doc! {};
+
+        if let Some(tag_ids) = self.tags {
+            if !tag_ids.is_empty() {
+                filter.extend(doc! { "tag_ids": {"$all": tag_ids} });
+            }
+        }
+
+        if let Some(search) = self.search {
+            if !search.is_empty() {
+                let reg = build_search_regex(&search);
+                filter.extend(doc! { "$or": [
+                    { "history.extension.title": &reg },
+                    { "history.extension.caption_html": &reg },
+                ]});
+            }
+        }
+
+        for (key, range) in [
+            ("history.extension.date", self.date_range),
+            ("first_seen_at", self.first_seen_range),
+            ("last_seen_at", self.last_seen_range),
+        ] {
+            if let Some((start, end)) = range {
+                let mut filter_date = Document::new();
+                if let Some(start) = start {
+                    filter_date.insert("$gte", start);
+                }
+                if let Some(end) = end {
+                    filter_date.insert("$lte", end);
+                }
+                if filter_date.len() > 0 {
+                    filter.insert(key, filter_date);
+                }
+            }
+        }
+
+        if let Some((min_bookmarks, max_bookmarks)) = self.bookmarks_range {
+            if min_bookmarks != 0 || max_bookmarks != 0 {
+                if max_bookmarks != 0 {
+                    filter.extend(doc! { "history.extension.bookmarks": {"$gte": min_bookmarks, "$lte": max_bookmarks} });
+                } else {
+                    filter.extend(doc! { "history.extension.bookmarks": {"$gte": min_bookmarks} });
+                }
+            }
+        }
+
+        // All the page filters narrow down the same range.
+        let mut min_pages = 0;
+        let mut max_pages = u32::MAX;
+        if let Some(count) = self.page_count {
+            min_pages = count;
+            max_pages = count;
+        }
+        if let Some((min, max)) = self.page_count_range {
+            min_pages = min_pages.max(min);
+            if max != 0 {
+                max_pages = max_pages.min(max);
+            }
+        }
+        match self.is_multi_page {
+            Some(true) => min_pages = min_pages.max(2),
+            Some(false) => max_pages = max_pages.min(1),
+            None => {}
+        }
+        if min_pages == max_pages {
+            filter.extend(doc! { "extension.page_count": min_pages });
+        } else if min_pages > 0 || max_pages != u32::MAX {
+            let mut filter_pages = Document::new();
+            if min_pages > 0 {
+                filter_pages.insert("$gte", min_pages);
+            }
+            if max_pages != u32::MAX {
+                filter_pages.insert("$lte", max_pages);
+            }
+            filter.extend(doc! { "extension.page_count": filter_pages });
+        }
+
+        if let Some(source_inaccessible) = self.source_inaccessible {
+            filter.extend(doc! {"source_inaccessible": source_inaccessible});
+        }
+
+        if let Some(visibility) = visibility_filter(self.visibility, config) {
+            filter.insert("extension.bookmark_visibility", visibility);
+        }
+
+        if let Some(bookmark_tags) = self.bookmark_tags {
+            if !bookmark_tags.is_empty() {
+                filter.insert("extension.bookmark_tags", doc! { "$all": bookmark_tags });
+            }
+        }
+
+        if let Some(series_id) = self.series_id {
+            filter.insert("extension.series.id", series_id);
+        }
+
+        if let Some(parent_ids) = self.parent_ids {
+            if !parent_ids.is_empty() {
+                filter.extend(doc! { "parent_id": {"$in": parent_ids} });
+            }
+        }
+
+        filter
+    }
+}
+
+/// The filter of `extension.bookmark_visibility`.
+/// The private bookmarks are hidden by a read-only server unless asked for.
+fn visibility_filter(visibility: Option<BookmarkVisibility>, config: &Config) -> Option<Bson> {
+    match visibility {
+        Some(visibility) => Some(to_bson(&visibility).unwrap()),
+        None if config.server.read_only => Some(Bson::Document(
+            doc! { "$ne": to_bson(&BookmarkVisibility::Private).unwrap() },
+        )),
+        None => None,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FindIllustForm {
+    #[serde(flatten)]
+    filter: IllustFilter,
     skip: u32,
+    /// `0` for no limit, still capped by `server.query_guard` when the query scans the collection.
     limit: u32,
 }
+
+/// The illusts matching the filter in the order of `sort_by`, paged by `skip` and `limit`.
 #[post("/find/illust")]
 async fn find_illust(
     db: Data<Database>,
+    config: Data<Config>,
     form: Json<FindIllustForm>,
-) -> Result<Json<Vec<PixivIllust>>> {
+) -> Result<ApiJson<Vec<PixivIllust>>> {
     let form = form.into_inner();
 
-    sort_by_guard(&form.sort_by)?;
+    sort_by_guard(&form.filter.sort_by)?;
+    let sort = form.filter.sort();
+    let filter = form.filter.into_document(&config);
 
-    let mut filter = doc! {};
+    debug!("find illust: {:?} sort: {:?}", filter, sort);
 
-    if let Some(tag_ids) = form.tags {
-        if !tag_ids.is_empty() {
-            filter.extend(doc! { "tag_ids": {"$all": tag_ids} });
-        }
+    let cap = guard_query(&db, "pixiv_illust", &filter, &sort, &config).await?;
+    let limit = match (form.limit, cap) {
+        (0, cap) => cap,
+        (limit, Some(cap)) => Some(limit.min(cap)),
+        (limit, None) => Some(limit),
+    };
+    let options = FindOptions::builder()
+        .sort(sort)
+        .skip(form.skip as u64)
+        .limit(limit.map(|l| l as i64))
+        .max_time(config.server.query_timeout())
+        .build();
+
+    let rv = db
+        .collection("pixiv_illust")
+        .find(filter, options)
+        .await
+        .with_query()?
+        .try_collect()
+        .await
+        .with_query()?;
+    Ok(ApiJson(rv))
+}
+
+/// The scalar value at a dotted path of the document.
+///
+/// `None` if it is missing, null or in an array. MongoDB sorts the documents by the
+/// smallest or the largest element of an array, and matches a range against any element,
+/// so such values cannot be compared like [`next_illust`] does.
+fn path_value(doc: &Document, path: &str) -> Option<Bson> {
+    let mut keys = path.split('.');
+    let mut value = doc.get(keys.next()?)?;
+    for key in keys {
+        value = match value {
+            Bson::Document(d) => d.get(key)?,
+            _ => return None,
+        };
+    }
+    match value {
+        Bson::Array(_) | Bson::Null => None,
+        value => Some(value.clone()),
     }
+}
 
-    if let Some(search) = form.search {
-        if !search.is_empty() {
-            let reg = build_search_regex(&search);
-            filter.extend(doc! { "$or": [
-                { "history.extension.title": &reg },
-                { "history.extension.caption_html": &reg },
-            ]});
+/// The first illust after `current` in the order of `sort`, among those matching `filter`.
+///
+/// The sort keys are compared in order with the `values` of `current`,
+/// e.g. for `{a: -1, _id: -1}` the illusts after it have a smaller `a`,
+/// or the same `a` and a smaller `_id`.
+async fn next_illust(
+    c_illust: &Collection<Document>,
+    filter: &Document,
+    sort: Document,
+    values: &[Bson],
+    config: &Config,
+) -> Result<Option<String>> {
+    let mut after = Vec::new();
+    let mut equal = Document::new();
+    for ((key, direction), value) in sort.iter().zip(values) {
+        let op = if direction.as_i32() == Some(1) {
+            "$gt"
+        } else {
+            "$lt"
+        };
+        let mut cond = equal.clone();
+        cond.insert(key, doc! { op: value.clone() });
+        after.push(cond);
+        equal.insert(key, value.clone());
+    }
+    let r = c_illust
+        .find_one(
+            doc! { "$and": [filter.clone(), { "$or": after }] },
+            FindOneOptions::builder()
+                .sort(sort)
+                .projection(doc! { "source_id": 1 })
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?;
+    Ok(r.and_then(|d| d.get_str("source_id").ok().map(|id| id.to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct IllustNeighborsForm {
+    source_id: String,
+    #[serde(flatten)]
+    filter: IllustFilter,
+}
+/// `null` at the first or the last illust.
+#[derive(Debug, Serialize)]
+struct IllustNeighbors {
+    prev: Option<String>,
+    next: Option<String>,
+}
+/// Find the neighbors by walking the results in order,
+/// for the illusts without a scalar value of every sort key.
+async fn scan_neighbors(
+    c_illust: &Collection<Document>,
+    filter: Document,
+    sort: Document,
+    source_id: &str,
+    config: &Config,
+) -> Result<IllustNeighbors> {
+    let mut cursor = c_illust
+        .find(
+            filter,
+            FindOptions::builder()
+                .sort(sort)
+                .projection(doc! { "source_id": 1 })
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?;
+    let source_id_of = |d: Document| d.get_str("source_id").ok().map(|id| id.to_string());
+    let mut prev = None;
+    while let Some(d) = cursor.try_next().await.with_query()? {
+        let id = source_id_of(d);
+        if id.as_deref() == Some(source_id) {
+            let next = cursor.try_next().await.with_query()?.and_then(source_id_of);
+            return Ok(IllustNeighbors { prev, next });
         }
+        prev = id;
     }
+    Err(Error::not_found())
+}
 
-    if let Some((start, end)) = form.date_range {
-        let mut filter_date = Document::new();
-        if let Some(start) = start {
-            filter_date.insert("$gte", start);
+/// The illusts before and after one in the results of `find_illust` with the same filter.
+///
+/// Found with range queries on the sort keys instead of offsets,
+/// so it is fast anywhere in the results if the sort is indexed.
+/// If a sort key of the illust is missing or an array, the results are walked instead.
+#[post("/find/illust/neighbors")]
+async fn illust_neighbors(
+    db: Data<Database>,
+    config: Data<Config>,
+    form: Json<IllustNeighborsForm>,
+) -> Result<ApiJson<IllustNeighbors>> {
+    let form = form.into_inner();
+    sort_by_guard(&form.filter.sort_by)?;
+    let sort = form.filter.sort();
+    let reversed: Document = sort
+        .iter()
+        .map(|(k, v)| (k.clone(), Bson::Int32(-v.as_i32().unwrap_or(-1))))
+        .collect();
+    let filter = form.filter.into_document(&config);
+
+    let c_illust = db.collection::<Document>("pixiv_illust");
+    let current = c_illust
+        .find_one(
+            doc! { "source_id": &form.source_id },
+            FindOneOptions::builder()
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?
+        .ok_or_else(Error::not_found)?;
+    let values: Option<Vec<Bson>> = sort.keys().map(|k| path_value(&current, k)).collect();
+    let values = match values {
+        Some(values) => values,
+        None => {
+            let neighbors =
+                scan_neighbors(&c_illust, filter, sort, &form.source_id, &config).await?;
+            return Ok(ApiJson(neighbors));
         }
-        if let Some(end) = end {
-            filter_date.insert("$lte", end);
+    };
+    Ok(ApiJson(IllustNeighbors {
+        prev: next_illust(&c_illust, &filter, reversed, &values, &config).await?,
+        next: next_illust(&c_illust, &filter, sort, &values, &config).await?,
+    }))
+}
+
+/// The saved media of an illust.
+struct IllustMediaSet {
+    /// With the page indices, in order.
+    pages: Vec<(usize, LocalMedia<MediaExtension>)>,
+    ugoira_zip: Option<LocalMedia<MediaExtension>>,
+    /// Transcoded from the zip.
+    ugoira_videos: Vec<LocalMedia<MediaExtension>>,
+}
+
+/// Every query is stopped after `timeout`.
+async fn find_illust_media(
+    db: &Database,
+    source_id: &str,
+    timeout: Option<Duration>,
+) -> Result<IllustMediaSet> {
+    let find_one = || FindOneOptions::builder().max_time(timeout).build();
+    let illust = db
+        .collection::<PixivIllust>("pixiv_illust")
+        .find_one(doc! { "source_id": source_id }, find_one())
+        .await
+        .with_query()?
+        .ok_or_else(Error::not_found)?;
+    let history = illust
+        .history
+        .into_iter()
+        .last()
+        .and_then(|h| h.extension)
+        .ok_or_else(Error::not_found)?;
+
+    let c_image = db.collection::<LocalMedia<MediaExtension>>("pixiv_image");
+    let mut set = IllustMediaSet {
+        pages: Vec::new(),
+        ugoira_zip: None,
+        ugoira_videos: Vec::new(),
+    };
+    for (page, url) in history.image_urls.iter().enumerate() {
+        if let Some(media) = c_image
+            .find_one(doc! { "url": url }, find_one())
+            .await
+            .with_query()?
+        {
+            set.pages.push((page, media));
         }
-        if filter_date.len() > 0 {
-            filter.extend(doc! { "history.extension.date": filter_date });
+    }
+    if history.illust_type == "ugoira" {
+        set.ugoira_zip = c_image
+            .find_one(
+                doc! {
+                    "mime": "application/zip",
+                    "url": { "$regex": format!("/{}_ugoira", regex::escape(source_id)) },
+                },
+                find_one(),
+            )
+            .await
+            .with_query()?;
+        if let Some(ref zip) = set.ugoira_zip {
+            let stem = zip.local_path.trim_end_matches(".zip");
+            let videos = match &zip.extension {
+                Some(MediaExtension::Ugoira(ugoira)) => ugoira.videos.as_slice(),
+                _ => &[],
+            };
+            // The zips saved before the transcoded videos were recorded list none.
+            let filter = if videos.is_empty() {
+                doc! { "local_path": {
+                    "$regex": format!("^{}\\.[^./]+$", regex::escape(stem)),
+                    "$ne": &zip.local_path,
+                }}
+            } else {
+                let paths: Vec<_> = videos.iter().map(|ext| format!("{stem}.{ext}")).collect();
+                doc! { "local_path": { "$in": paths } }
+            };
+            set.ugoira_videos = c_image
+                .find(filter, FindOptions::builder().max_time(timeout).build())
+                .await
+                .with_query()?
+                .try_collect()
+                .await
+                .with_query()?;
         }
     }
+    Ok(set)
+}
 
-    if let Some((min_bookmarks, max_bookmarks)) = form.bookmarks_range {
-        if min_bookmarks != 0 || max_bookmarks != 0 {
-            if max_bookmarks != 0 {
-                filter.extend(doc! { "history.extension.bookmarks": {"$gte": min_bookmarks, "$lte": max_bookmarks} });
-            } else {
-                filter.extend(doc! { "history.extension.bookmarks": {"$gte": min_bookmarks} });
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum IllustMediaKind {
+    Page,
+    UgoiraZip,
+    UgoiraVideo,
+}
+
+#[derive(Debug, Serialize)]
+struct IllustMedia {
+    kind: IllustMediaKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page: Option<usize>,
+    #[serde(flatten)]
+    media: LocalMedia<MediaExtension>,
+}
+#[derive(Debug, Clone, Deserialize)]
+struct PaletteQuery {
+    /// Defaults to the first page.
+    page: Option<usize>,
+    /// Show at most this number of colors.
+    count: Option<usize>,
+}
+/// The palette of a page as a strip of colors, the main color first.
+#[get("/illust/{source_id}/palette.svg")]
+async fn illust_palette(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<PaletteQuery>,
+    db: Data<Database>,
+    config: Data<Config>,
+) -> Result<HttpResponse> {
+    let page = query.page.unwrap_or(0);
+    let media = find_illust_media(&db, &path.into_inner().0, config.server.query_timeout()).await?;
+    let palette = match media.pages.into_iter().find(|(p, _)| *p == page) {
+        Some((
+            _,
+            LocalMedia {
+                extension: Some(MediaExtension::Image(image)),
+                ..
+            },
+        )) => image.palette_hsv,
+        _ => return Err(Error::not_found()),
+    };
+    let count = query.count.unwrap_or(palette.len()).min(palette.len());
+    if count == 0 {
+        return Err(Error::not_found());
+    }
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {count} 1" width="{}" height="16" preserveAspectRatio="none">"#,
+        count * 16
+    );
+    for (i, hsv) in palette.iter().take(count).enumerate() {
+        let (r, g, b) = crate::utils::hsv_to_rgb(hsv.h, hsv.s, hsv.v);
+        svg.push_str(&format!(
+            r##"<rect x="{i}" width="1" height="1" fill="#{r:02x}{g:02x}{b:02x}"/>"##
+        ));
+    }
+    svg.push_str("</svg>");
+
+    // The palette never changes once saved, so the content is a strong validator.
+    let etag = header::EntityTag::new_strong(format!("{:08x}", crc32fast::hash(svg.as_bytes())));
+    let res = if is_fresh(&req, &etag, None) {
+        HttpResponse::NotModified()
+            .append_header(header::ETag(etag))
+            .finish()
+    } else {
+        HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .append_header(header::ETag(etag))
+            .body(svg)
+    };
+    res.extensions_mut().insert(CacheGroup::Thumbnail);
+    Ok(res)
+}
+
+/// All the saved media of an illust: the pages in order, then the ugoira zip and its videos.
+#[get("/illust/{source_id}/media")]
+async fn illust_media(
+    path: web::Path<(String,)>,
+    db: Data<Database>,
+    config: Data<Config>,
+) -> Result<ApiJson<Vec<IllustMedia>>> {
+    let media = find_illust_media(&db, &path.into_inner().0, config.server.query_timeout()).await?;
+    let mut rv: Vec<_> = media
+        .pages
+        .into_iter()
+        .map(|(page, media)| IllustMedia {
+            kind: IllustMediaKind::Page,
+            page: Some(page),
+            media,
+        })
+        .collect();
+    rv.extend(media.ugoira_zip.map(|media| IllustMedia {
+        kind: IllustMediaKind::UgoiraZip,
+        page: None,
+        media,
+    }));
+    rv.extend(media.ugoira_videos.into_iter().map(|media| IllustMedia {
+        kind: IllustMediaKind::UgoiraVideo,
+        page: None,
+        media,
+    }));
+    Ok(ApiJson(rv))
+}
+
+/// `no-cache` with the validators of the files, for the responses made from them
+/// which change once a video is transcoded or a file replaced.
+fn revalidated(
+    req: &HttpRequest,
+    validators: Option<(header::EntityTag, std::time::SystemTime)>,
+) -> (actix_web::HttpResponseBuilder, bool) {
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]));
+    let fresh = match validators {
+        Some((etag, last_modified)) => {
+            let fresh = is_fresh(req, &etag, Some(last_modified));
+            if fresh {
+                builder.status(StatusCode::NOT_MODIFIED);
             }
+            builder
+                .insert_header(header::ETag(etag))
+                .insert_header(header::LastModified(last_modified.into()));
+            fresh
         }
+        None => false,
+    };
+    (builder, fresh)
+}
+
+/// Play an ugoira: redirect to a transcoded video, preferring MP4,
+/// or make an animated GIF from the zip if there is none, e.g. without ffmpeg.
+#[get("/illust/{source_id}/ugoira")]
+async fn illust_ugoira(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    db: Data<Database>,
+    config: Data<Config>,
+    pixiv_config: Data<PixivConfig>,
+    cache: Data<Mutex<UgoiraCache>>,
+    semaphore: Data<Semaphore>,
+) -> Result<HttpResponse> {
+    let media = find_illust_media(&db, &path.into_inner().0, config.server.query_timeout()).await?;
+    let video = media
+        .ugoira_videos
+        .iter()
+        .find(|m| m.mime.as_deref() == Some("video/mp4"))
+        .or_else(|| media.ugoira_videos.first());
+    if let Some(video) = video {
+        return Ok(HttpResponse::TemporaryRedirect()
+            .append_header((
+                header::LOCATION,
+                format!("../../storage/{}", video.local_path),
+            ))
+            .finish());
     }
 
-    if let Some(source_inaccessible) = form.source_inaccessible {
-        filter.extend(doc! {"source_inaccessible": source_inaccessible});
+    let zip = media.ugoira_zip.ok_or_else(Error::not_found)?;
+    let frame_delay = match zip.extension {
+        Some(MediaExtension::Ugoira(ugoira)) => ugoira.frame_delay,
+        _ => return Err(Error::not_found()),
+    };
+    let zip_path = pixiv_config.path(&zip.local_path);
+    let (mut builder, fresh) =
+        revalidated(&req, file_validators(std::slice::from_ref(&zip_path)).await);
+    if fresh {
+        return Ok(builder.finish());
     }
+    let gif = cached_ugoira_gif(zip_path, frame_delay, cache.as_ref(), semaphore.as_ref()).await?;
+    Ok(builder.content_type("image/gif").body(gif))
+}
 
-    if let Some(parent_ids) = form.parent_ids {
-        if !parent_ids.is_empty() {
-            filter.extend(doc! { "parent_id": {"$in": parent_ids} });
-        }
+/// The original zip of the frames of an ugoira, as downloaded from pixiv.
+/// Supports range requests.
+#[get("/illust/{source_id}/ugoira.zip")]
+async fn illust_ugoira_zip(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    db: Data<Database>,
+    config: Data<Config>,
+    pixiv_config: Data<PixivConfig>,
+) -> Result<HttpResponse> {
+    let media = find_illust_media(&db, &path.into_inner().0, config.server.query_timeout()).await?;
+    let zip = media.ugoira_zip.ok_or_else(Error::not_found)?;
+    let file = NamedFile::open_async(pixiv_config.path(&zip.local_path))
+        .await
+        .map_err(|_| Error::not_found())?;
+    let name = zip.local_path.rsplit('/').next().unwrap_or(&zip.local_path);
+    let res = file
+        .set_content_type("application/zip".parse().unwrap())
+        .set_content_disposition(header::ContentDisposition {
+            disposition: header::DispositionType::Attachment,
+            parameters: vec![header::DispositionParam::Filename(name.to_string())],
+        })
+        .into_response(&req);
+    res.extensions_mut().insert(CacheGroup::Media);
+    Ok(res)
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ArchiveUgoira {
+    /// The transcoded videos.
+    Video,
+    /// The original zip of frames.
+    Zip,
+    Both,
+}
+
+impl Default for ArchiveUgoira {
+    fn default() -> Self {
+        Self::Video
     }
+}
 
-    debug!("find illust: {:?} sort: {:?}", filter, form.sort_by);
+#[derive(Debug, Clone, Deserialize)]
+struct IllustArchiveQuery {
+    #[serde(default)]
+    ugoira: ArchiveUgoira,
+}
+/// Download all pages of an illust as a zip, built while streaming.
+#[get("/illust/{source_id}/archive")]
+async fn illust_archive(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<IllustArchiveQuery>,
+    db: Data<Database>,
+    config: Data<Config>,
+    pixiv_config: Data<PixivConfig>,
+) -> Result<HttpResponse> {
+    let source_id = path.into_inner().0;
+    let media = find_illust_media(&db, &source_id, config.server.query_timeout()).await?;
 
-    let options = FindOptions::builder()
-        .sort(
-            form.sort_by
-                .map_or(doc! {"_id": -1}, |s| to_document(&s).unwrap()),
-        )
-        .build();
+    let mut local_paths: Vec<_> = media.pages.into_iter().map(|(_, m)| m.local_path).collect();
+    if query.ugoira != ArchiveUgoira::Zip {
+        local_paths.extend(media.ugoira_videos.into_iter().map(|m| m.local_path));
+    }
+    if query.ugoira != ArchiveUgoira::Video {
+        local_paths.extend(media.ugoira_zip.map(|m| m.local_path));
+    }
+    if local_paths.is_empty() {
+        return Err(Error::not_found());
+    }
 
-    let rv = db
-        .collection("pixiv_illust")
-        .find(filter, options)
+    let entries: Vec<_> = local_paths
+        .into_iter()
+        .map(|p| {
+            let name = p.rsplit('/').next().unwrap_or(&p).to_string();
+            (name, pixiv_config.path(&p))
+        })
+        .collect();
+    let paths: Vec<_> = entries.iter().map(|(_, p)| p.clone()).collect();
+    let (mut builder, fresh) = revalidated(&req, file_validators(&paths).await);
+    if fresh {
+        return Ok(builder.finish());
+    }
+    Ok(builder
+        .content_type("application/zip")
+        .append_header(header::ContentDisposition {
+            disposition: header::DispositionType::Attachment,
+            parameters: vec![header::DispositionParam::Filename(format!(
+                "{source_id}.zip"
+            ))],
+        })
+        .streaming(zip_stream(entries)))
+}
+
+#[derive(Debug, Serialize)]
+struct SeriesIllusts {
+    series: Series,
+    /// In the order of publication.
+    illusts: Vec<PixivIllust>,
+}
+#[get("/series/{id}")]
+async fn series(
+    path: web::Path<(String,)>,
+    db: Data<Database>,
+    config: Data<Config>,
+) -> Result<ApiJson<SeriesIllusts>> {
+    let id = path.into_inner().0;
+    let illusts: Vec<PixivIllust> = db
+        .collection::<PixivIllust>("pixiv_illust")
+        .find(
+            doc! { "extension.series.id": &id },
+            FindOptions::builder()
+                .sort(doc! { "history.0.extension.date": 1, "source_id": 1 })
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
         .await
-        .with_interal()?
+        .with_query()?
         .try_collect()
         .await
-        .with_interal()?;
-    Ok(Json(rv))
+        .with_query()?;
+    let series = illusts
+        .first()
+        .and_then(|i| i.extension.as_ref())
+        .and_then(|e| e.series.clone())
+        .ok_or_else(Error::not_found)?;
+    Ok(ApiJson(SeriesIllusts { series, illusts }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_values() {
+        let d = doc! {
+            "_id": 1,
+            "a": { "b": "x", "n": null },
+            "history": [{ "extension": { "bookmarks": 3 } }],
+        };
+        assert_eq!(path_value(&d, "_id"), Some(Bson::Int32(1)));
+        assert_eq!(path_value(&d, "a.b"), Some(Bson::String("x".to_string())));
+        assert_eq!(path_value(&d, "a.n"), None);
+        assert_eq!(path_value(&d, "a.missing"), None);
+        assert_eq!(path_value(&d, "a.b.c"), None);
+        assert_eq!(path_value(&d, "history.extension.bookmarks"), None);
+        assert_eq!(path_value(&d, "history"), None);
+    }
 }
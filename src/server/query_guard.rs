@@ -0,0 +1,127 @@
+use bson::{doc, Bson, Document};
+use log::warn;
+use mongodb::{options::EstimatedDocumentCountOptions, Database};
+
+use super::{error::*, Result};
+use crate::config::{Config, QueryGuardMode};
+
+/// Whether any stage of the plan scans the whole collection.
+fn has_collection_scan(plan: &Bson) -> bool {
+    match plan {
+        Bson::Document(d) => {
+            matches!(d.get_str("stage"), Ok("COLLSCAN")) || d.values().any(has_collection_scan)
+        }
+        Bson::Array(a) => a.iter().any(has_collection_scan),
+        _ => false,
+    }
+}
+
+/// Whether the filter searches by a regex or the text index.
+fn has_search(filter: &Bson) -> bool {
+    match filter {
+        Bson::RegularExpression(_) => true,
+        Bson::Document(d) => d
+            .iter()
+            .any(|(k, v)| k == "$regex" || k == "$text" || has_search(v)),
+        Bson::Array(a) => a.iter().any(has_search),
+        _ => false,
+    }
+}
+
+/// Check the plan of the query before running it, according to `server.query_guard`.
+///
+/// Returns the limit to cap the results with, if the query is allowed with fewer results.
+///
+/// The searches by keywords are not checked, as they scan by nature,
+/// and are only bounded by `server.query_timeout_millis`.
+pub async fn guard_query(
+    db: &Database,
+    collection: &str,
+    filter: &Document,
+    sort: &Document,
+    config: &Config,
+) -> Result<Option<u32>> {
+    let guard = &config.server.query_guard;
+    if guard.mode == QueryGuardMode::Off || has_search(&Bson::Document(filter.clone())) {
+        return Ok(None);
+    }
+    let documents = db
+        .collection::<Document>(collection)
+        .estimated_document_count(
+            EstimatedDocumentCountOptions::builder()
+                .max_time(config.server.query_timeout())
+                .build(),
+        )
+        .await
+        .with_query()?;
+    if documents < guard.min_documents {
+        return Ok(None);
+    }
+    let mut find = doc! { "find": collection, "filter": filter, "sort": sort };
+    if let Some(max_time) = config.server.query_timeout() {
+        find.insert("maxTimeMS", max_time.as_millis() as i64);
+    }
+    let explained = db
+        .run_command(doc! { "explain": find, "verbosity": "queryPlanner" }, None)
+        .await
+        .with_query()?;
+    let plan = explained
+        .get_document("queryPlanner")
+        .and_then(|p| p.get_document("winningPlan"))
+        .map(|p| Bson::Document(p.clone()))
+        .unwrap_or(Bson::Null);
+    if !has_collection_scan(&plan) {
+        return Ok(None);
+    }
+    match guard.mode {
+        QueryGuardMode::Reject => Err(Error::query_too_expensive(documents)),
+        _ => {
+            warn!(
+                "query on {} scans all the {} documents, capped to {} results: {}",
+                collection, documents, guard.capped_limit, filter
+            );
+            Ok(Some(guard.capped_limit))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_scans() {
+        let indexed = doc! {
+            "stage": "LIMIT",
+            "inputStage": { "stage": "FETCH", "inputStage": { "stage": "IXSCAN" } },
+        };
+        assert!(!has_collection_scan(&Bson::Document(indexed)));
+
+        let nested = doc! {
+            "stage": "SORT",
+            "inputStage": {
+                "stage": "OR",
+                "inputStages": [{ "stage": "IXSCAN" }, { "stage": "COLLSCAN" }],
+            },
+        };
+        assert!(has_collection_scan(&Bson::Document(nested)));
+        assert!(!has_collection_scan(&Bson::Null));
+    }
+
+    #[test]
+    fn searches() {
+        let regex = bson::Regex {
+            pattern: "a".to_string(),
+            options: "i".to_string(),
+        };
+        assert!(has_search(&Bson::Document(
+            doc! { "$or": [{ "title": regex }] }
+        )));
+        assert!(has_search(&Bson::Document(
+            doc! { "$text": { "$search": "a" } }
+        )));
+        assert!(!has_search(&Bson::Document(
+            doc! { "user_id": "1", "tags": { "$all": ["a"] } }
+        )));
+    }
+}
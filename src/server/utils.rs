@@ -1,28 +1,90 @@
-use actix_web::http::StatusCode;
+use actix_web::{
+    body::BoxBody,
+    http::{header, StatusCode},
+    HttpRequest, HttpResponse, Responder, ResponseError,
+};
 use bson::Regex;
 use bytes::Bytes;
-use image::{imageops::FilterType::Lanczos3, GenericImageView, ImageOutputFormat};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    imageops::FilterType::Lanczos3,
+    Delay, Frame, GenericImageView, ImageOutputFormat,
+};
 use log::debug;
+use serde::Serialize;
+use serde_json::{Map, Value};
 use std::{
-    collections::HashMap,
-    io::Cursor,
+    collections::{HashMap, VecDeque},
+    io::{Cursor, Read},
     path::{Path, PathBuf},
-    sync::Mutex,
-    time::Instant,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    sync::{Mutex as AsyncMutex, Semaphore},
+    task::spawn_blocking,
 };
-use tokio::{sync::Semaphore, task::spawn_blocking};
 
-use crate::server::error::ServerErrorExt;
+use crate::{config::Config, server::error::ServerErrorExt};
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub struct ThumbnailCacheKey {
-    size: u32,
-    local_path: PathBuf,
-    target_ratio: Option<u32>,
+    pub size: u32,
+    pub local_path: PathBuf,
+    /// The target ratio multiplied by 100.
+    pub target_ratio: Option<u32>,
 }
 
 pub type ThumbnailCache = HashMap<ThumbnailCacheKey, Bytes>;
 
+/// Animated GIFs made from ugoira zips, keyed by the path of the zip.
+/// The oldest are dropped when the total size exceeds `max_bytes`.
+#[derive(Debug)]
+pub struct UgoiraCache {
+    gifs: HashMap<PathBuf, Bytes>,
+    order: VecDeque<PathBuf>,
+    bytes: u64,
+    max_bytes: u64,
+    /// The GIFs being made, so that concurrent requests for one zip make it once.
+    making: HashMap<PathBuf, Arc<AsyncMutex<()>>>,
+}
+
+impl UgoiraCache {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            gifs: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+            making: HashMap::new(),
+        }
+    }
+
+    fn get(&self, zip_path: &Path) -> Option<Bytes> {
+        self.gifs.get(zip_path).cloned()
+    }
+
+    fn insert(&mut self, zip_path: PathBuf, gif: Bytes) {
+        let size = gif.len() as u64;
+        if size > self.max_bytes || self.gifs.contains_key(&zip_path) {
+            return;
+        }
+        while self.bytes + size > self.max_bytes {
+            match self.order.pop_front() {
+                Some(k) => {
+                    if let Some(b) = self.gifs.remove(&k) {
+                        self.bytes -= b.len() as u64;
+                    }
+                }
+                None => break,
+            }
+        }
+        self.bytes += size;
+        self.order.push_back(zip_path.clone());
+        self.gifs.insert(zip_path, gif);
+    }
+}
+
 /// Spawns cpu-bound task and await for result.
 /// The spawned task is aborted when the handle is dropped.
 ///
@@ -127,9 +189,214 @@ fn make_thumbnail(
     Ok(Bytes::from(b))
 }
 
+/// The ugoira zip as an animated GIF, for the ugoira without any transcoded video.
+pub async fn cached_ugoira_gif(
+    zip_path: PathBuf,
+    frame_delay: Vec<i32>,
+    cache: &Mutex<UgoiraCache>,
+    semaphore: &Semaphore,
+) -> super::Result<Bytes> {
+    let making = {
+        let mut cache_lock = cache.lock().unwrap();
+        if let Some(b) = cache_lock.get(&zip_path) {
+            return Ok(b);
+        }
+        cache_lock
+            .making
+            .entry(zip_path.clone())
+            .or_default()
+            .clone()
+    };
+    let _making = making.lock().await;
+    // Made by the request holding the lock before.
+    if let Some(b) = cache.lock().unwrap().get(&zip_path) {
+        return Ok(b);
+    }
+
+    let b = spawn_semaphore(semaphore, {
+        let zip_path = zip_path.clone();
+        move || make_ugoira_gif(&zip_path, &frame_delay)
+    })
+    .await;
+
+    let mut cache_lock = cache.lock().unwrap();
+    cache_lock.making.remove(&zip_path);
+    let b = b?;
+    cache_lock.insert(zip_path, b.clone());
+    Ok(b)
+}
+
+/// Encode the frames in the zip in order, with the delays in milliseconds.
+fn make_ugoira_gif(zip_path: &Path, frame_delay: &[i32]) -> super::Result<Bytes> {
+    let t = Instant::now();
+    let file = std::fs::File::open(zip_path).with_status(StatusCode::NOT_FOUND)?;
+    let mut zip_file = zip::ZipArchive::new(file).with_interal()?;
+    let mut b = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut b);
+        encoder.set_repeat(Repeat::Infinite).with_interal()?;
+        for i in 0..zip_file.len() {
+            let mut frame = Vec::new();
+            zip_file
+                .by_index(i)
+                .with_interal()?
+                .read_to_end(&mut frame)
+                .with_interal()?;
+            let img = image::load_from_memory(&frame).with_interal()?.to_rgba8();
+            let delay = frame_delay.get(i).copied().unwrap_or(100).max(0) as u32;
+            encoder
+                .encode_frame(Frame::from_parts(
+                    img,
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(delay, 1),
+                ))
+                .with_interal()?;
+        }
+    }
+    debug!("made ugoira gif for {:?}: {:?}", zip_path, t.elapsed());
+    Ok(Bytes::from(b))
+}
+
+/// Reject the request if the server is read-only.
+///
+/// Must be called first by every endpoint writing to the database or triggering downloads.
+pub fn check_writable(config: &Config) -> super::Result<()> {
+    if config.server.read_only {
+        Err(super::error::Error::read_only())
+    } else {
+        Ok(())
+    }
+}
+
+/// Reject the request if the admin token in the config is not given.
+pub fn check_admin(req: &HttpRequest, config: &Config) -> super::Result<()> {
+    let token = &config.server.admin_token;
+    if token.is_empty() {
+        return Err(super::error::Error::with_msg(
+            StatusCode::FORBIDDEN,
+            "admin endpoints are disabled",
+        ));
+    }
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map_or(false, |v| v == token);
+    if authorized {
+        Ok(())
+    } else {
+        Err(super::error::Error::with_msg(
+            StatusCode::UNAUTHORIZED,
+            "invalid admin token",
+        ))
+    }
+}
+
+/// The validators of a response made from the files, changed with the size or the
+/// modification time of any of them. `None` if one is missing.
+///
+/// The modification time is truncated to seconds, the precision of `If-Modified-Since`.
+pub async fn file_validators(paths: &[PathBuf]) -> Option<(header::EntityTag, SystemTime)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut last_modified = SystemTime::UNIX_EPOCH;
+    for path in paths {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        let secs = metadata
+            .modified()
+            .ok()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?
+            .as_secs();
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update(&metadata.len().to_le_bytes());
+        hasher.update(&secs.to_le_bytes());
+        last_modified = last_modified.max(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    Some((
+        header::EntityTag::new_weak(format!("{:08x}", hasher.finalize())),
+        last_modified,
+    ))
+}
+
+/// Whether the cached response of the client is still fresh, so `304 Not Modified` can be sent.
+///
+/// `If-Modified-Since` is ignored if `If-None-Match` is given.
+pub fn is_fresh(
+    req: &HttpRequest,
+    etag: &header::EntityTag,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    let headers = req.headers();
+    if let Some(v) = headers.get(header::IF_NONE_MATCH) {
+        return v.to_str().map_or(false, |v| {
+            v.split(',').map(str::trim).any(|t| {
+                t == "*"
+                    || t.parse::<header::EntityTag>()
+                        .map_or(false, |t| t.weak_eq(etag))
+            })
+        });
+    }
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<header::HttpDate>().ok());
+    match (since, last_modified) {
+        (Some(since), Some(last_modified)) => SystemTime::from(since) >= last_modified,
+        _ => false,
+    }
+}
+
 pub fn build_search_regex(search: &str) -> Regex {
     Regex {
         pattern: regex::escape(search),
         options: "i".to_string(),
     }
 }
+
+/// Like `Json`, but the BSON dates are RFC 3339 strings in UTC instead of `{"$date": ...}`.
+pub struct ApiJson<T>(pub T);
+
+impl<T: Serialize> Responder for ApiJson<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        match serde_json::to_value(&self.0).with_interal() {
+            Ok(mut value) => {
+                rfc3339_dates(&mut value);
+                HttpResponse::Ok().json(value)
+            }
+            Err(e) => e.error_response(),
+        }
+    }
+}
+
+/// The date in `{"$date": {"$numberLong": "..."}}`, as BSON dates are serialized to JSON.
+fn bson_date(m: &Map<String, Value>) -> Option<String> {
+    if m.len() != 1 {
+        return None;
+    }
+    let millis = m.get("$date")?.get("$numberLong")?.as_str()?.parse().ok()?;
+    Some(
+        bson::DateTime::from_millis(millis)
+            .to_chrono()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    )
+}
+
+fn rfc3339_dates(value: &mut Value) {
+    let date = match value {
+        Value::Object(m) => bson_date(m),
+        _ => None,
+    };
+    if let Some(date) = date {
+        *value = Value::String(date);
+        return;
+    }
+    match value {
+        Value::Object(m) => m.values_mut().for_each(rfc3339_dates),
+        Value::Array(a) => a.iter_mut().for_each(rfc3339_dates),
+        _ => {}
+    }
+}
@@ -0,0 +1,922 @@
+//! Run the syncs without the command line, e.g. when embedding bowerbird in another binary.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut config = bowerbird::config::Config::from_file("config.json")?;
+//! let result = bowerbird::sync::sync_illust_bookmarks(
+//!     &mut config,
+//!     &bowerbird::sync::PixivSyncParams::default(),
+//!     false,
+//! )
+//! .await?;
+//! println!("{} illusts examined", result.examined);
+//! ```
+
+use bson::doc;
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use mongodb::{
+    error::ErrorKind,
+    options::{ClientOptions, FindOneOptions},
+    Database,
+};
+use path_slash::PathBufExt;
+use regex::Regex;
+use snafu::ResultExt;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{process::Command, time::timeout};
+
+use crate::{
+    command::{
+        self,
+        pixiv::{
+            transcode::TranscodePool,
+            utils::{set_rate_limit_cooldown, Ffmpeg},
+            PageRange, SizeGuard, TaskConfig,
+        },
+    },
+    config::{
+        redact_mongodb_password, redact_mongodb_uri, redact_proxy, Config, DownloadBackend,
+        PartialPolicy, UgoiraFormat,
+    },
+    downloader::{Aria2Downloader, Downloader, NativeDownloader},
+    error,
+    model::pixiv::PixivUser,
+    utils::{set_db_retry, set_pacing, set_throttle_window, CpuPool},
+};
+
+pub use crate::{
+    command::pixiv::{
+        IdImportStatus, PageStart, RankingMode, SearchSort, SearchTarget, SyncResult,
+    },
+    downloader::{ProgressTracker, ProgressWriter},
+};
+pub use tokio_util::sync::CancellationToken;
+
+/// Connect to the database in the config.
+///
+/// Fails if the database schema is newer than this version,
+/// or older if `fail_if_out_of_date` is set.
+pub async fn connect_db(config: &Config, fail_if_out_of_date: bool) -> crate::Result<Database> {
+    let db = open_db(config).await?;
+    command::migrate::guard(&db, fail_if_out_of_date).await?;
+    debug!(
+        "connected to mongodb: {}",
+        redact_mongodb_uri(&config.mongodb.uri)
+    );
+    Ok(db)
+}
+
+/// Get the database in the config without connecting to it.
+pub(crate) async fn open_db(config: &Config) -> crate::Result<Database> {
+    set_db_retry(
+        config.mongodb.retry.retries,
+        Duration::from_millis(config.mongodb.retry.backoff_millis),
+    );
+    let db_client = mongodb::Client::with_options(
+        ClientOptions::parse(&config.mongodb.uri)
+            .await
+            .context(error::MongoDb)?,
+    )
+    .context(error::MongoDb)?;
+
+    Ok(db_client.database(&config.mongodb.database_name))
+}
+
+/// Give up connecting to the database in the config after this long in `test_db`.
+const DB_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why `test_db` failed. The messages never contain the password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DbTestFailure {
+    InvalidUri(String),
+    Unreachable(String),
+    AuthFailed(String),
+    Other(String),
+}
+
+impl DbTestFailure {
+    fn new(err: &mongodb::error::Error, uri: &str) -> Self {
+        let message = redact_mongodb_password(&err.to_string(), uri);
+        match &*err.kind {
+            ErrorKind::InvalidArgument { .. } => Self::InvalidUri(message),
+            ErrorKind::Authentication { .. } => Self::AuthFailed(message),
+            // Unauthorized and AuthenticationFailed.
+            ErrorKind::Command(e) if e.code == 13 || e.code == 18 => Self::AuthFailed(message),
+            ErrorKind::ServerSelection { .. } | ErrorKind::DnsResolve { .. } | ErrorKind::Io(_) => {
+                Self::Unreachable(message)
+            }
+            _ => Self::Other(message),
+        }
+    }
+
+    /// How to fix it.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::InvalidUri(_) => "fix the format of `mongodb.uri` in the config",
+            Self::Unreachable(_) => {
+                "make sure MongoDB is running and the host in `mongodb.uri` is correct"
+            }
+            Self::AuthFailed(_) => "check the user, password and `authSource` in `mongodb.uri`",
+            Self::Other(_) => "check `mongodb.uri` in the config",
+        }
+    }
+}
+
+impl fmt::Display for DbTestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUri(m) => write!(f, "invalid uri: {m}"),
+            Self::Unreachable(m) => write!(f, "host unreachable: {m}"),
+            Self::AuthFailed(m) => write!(f, "authentication failed: {m}"),
+            Self::Other(m) => write!(f, "{m}"),
+        }
+    }
+}
+
+/// Connect to the database in the config and log in, failing fast if it is not reachable.
+///
+/// Returns the number of collections, `0` for a database not created yet.
+pub(crate) async fn test_db(config: &Config) -> Result<usize, DbTestFailure> {
+    let uri = &config.mongodb.uri;
+    let mut options = ClientOptions::parse(uri)
+        .await
+        .map_err(|e| DbTestFailure::new(&e, uri))?;
+    options.server_selection_timeout = Some(DB_TEST_TIMEOUT);
+    options.connect_timeout = Some(DB_TEST_TIMEOUT);
+    let client = mongodb::Client::with_options(options).map_err(|e| DbTestFailure::new(&e, uri))?;
+    let names = client
+        .database(&config.mongodb.database_name)
+        .list_collection_names(None)
+        .await
+        .map_err(|e| DbTestFailure::new(&e, uri))?;
+    Ok(names.len())
+}
+
+pub(crate) fn configured_ffmpeg_path(config: &Config) -> PathBuf {
+    if config.ffmpeg_path().is_empty() {
+        PathBuf::from("ffmpeg")
+    } else {
+        PathBuf::from(config.ffmpeg_path())
+    }
+}
+
+/// Find the ffmpeg in the config. Returns `None` if it cannot be started.
+pub async fn probe_ffmpeg(config: &Config) -> Option<PathBuf> {
+    let ffmpeg_path = configured_ffmpeg_path(config);
+
+    debug!("checking ffmpeg: {:?}", ffmpeg_path);
+
+    let mut ffmpeg = Command::new(&ffmpeg_path);
+    ffmpeg.args(["-hide_banner", "-loglevel", "error"]);
+    match ffmpeg.spawn() {
+        Ok(mut child) => {
+            let _ = timeout(Duration::from_secs(1), child.wait()).await;
+            Some(ffmpeg_path)
+        }
+        Err(err) => {
+            warn!(
+                "ffmpeg not found, some functions will not work: {}: {}",
+                ffmpeg_path.to_string_lossy(),
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Create the directory if needed and make sure files can be written into it.
+pub(crate) fn check_dir_writable(dir: &Path) -> crate::Result<()> {
+    let path = dir.to_string_lossy().to_string();
+    std::fs::create_dir_all(dir).context(error::OutputDirIo { path: path.clone() })?;
+    let probe = dir.join(".bowerbird_write_test");
+    std::fs::write(&probe, b"").context(error::OutputDirIo { path: path.clone() })?;
+    std::fs::remove_file(&probe).context(error::OutputDirIo { path })?;
+    Ok(())
+}
+
+/// Make sure the proxy can be connected to and accepts the credentials.
+pub(crate) async fn check_proxy(proxy: &str) -> crate::Result<()> {
+    let fail = |message: String| {
+        error::ProxyUnreachable {
+            proxy: redact_proxy(proxy),
+            message,
+        }
+        .fail()
+    };
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy).context(error::ProxyParse)?)
+        .timeout(Duration::from_secs(15))
+        .build()
+        .context(error::ProxyParse)?;
+    // Any response from pixiv means the proxy works.
+    match client.head("https://app-api.pixiv.net/").send().await {
+        Ok(r) if r.status() == reqwest::StatusCode::PROXY_AUTHENTICATION_REQUIRED => {
+            fail("proxy authentication failed".to_string())
+        }
+        Ok(_) => Ok(()),
+        Err(e) => fail(e.to_string()),
+    }
+}
+
+lazy_static! {
+    /// Match the profile URLs, e.g. `https://www.pixiv.net/en/users/12345`
+    /// and `https://www.pixiv.net/member.php?id=12345`.
+    static ref RE_USER_URL: Regex = Regex::new(
+        r"^(?:https?://)?(?:www\.)?pixiv\.net/(?:(?:[a-z]{2}/)?users/(\d+)|member(?:_illust)?\.php\?(?:.*&)?id=(\d+))"
+    )
+    .unwrap();
+}
+
+/// A pixiv user given by the id, the profile URL or the account name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserRef {
+    Id(String),
+    /// The account name, e.g. `example` in `https://pixiv.me/example`.
+    /// Resolved from the users saved in the database.
+    Account(String),
+}
+
+impl UserRef {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            return Ok(Self::Id(s.to_string()));
+        }
+        if let Some(c) = RE_USER_URL.captures(s) {
+            let id = c.get(1).or_else(|| c.get(2)).unwrap().as_str();
+            return Ok(Self::Id(id.to_string()));
+        }
+        let account = s
+            .trim_start_matches("https://")
+            .trim_start_matches("pixiv.me/")
+            .trim_start_matches('@');
+        if account.is_empty()
+            || !account
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!("not a pixiv user id, profile url or account: {s}"));
+        }
+        Ok(Self::Account(account.to_string()))
+    }
+
+    async fn resolve(&self, db: &Database, no_db: bool) -> crate::Result<String> {
+        let account = match self {
+            Self::Id(id) => return Ok(id.clone()),
+            Self::Account(account) => account,
+        };
+        if no_db {
+            return error::NoDbUnsupported {
+                message: "account names are resolved from the database",
+            }
+            .fail();
+        }
+        let user = db
+            .collection::<PixivUser>("pixiv_user")
+            .find_one(
+                doc! { "history.extension.account": account },
+                FindOneOptions::builder().sort(doc! { "_id": -1 }).build(),
+            )
+            .await
+            .context(error::MongoDb)?;
+        match user.and_then(|u| u.source_id) {
+            Some(id) => {
+                info!("resolved pixiv account {} to user {}", account, id);
+                Ok(id)
+            }
+            None => error::UserUnresolved { input: account }.fail(),
+        }
+    }
+}
+
+/// Parameters shared by all the pixiv syncs.
+#[derive(Debug, Clone, Default)]
+pub struct PixivSyncParams {
+    /// The user to sync. Defaults to the logged in user.
+    pub user_id: Option<UserRef>,
+    /// Stop after this number of works.
+    pub limit: Option<u32>,
+    /// Download to this directory instead of the configured storage dir.
+    pub output_dir: Option<PathBuf>,
+    /// Report the progress of downloads.
+    pub progress: Option<ProgressWriter>,
+    /// Track the progress of the downloads of the native backend,
+    /// to poll it while the sync is running.
+    pub progress_tracker: Option<ProgressTracker>,
+    /// Transcode ugoira to these formats instead of the configured ones.
+    /// Fails if ffmpeg is not available.
+    pub ugoira_formats: Option<Vec<UgoiraFormat>>,
+    /// Only download illusts with any of these tags, if not empty.
+    pub include_tags: Vec<String>,
+    /// Skip illusts with any of these tags, even if they are included.
+    pub exclude_tags: Vec<String>,
+    /// Skip illusts larger than this number of bytes in total.
+    pub max_illust_size: Option<u64>,
+    /// Only download the first pages of the illusts with more pages, instead of the config.
+    pub max_pages: Option<usize>,
+    /// Only download the illusts with this number of pages.
+    pub page_range: Option<PageRange>,
+    /// Only download the illusts with at least this number of bookmarks.
+    pub min_bookmarks: Option<u32>,
+    /// Count the illusts skipped by the filters towards the limit.
+    pub count_filtered: bool,
+    /// Only the works created on or after this day in Japan.
+    /// The newest first lists stop at the first page created before it.
+    pub since: Option<NaiveDate>,
+    /// Only the works created on or before this day in Japan.
+    pub until: Option<NaiveDate>,
+    /// What to do with illusts with some pages failed, instead of the configured policy.
+    pub partial_policy: Option<PartialPolicy>,
+    /// Download the existing files again, e.g. after pixiv re-encodes them.
+    /// The records in the database are kept.
+    pub replace: bool,
+    /// Only sync the illusts of the users without any illust in the database,
+    /// to find the artists not archived yet. Illusts only.
+    pub only_new_users: bool,
+    /// Only download the files, without touching the database.
+    /// The server cannot find these files until they are imported.
+    pub no_db: bool,
+    /// Cancel the sync. The works saved so far are kept in the database,
+    /// and unfinished downloads are resumed next time.
+    pub cancel: CancellationToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PixivSyncKind {
+    IllustBookmarks {
+        private: bool,
+        start: PageStart,
+    },
+    IllustUploads {
+        start: PageStart,
+    },
+    IllustRanking {
+        mode: RankingMode,
+        date: Option<NaiveDate>,
+    },
+    IllustSearch {
+        word: String,
+        sort: SearchSort,
+        target: SearchTarget,
+    },
+    NovelBookmarks {
+        private: bool,
+        update_exists: bool,
+    },
+    NovelUploads {
+        update_exists: bool,
+    },
+}
+
+struct PixivSession {
+    db: Database,
+    api: pixivcrab::AppApi,
+    user_id: String,
+    downloader: Box<dyn Downloader>,
+    task_config: TaskConfig,
+}
+
+fn task_dirs(config: &Config, output_dir: Option<&Path>) -> crate::Result<(PathBuf, String)> {
+    let storage_dir = config.sub_dir(&config.pixiv.storage_dir);
+    let output_dir = match output_dir {
+        Some(output_dir) => std::env::current_dir()
+            .context(error::OutputDirIo {
+                path: output_dir.to_string_lossy().to_string(),
+            })?
+            .join(output_dir),
+        None => {
+            check_dir_writable(&storage_dir)?;
+            return Ok((storage_dir, String::new()));
+        }
+    };
+    check_dir_writable(&output_dir)?;
+    // Keep paths in the database relative to the storage dir when possible,
+    // so the server can still serve the files.
+    let prefix = match output_dir.strip_prefix(&storage_dir) {
+        Ok(rel) => rel.to_path_buf().to_slash_lossy(),
+        Err(_) => {
+            warn!(
+                "output dir is outside the storage dir, files will not be served: {}",
+                output_dir.to_string_lossy()
+            );
+            output_dir.to_slash_lossy()
+        }
+    };
+    let prefix = if prefix.is_empty() {
+        prefix
+    } else {
+        format!("{}/", prefix.trim_end_matches('/'))
+    };
+    info!("downloading to: {}", output_dir.to_string_lossy());
+    Ok((output_dir, prefix))
+}
+
+async fn pixiv_session(
+    config: &mut Config,
+    params: &PixivSyncParams,
+) -> crate::Result<PixivSession> {
+    use pixivcrab::AuthMethod;
+
+    set_throttle_window(Duration::from_secs(config.warning_dedup_window_secs));
+    set_pacing(
+        Duration::from_millis(config.pacing.interval_millis),
+        Duration::from_millis(config.pacing.jitter_millis),
+    );
+    set_rate_limit_cooldown(
+        Duration::from_secs(config.pacing.rate_limit_cooldown_secs),
+        config.pacing.rate_limit_max_cooldowns,
+    );
+    let db = if params.no_db {
+        info!("download only, nothing is saved to the database");
+        open_db(config).await?
+    } else {
+        let db = connect_db(config, true).await?;
+        command::pixiv::database::create_indexes(&db).await?;
+        db
+    };
+    let ffmpeg_path = probe_ffmpeg(config).await;
+
+    let mut api_client = config.http_client.apply(reqwest::ClientBuilder::new());
+    if let Some(proxy) = config.pxoxy(&config.pixiv.proxy_api)? {
+        if let Some(proxy_string) = config.pxoxy_string(&config.pixiv.proxy_api) {
+            debug!("pixiv api proxy set: {}", redact_proxy(&proxy_string));
+            check_proxy(&proxy_string).await?;
+        }
+        api_client = api_client.proxy(proxy);
+    }
+    let download_proxy = config.pxoxy_string(&config.pixiv.proxy_download);
+    if let Some(ref proxy) = download_proxy {
+        if Some(proxy) != config.pxoxy_string(&config.pixiv.proxy_api).as_ref() {
+            debug!("pixiv download proxy set: {}", redact_proxy(proxy));
+            check_proxy(proxy).await?;
+        }
+    }
+    if std::env::var("BOWERBIRD_ACCEPT_INVALID_CERTS").is_ok() {
+        warn!("invalid certs will be accepted for pixiv api requests");
+        api_client = api_client.danger_accept_invalid_certs(true);
+    }
+    let api = pixivcrab::AppApi::new(
+        AuthMethod::RefreshToken(config.pixiv.refresh_token.clone()),
+        &config.pixiv.language,
+        api_client,
+    )
+    .context(error::PixivApi)?;
+    let auth_result = api.auth().await.context(error::PixivAuth)?;
+    debug!("pixiv authed: {:?}", auth_result);
+    info!(
+        "pixiv logged in: {} ({})",
+        auth_result.user.name, auth_result.user.id
+    );
+    config.pixiv.refresh_token = auth_result.refresh_token;
+    if config.config_path().is_some() {
+        config.save()?;
+    }
+    let user_id = match params.user_id {
+        Some(ref user) => user.resolve(&db, params.no_db).await?,
+        None => auth_result.user.id.clone(),
+    };
+    // Bookmark tags are only visible to the owner of the bookmarks.
+    let bookmark_tags = config.pixiv.bookmark_tags && user_id == auth_result.user.id;
+
+    let downloader = new_downloader(config, params).await?;
+
+    let ugoira_formats = match &params.ugoira_formats {
+        Some(formats) => {
+            if let (None, Some(format)) = (&ffmpeg_path, formats.first()) {
+                return error::UgoiraFormatNoFfmpeg {
+                    format: format.extension(),
+                }
+                .fail();
+            }
+            formats.clone()
+        }
+        None => config.pixiv.ugoira_formats.clone(),
+    };
+
+    let size_guard = match params.max_illust_size {
+        Some(max_bytes) => {
+            let mut client = config.http_client.apply(reqwest::ClientBuilder::new());
+            if let Some(proxy) = config.pxoxy(&config.pixiv.proxy_download)? {
+                client = client.proxy(proxy);
+            }
+            let client = config.download.network.apply(client)?;
+            Some(SizeGuard {
+                max_bytes,
+                client: client.build().context(error::HttpClientBuild)?,
+            })
+        }
+        None => None,
+    };
+
+    let quota_enabled = config.quota.max_bytes != 0 || config.quota.warn_bytes != 0;
+    let quota = if !quota_enabled {
+        None
+    } else if params.no_db {
+        warn!("quota is not counted without the database");
+        None
+    } else {
+        Some(Arc::new(
+            command::pixiv::quota::Quota::load(&db, config.quota.clone()).await?,
+        ))
+    };
+
+    let write_batch = (config.mongodb.write_batch.size > 1 && !params.no_db).then(|| {
+        Arc::new(command::pixiv::database::WriteBatch::new(
+            db.clone(),
+            &config.mongodb.write_batch,
+        ))
+    });
+
+    #[cfg(feature = "embedding")]
+    let embedding = if config.pixiv.embedding.model_path.is_empty() || params.no_db {
+        None
+    } else {
+        Some(command::pixiv::embedding::EmbeddingTask {
+            embedder: Arc::new(command::pixiv::embedding::Embedder::new(
+                &config.pixiv.embedding,
+            )?),
+            c_embedding: db.collection("pixiv_image_embedding"),
+        })
+    };
+    #[cfg(not(feature = "embedding"))]
+    if !config.pixiv.embedding.model_path.is_empty() {
+        warn!("embedding model is set, but bowerbird is built without the `embedding` feature");
+    }
+
+    let cpu = CpuPool::new(config.analysis_threads);
+    debug!("analyzing images with {} threads", cpu.threads());
+    let transcode = (!ugoira_formats.is_empty()).then(|| {
+        let pool = TranscodePool::new(
+            config.pixiv.transcode_workers,
+            config.pixiv.transcode_queue_depth,
+        );
+        debug!("transcoding ugoira with {} workers", pool.workers());
+        pool
+    });
+
+    let (parent_dir, db_path_prefix) = task_dirs(config, params.output_dir.as_deref())?;
+    let task_config = TaskConfig {
+        ffmpeg: Ffmpeg::new(configured_ffmpeg_path(config), ffmpeg_path.is_some()),
+        parent_dir,
+        db_path_prefix,
+        storage_tiers: config.pixiv_storage_dirs().split_off(1),
+        collision_policy: config.pixiv.collision_policy,
+        directory_sharding: config.pixiv.directory_sharding,
+        tag_routes: config.pixiv.tag_routes.clone(),
+        size_guard,
+        max_pages: params.max_pages.or(config.pixiv.max_pages),
+        page_range: params.page_range,
+        min_bookmarks: params.min_bookmarks,
+        count_filtered: params.count_filtered,
+        since: params.since,
+        until: params.until,
+        write_batch,
+        quota,
+        include_tags: params.include_tags.clone(),
+        exclude_tags: params.exclude_tags.clone(),
+        ugoira_formats,
+        transcode,
+        sidecar: config.pixiv.sidecar,
+        derivative: Some(config.pixiv.derivative.clone()).filter(|d| d.enabled),
+        #[cfg(feature = "embedding")]
+        embedding,
+        cpu,
+        prefetch_pages: config.pixiv.prefetch_pages,
+        partial_policy: params.partial_policy.unwrap_or(config.pixiv.partial_policy),
+        replace: params.replace,
+        verify_existing: config.pixiv.verify_existing,
+        only_new_users: params.only_new_users && !params.no_db,
+        bookmark_tags,
+        proxy: download_proxy,
+        no_db: params.no_db,
+        cancel: params.cancel.clone(),
+        stats: Default::default(),
+        page_token: Default::default(),
+    };
+    Ok(PixivSession {
+        db,
+        api,
+        user_id,
+        downloader,
+        task_config,
+    })
+}
+
+/// The downloader of `download.backend` in the config.
+pub(crate) async fn new_downloader(
+    config: &Config,
+    params: &PixivSyncParams,
+) -> crate::Result<Box<dyn Downloader>> {
+    Ok(match config.download.backend {
+        DownloadBackend::Aria2 => {
+            let mut downloader =
+                Aria2Downloader::new(config.aria2_path(), &config.download.network)
+                    .await?
+                    .with_cancellation(params.cancel.clone())
+                    .with_circuit_breaker(config.circuit_breaker.clone());
+            if let Some(secs) = config.aria2_idle_timeout_secs {
+                downloader = downloader.with_idle_timeout(Duration::from_secs(secs));
+            }
+            if let Some(ref progress) = params.progress {
+                downloader = downloader.with_progress(progress.clone());
+            }
+            Box::new(downloader)
+        }
+        DownloadBackend::Native => {
+            let mut client = config.http_client.apply(reqwest::ClientBuilder::new());
+            if let Some(proxy) = config.pxoxy(&config.pixiv.proxy_download)? {
+                client = client.proxy(proxy);
+            }
+            let client = config
+                .download
+                .network
+                .apply(client)?
+                .build()
+                .context(error::HttpClientBuild)?;
+            let mut downloader = NativeDownloader::new(client, config.download.native_concurrency)
+                .with_request_builder(|builder| {
+                    builder.header(reqwest::header::REFERER, "https://app-api.pixiv.net/")
+                })
+                .with_cancellation(params.cancel.clone())
+                .with_circuit_breaker(config.circuit_breaker.clone());
+            if let Some(ref progress) = params.progress {
+                downloader = downloader.with_progress(progress.clone());
+            }
+            if let Some(ref tracker) = params.progress_tracker {
+                downloader = downloader.with_progress_tracker(tracker.clone());
+            }
+            if config.download.stall_timeout_secs != 0 {
+                downloader = downloader.with_stall_watchdog(
+                    Duration::from_secs(config.download.stall_timeout_secs),
+                    config.download.stall_restarts,
+                );
+            }
+            if config.download.retries != 0 {
+                downloader = downloader.with_retries(
+                    config.download.retries,
+                    Duration::from_millis(config.download.retry_backoff_millis),
+                );
+            }
+            Box::new(downloader)
+        }
+    })
+}
+
+/// Log in to pixiv, save the works to the database and download them.
+///
+/// The refresh token in `config` is updated, and saved if it is loaded from a file.
+pub async fn sync_pixiv(
+    config: &mut Config,
+    params: &PixivSyncParams,
+    kind: PixivSyncKind,
+) -> crate::Result<SyncResult> {
+    let PixivSession {
+        db,
+        api,
+        user_id,
+        downloader,
+        task_config,
+    } = pixiv_session(config, params).await?;
+    let limit = params.limit;
+
+    let mut result = match kind {
+        PixivSyncKind::IllustBookmarks { private, start } => {
+            command::pixiv::illust_bookmarks(
+                &api,
+                &db,
+                downloader.as_ref(),
+                &user_id,
+                private,
+                &start,
+                limit,
+                &task_config,
+            )
+            .await?
+        }
+        PixivSyncKind::IllustUploads { start } => {
+            command::pixiv::illust_uploads(
+                &api,
+                &db,
+                downloader.as_ref(),
+                &user_id,
+                &start,
+                limit,
+                &task_config,
+            )
+            .await?
+        }
+        PixivSyncKind::IllustRanking { mode, date } => {
+            command::pixiv::illust_ranking(
+                &api,
+                &db,
+                downloader.as_ref(),
+                mode,
+                date,
+                limit,
+                &task_config,
+            )
+            .await?
+        }
+        PixivSyncKind::IllustSearch { word, sort, target } => {
+            command::pixiv::illust_search(
+                &api,
+                &db,
+                downloader.as_ref(),
+                &word,
+                sort,
+                target,
+                limit,
+                &task_config,
+            )
+            .await?
+        }
+        PixivSyncKind::NovelBookmarks {
+            private,
+            update_exists,
+        } => {
+            command::pixiv::novel_bookmarks(
+                &api,
+                &db,
+                downloader.as_ref(),
+                update_exists,
+                &user_id,
+                private,
+                limit,
+                &task_config,
+            )
+            .await?
+        }
+        PixivSyncKind::NovelUploads { update_exists } => {
+            command::pixiv::novel_uploads(
+                &api,
+                &db,
+                downloader.as_ref(),
+                update_exists,
+                &user_id,
+                limit,
+                &task_config,
+            )
+            .await?
+        }
+    };
+    downloader.wait_shutdown().await;
+    if let Some(batch) = &task_config.write_batch {
+        batch.close().await;
+    }
+    if !params.cancel.is_cancelled() {
+        task_config.page_token.save().await?;
+    } else if task_config.page_token.is_set() {
+        // Some of the downloads queued from the pages before may be unfinished.
+        info!("sync cancelled, the page token is not saved, the next run starts over from the same page");
+    }
+    task_config.stats.fill(&mut result);
+    result.failed = downloader.failed_tasks();
+    Ok(result)
+}
+
+/// Download the illusts by id, skipping those already downloaded.
+///
+/// Returns the status of each id, and the number of the failed downloads.
+pub async fn sync_illust_ids(
+    config: &mut Config,
+    params: &PixivSyncParams,
+    ids: Vec<String>,
+) -> crate::Result<(Vec<(String, IdImportStatus)>, usize)> {
+    let PixivSession {
+        db,
+        api,
+        downloader,
+        task_config,
+        ..
+    } = pixiv_session(config, params).await?;
+    let report =
+        command::pixiv::illust_ids(&db, &api, downloader.as_ref(), ids, false, &task_config)
+            .await?;
+    downloader.wait_shutdown().await;
+    if let Some(batch) = &task_config.write_batch {
+        batch.close().await;
+    }
+    Ok((report, downloader.failed_tasks()))
+}
+
+/// Download an illust by id, updating it if it is already in the database.
+///
+/// The files already downloaded are kept unless `params.replace`.
+pub async fn sync_illust_id(
+    config: &mut Config,
+    params: &PixivSyncParams,
+    id: u64,
+) -> crate::Result<(IdImportStatus, usize)> {
+    let PixivSession {
+        db,
+        api,
+        downloader,
+        task_config,
+        ..
+    } = pixiv_session(config, params).await?;
+    let ids = vec![id.to_string()];
+    let mut report =
+        command::pixiv::illust_ids(&db, &api, downloader.as_ref(), ids, true, &task_config).await?;
+    downloader.wait_shutdown().await;
+    if let Some(batch) = &task_config.write_batch {
+        batch.close().await;
+    }
+    let status = match report.pop() {
+        Some((_, status)) => status,
+        // Cancelled before getting it.
+        None => IdImportStatus::Failed("cancelled".to_string()),
+    };
+    Ok((status, downloader.failed_tasks()))
+}
+
+pub async fn sync_illust_bookmarks(
+    config: &mut Config,
+    params: &PixivSyncParams,
+    private: bool,
+) -> crate::Result<SyncResult> {
+    sync_pixiv(
+        config,
+        params,
+        PixivSyncKind::IllustBookmarks {
+            private,
+            start: PageStart::First,
+        },
+    )
+    .await
+}
+
+pub async fn sync_illust_uploads(
+    config: &mut Config,
+    params: &PixivSyncParams,
+) -> crate::Result<SyncResult> {
+    sync_pixiv(
+        config,
+        params,
+        PixivSyncKind::IllustUploads {
+            start: PageStart::First,
+        },
+    )
+    .await
+}
+
+pub async fn sync_illust_ranking(
+    config: &mut Config,
+    params: &PixivSyncParams,
+    mode: RankingMode,
+    date: Option<NaiveDate>,
+) -> crate::Result<SyncResult> {
+    sync_pixiv(config, params, PixivSyncKind::IllustRanking { mode, date }).await
+}
+
+pub async fn sync_illust_search(
+    config: &mut Config,
+    params: &PixivSyncParams,
+    word: String,
+    sort: SearchSort,
+    target: SearchTarget,
+) -> crate::Result<SyncResult> {
+    sync_pixiv(
+        config,
+        params,
+        PixivSyncKind::IllustSearch { word, sort, target },
+    )
+    .await
+}
+
+pub async fn sync_novel_bookmarks(
+    config: &mut Config,
+    params: &PixivSyncParams,
+    private: bool,
+    update_exists: bool,
+) -> crate::Result<SyncResult> {
+    sync_pixiv(
+        config,
+        params,
+        PixivSyncKind::NovelBookmarks {
+            private,
+            update_exists,
+        },
+    )
+    .await
+}
+
+pub async fn sync_novel_uploads(
+    config: &mut Config,
+    params: &PixivSyncParams,
+    update_exists: bool,
+) -> crate::Result<SyncResult> {
+    sync_pixiv(
+        config,
+        params,
+        PixivSyncKind::NovelUploads { update_exists },
+    )
+    .await
+}
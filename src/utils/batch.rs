@@ -0,0 +1,157 @@
+use std::{future::Future, sync::Mutex, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+
+type Queued<T, E> = (T, oneshot::Sender<Result<(), E>>);
+
+/// Write the items in batches, when `max_size` items are queued or `interval` after the first.
+///
+/// The batches are written one at a time in the order the items are queued.
+pub struct Batcher<T, E> {
+    tx: Mutex<Option<mpsc::Sender<Queued<T, E>>>>,
+    worker: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<T, E> Batcher<T, E>
+where
+    T: Send + 'static,
+    E: Clone + Send + 'static,
+{
+    pub fn new<F, Fut>(max_size: usize, interval: Duration, flush: F) -> Self
+    where
+        F: FnMut(Vec<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        let max_size = max_size.max(1);
+        let (tx, rx) = mpsc::channel(max_size * 2);
+        let worker = tokio::spawn(run(rx, max_size, interval, flush));
+        Self {
+            tx: Mutex::new(Some(tx)),
+            worker: tokio::sync::Mutex::new(Some(worker)),
+        }
+    }
+
+    /// Queue the item and wait until its batch is written.
+    ///
+    /// Every item of a failed batch gets the error. `None` if the batcher is closed.
+    pub async fn write(&self, item: T) -> Option<Result<(), E>> {
+        let tx = self.tx.lock().unwrap().clone()?;
+        let (done, written) = oneshot::channel();
+        tx.send((item, done)).await.ok()?;
+        written.await.ok()
+    }
+
+    /// Write all the queued items and stop.
+    pub async fn close(&self) {
+        self.tx.lock().unwrap().take();
+        if let Some(worker) = self.worker.lock().await.take() {
+            let _ = worker.await;
+        }
+    }
+}
+
+async fn run<T, E, F, Fut>(
+    mut rx: mpsc::Receiver<Queued<T, E>>,
+    max_size: usize,
+    interval: Duration,
+    mut flush: F,
+) where
+    E: Clone,
+    F: FnMut(Vec<T>) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    let mut items = Vec::with_capacity(max_size);
+    let mut waiters = Vec::with_capacity(max_size);
+    while let Some((item, done)) = rx.recv().await {
+        items.push(item);
+        waiters.push(done);
+        let deadline = Instant::now() + interval;
+        while items.len() < max_size {
+            match timeout_at(deadline, rx.recv()).await {
+                Ok(Some((item, done))) => {
+                    items.push(item);
+                    waiters.push(done);
+                }
+                // Closed or timed out, the closed channel ends the outer loop.
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let r = flush(std::mem::replace(&mut items, Vec::with_capacity(max_size))).await;
+        for done in waiters.drain(..) {
+            let _ = done.send(r.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::BTreeSet,
+        sync::{
+            atomic::{AtomicUsize, Ordering::SeqCst},
+            Arc,
+        },
+    };
+
+    #[tokio::test]
+    async fn concurrent_writes_all_saved_once() {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let largest = Arc::new(AtomicUsize::new(0));
+        let batcher = Arc::new(Batcher::new(16, Duration::from_millis(10), {
+            let saved = saved.clone();
+            let largest = largest.clone();
+            move |items: Vec<u32>| {
+                largest.fetch_max(items.len(), SeqCst);
+                saved.lock().unwrap().extend(items);
+                async { Ok::<_, String>(()) }
+            }
+        }));
+        let writes: Vec<_> = (0..500)
+            .map(|i| {
+                let batcher = batcher.clone();
+                tokio::spawn(async move { batcher.write(i).await })
+            })
+            .collect();
+        for w in writes {
+            assert_eq!(w.await.unwrap(), Some(Ok(())));
+        }
+        batcher.close().await;
+
+        let saved = saved.lock().unwrap();
+        assert_eq!(saved.len(), 500);
+        let expected: BTreeSet<_> = (0..500).collect();
+        assert_eq!(saved.iter().copied().collect::<BTreeSet<_>>(), expected);
+        assert!(largest.load(SeqCst) <= 16);
+    }
+
+    #[tokio::test]
+    async fn error_reaches_every_item_of_batch() {
+        let batcher = Arc::new(Batcher::new(
+            4,
+            Duration::from_millis(50),
+            |_: Vec<u32>| async { Err("write failed".to_string()) },
+        ));
+        let writes: Vec<_> = (0..4)
+            .map(|i| {
+                let batcher = batcher.clone();
+                tokio::spawn(async move { batcher.write(i).await })
+            })
+            .collect();
+        for w in writes {
+            assert_eq!(w.await.unwrap(), Some(Err("write failed".to_string())));
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_batcher_rejects_writes() {
+        let batcher = Batcher::new(4, Duration::ZERO, |_: Vec<u32>| async {
+            Ok::<_, String>(())
+        });
+        batcher.close().await;
+        assert_eq!(batcher.write(1).await, None);
+    }
+}
@@ -0,0 +1,143 @@
+use lazy_static::lazy_static;
+use log::LevelFilter;
+use std::{str::FromStr, sync::RwLock};
+
+lazy_static! {
+    static ref LOG_LEVELS: RwLock<LogLevels> = RwLock::new(LogLevels::default());
+}
+
+/// The level of the logs of each module, like `RUST_LOG`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLevels {
+    /// The modules without a directive.
+    default: LevelFilter,
+    /// The modules and their levels, e.g. `downloader` for `bowerbird::downloader`.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogLevels {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Warn,
+            directives: vec![("bowerbird".to_string(), LevelFilter::Debug)],
+        }
+    }
+}
+
+impl LogLevels {
+    /// `1` for info on every module, `2` for debug and `3` or more for trace.
+    /// bowerbird itself logs at debug at least.
+    pub fn with_verbosity(mut self, verbosity: u64) -> Self {
+        let level = match verbosity {
+            0 => return self,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        self.default = self.default.max(level);
+        for (_, l) in &mut self.directives {
+            *l = (*l).max(level);
+        }
+        self
+    }
+
+    /// Apply directives separated by commas, e.g. `info,downloader=debug,mongodb=off`.
+    /// A directive without a module sets the level of the others.
+    pub fn with_directives(mut self, directives: &str) -> Result<Self, String> {
+        for directive in directives
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+        {
+            let parse_level = |level: &str| {
+                LevelFilter::from_str(level.trim())
+                    .map_err(|_| format!("invalid log level in directive {directive}"))
+            };
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim().trim_start_matches("bowerbird::");
+                    let level = parse_level(level)?;
+                    self.directives.retain(|(m, _)| m != module);
+                    self.directives.push((module.to_string(), level));
+                }
+                None => self.default = parse_level(directive)?,
+            }
+        }
+        Ok(self)
+    }
+
+    /// The level of the directive of the longest module matching the target.
+    ///
+    /// A module matches itself and its submodules, in bowerbird or not.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        let local = target.strip_prefix("bowerbird::");
+        let matches = |module: &str, target: &str| {
+            target == module
+                || target
+                    .strip_prefix(module)
+                    .map_or(false, |rest| rest.starts_with("::"))
+        };
+        self.directives
+            .iter()
+            .filter(|(module, _)| {
+                matches(module, target) || local.map_or(false, |t| matches(module, t))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level of any module.
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+/// Whether a log of the target at the level is written.
+pub fn log_enabled(target: &str, level: log::Level) -> bool {
+    level <= LOG_LEVELS.read().unwrap().level(target)
+}
+
+pub fn set_log_levels(levels: LogLevels) {
+    log::set_max_level(levels.max_level());
+    *LOG_LEVELS.write().unwrap() = levels;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_module_wins() {
+        let levels = LogLevels::default()
+            .with_directives("info,downloader=trace,downloader::progress=error,mongodb=off")
+            .unwrap();
+        assert_eq!(levels.level("reqwest::connect"), LevelFilter::Info);
+        assert_eq!(levels.level("bowerbird::sync"), LevelFilter::Debug);
+        assert_eq!(
+            levels.level("bowerbird::downloader::aria2"),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            levels.level("bowerbird::downloader::progress"),
+            LevelFilter::Error
+        );
+        assert_eq!(levels.level("bowerbird::downloaders"), LevelFilter::Debug);
+        assert_eq!(levels.level("mongodb::cmap"), LevelFilter::Off);
+        assert_eq!(levels.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn verbosity() {
+        let levels = LogLevels::default().with_verbosity(1);
+        assert_eq!(levels.level("reqwest"), LevelFilter::Info);
+        assert_eq!(levels.level("bowerbird::sync"), LevelFilter::Debug);
+        let levels = LogLevels::default().with_verbosity(3);
+        assert_eq!(levels.level("bowerbird::sync"), LevelFilter::Trace);
+        assert!(LogLevels::default()
+            .with_directives("downloader=loud")
+            .is_err());
+    }
+}
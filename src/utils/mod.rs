@@ -1,7 +1,19 @@
-use std::net::TcpListener;
+use std::{net::TcpListener, path::PathBuf};
 
+mod batch;
+mod log_levels;
+mod pacer;
+mod pool;
+mod retry;
+mod throttle;
 mod waitgroup;
 
+pub use batch::Batcher;
+pub use log_levels::{log_enabled, set_log_levels, LogLevels};
+pub use pacer::{pace, pause_pacing, set_pacing};
+pub use pool::CpuPool;
+pub use retry::{retry_db, set_db_retry};
+pub use throttle::{flush_throttled, set_throttle_window, warn_throttled};
 pub use waitgroup::WaitGroup;
 
 pub fn get_available_port<T>(ra: T) -> Option<u16>
@@ -44,8 +56,76 @@ pub fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
     (h, s, v)
 }
 
+/// The inverse of `rgb_to_hsv`.
+pub fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (u8, u8, u8) {
+    let c = v * s;
+    let h = (h.rem_euclid(360.0)) / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    let to_u8 = |f: f32| ((f + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    (to_u8(r), to_u8(g), to_u8(b))
+}
+
+/// The first of the storage dirs holding the file, with its index in `storage_dirs`.
+pub fn find_in_storage_dirs(
+    storage_dirs: &[PathBuf],
+    local_path: &str,
+) -> Option<(usize, PathBuf)> {
+    storage_dirs
+        .iter()
+        .map(|d| d.join(local_path))
+        .enumerate()
+        .find(|(_, p)| p.exists())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+
+    #[test]
+    fn find_in_storage_dirs() {
+        let root = std::env::temp_dir().join(format!("bowerbird-tiers-{}", std::process::id()));
+        let dirs: Vec<PathBuf> = ["hot", "cold", "empty"]
+            .iter()
+            .map(|d| root.join(d))
+            .collect();
+        for d in &dirs[..2] {
+            std::fs::create_dir_all(d.join("1")).unwrap();
+        }
+        std::fs::write(dirs[0].join("1/a.jpg"), b"a").unwrap();
+        std::fs::write(dirs[1].join("1/a.jpg"), b"a").unwrap();
+        std::fs::write(dirs[1].join("1/b.jpg"), b"b").unwrap();
+
+        let found = |p: &str| super::find_in_storage_dirs(&dirs, p);
+        assert_eq!(found("1/a.jpg"), Some((0, dirs[0].join("1/a.jpg"))));
+        assert_eq!(found("1/b.jpg"), Some((1, dirs[1].join("1/b.jpg"))));
+        assert_eq!(found("1/c.jpg"), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn hsv2rgb() {
+        for (r, g, b) in [
+            (255, 0, 0),
+            (0, 255, 0),
+            (0, 0, 255),
+            (255, 255, 255),
+            (0, 0, 0),
+            (108, 52, 62),
+        ] {
+            let (h, s, v) = super::rgb_to_hsv(r, g, b);
+            assert_eq!(super::hsv_to_rgb(h, s, v), (r, g, b));
+        }
+    }
+
     #[test]
     fn rgb2hsv() {
         assert_eq!(super::rgb_to_hsv(255, 0, 0), (0.0, 1.0, 1.0));
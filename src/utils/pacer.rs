@@ -0,0 +1,138 @@
+use lazy_static::lazy_static;
+use log::debug;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+lazy_static! {
+    static ref PACER: Pacer = Pacer::new(Duration::ZERO, Duration::ZERO);
+}
+
+/// Spaces out the outgoing requests by a minimum interval plus a random jitter,
+/// so a sync starts steadily instead of sending a burst.
+#[derive(Debug)]
+pub struct Pacer {
+    interval_millis: AtomicU64,
+    jitter_millis: AtomicU64,
+    next: Mutex<Option<Instant>>,
+}
+
+impl Pacer {
+    pub fn new(interval: Duration, jitter: Duration) -> Self {
+        Self {
+            interval_millis: AtomicU64::new(interval.as_millis() as u64),
+            jitter_millis: AtomicU64::new(jitter.as_millis() as u64),
+            next: Mutex::new(None),
+        }
+    }
+
+    pub fn set(&self, interval: Duration, jitter: Duration) {
+        self.interval_millis
+            .store(interval.as_millis() as u64, SeqCst);
+        self.jitter_millis.store(jitter.as_millis() as u64, SeqCst);
+    }
+
+    /// Hold the requests reserving a slot from now on until `until`.
+    /// The ones already sleeping for their slot are not held.
+    fn pause_until(&self, until: Instant) {
+        let mut next = self.next.lock().unwrap();
+        if next.map_or(true, |n| n < until) {
+            *next = Some(until);
+        }
+    }
+
+    /// Take the next slot and return how long to wait for it.
+    fn reserve(&self, now: Instant, jitter: Duration) -> Duration {
+        let interval = Duration::from_millis(self.interval_millis.load(SeqCst));
+        let mut next = self.next.lock().unwrap();
+        let slot = match *next {
+            Some(n) if n > now => n,
+            _ => now,
+        };
+        *next = Some(slot + interval + jitter);
+        slot - now
+    }
+
+    fn random_jitter(&self) -> Duration {
+        let max = self.jitter_millis.load(SeqCst);
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos() as u64;
+        Duration::from_millis(nanos % (max + 1))
+    }
+
+    pub async fn wait(&self) {
+        let delay = self.reserve(Instant::now(), self.random_jitter());
+        if !delay.is_zero() {
+            debug!("pacing: request delayed for {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Wait for the turn of the next outgoing request.
+pub async fn pace() {
+    PACER.wait().await;
+}
+
+pub fn set_pacing(interval: Duration, jitter: Duration) {
+    PACER.set(interval, jitter);
+}
+
+/// Hold the outgoing requests for `duration`, e.g. after pixiv limits the rate.
+/// The downloads from the pixiv CDN are held too, as they share the pacing.
+pub fn pause_pacing(duration: Duration) {
+    PACER.pause_until(Instant::now() + duration);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn space_out_requests() {
+        let p = Pacer::new(Duration::from_millis(100), Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(p.reserve(now, Duration::ZERO), Duration::ZERO);
+        assert_eq!(
+            p.reserve(now, Duration::from_millis(10)),
+            Duration::from_millis(100)
+        );
+        assert_eq!(p.reserve(now, Duration::ZERO), Duration::from_millis(210));
+        // Idle for long enough, no need to wait.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(p.reserve(later, Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn pause_holds_next_request() {
+        let p = Pacer::new(Duration::from_millis(100), Duration::ZERO);
+        let now = Instant::now();
+        p.pause_until(now + Duration::from_secs(5));
+        assert_eq!(p.reserve(now, Duration::ZERO), Duration::from_secs(5));
+        // A shorter pause never brings the next slot forward.
+        p.pause_until(now + Duration::from_secs(1));
+        assert_eq!(p.reserve(now, Duration::ZERO), Duration::from_millis(5100));
+    }
+
+    #[test]
+    fn pause_after_reserve() {
+        let p = Pacer::new(Duration::from_millis(100), Duration::ZERO);
+        let now = Instant::now();
+        // Already reserved, so not held by the pause.
+        assert_eq!(p.reserve(now, Duration::ZERO), Duration::ZERO);
+        p.pause_until(now + Duration::from_secs(5));
+        // An earlier pause keeps the slots already spaced out.
+        p.pause_until(now + Duration::from_millis(50));
+        assert_eq!(p.reserve(now, Duration::ZERO), Duration::from_secs(5));
+        assert_eq!(p.reserve(now, Duration::ZERO), Duration::from_millis(5100));
+    }
+}
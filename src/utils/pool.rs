@@ -0,0 +1,82 @@
+use log::error;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+use crate::error::BoxError;
+
+/// A thread pool for the CPU heavy work, e.g. analyzing the downloaded images.
+///
+/// Separate from the blocking pool of tokio,
+/// so the work is bounded by the number of threads instead of queuing behind file IO.
+#[derive(Debug, Clone)]
+pub struct CpuPool {
+    pool: Arc<rayon::ThreadPool>,
+}
+
+impl CpuPool {
+    /// `0` threads for one per CPU.
+    pub fn new(threads: usize) -> Self {
+        let threads = if threads == 0 {
+            num_cpus::get()
+        } else {
+            threads
+        };
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("bowerbird-cpu-{i}"))
+            // rayon aborts on panics by default.
+            .panic_handler(|_| error!("a job panicked in the cpu pool"))
+            .build()
+            .expect("cannot start the cpu pool");
+        Self {
+            pool: Arc::new(pool),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Run `f` in the pool and wait for the result.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, BoxError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let _ = tx.send(f());
+        });
+        rx.await
+            .map_err(|_| "the job panicked in the cpu pool".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn jobs_run_in_parallel() {
+        let pool = CpuPool::new(4);
+        let started = Instant::now();
+        let jobs = (0..8).map(|i| {
+            pool.run(move || {
+                std::thread::sleep(Duration::from_millis(100));
+                i
+            })
+        });
+        let results = futures::future::try_join_all(jobs).await.unwrap();
+        assert_eq!(results, (0..8).collect::<Vec<_>>());
+        // 2 rounds of 4 jobs, instead of 8 in a row.
+        assert!(started.elapsed() < Duration::from_millis(600));
+    }
+
+    #[tokio::test]
+    async fn panic_is_an_error() {
+        let pool = CpuPool::new(1);
+        assert!(pool.run(|| panic!("boom")).await.is_err());
+        assert_eq!(pool.run(|| 1).await.unwrap(), 1);
+    }
+}
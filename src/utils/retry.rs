@@ -0,0 +1,170 @@
+use lazy_static::lazy_static;
+use log::debug;
+use mongodb::error::{ErrorKind, WriteFailure, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR};
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering::SeqCst},
+    time::Duration,
+};
+
+lazy_static! {
+    static ref DB_RETRY: RetryPolicy = RetryPolicy::new(3, Duration::from_millis(200));
+}
+
+/// Codes of the errors worth retrying, e.g. when the primary steps down.
+const RETRYABLE_CODES: [i32; 12] = [
+    6, 7, 89, 91, 189, 262, 9001, 10107, 11600, 11602, 13435, 13436,
+];
+
+/// Whether an error may go away by trying again.
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for mongodb::error::Error {
+    fn is_transient(&self) -> bool {
+        if self.contains_label(RETRYABLE_WRITE_ERROR)
+            || self.contains_label(TRANSIENT_TRANSACTION_ERROR)
+        {
+            return true;
+        }
+        match &*self.kind {
+            ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } => true,
+            ErrorKind::ServerSelection { .. } => true,
+            ErrorKind::Command(e) => RETRYABLE_CODES.contains(&e.code),
+            ErrorKind::Write(WriteFailure::WriteConcernError(e)) => {
+                RETRYABLE_CODES.contains(&e.code)
+            }
+            // Duplicate keys, validation and the other write errors stay the same.
+            _ => false,
+        }
+    }
+}
+
+/// Retry the transient errors with an exponential backoff.
+#[derive(Debug)]
+pub struct RetryPolicy {
+    retries: AtomicU32,
+    backoff_millis: AtomicU64,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, backoff: Duration) -> Self {
+        Self {
+            retries: AtomicU32::new(retries),
+            backoff_millis: AtomicU64::new(backoff.as_millis() as u64),
+        }
+    }
+
+    pub fn set(&self, retries: u32, backoff: Duration) {
+        self.retries.store(retries, SeqCst);
+        self.backoff_millis
+            .store(backoff.as_millis() as u64, SeqCst);
+    }
+
+    pub async fn run<T, E, F, Fut>(&self, what: &str, mut f: F) -> Result<T, E>
+    where
+        E: Transient + Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let retries = self.retries.load(SeqCst);
+        let backoff = Duration::from_millis(self.backoff_millis.load(SeqCst));
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Err(e) if attempt < retries && e.is_transient() => {
+                    let delay = backoff * 2u32.saturating_pow(attempt);
+                    attempt += 1;
+                    debug!(
+                        "transient error on {}, retry {}/{} in {:?}: {}",
+                        what, attempt, retries, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+/// Run a database operation, retrying it on transient errors.
+///
+/// `f` is called again for every attempt, so the operation must be safe to repeat.
+pub async fn retry_db<T, F, Fut>(what: &str, f: F) -> Result<T, mongodb::error::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, mongodb::error::Error>>,
+{
+    DB_RETRY.run(what, f).await
+}
+
+pub fn set_db_retry(retries: u32, backoff: Duration) {
+    DB_RETRY.set(retries, backoff);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Debug)]
+    struct MockError(bool);
+
+    impl Display for MockError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "mock error, transient: {}", self.0)
+        }
+    }
+
+    impl Transient for MockError {
+        fn is_transient(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_transient_once() {
+        let policy = RetryPolicy::new(3, Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+        let r = policy
+            .run("mock", || async {
+                match calls.fetch_add(1, SeqCst) {
+                    0 => Err(MockError(true)),
+                    _ => Ok(42),
+                }
+            })
+            .await;
+        assert_eq!(r.unwrap(), 42);
+        assert_eq!(calls.load(SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn no_retry_on_permanent() {
+        let policy = RetryPolicy::new(3, Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+        let r: Result<(), _> = policy
+            .run("mock", || async {
+                calls.fetch_add(1, SeqCst);
+                Err(MockError(false))
+            })
+            .await;
+        assert!(r.is_err());
+        assert_eq!(calls.load(SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn give_up_after_retries() {
+        let policy = RetryPolicy::new(2, Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+        let r: Result<(), _> = policy
+            .run("mock", || async {
+                calls.fetch_add(1, SeqCst);
+                Err(MockError(true))
+            })
+            .await;
+        assert!(r.is_err());
+        assert_eq!(calls.load(SeqCst), 3);
+    }
+}
@@ -0,0 +1,175 @@
+use lazy_static::lazy_static;
+use log::warn;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref WARN_THROTTLE: WarnThrottle = WarnThrottle::new(Duration::from_secs(60));
+}
+
+/// The distinct suppressed messages reported with the count, e.g. the first failed URLs.
+const SAMPLES: usize = 3;
+
+#[derive(Debug, Default)]
+struct Suppressed {
+    count: u64,
+    /// The first distinct messages.
+    samples: Vec<String>,
+}
+
+impl Suppressed {
+    fn add(&mut self, message: String) {
+        self.count += 1;
+        if self.samples.len() < SAMPLES && !self.samples.contains(&message) {
+            self.samples.push(message);
+        }
+    }
+
+    fn report(&self, key: &str, window: Duration) {
+        if self.count == 0 {
+            return;
+        }
+        let more = if self.count > self.samples.len() as u64 {
+            ", ..."
+        } else {
+            ""
+        };
+        warn!(
+            "{key} x{} in last {:?}: {}{more}",
+            self.count,
+            window,
+            self.samples.join("; ")
+        );
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    start: Instant,
+    suppressed: Suppressed,
+}
+
+/// What to do with a warning.
+#[derive(Debug, PartialEq, Eq)]
+enum Decision {
+    /// Print the warning, after reporting the suppressed ones in the last window.
+    Emit,
+    Suppress,
+}
+
+/// Collapses the warnings of the same kind emitted within a time window into a count,
+/// with the first distinct messages so the ones for different items are not lost.
+#[derive(Debug)]
+pub struct WarnThrottle {
+    window_millis: AtomicU64,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl WarnThrottle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_millis: AtomicU64::new(window.as_millis() as u64),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_millis.load(SeqCst))
+    }
+
+    pub fn set_window(&self, window: Duration) {
+        self.window_millis.store(window.as_millis() as u64, SeqCst);
+    }
+
+    /// Returns the decision, and the suppressed warnings to report first.
+    fn hit(&self, key: &str, message: String, now: Instant) -> (Decision, Suppressed) {
+        let window = self.window();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.start) < window => {
+                entry.suppressed.add(message);
+                (Decision::Suppress, Suppressed::default())
+            }
+            Some(entry) => {
+                entry.start = now;
+                (Decision::Emit, std::mem::take(&mut entry.suppressed))
+            }
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        start: now,
+                        suppressed: Suppressed::default(),
+                    },
+                );
+                (Decision::Emit, Suppressed::default())
+            }
+        }
+    }
+
+    pub fn warn(&self, key: &str, message: impl Display) {
+        let message = message.to_string();
+        let (decision, suppressed) = self.hit(key, message.clone(), Instant::now());
+        if decision == Decision::Emit {
+            suppressed.report(key, self.window());
+            warn!("{message}");
+        }
+    }
+
+    /// Report the suppressed warnings that have not been reported yet.
+    pub fn flush(&self) {
+        let window = self.window();
+        for (key, entry) in self.entries.lock().unwrap().drain() {
+            entry.suppressed.report(&key, window);
+        }
+    }
+}
+
+/// Print a warning unless a warning with the same `key` was printed in the last window.
+/// The first distinct suppressed messages are printed with their count.
+pub fn warn_throttled(key: &str, message: impl Display) {
+    WARN_THROTTLE.warn(key, message);
+}
+
+pub fn flush_throttled() {
+    WARN_THROTTLE.flush();
+}
+
+pub fn set_throttle_window(window: Duration) {
+    WARN_THROTTLE.set_window(window);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapse_in_window() {
+        let t = WarnThrottle::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let hit = |key: &str, message: &str, secs| {
+            let (decision, suppressed) =
+                t.hit(key, message.to_string(), now + Duration::from_secs(secs));
+            (decision, suppressed.count, suppressed.samples)
+        };
+        assert_eq!(hit("a", "a 1", 0), (Decision::Emit, 0, vec![]));
+        assert_eq!(hit("a", "a 2", 1), (Decision::Suppress, 0, vec![]));
+        assert_eq!(hit("b", "b 1", 1), (Decision::Emit, 0, vec![]));
+        assert_eq!(hit("a", "a 2", 2), (Decision::Suppress, 0, vec![]));
+        for i in 3..6 {
+            assert_eq!(
+                hit("a", &format!("a {i}"), i),
+                (Decision::Suppress, 0, vec![])
+            );
+        }
+        let samples = ["a 2", "a 3", "a 4"].map(String::from).to_vec();
+        assert_eq!(hit("a", "a 6", 61), (Decision::Emit, 5, samples));
+    }
+}
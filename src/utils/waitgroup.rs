@@ -29,6 +29,11 @@ impl WaitGroup {
         self.0.num.fetch_add(n, SeqCst);
     }
 
+    /// Number of the tasks not done yet.
+    pub fn pending(&self) -> usize {
+        self.0.num.load(SeqCst)
+    }
+
     pub fn done(&self) {
         if self.0.num.fetch_sub(1, SeqCst) <= 1 {
             self.0.waker.wake();